@@ -274,6 +274,7 @@ pub fn crit_bench_simd(c: &mut Criterion) {
     group.finish();
 }
 
+#[cfg(feature = "nope")]
 pub fn crit_bench_1(c: &mut Criterion) {
 
     // let fen = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - "; // Position 2
@@ -747,9 +748,100 @@ pub fn crit_bench_nnue(c: &mut Criterion) {
     group.finish();
 }
 
+/// King move refreshes, with and without the refresh table
+pub fn crit_bench_refresh(c: &mut Criterion) {
+
+    let mut group = c.benchmark_group("group");
+
+    group.warm_up_time(Duration::from_secs_f64(2.0));
+
+    group.sample_size(20);
+    group.measurement_time(Duration::from_secs_f64(10.));
+
+    let ts = Tables::new();
+
+    use rchess_engine_lib::sf_compat::*;
+    use rchess_engine_lib::movegen::MoveGen;
+    use rchess_engine_lib::timer::TimeSettings;
+
+    /// Random weights if the net isn't there, refresh cost doesn't depend on them
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../nn-63376713ba63.nnue");
+    let nn = NNUE4::read_nnue(path).unwrap_or_else(|_| {
+        let mut nn = NNUE4::new_empty();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234u64);
+        simd::check::randomize(&mut nn, &mut rng);
+        nn
+    });
+
+    let mut nn_full = nn.clone();
+    nn_full.ft.refresh_table = None;
+
+    let fens = read_epd_no_bm(concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt")).unwrap();
+    let wacs: Vec<Game> = fens.iter().flat_map(|fen| {
+        Game::from_fen(&ts, fen.split(';').next().unwrap())
+    }).collect();
+
+    let king_moves: Vec<(Game,Game,Move)> = wacs.iter().flat_map(|g| {
+        let moves = MoveGen::generate_list_legal(&ts, g, None);
+        moves.into_iter().filter(|mv| mv.piece() == Some(King)).flat_map(|mv| {
+            g.make_move_unchecked(&ts, mv).ok().map(|g2| (g.clone(),g2,mv))
+        }).collect::<Vec<_>>()
+    }).collect();
+
+    for (name,nn) in [("full", &nn_full), ("table", &nn)] {
+        let mut ft = nn.ft.clone();
+        group.bench_function(&format!("nnue king moves, {}", name), |b| b.iter(|| {
+            for (g,g2,mv) in king_moves.iter() {
+                ft.reset_accum(g);
+                ft.make_move(g2, *mv);
+                ft.accum_pop();
+            }
+        }));
+    }
+
+    /// Nodes are counted from the searches, so NPS doesn't assume both search the same tree
+    let depth = 9;
+    let fens = [
+        "r4rk1/4npp1/1p1q2b1/1B2p3/1B1P2Q1/P3P3/5PP1/R3K2R b KQ - 1 1",
+        "8/k7/3p4/p2P1p2/P2P1P2/8/8/K7 w - - ",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - ",
+    ];
+    let games: Vec<Game> = fens.iter().map(|fen| Game::from_fen(&ts, fen).unwrap()).collect();
+
+    let mut nps = vec![];
+    for (name,nn) in [("full", &nn_full), ("table", &nn)] {
+        let (mut nodes, mut time) = (0u64, Duration::ZERO);
+        group.bench_function(&format!("nnue search, {}", name), |b| b.iter_custom(|iters| {
+            let mut t = Duration::ZERO;
+            for _ in 0..iters {
+                for g in games.iter() {
+                    let mut ex = Explorer::new(g.state.side_to_move, g.clone(), depth, TimeSettings::new_infinite());
+                    ex.cfg.num_threads = Some(1);
+                    ex.cfg.clear_table = true;
+                    ex.add_nnue(nn.clone());
+                    let t0 = std::time::Instant::now();
+                    let (_,stats) = ex.explore(&ts);
+                    t += t0.elapsed();
+                    nodes += stats.nodes;
+                }
+            }
+            time += t;
+            t
+        }));
+        nps.push((name, nodes as f64 / time.as_secs_f64()));
+    }
+    for (name,n) in nps.iter() {
+        eprintln!("nnue search, {:<5}: {:>10.0} nodes / sec", name, n);
+    }
+    eprintln!("table / full = {:.3}", nps[1].1 / nps[0].1);
+
+    group.finish();
+}
+
+criterion_group!(benches, crit_bench_refresh);
 // criterion_group!(benches, crit_bench_nnue);
 // criterion_group!(benches, crit_bench_simd);
-criterion_group!(benches, crit_bench_1);
+// criterion_group!(benches, crit_bench_1);
 // criterion_group!(benches, crit_bench_2);
 criterion_main!(benches);
 
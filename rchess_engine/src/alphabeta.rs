@@ -9,6 +9,7 @@ use crate::tables::*;
 use crate::evaluate::*;
 use crate::pruning::*;
 use crate::explore::*;
+use crate::sf_compat::NNEvaluator;
 #[cfg(feature = "syzygy")]
 use crate::syzygy::{SyzygyTB, Wdl, Dtz};
 
@@ -174,7 +175,7 @@ impl ExHelper {
             #[cfg(feature = "nnue")]
             if let Some(nnue) = self.nnue.as_mut() {
                 // let mut nn = nnue.borrow_mut();
-                nnue.ft.make_move(&g2, mv);
+                nnue.make_move(&g2, mv);
             }
 
             stack.move_history.push((g2.zobrist,mv));
@@ -188,7 +189,7 @@ impl ExHelper {
         #[cfg(feature = "nnue")]
         if let Some(nnue) = self.nnue.as_mut() {
             // let mut nn = nnue.borrow_mut();
-            nnue.ft.accum_pop();
+            nnue.unmake_move();
         }
         stack.move_history.pop();
     }
@@ -452,7 +453,7 @@ impl ExHelper {
 
             let mut eval = if let Some(eval) = meval { eval } else {
                 // self.eval_nn_or_hce(ts, g)
-                self.evaluate(ts, stats, g, ply, false)
+                self.evaluate_cached(ts, stats, g, ply, false)
             };
 
             if let Some(si) = msi {
@@ -472,7 +473,7 @@ impl ExHelper {
             Some(eval)
         } else {
             // let eval = self.eval_nn_or_hce(ts, g);
-            let eval = self.evaluate(ts, stats, g, ply, false);
+            let eval = self.evaluate_cached(ts, stats, g, ply, false);
             stack.with(ply, |st| st.static_eval = Some(eval));
 
             self.tt_insert_deepest_eval(g.zobrist, Some(eval));
@@ -558,7 +559,7 @@ impl ExHelper {
         if ply >= MAX_SEARCH_PLY {
             if !in_check {
                 // let score = self.eval_nn_or_hce(ts, g);
-                let score = self.evaluate(ts, stats, g, ply, false);
+                let score = self.evaluate_cached(ts, stats, g, ply, false);
                 return ABSingle(ABResult::new_null_score(score));
             } else {
                 let score = draw_value(stats);
@@ -615,6 +616,14 @@ impl ExHelper {
                 return ABHalt;
             }
 
+            /// Node limit, checked every node so the last iteration doesn't overshoot
+            if let Some(max_nodes) = self.cfg.max_nodes {
+                if self.nodes.fetch_add(1, Relaxed) + 1 >= max_nodes {
+                    self.stop.store(true, Relaxed);
+                    return ABHalt;
+                }
+            }
+
             // /// Mate found
             // {
             //     let r = self.best_mate.read();
@@ -854,6 +863,12 @@ impl ExHelper {
             }
         }
 
+        /// Step 5b. KPK bitbase, doesn't need syzygy
+        if !is_root_node && self.prune_kpk_draw(ts, g) {
+            stats!(stats.kpk_draws += 1);
+            return ABSingle(ABResult::new_single(g.last_move.unwrap(), DRAW_VALUE));
+        }
+
         /// Static eval, possibly from TT
         let static_eval = self.get_static_eval(ts, g, ply, stack, stats, meval, msi);
 
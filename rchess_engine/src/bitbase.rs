@@ -0,0 +1,236 @@
+
+use crate::types::*;
+use crate::tables::*;
+
+/// stm, 2 kings, pawn on files A-D and ranks 2-7
+const MAX_INDEX: usize = 2 * 24 * 64 * 64;
+
+/// KPK win/draw bitbase, generated by retrograde analysis along with Tables,
+/// so it's never built during a search.
+/// The strong side is White, and the pawn is mirrored to files A-D
+#[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy)]
+pub struct KPKBitbase {
+    wins:       [u64; MAX_INDEX / 64],
+}
+
+impl KPKBitbase {
+
+    /// Everything a draw, until generated
+    pub fn empty() -> Self {
+        Self { wins: [0; MAX_INDEX / 64] }
+    }
+
+    /// Squares from the strong side's perspective, see endgame::helpers::normalize.
+    /// stm is White when the strong side is to move.
+    /// true if the strong side wins
+    pub fn probe(&self, strong_king: Coord, pawn: Coord, weak_king: Coord, stm: Color) -> bool {
+        let (sk,p,wk) = if pawn.file() > 3 {
+            (strong_king.flip_horizontal(), pawn.flip_horizontal(), weak_king.flip_horizontal())
+        } else {
+            (strong_king, pawn, weak_king)
+        };
+        let idx = Self::index(stm, wk, sk, p);
+        self.wins[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn index(stm: Color, weak_king: Coord, strong_king: Coord, pawn: Coord) -> usize {
+        strong_king.inner() as usize
+            | (weak_king.inner() as usize) << 6
+            | stm.fold(0, 1) << 12
+            | (pawn.file() as usize) << 13
+            | (6 - pawn.rank() as usize) << 15
+    }
+
+}
+
+/// Generation
+impl KPKBitbase {
+
+    pub fn generate(ts: &Tables) -> Self {
+
+        let mut db: Vec<KPKPosition> = (0..MAX_INDEX)
+            .map(|idx| KPKPosition::new(ts, idx))
+            .collect();
+
+        /// Iterate until no unknown position can be classified
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for idx in 0..MAX_INDEX {
+                if db[idx].result == KPKResult::UNKNOWN {
+                    let r = db[idx].classify(ts, &db);
+                    if r != KPKResult::UNKNOWN {
+                        db[idx].result = r;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut wins = [0u64; MAX_INDEX / 64];
+        for (idx,pos) in db.iter().enumerate() {
+            if pos.result == KPKResult::WIN {
+                wins[idx / 64] |= 1 << (idx % 64);
+            }
+        }
+
+        Self { wins }
+    }
+
+}
+
+/// Bit flags, so the results of all successors can be combined
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+struct KPKResult(u8);
+
+impl KPKResult {
+    const INVALID: Self = Self(0);
+    const UNKNOWN: Self = Self(1);
+    const DRAW: Self    = Self(2);
+    const WIN: Self     = Self(4);
+}
+
+#[derive(Debug,Clone,Copy)]
+struct KPKPosition {
+    stm:        Color,
+    /// [strong, weak]
+    ksq:        [Coord; 2],
+    pawn:       Coord,
+    result:     KPKResult,
+}
+
+impl KPKPosition {
+
+    fn new(ts: &Tables, idx: usize) -> Self {
+        let strong_king = Coord::new_int(idx & 0x3f);
+        let weak_king   = Coord::new_int((idx >> 6) & 0x3f);
+        let stm         = if (idx >> 12) & 1 == 0 { White } else { Black };
+        let pawn        = Coord::new(((idx >> 13) & 3) as u8, 6 - ((idx >> 15) & 7) as u8);
+
+        let mut out = Self { stm, ksq: [strong_king, weak_king], pawn, result: KPKResult::UNKNOWN };
+
+        let pawn_attacks = ts.get_pawn(pawn).white_capture;
+        let weak_moves   = ts.get_king(weak_king);
+        let strong_moves = ts.get_king(strong_king);
+
+        let push = Coord::new(pawn.file(), pawn.rank() + 1);
+
+        if strong_king.square_dist(weak_king) <= 1
+            || strong_king == pawn
+            || weak_king == pawn
+            || (stm == White && pawn_attacks.is_one_at(weak_king))
+        {
+            out.result = KPKResult::INVALID;
+        } else if stm == White
+            && pawn.rank() == 6
+            && strong_king != push
+            && (weak_king.square_dist(push) > 1 || strong_king.square_dist(push) == 1)
+        {
+            // Promotes without being captured
+            out.result = KPKResult::WIN;
+        } else if stm == Black
+            && ((weak_moves & !(strong_moves | pawn_attacks)).is_empty()
+                || (weak_moves & !strong_moves).is_one_at(pawn))
+        {
+            // Stalemate, or the pawn is captured
+            out.result = KPKResult::DRAW;
+        }
+
+        out
+    }
+
+    /// A position is won if the strong side has a move to a win, or every move by the weak side
+    /// leads to a win. Moves into invalid positions are ignored.
+    fn classify(&self, ts: &Tables, db: &[KPKPosition]) -> KPKResult {
+        let (good,bad) = self.stm.fold(
+            (KPKResult::WIN, KPKResult::DRAW),
+            (KPKResult::DRAW, KPKResult::WIN));
+
+        let [strong_king,weak_king] = self.ksq;
+        let mut r = 0;
+
+        if self.stm == White {
+            for sq in ts.get_king(strong_king).into_iter() {
+                r |= db[KPKBitbase::index(Black, weak_king, sq, self.pawn)].result.0;
+            }
+
+            if self.pawn.rank() < 6 {
+                let push = Coord::new(self.pawn.file(), self.pawn.rank() + 1);
+                r |= db[KPKBitbase::index(Black, weak_king, strong_king, push)].result.0;
+
+                if self.pawn.rank() == 1 && push != strong_king && push != weak_king {
+                    let push2 = Coord::new(self.pawn.file(), 3);
+                    r |= db[KPKBitbase::index(Black, weak_king, strong_king, push2)].result.0;
+                }
+            }
+        } else {
+            for sq in ts.get_king(weak_king).into_iter() {
+                r |= db[KPKBitbase::index(White, sq, strong_king, self.pawn)].result.0;
+            }
+        }
+
+        if r & good.0 != 0 {
+            good
+        } else if r & KPKResult::UNKNOWN.0 != 0 {
+            KPKResult::UNKNOWN
+        } else {
+            bad
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endgame::{EndGame,EndGameType};
+
+    #[test]
+    fn kpk_win_draw_counts() {
+        let ts = Tables::new();
+
+        let valid = (0..MAX_INDEX)
+            .filter(|&idx| KPKPosition::new(&ts, idx).result != KPKResult::INVALID)
+            .count();
+        let wins = ts.kpk_bitbase.wins.iter().map(|w| w.count_ones() as usize).sum::<usize>();
+
+        /// Same as the stockfish bitbase
+        assert_eq!(wins, 111282);
+        assert_eq!(valid - wins, 54394);
+    }
+
+    #[test]
+    fn kpk_textbook_positions() {
+        let ts = Tables::new();
+
+        let cases = [
+            /// King on the 6th in front of the pawn
+            ("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1", true),
+            ("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1", true),
+            /// King in front of the pawn, wins only with the opposition
+            ("8/4k3/8/4K3/4P3/8/8/8 b - - 0 1", true),
+            ("8/4k3/8/4K3/4P3/8/8/8 w - - 0 1", false),
+            /// Rook pawn with the weak king in the corner
+            ("k7/8/1K6/P7/8/8/8/8 w - - 0 1",   false),
+            ("k7/8/8/P7/8/8/8/7K b - - 0 1",    false),
+            /// Outside the square of the pawn, unless the weak side moves first
+            ("8/8/8/P3k3/8/8/8/K7 w - - 0 1",   true),
+            ("8/8/8/P3k3/8/8/8/K7 b - - 0 1",   false),
+        ];
+
+        for (fen, win) in cases {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            let mut gs = vec![(g.clone(), White), (g.flip_sides(&ts), Black)];
+            if let Some(g2) = g.mirror_horizontal(&ts) {
+                gs.push((g2.flip_sides(&ts), Black));
+                gs.push((g2, White));
+            }
+            for (g, strong) in gs {
+                let score = EndGameType::KPvK.evaluate(&ts, &g, strong);
+                assert_eq!(score > KNOWN_WIN_VALUE, win, "{}", g.to_fen());
+                if !win { assert_eq!(score, DRAW_VALUE); }
+            }
+        }
+    }
+
+}
@@ -476,7 +476,7 @@ impl BitBoard {
     pub const fn shift_dir(&self, d: D) -> Self {
         match d {
             D::N  => BitBoard(self.0.overflowing_shl(8).0),
-            D::NE => BitBoard(self.0.overflowing_shl(9).0),
+            D::NE => BitBoard(self.0.overflowing_shl(9).0 & !(MASK_FILES[0].0)),
             D::E  => BitBoard(self.0.overflowing_shl(1).0 & !(MASK_FILES[0].0)),
             D::SE => BitBoard(self.0.overflowing_shr(7).0 & !(MASK_FILES[0].0)),
             D::S  => BitBoard(self.0.overflowing_shr(8).0),
@@ -613,3 +613,38 @@ impl std::fmt::Debug for BitBoard {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each square shifted one step, nothing wraps around to the other side of the board
+    #[test]
+    fn shift_dir_all_squares() {
+        let dirs = [
+            (D::N,0,1), (D::NE,1,1), (D::E,1,0), (D::SE,1,-1),
+            (D::S,0,-1), (D::SW,-1,-1), (D::W,-1,0), (D::NW,-1,1),
+        ];
+        for sq in 0u8..64 {
+            let c0 = Coord::new_int(sq);
+            let bb = BitBoard::single(c0);
+            for &(d,dx,dy) in dirs.iter() {
+                let (x,y) = (c0.file() as i8 + dx, c0.rank() as i8 + dy);
+                let expected = if (0..8).contains(&x) && (0..8).contains(&y) {
+                    BitBoard::single(Coord::new(x as u8, y as u8))
+                } else {
+                    BitBoard::empty()
+                };
+                assert_eq!(bb.shift_dir(d), expected, "{:?} {:?}", c0, d);
+            }
+        }
+
+        /// Whole board, e.g. pawn attacks
+        let all = BitBoard(!0);
+        assert_eq!(all.shift_dir(D::NE), all & !MASK_FILES[0] & !BitBoard::mask_rank(0));
+        assert_eq!(all.shift_dir(D::NW), all & !MASK_FILES[7] & !BitBoard::mask_rank(0));
+        assert_eq!(all.shift_dir(D::SE), all & !MASK_FILES[0] & !BitBoard::mask_rank(7));
+        assert_eq!(all.shift_dir(D::SW), all & !MASK_FILES[7] & !BitBoard::mask_rank(7));
+    }
+
+}
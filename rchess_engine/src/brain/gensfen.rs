@@ -2,19 +2,24 @@
 use crate::evmap_tables;
 use crate::explore::*;
 use crate::opening_book::*;
-use crate::pawn_hash_table::PHTableFactory;
-use crate::qsearch::exhelper_once;
 use crate::tables::*;
 use crate::types::*;
 use crate::evaluate::*;
 use crate::alphabeta::*;
+use crate::movegen::MoveGen;
+use crate::game_record::{GameRecord,GameOver,DrawReason};
+use crate::lockless_map::TransTable;
+use crate::searchstats::SearchStats;
+use crate::sf_compat::{NNEval,NNEvaluator};
+use crate::timer::*;
 
 pub use self::td_tree::*;
 pub use self::td_builder::*;
 pub use self::sprt::*;
 
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap,HashSet};
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
 use std::time::{Instant,Duration};
 
 use serde::{Serialize,Deserialize};
@@ -23,7 +28,8 @@ use rand::{prelude::{StdRng,SliceRandom},Rng,SeedableRng};
 use rand::distributions::{Uniform,uniform::SampleUniform};
 
 use crossbeam::channel::{Sender,Receiver,RecvError,TryRecvError};
-use std::sync::atomic::{Ordering,AtomicU64};
+use std::sync::atomic::{Ordering,AtomicBool,AtomicU64};
+use parking_lot::Mutex;
 use rayon::prelude::*;
 
 mod td_tree {
@@ -87,314 +93,618 @@ mod td_tree {
 }
 
 mod td_builder {
-    use std::collections::VecDeque;
-
     use super::*;
     use crate::builder_field;
+    use crate::brain::binpack::{BPEntry,BPWriter,BPReader};
+    use crate::brain::plain::{PlainReader,PlainWriter};
+
+    use std::io::{BufWriter,BufReader};
+    use std::fs::File;
+
+    #[derive(Debug,Eq,PartialEq,Clone,Copy)]
+    pub enum SfenFormat {
+        Binpack,
+        /// TrainingData, bincode
+        Bincode,
+        /// Stockfish text format
+        Plain,
+    }
+
+    impl SfenFormat {
+        pub fn extension(self) -> &'static str {
+            match self {
+                Self::Binpack => "binpack",
+                Self::Bincode => "bin",
+                Self::Plain   => "plain",
+            }
+        }
 
-    // use derive_builder::Builder;
-    // #[derive(Debug,Builder,PartialEq,Clone)]
-    // #[builder(pattern = "owned")]
-    // #[builder(setter(prefix = "with"))]
+        pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+            match path.as_ref().extension()?.to_str()? {
+                "binpack" => Some(Self::Binpack),
+                "bin"     => Some(Self::Bincode),
+                "plain"   => Some(Self::Plain),
+                _         => None,
+            }
+        }
+    }
 
+    /// Self play with fixed depth or nodes per move.
+    /// Output is a directory of numbered files, see SfenDir
     #[derive(Debug,Clone)]
     pub struct TDBuilder {
-        // opening:         Option<OBSelection>,
-        min_depth:       Depth,
-        max_depth:       Depth,
-        nodes_per_pos:   Option<u64>,
-        num_positions:   Option<u64>,
-        num_threads:     usize,
-        time:            f64,
-        print:           bool,
+        max_depth:          Depth,
+        nodes_per_pos:      Option<u64>,
+        /// Total, including positions already in the output dir
+        num_positions:      Option<u64>,
+        num_threads:        usize,
+        /// Moves taken from the book, then random moves
+        book_plies:         usize,
+        random_plies:       usize,
+        /// Adjudicated as a draw after this many searched moves
+        max_plies:          usize,
+        hash_size_mb:       usize,
+        positions_per_file: u64,
+        format:             SfenFormat,
+        seed:               u64,
+        syzygy_path:        Option<PathBuf>,
+        nnue_path:          Option<PathBuf>,
+        print:              bool,
     }
 
     impl TDBuilder {
         pub fn new() -> Self {
             Self {
-                // opening:        None,
-                min_depth:      2,
-                max_depth:      5,
-                nodes_per_pos:  None,
-                num_positions:  None,
-                num_threads:    1,
-                time:           0.5,
-                print:          true,
+                max_depth:          8,
+                nodes_per_pos:      None,
+                num_positions:      None,
+                num_threads:        1,
+                book_plies:         8,
+                random_plies:       8,
+                max_plies:          400,
+                hash_size_mb:       16,
+                positions_per_file: 1_000_000,
+                format:             SfenFormat::Binpack,
+                seed:               1234,
+                syzygy_path:        None,
+                nnue_path:          None,
+                print:              true,
             }
         }
-        // builder_field!(opening, Option<OBSelection>);
-        builder_field!(min_depth, Depth);
         builder_field!(max_depth, Depth);
         builder_field!(nodes_per_pos, Option<u64>);
         builder_field!(num_positions, Option<u64>);
         builder_field!(num_threads, usize);
-        builder_field!(time, f64);
+        builder_field!(book_plies, usize);
+        builder_field!(random_plies, usize);
+        builder_field!(max_plies, usize);
+        builder_field!(hash_size_mb, usize);
+        builder_field!(positions_per_file, u64);
+        builder_field!(format, SfenFormat);
+        builder_field!(seed, u64);
+        builder_field!(syzygy_path, Option<PathBuf>);
+        builder_field!(nnue_path, Option<PathBuf>);
         builder_field!(print, bool);
     }
 
-    /// Generate single
-    impl TDBuilder {
+    /// Shared between workers and the writer
+    #[derive(Debug,Default)]
+    struct SfenShared {
+        stop:        AtomicBool,
+        games:       AtomicU64,
+        positions:   AtomicU64,
+        /// Keys of positions already recorded, in this run or a previous one
+        seen:        Mutex<HashSet<u64>>,
+    }
+
+    /// Zobrist without en passant, a binpack only keeps it if the capture is legal
+    pub(crate) fn dedup_key(ts: &Tables, g: &Game) -> u64 {
+        match g.state.en_passant {
+            Some(ep) => g.zobrist.update_ep(ts, ep).0,
+            None     => g.zobrist.0,
+        }
+    }
 
-        // fn recurse(&self, ts: &Tables, mut ex: &mut Explorer, )
+    /// Side to move's WDL, None if it isn't in the tables.
+    /// WDL is only exact right after a capture or pawn move, with a nonzero
+    /// 50-move counter a win can really be a draw
+    #[cfg(feature = "syzygy")]
+    pub(crate) fn probe_wdl_exact(ts: &Tables, ex: &Explorer, g: &Game) -> Option<crate::syzygy::Wdl> {
+        let tb = ex.syzygy.as_ref()?;
+        if g.halfmove != 0 || g.all_occupied().popcount() > 7 {
+            return None;
+        }
+        tb.probe_wdl(ts, g).ok()
+    }
 
-        fn watch_sfen(
-            t0:       Instant,
-            sfen_n:   Arc<(AtomicU64,AtomicU64)>,
-            rx:       Receiver<TrainingData>,
-        ) {
+    /// Output directory.
+    /// Finished files are "sfen_00000.binpack", "sfen_00001.binpack", ...
+    /// The file being written has a ".tmp" suffix, and is renamed when it's complete,
+    /// so an interrupted run loses at most one file and can be restarted with the same dir.
+    #[derive(Debug,Clone)]
+    pub struct SfenDir {
+        pub dir:        PathBuf,
+        pub format:     SfenFormat,
+        /// Index of the next file to write
+        pub next:       usize,
+        /// In finished files
+        pub positions:  u64,
+    }
+
+    impl SfenDir {
+
+        pub fn file_name(&self, n: usize) -> PathBuf {
+            self.dir.join(format!("sfen_{:05}.{}", n, self.format.extension()))
+        }
+
+        fn parse_index(&self, path: &Path) -> Option<usize> {
+            if SfenFormat::from_path(path) != Some(self.format) { return None; }
+            let stem = path.file_stem()?.to_str()?;
+            stem.strip_prefix("sfen_")?.parse().ok()
+        }
+
+        /// Creates dir if needed, also returns the keys of every recorded position
+        pub fn open<P: AsRef<Path>>(
+            ts:       &Tables,
+            dir:      P,
+            format:   SfenFormat,
+        ) -> std::io::Result<(Self, HashSet<u64>)> {
+            std::fs::create_dir_all(&dir)?;
+            let mut out = Self {
+                dir:        dir.as_ref().to_path_buf(),
+                format,
+                next:       0,
+                positions:  0,
+            };
+            let mut seen = HashSet::default();
+
+            let mut files = vec![];
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if let Some(n) = out.parse_index(&path) {
+                    files.push((n,path));
+                }
+            }
+            files.sort();
+
+            for (n,path) in files.into_iter() {
+                out.next = out.next.max(n + 1);
+                match format {
+                    SfenFormat::Binpack => {
+                        for e in BPReader::open(ts, &path)? {
+                            seen.insert(dedup_key(ts, &e?.game));
+                            out.positions += 1;
+                        }
+                    },
+                    SfenFormat::Plain   => {
+                        for e in PlainReader::open(ts, &path)? {
+                            seen.insert(dedup_key(ts, &e?.game));
+                            out.positions += 1;
+                        }
+                    },
+                    SfenFormat::Bincode => {
+                        for td in TrainingData::load_all(&path, None)?.iter() {
+                            td.for_each_position(ts, |g,te| {
+                                if !te.skip {
+                                    seen.insert(dedup_key(ts, g));
+                                    out.positions += 1;
+                                }
+                            });
+                        }
+                    },
+                }
+            }
+
+            Ok((out, seen))
+        }
+    }
+
+    enum SfenWriterInner<'a> {
+        Binpack(BPWriter<'a, BufWriter<File>>),
+        Bincode(BufWriter<File>),
+        Plain(PlainWriter<BufWriter<File>>),
+    }
+
+    /// One output file
+    pub(crate) struct SfenWriter<'a> {
+        ts:          &'a Tables,
+        w:           SfenWriterInner<'a>,
+        path:        PathBuf,
+        positions:   u64,
+    }
+
+    impl<'a> SfenWriter<'a> {
+        fn tmp_path(path: &Path) -> PathBuf {
+            let mut s = path.as_os_str().to_owned();
+            s.push(".tmp");
+            PathBuf::from(s)
+        }
+
+        pub(crate) fn create(ts: &'a Tables, dir: &SfenDir, n: usize) -> std::io::Result<Self> {
+            let path = dir.file_name(n);
+            let tmp = Self::tmp_path(&path);
+            let w = match dir.format {
+                SfenFormat::Binpack => SfenWriterInner::Binpack(BPWriter::create(ts, &tmp)?),
+                SfenFormat::Bincode => SfenWriterInner::Bincode(BufWriter::new(File::create(&tmp)?)),
+                SfenFormat::Plain   => SfenWriterInner::Plain(PlainWriter::create(&tmp)?),
+            };
+            Ok(Self { ts, w, path, positions: 0 })
+        }
+
+        fn write(&mut self, td: &TrainingData) -> std::io::Result<u64> {
+            let n = match &mut self.w {
+                SfenWriterInner::Binpack(w) => {
+                    let mut n = 0;
+                    for e in BPEntry::from_training_data(self.ts, td) {
+                        w.write_entry(&e)?;
+                        n += 1;
+                    }
+                    n
+                },
+                SfenWriterInner::Bincode(w) => {
+                    bincode::serialize_into(w, td).map_err(|e| {
+                        std::io::Error::new(std::io::ErrorKind::Other, e)
+                    })?;
+                    td.moves.iter().filter(|te| !te.skip).count() as u64
+                },
+                SfenWriterInner::Plain(w) => {
+                    let mut n = 0;
+                    for e in BPEntry::from_training_data(self.ts, td) {
+                        w.write_entry(&e)?;
+                        n += 1;
+                    }
+                    n
+                },
+            };
+            self.positions += n;
+            Ok(n)
+        }
+
+        /// Single position, a bincode file gets a TrainingData for each
+        pub(crate) fn write_entry(&mut self, e: &BPEntry) -> std::io::Result<()> {
+            match &mut self.w {
+                SfenWriterInner::Binpack(w) => w.write_entry(e)?,
+                SfenWriterInner::Plain(w)   => w.write_entry(e)?,
+                SfenWriterInner::Bincode(w) => {
+                    for td in BPEntry::to_training_data(self.ts, std::iter::once(e.clone())) {
+                        bincode::serialize_into(&mut *w, &td).map_err(|e| {
+                            std::io::Error::new(std::io::ErrorKind::Other, e)
+                        })?;
+                    }
+                },
+            }
+            self.positions += 1;
+            Ok(())
+        }
+
+        pub(crate) fn positions(&self) -> u64 {
+            self.positions
+        }
+
+        /// Empty files are removed
+        pub(crate) fn finish(self) -> std::io::Result<()> {
+            let tmp = Self::tmp_path(&self.path);
+            match self.w {
+                SfenWriterInner::Binpack(w) => { w.finish()?; },
+                SfenWriterInner::Bincode(mut w) => {
+                    use std::io::Write;
+                    w.flush()?;
+                },
+                SfenWriterInner::Plain(w) => { w.finish()?; },
+            }
+            if self.positions == 0 {
+                std::fs::remove_file(&tmp)
+            } else {
+                std::fs::rename(&tmp, &self.path)
+            }
+        }
+    }
+
+    /// Generate
+    impl TDBuilder {
+
+        fn watch_sfen(t0: Instant, shared: &SfenShared, prev: u64) {
             loop {
-                std::thread::sleep(std::time::Duration::from_millis(1000));
+                for _ in 0..10 {
+                    std::thread::sleep(Duration::from_millis(100));
+                    if shared.stop.load(Ordering::Relaxed) { return; }
+                }
 
-                let sfens = sfen_n.0.load(Ordering::Relaxed);
-                let moves = sfen_n.1.load(Ordering::Relaxed);
+                let games     = shared.games.load(Ordering::Relaxed);
+                let positions = shared.positions.load(Ordering::Relaxed);
 
                 let t1 = t0.elapsed().as_secs_f64();
-                eprintln!("{:>6} games, {:>6} sfens, {:.1}s, avg {:.1} sfens / sec, {:.1} moves / sec",
-                          sfens, moves, t1,
-                          sfens as f64 / t1,
-                          moves as f64 / t1,
+                eprintln!("{:>6} games, {:>8} positions ({} total), {:.1}s, {:.1} positions / sec",
+                          games, positions, positions + prev, t1,
+                          positions as f64 / t1,
                 );
-
-                match rx.try_recv() {
-                    Err(TryRecvError::Disconnected) => break,
-                    _                               => {},
-                }
             }
         }
 
-        pub fn do_explore<P: AsRef<Path> + Send>(
+        /// Returns the number of positions written
+        pub fn do_explore<P: AsRef<Path>>(
             &self,
-            ts:         &'static Tables,
-            ob:         &OpeningBook,
-            count:      u64,
-            print:      bool,
-            mut rng:    StdRng,
-            save_bin:   bool,
-            path:       P,
-        ) -> std::io::Result<()> {
+            ts:         &Tables,
+            ob:         Option<&OpeningBook>,
+            dir:        P,
+        ) -> std::io::Result<u64> {
+
+            let (dir, seen) = SfenDir::open(ts, dir, self.format)?;
+
+            let remaining = match self.num_positions {
+                Some(n) if n <= dir.positions => {
+                    if self.print {
+                        eprintln!("{} positions already in {:?}", dir.positions, dir.dir);
+                    }
+                    return Ok(0);
+                },
+                Some(n) => Some(n - dir.positions),
+                None    => None,
+            };
+
+            let nnue = match &self.nnue_path {
+                Some(path) => Some(NNEval::read(path).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+                })?),
+                None       => None,
+            };
+
+            if self.print {
+                eprintln!("gensfen: {:?}, {} positions already, starting at file {}",
+                          dir.dir, dir.positions, dir.next);
+            }
 
-            let (tx,rx): (Sender<TrainingData>,
-                          Receiver<TrainingData>) =
-                crossbeam::channel::unbounded();
+            let shared = SfenShared {
+                seen: Mutex::new(seen),
+                ..Default::default()
+            };
 
-            let sfen_n = Arc::new((AtomicU64::new(0), AtomicU64::new(0)));
+            let (tx,rx): (Sender<TrainingData>, Receiver<TrainingData>) =
+                crossbeam::channel::bounded(self.num_threads * 4);
 
             let t0 = Instant::now();
+            let mut result = Ok(0);
 
             crossbeam::scope(|s| {
-                s.spawn(|_| Self::save_listener(save_bin, count, path, rx.clone()));
-
-                if print {
-                    s.spawn(|_| Self::watch_sfen(t0, sfen_n.clone(), rx.clone()));
+                if self.print {
+                    s.spawn(|_| Self::watch_sfen(t0, &shared, dir.positions));
                 }
 
                 for id in 0..self.num_threads {
-                    let rng2: u64 = rng.gen();
-                    let mut rng2: StdRng = SeedableRng::seed_from_u64(1234u64);
+                    /// Different games after resuming
+                    let seed = self.seed ^ ((dir.next as u64) << 32) ^ id as u64;
+                    let rng: StdRng = SeedableRng::seed_from_u64(seed);
                     let tx2 = tx.clone();
+                    let shared = &shared;
+                    let nnue = nnue.clone();
+                    /// 4 MB is needed to prevent stack overflow
                     s.builder()
-                        .spawn(|_| self._do_explore(ts, ob, count, rng2, sfen_n.clone(), tx2))
+                        .stack_size(1024 * 1024 * 4)
+                        .spawn(move |_| self._do_explore(ts, ob, nnue, rng, shared, tx2))
                         .unwrap();
                 }
                 drop(tx);
-            }).unwrap_or_else(|_| ());
 
-            Ok(())
+                result = self.save_listener(ts, &dir, remaining, &shared, rx);
+                shared.stop.store(true, Ordering::SeqCst);
+            }).unwrap();
+
+            if self.print {
+                eprintln!("gensfen done, {} games, {} positions in {:.1}s",
+                          shared.games.load(Ordering::SeqCst),
+                          shared.positions.load(Ordering::SeqCst),
+                          t0.elapsed().as_secs_f64());
+            }
+
+            result
         }
 
-        fn save_listener<P: AsRef<Path>>(
-            save_bin:   bool,
-            count:      u64,
-            path:       P,
+        fn save_listener(
+            &self,
+            ts:         &Tables,
+            dir:        &SfenDir,
+            remaining:  Option<u64>,
+            shared:     &SfenShared,
             rx:         Receiver<TrainingData>,
-        ) {
-            // let mut out = vec![];
-            let mut file = std::fs::File::create(path).unwrap();
-            let mut n = 0;
+        ) -> std::io::Result<u64> {
+            let mut n_file = dir.next;
+            let mut w = SfenWriter::create(ts, dir, n_file)?;
+            let mut total = 0;
+
+            while let Ok(td) = rx.recv() {
+                let n = w.write(&td)?;
+                total += n;
+                shared.games.fetch_add(1, Ordering::Relaxed);
+                shared.positions.fetch_add(n, Ordering::Relaxed);
+
+                if remaining.map_or(false, |r| total >= r) {
+                    break;
+                }
 
-            loop {
-                match rx.recv() {
-                    Ok(td) => {
-                        // out.push(td);
-                        // eprintln!("rx, len out = {:?}", out.len());
-                        // eprintln!("rx, len out = {:?}", n);
-                        n += 1;
-                        match TrainingData::save_into(save_bin, &mut file, &td) {
-                            Ok(_)  => {},
-                            Err(e) => {
-                                eprintln!("save_all error = {:?}", e);
-                            },
-                        }
-                        if n >= count {
-                            break;
-                        }
-                    },
-                    // Err(TryRecvError::Empty)    => {
-                    //     std::thread::sleep(std::time::Duration::from_millis(1));
-                    // },
-                    // Err(TryRecvError::Disconnected)    => {
-                    Err(_)    => {
-                        trace!("Breaking save listener loop (Disconnect)");
-                        break;
-                    },
+                if w.positions >= self.positions_per_file {
+                    w.finish()?;
+                    n_file += 1;
+                    w = SfenWriter::create(ts, dir, n_file)?;
                 }
             }
+
+            /// workers waiting to send are unblocked by the dropped receiver
+            shared.stop.store(true, Ordering::SeqCst);
+            drop(rx);
+
+            w.finish()?;
+            Ok(total)
         }
 
-        pub fn _do_explore(
+        /// Book moves then random moves, None if the game ended
+        fn start_position(
             &self,
-            ts:         &'static Tables,
-            ob:         &OpeningBook,
-            count:      u64,
-            mut rng:    StdRng,
-            sfen_n:     Arc<(AtomicU64,AtomicU64)>,
-            tx:         Sender<TrainingData>,
-        ) {
-            unimplemented!()
+            ts:         &Tables,
+            ob:         Option<&OpeningBook>,
+            rng:        &mut StdRng,
+        ) -> Option<(Game,Vec<Move>)> {
+            let (mut g, mut opening) = match ob {
+                Some(ob) if self.book_plies > 0 => {
+                    let mut s = OBSelection::new_random_seeded(rng.gen());
+                    ob.start_game(ts, Some(self.book_plies), &mut s)?
+                },
+                _ => (Game::from_fen(ts, STARTPOS).unwrap(), vec![]),
+            };
+
+            for _ in 0..self.random_plies {
+                let mvs = MoveGen::generate_list_legal(ts, &g, None);
+                let mv = *mvs.choose(rng)?;
+                g = g.make_move_unchecked(ts, mv).ok()?;
+                opening.push(mv);
+            }
+
+            if GameRecord::new(ts, g).adjudicate(ts).is_some() {
+                return None;
+            }
+            Some((g,opening))
+        }
+
+        #[cfg(feature = "syzygy")]
+        fn adjudicate_syzygy(ts: &Tables, ex: &Explorer, g: &Game) -> Option<TDOutcome> {
+            use crate::syzygy::Wdl;
+            match probe_wdl_exact(ts, ex, g)? {
+                Wdl::Win  => Some(TDOutcome::Win(g.state.side_to_move)),
+                Wdl::Loss => Some(TDOutcome::Win(!g.state.side_to_move)),
+                _         => Some(TDOutcome::Draw),
+            }
+        }
+
+        fn new_explorer(&self, ts: &Tables, nnue: Option<NNEval>) -> Explorer {
+            let g = Game::from_fen(ts, STARTPOS).unwrap();
+            let mut ex = Explorer::new(White, g, self.max_depth, TimeSettings::new_infinite());
+            ex.cfg.num_threads = Some(1);
+            ex.cfg.max_nodes   = self.nodes_per_pos;
+            #[cfg(feature = "lockless_hashmap")]
+            {
+                ex.ptr_tt = Arc::new(TransTable::new_mb(self.hash_size_mb));
+            }
+            if let Some(nn) = nnue {
+                ex.add_nnue(nn);
+            }
+            if let Some(path) = &self.syzygy_path {
+                if let Err(e) = ex.load_syzygy(path) {
+                    eprintln!("gensfen: couldn't load syzygy from {:?}: {:?}", path, e);
+                }
+            }
+            ex
         }
 
-        #[cfg(feature = "nope")]
-        pub fn _do_explore(
+        fn _do_explore(
             &self,
-            ts:         &'static Tables,
-            ob:         &OpeningBook,
-            count:      u64,
+            ts:         &Tables,
+            ob:         Option<&OpeningBook>,
+            nnue:       Option<NNEval>,
             mut rng:    StdRng,
-            sfen_n:     Arc<(AtomicU64,AtomicU64)>,
+            shared:     &SfenShared,
             tx:         Sender<TrainingData>,
         ) {
+            let mut ex = self.new_explorer(ts, nnue);
 
-            let opening_ply = 16;
-
-            let mut g = Game::from_fen(ts, STARTPOS).unwrap();
-            let timesettings = TimeSettings::new_f64(0.0, self.time);
-            // let mut ex = Explorer::new(g.state.side_to_move, g.clone(), self.max_depth, timesettings);
-            let mut ex_white = Explorer::new(g.state.side_to_move, g.clone(), self.max_depth, timesettings);
-            let mut ex_black = Explorer::new(g.state.side_to_move, g.clone(), self.max_depth, timesettings);
-            // ex.load_syzygy("/home/me/code/rust/rchess/tables/syzygy/").unwrap();
-            ex_white.load_syzygy("/home/me/code/rust/rchess/tables/syzygy/").unwrap();
-            ex_black.load_syzygy("/home/me/code/rust/rchess/tables/syzygy/").unwrap();
-
-            // ex.cfg.num_threads = Some(1);
-            ex_white.cfg.num_threads = Some(1);
-            ex_black.cfg.num_threads = Some(1);
-            // ex.cfg.clear_table = false;
-            ex_white.cfg.clear_table = false;
-            ex_black.cfg.clear_table = false;
-
-            // let mut ex_white = ex.clone();
-            // let mut ex_black = ex.clone();
-            ex_black.side = Black;
-
-            let mut exs = [ex_white, ex_black];
+            while !shared.stop.load(Ordering::Relaxed) {
 
-            let mut s = OBSelection::Random(rng);
-
-            'outer: for gk in 0..count {
-
-                let (mut g,opening) = ob.start_game(&ts, Some(opening_ply), &mut s).unwrap();
-
-                let mut ex;
-
-                // let fen = "7k/8/8/8/8/8/4Q3/7K w - - 0 1"; // Queen endgame, #7
-                // let mut g = Game::from_fen(ts, fen).unwrap();
+                let (g0,opening) = match self.start_position(ts, ob, &mut rng) {
+                    Some(x) => x,
+                    None    => continue,
+                };
 
-                let mut ply = opening.len();
+                #[cfg(feature = "lockless_hashmap")]
+                ex.ptr_tt.clear_table();
+                #[cfg(not(feature = "lockless_hashmap"))]
+                ex.clear_tt();
+                ex.new_game(ts, g0);
 
-                let mut moves = vec![];
-                // let mut result: Option<TDOutcome> = None;
+                let mut record = GameRecord::new(ts, g0);
+                let mut moves: Vec<TDEntry> = vec![];
+                /// Index into moves and dedup key of each kept position,
+                /// only marked as seen if the game is sent
+                let mut keys: Vec<(usize,u64)> = vec![];
 
                 let result: Option<TDOutcome> = 'game: loop {
+                    if shared.stop.load(Ordering::Relaxed) { break 'game None; }
+
+                    if let Some(end) = record.adjudicate(ts) {
+                        break 'game Some(match end {
+                            GameOver::Checkmate { win }          => TDOutcome::Win(win),
+                            GameOver::Draw(DrawReason::Stalemate) => TDOutcome::Stalemate,
+                            GameOver::Draw(_)                    => TDOutcome::Draw,
+                        });
+                    }
 
-                    ex = &mut exs[g.state.side_to_move];
-
-                    assert_eq!(ex.side, g.state.side_to_move);
-
-                    ex.game = g.clone();
-                    // ex.update_game(g.clone());
-                    ex.clear_tt();
-
-                    let (res,_,stats) = ex.lazy_smp_2(ts);
-
-                    match res.get_result() {
-                        Some(res) => {
+                    if moves.len() >= self.max_plies {
+                        break 'game Some(TDOutcome::Draw);
+                    }
 
-                            let skip = false;
+                    #[cfg(feature = "syzygy")]
+                    if let Some(res) = Self::adjudicate_syzygy(ts, &ex, record.current()) {
+                        break 'game Some(res);
+                    }
 
-                            if !skip { sfen_n.1.fetch_add(1, Ordering::SeqCst); }
-                            let e = TDEntry::new(res.mv, res.score, skip);
+                    let g = *record.current();
+                    ex.update_game_record(&record);
+                    let (res,_,_) = ex.lazy_smp_2(ts);
 
-                            moves.push(e);
+                    let (mv,score) = match res.get_result() {
+                        Some(ABResult { mv: Some(mv), score }) => (mv,score),
+                        _ => {
+                            trace!("no result: {:?}, {:?}", g.to_fen(), res);
+                            break 'game None;
+                        },
+                    };
 
-                            match g.make_move_unchecked(ts, res.mv) {
-                                Ok(g2) => {
+                    /// Quiet positions only, each position once
+                    let key = dedup_key(ts, &g);
+                    let skip = g.state.in_check
+                        || mv.filter_all_captures()
+                        || keys.iter().any(|&(_,k)| k == key)
+                        || shared.seen.lock().contains(&key);
 
-                                    if res.score > CHECKMATE_VALUE - 100 {
-                                        trace!("score > mate value, res = {:?}", res);
-                                        break 'game Some(TDOutcome::Win(!g.state.side_to_move));
-                                    } else if res.score.abs() > CHECKMATE_VALUE - 100 {
-                                        trace!("score < mate value, res = {:?}", res);
-                                        break 'game Some(TDOutcome::Win(g.state.side_to_move));
-                                    }
+                    if !skip {
+                        keys.push((moves.len(), key));
+                    }
+                    moves.push(TDEntry::new(mv, score, skip));
 
-                                    g = g2;
-                                },
-                                Err(e) => {
-                                    let e = match e {
-                                        GameEnd::Checkmate { win } => TDOutcome::Win(win),
-                                        GameEnd::Stalemate         => TDOutcome::Stalemate,
-                                        _                          => panic!("GameEnd other ?? {:?}", e),
-                                    };
-                                    break 'game Some(e);
-                                },
-                            }
-                        },
-                        None => {
-                            trace!("None res = {:?}", res);
-                            break 'game None;
-                        },
+                    /// Mate found, score the game without playing it out
+                    if score.abs() > CHECKMATE_VALUE - (MAX_SEARCH_PLY as Score * 2) {
+                        let side = g.state.side_to_move;
+                        break 'game Some(TDOutcome::Win(if score > 0 { side } else { !side }));
                     }
 
+                    if record.push(ts, mv).is_err() {
+                        debug!("bad move from search: {:?}, {:?}", g.to_fen(), mv);
+                        break 'game None;
+                    }
                 };
 
-                debug!("game done, result = {:?}", result);
-
-                let n = moves.iter().filter(|x| !x.skip).count();
-                sfen_n.0.fetch_add(1, Ordering::SeqCst);
-
                 if let Some(result) = result {
-                    // let opening = opening.iter().map(|&mv| PackedMove::convert(mv)).collect();
-                    let mut td = TrainingData {
+                    /// Another thread may have recorded the same position since
+                    {
+                        let mut seen = shared.seen.lock();
+                        for &(i,key) in keys.iter() {
+                            if !seen.insert(key) {
+                                moves[i].skip = true;
+                            }
+                        }
+                    }
+                    let td = TrainingData {
                         result,
                         opening,
                         moves,
+                        start: None,
                     };
-
-                    match tx.send(td) {
-                        Ok(_)  => {},
-                        // Err(e) => eprintln!("tx.send error = {:?}", e),
-                        Err(e) => {},
+                    if tx.send(td).is_err() {
+                        break;
                     }
-                } else {
-                    trace!("result = None ??");
-                }
-
-                let n_fens = sfen_n.0.load(Ordering::SeqCst);
-                if n_fens > count { break 'outer; }
-
-                if let Some(n) = self.num_positions {
-                    let n_moves = sfen_n.1.load(Ordering::SeqCst);
-                    if n_moves > n { break 'outer; }
                 }
-
-                // eprintln!("_do_explore: breaking outer loop");
-                // break 'outer;
             }
         }
 
     }
 
-
 }
 
 mod sprt {
@@ -465,6 +775,8 @@ pub struct TrainingData {
     pub opening:      Vec<Move>,
     // pub moves:        TDTree<TDEntry>,
     pub moves:        Vec<TDEntry>,
+    /// FEN and ply of the first position, None = startpos
+    pub start:        Option<(String,u16)>,
 }
 
 /// Generate data set
@@ -517,7 +829,10 @@ impl TrainingData {
 /// Init opening
 impl TrainingData {
     pub fn init_opening(&self, ts: &Tables) -> Option<Game> {
-        let mut g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut g = match &self.start {
+            Some((fen,_)) => Game::from_fen(&ts, fen)?,
+            None          => Game::from_fen(&ts, STARTPOS).unwrap(),
+        };
         for mv in self.opening.iter() {
             if let Ok(g2) = g.make_move_unchecked(&ts, *mv) {
                 g = g2;
@@ -527,88 +842,87 @@ impl TrainingData {
         }
         Some(g)
     }
+
+    pub fn start_ply(&self) -> u16 {
+        self.start.as_ref().map_or(0, |(_,ply)| *ply)
+    }
 }
 
 /// Filter only quiet positions
 impl TrainingData {
 
+    /// Skips positions where the static eval doesn't match a qsearch
     pub fn filter_quiet(
-        ts:               &'static Tables,
-        (ev_mid,ev_end):  &(EvalParams,EvalParams),
+        ts:               &Tables,
         tds:              Vec<TrainingData>,
     ) -> Vec<TrainingData> {
-
         let ncpus = num_cpus::get();
-        let g = Game::start_pos(ts);
-
-        let ph_factory = PHTableFactory::new();
-
-        let tds = tds.chunks(tds.len() / ncpus).map(|xs| {
-            let ph_rw = ph_factory.handle();
-            let exhelper = exhelper_once(ts, &g, White, ev_mid, ev_end, Some(&ph_rw), None);
-            (exhelper, xs)
-        }).collect::<Vec<(ExHelper, &[TrainingData])>>();
-
-        let out = tds.into_par_iter().map(|(exhelper,xs)| {
-            // let out = xs.iter().filter(|td| td._filter_quiet(ts, &exhelper, &mut stats));
-            let mut xs: Vec<TrainingData> = xs.to_vec();
-            xs.iter_mut().for_each(|td| {
-                td._filter_quiet(ts, &exhelper)
-            });
-            xs
-        }).flatten().collect::<Vec<_>>();
-
-        out
+        let chunk = (tds.len() / ncpus).max(1);
+
+        tds.par_chunks(chunk).map(|xs| {
+            let ex = Explorer::new(White, Game::start_pos(ts), 1, TimeSettings::new_infinite());
+            let mut helper = ex.build_exhelper(0, PerThreadData::default());
+            xs.iter().cloned().map(|mut td| {
+                td._filter_quiet(ts, &mut helper);
+                td
+            }).collect::<Vec<_>>()
+        }).flatten().collect()
     }
 
     pub fn _filter_quiet(
         &mut self,
         ts:               &Tables,
-        exhelper:         &ExHelper,
+        exhelper:         &mut ExHelper,
     ) {
         let mut stats = SearchStats::default();
 
         let mut g = if let Some(g) = self.init_opening(ts) { g } else { return; };
 
-        let (ev_mid,ev_end) = (&exhelper.cfg.eval_params_mid,&exhelper.cfg.eval_params_end);
-
-        let mut done = false;
-        for mut te in self.moves.iter_mut() {
-
-            if done {
-                te.skip = true;
-                continue;
+        for te in self.moves.iter_mut() {
+            if !te.skip {
+                if !Self::is_quiet(ts, exhelper, &mut stats, &g, te.mv) {
+                    te.skip = true;
+                }
             }
 
             if let Ok(g2) = g.make_move_unchecked(&ts, te.mv) {
                 g = g2;
+            } else {
+                break;
+            }
+        }
+    }
 
-                if te.skip
-                    || te.mv.filter_all_captures()
-                    || g.state.in_check {
-                        te.skip = true;
-                    } else {
-
-                        let score   = g.sum_evaluate(&ts, &ev_mid, &ev_end, None);
-                        let q_score = exhelper.qsearch_once(&ts, &g, &mut stats);
-                        let q_score = g.state.side_to_move.fold(q_score, -q_score);
-
-                        if score != q_score {
-                            te.skip = true;
-                        // } else if score.abs() > DRAW_VALUE - 100 {
-                        } else if score.abs() > 0 - 100 {
-                            te.skip = true;
-                            done = true;
-                        }
-                    }
-
+    /// Not in check, mv isn't a capture, and the static eval matches a qsearch
+    pub fn is_quiet(
+        ts:               &Tables,
+        exhelper:         &mut ExHelper,
+        stats:            &mut SearchStats,
+        g:                &Game,
+        mv:               Move,
+    ) -> bool {
+        if g.state.in_check || mv.filter_all_captures() {
+            return false;
+        }
+        if let Some(nn) = exhelper.nnue.as_mut() {
+            nn.reset(g);
+        }
+        let score   = exhelper.evaluate(ts, stats, g, 0, true);
+        let q_score = exhelper.qsearch_once_mut(ts, g, stats);
+        score == q_score
+    }
 
+    /// f is called with the position each move is played from
+    pub fn for_each_position<F: FnMut(&Game, &TDEntry)>(&self, ts: &Tables, mut f: F) {
+        let mut g = if let Some(g) = self.init_opening(ts) { g } else { return; };
+        for te in self.moves.iter() {
+            f(&g, te);
+            if let Ok(g2) = g.make_move_unchecked(&ts, te.mv) {
+                g = g2;
             } else {
-                // panic!("bad move: {:?}\n{:?},{:?}", g.to_fen(), g, te.mv);
                 break;
             }
         }
-
     }
 }
 
@@ -617,6 +931,7 @@ pub struct TDEntry {
     // mv:       PackedMove,
     pub mv:       Move,
     // eval:     i8,
+    /// Search score before mv, side to move
     pub eval:     Score,
     pub skip:     bool,
 }
@@ -743,3 +1058,68 @@ impl TrainingData {
 
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::td_builder::dedup_key;
+    use crate::brain::binpack::BPReader;
+
+    fn sfen_files(dir: &Path) -> Vec<String> {
+        let mut out = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn gensfen_resumes() {
+        let ts = Tables::new();
+        let dir = std::env::temp_dir().join(format!("rchess_gensfen_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let builder = |n| TDBuilder::new()
+            .max_depth(2)
+            .num_positions(Some(n))
+            .num_threads(2)
+            .max_plies(30)
+            .positions_per_file(10)
+            .print(false);
+
+        let n0 = builder(30).do_explore(&ts, None, &dir).unwrap();
+        let files0 = sfen_files(&dir);
+        let (sd0,_) = SfenDir::open(&ts, &dir, SfenFormat::Binpack).unwrap();
+        assert!(n0 >= 30);
+        assert_eq!(sd0.positions, n0);
+
+        let n1 = builder(sd0.positions + 30).do_explore(&ts, None, &dir).unwrap();
+        let files1 = sfen_files(&dir);
+        let (sd1,_) = SfenDir::open(&ts, &dir, SfenFormat::Binpack).unwrap();
+        assert!(n1 >= 30);
+        assert_eq!(sd1.positions, n0 + n1);
+
+        /// Numbering continues after the first run's files, which are untouched
+        assert!(files1.len() > files0.len());
+        assert_eq!(files1[..files0.len()], files0[..]);
+        let expected = (0..sd1.next)
+            .map(|n| sd1.file_name(n).file_name().unwrap().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(files1, expected);
+        assert!(files1.iter().all(|f| !f.ends_with(".tmp")));
+
+        /// Already done, nothing written
+        assert_eq!(builder(sd1.positions).do_explore(&ts, None, &dir).unwrap(), 0);
+
+        let mut seen = HashSet::new();
+        for f in files1.iter() {
+            for e in BPReader::open(&ts, dir.join(f)).unwrap() {
+                let key = dedup_key(&ts, &e.unwrap().game);
+                assert!(seen.insert(key), "duplicate position in {}", f);
+            }
+        }
+        assert_eq!(seen.len() as u64, n0 + n1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -1,31 +1,44 @@
 
 #![allow(clippy::all)]
 
-pub mod types;
+/// Old float networks, need nalgebra and ndarray
+// pub mod types;
+// pub mod nnue;
+// pub mod matrix;
+// pub mod trainer;
+// pub mod networks2;
+// pub mod autodiff;
+
 // pub mod filter;
-pub mod nnue;
-pub mod matrix;
-pub mod trainer;
 pub mod gensfen;
 // pub mod accumulator;
 pub mod binpack;
-pub mod networks2;
-pub mod autodiff;
+pub mod plain;
+pub mod td_pipeline;
+pub mod nnue_trainer;
+pub mod perspective_trainer;
 // pub mod sf_compat;
 
 use crate::types::*;
+#[cfg(feature = "nope")]
 use crate::brain::types::*;
+#[cfg(feature = "nope")]
 use crate::brain::matrix::*;
+#[cfg(feature = "nope")]
 use crate::brain::types::g_networks::*;
 
+#[cfg(feature = "nope")]
 use ndarray::prelude::*;
 
+#[cfg(feature = "nope")]
 use nalgebra::{SMatrix,SVector,Matrix,Vector,matrix,vector,DVector,DMatrix};
+#[cfg(feature = "nope")]
 use nalgebra as na;
 
 use rand::{Rng,SeedableRng};
 use rand::prelude::StdRng;
 
+#[cfg(feature = "nope")]
 pub fn test_mnist(
     n0:               &MNNetwork,
     mut data:         Vec<(SVector<f32,784>,u8)>,
@@ -46,6 +59,7 @@ pub fn test_mnist(
               score, out.len(), score as f32 / out.len() as f32);
 }
 
+#[cfg(feature = "nope")]
 pub fn test_mnist2(
     n0:               &DNetwork<f32,784,10>,
     data:             &[(SVector<f32,784>,u8)],
@@ -84,6 +98,7 @@ pub fn test_mnist2(
     score as f32 / out.len() as f32
 }
 
+#[cfg(feature = "nope")]
 impl<const IS: usize, const OS: usize> DNetwork<f32,IS,OS> {
     pub fn run(&self, input: &DVector<f32>) -> DVector<f32> {
         let (out,_,_) = self._run(input);
@@ -420,6 +435,7 @@ impl<const IS: usize, const OS: usize> DNetwork<f32,IS,OS> {
 
 }
 
+#[cfg(feature = "nope")]
 impl<const IS: usize, const HS: usize, const OS: usize> GNetwork<f32,IS,HS,OS> {
 
     pub fn fill_input_matrix<const ISS: usize>(
@@ -607,6 +623,7 @@ impl<const IS: usize, const HS: usize, const OS: usize> GNetwork<f32,IS,HS,OS> {
 
 }
 
+#[cfg(feature = "nope")]
 impl<const IS: usize, const HS: usize, const OS: usize> GNetwork<f32,IS,HS,OS> {
 
     pub fn run(&self, input: &SVector<f32,IS>) -> SVector<f32,OS> {
@@ -0,0 +1,1065 @@
+
+use crate::tables::*;
+use crate::types::*;
+use crate::brain::gensfen::{TrainingData,TDOutcome,SfenFormat};
+use crate::brain::binpack::{BPEntry,read_training_data};
+use crate::brain::plain::PlainReader;
+use crate::sf_compat::{NNUE4,NNAccum,NNIndex,NNLayer,NNFeatureTrans,NNEvaluator,SimdLevel,Layer1,Layer2};
+use crate::sf_compat::{HALF_DIMS,OUTPUT_SCALE};
+use crate::sf_compat::layers::WEIGHT_SCALE_BITS;
+use crate::builder_field;
+
+use std::io::{self,Read,Write};
+use std::path::{Path,PathBuf};
+use std::time::Instant;
+
+use arrayvec::ArrayVec;
+use byteorder::{ReadBytesExt,WriteBytesExt,LittleEndian};
+use rand::{prelude::{StdRng,SliceRandom},Rng,SeedableRng};
+use rayon::prelude::*;
+
+pub use self::sample::*;
+pub use self::net::*;
+pub use self::trainer::*;
+
+/// Number of inputs, each of which is a row of FT_ROW in HalfKAv2Net::ft
+const FT_IN: usize = NNFeatureTrans::DIMS_IN;
+const BUCKETS: usize = NNFeatureTrans::LAYER_STACKS;
+/// FT weights, then PSQT weights
+const FT_ROW: usize = HALF_DIMS + BUCKETS;
+
+const L1_IN: usize  = HALF_DIMS * 2;
+const L1_OUT: usize = Layer1::SIZE_OUTPUT;
+const L2_OUT: usize = Layer2::SIZE_OUTPUT;
+
+/// Offsets into a layer stack in HalfKAv2Net::dense
+const L1_W: usize       = 0;
+const L1_B: usize       = L1_W + L1_OUT * L1_IN;
+const L2_W: usize       = L1_B + L1_OUT;
+const L2_B: usize       = L2_W + L2_OUT * L1_OUT;
+const OUT_W: usize      = L2_B + L2_OUT;
+const OUT_B: usize      = OUT_W + L2_OUT;
+const STACK_SIZE: usize = OUT_B + 1;
+
+/// FT biases, then each layer stack
+const DENSE_SIZE: usize = HALF_DIMS + BUCKETS * STACK_SIZE;
+
+/// Float output to eval, same as nnue-pytorch
+pub const NNUE2SCORE: f32 = 600.0;
+
+/// Activations, 1.0 = 127
+const QA: f32 = 127.0;
+/// Hidden layer weights, 1.0 = 64
+const QW: f32 = (1 << WEIGHT_SCALE_BITS) as f32;
+/// Output layer and PSQT weights, 1.0 = NNUE2SCORE after the final divide by OUTPUT_SCALE
+const QO: f32 = NNUE2SCORE * OUTPUT_SCALE as f32;
+
+/// Largest weights that still fit in an i8 after quantizing
+const MAX_HIDDEN_WEIGHT: f32 = 127.0 / QW;
+const MAX_OUTPUT_WEIGHT: f32 = 127.0 * QA / QO;
+
+/// Integer units per unit of float weight.
+/// The engine clamps activations at 127 and shifts hidden layers by WEIGHT_SCALE_BITS, so the
+/// quantized net only matches the float net with the default activation and hidden scales.
+/// The output scale multiplies the eval by output / QO.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct QuantScales {
+    /// FT weights and biases, and the activation range
+    pub activation:  f32,
+    /// L1 and L2 weights
+    pub hidden:      f32,
+    /// Output layer and PSQT weights
+    pub output:      f32,
+}
+
+impl Default for QuantScales {
+    fn default() -> Self {
+        Self { activation: QA, hidden: QW, output: QO }
+    }
+}
+
+impl QuantScales {
+    /// Err if the engine can't run a net quantized with these scales
+    pub fn check(&self) -> Result<(), String> {
+        if self.activation != QA {
+            return Err(format!("activation scale must be {}, the engine clamps activations at 127", QA));
+        }
+        if self.hidden != QW {
+            return Err(format!("hidden scale must be {}, the engine shifts by WEIGHT_SCALE_BITS = {}",
+                               QW, WEIGHT_SCALE_BITS));
+        }
+        if !(self.output.is_finite() && self.output > 0.0) {
+            return Err(format!("bad output scale {}", self.output));
+        }
+        Ok(())
+    }
+
+    /// Quantized eval over float eval
+    pub fn eval_ratio(&self) -> f32 {
+        self.output / QO
+    }
+}
+
+mod sample {
+    use super::*;
+
+    /// One position, as the sparse inputs to the feature transformer
+    #[derive(Debug,Clone)]
+    pub struct TrainSample {
+        /// HalfKAv2 indices, side to move first
+        pub features: [ArrayVec<u16, 32>; 2],
+        /// PSQT and layer stack
+        pub bucket:   u8,
+        /// Search score, side to move
+        pub score:    i16,
+        /// Game result, side to move, 1.0 = win
+        pub result:   f32,
+    }
+
+    impl TrainSample {
+
+        pub fn from_game(g: &Game, score: Score, result: f32) -> Self {
+            let persps = [g.state.side_to_move, !g.state.side_to_move];
+            let mut features = [ArrayVec::new(), ArrayVec::new()];
+            for (p,persp) in persps.iter().enumerate() {
+                let mut active: ArrayVec<NNIndex, 32> = ArrayVec::new();
+                NNAccum::append_active(g, *persp, &mut active);
+                features[p].extend(active.iter().map(|idx| idx.0 as u16));
+            }
+
+            /// Same as NNUE4::evaluate
+            let bucket = ((g.state.material.count() as usize - 1) / 4).min(BUCKETS - 1);
+
+            Self {
+                features,
+                bucket: bucket as u8,
+                score:  score.clamp(-i16::MAX as Score, i16::MAX as Score) as i16,
+                result,
+            }
+        }
+
+        pub fn from_training_data(ts: &Tables, td: &TrainingData) -> Vec<Self> {
+            let mut out = vec![];
+            td.for_each_position(ts, |g, te| {
+                if te.skip { return; }
+                let result = match td.result {
+                    TDOutcome::Win(c) if c == g.state.side_to_move => 1.0,
+                    TDOutcome::Win(_)                              => 0.0,
+                    TDOutcome::Draw | TDOutcome::Stalemate         => 0.5,
+                };
+                out.push(Self::from_game(g, te.eval, result));
+            });
+            out
+        }
+
+        /// Each path is a .binpack, .bin or .plain file, or a directory of them
+        pub fn load_all<P: AsRef<Path>>(ts: &Tables, paths: &[P]) -> io::Result<Vec<Self>> {
+            let mut out = vec![];
+            for path in Self::data_files(paths)?.iter() {
+                let tds = Self::read_file(ts, path)?;
+                out.par_extend(tds.par_iter().flat_map_iter(|td| Self::from_training_data(ts, td)));
+            }
+            Ok(out)
+        }
+
+        pub fn read_file<P: AsRef<Path>>(ts: &Tables, path: P) -> io::Result<Vec<TrainingData>> {
+            match SfenFormat::from_path(&path) {
+                Some(SfenFormat::Binpack) => read_training_data(ts, path),
+                Some(SfenFormat::Bincode) => TrainingData::load_all(path, None),
+                Some(SfenFormat::Plain)   => {
+                    let entries = PlainReader::open(ts, path)?.collect::<io::Result<Vec<_>>>()?;
+                    Ok(BPEntry::to_training_data(ts, entries.into_iter()))
+                },
+                None                      => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown training data format: {:?}", path.as_ref()))),
+            }
+        }
+
+        /// Expands directories
+        pub fn data_files<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<PathBuf>> {
+            let mut files: Vec<PathBuf> = vec![];
+            for path in paths.iter() {
+                let path = path.as_ref();
+                if path.is_dir() {
+                    let mut xs = std::fs::read_dir(path)?
+                        .map(|e| e.map(|e| e.path()))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    xs.retain(|p| SfenFormat::from_path(p).is_some());
+                    xs.sort();
+                    files.extend(xs);
+                } else {
+                    files.push(path.to_path_buf());
+                }
+            }
+            Ok(files)
+        }
+
+    }
+
+}
+
+mod net {
+    use super::*;
+
+    /// Float version of NNUE4, for training
+    #[derive(Debug,Clone)]
+    pub struct HalfKAv2Net {
+        /// Per input: HALF_DIMS FT weights, then BUCKETS PSQT weights
+        pub ft:     Vec<f32>,
+        /// FT biases, then per bucket: L1, L2, output
+        pub dense:  Vec<f32>,
+    }
+
+    /// Activations of one sample, kept for backprop
+    pub(super) struct Scratch {
+        pub psqt:  [f32; 2],
+        /// Clamped FT output, side to move first
+        pub x:     Vec<f32>,
+        pub h1:    [f32; L1_OUT],
+        pub h2:    [f32; L2_OUT],
+    }
+
+    impl Scratch {
+        pub fn new() -> Self {
+            Self {
+                psqt:  [0.0; 2],
+                x:     vec![0.0; L1_IN],
+                h1:    [0.0; L1_OUT],
+                h2:    [0.0; L2_OUT],
+            }
+        }
+    }
+
+    fn active(x: f32) -> bool { x > 0.0 && x < 1.0 }
+
+    /// New
+    impl HalfKAv2Net {
+
+        /// PSQT weights start at the material values
+        pub fn new_random(seed: u64) -> Self {
+            let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+
+            let mut ft = vec![0.0; FT_IN * FT_ROW];
+            for row in ft.chunks_mut(FT_ROW) {
+                for w in row[..HALF_DIMS].iter_mut() {
+                    *w = rng.gen_range(-0.05..0.05);
+                }
+            }
+
+            for ksq in 0u8..64 {
+                for pc in Piece::iter_nonking_pieces() {
+                    for side in [White,Black] {
+                        for sq in 0u8..64 {
+                            let idx = NNUE4::make_index_half_ka_v2(
+                                Coord::new_int(ksq), White, pc, side, Coord::new_int(sq));
+                            let v = pc.score() as f32 / NNUE2SCORE;
+                            let v = if side == White { v } else { -v };
+                            for b in 0..BUCKETS {
+                                ft[idx.0 * FT_ROW + HALF_DIMS + b] = v;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let mut dense = vec![0.0; DENSE_SIZE];
+            dense[..HALF_DIMS].fill(0.5);
+            for stack in dense[HALF_DIMS..].chunks_mut(STACK_SIZE) {
+                let mut init = |xs: &mut [f32], fan_in: usize| {
+                    let k = 1.0 / (fan_in as f32).sqrt();
+                    xs.iter_mut().for_each(|w| *w = rng.gen_range(-k..k));
+                };
+                init(&mut stack[L1_W..L1_B], L1_IN);
+                init(&mut stack[L2_W..L2_B], L1_OUT);
+                init(&mut stack[OUT_W..OUT_B], L2_OUT);
+            }
+
+            let mut out = Self { ft, dense };
+            out.clip_weights();
+            out
+        }
+
+        pub fn stack(&self, bucket: usize) -> &[f32] {
+            &self.dense[HALF_DIMS + bucket * STACK_SIZE..][..STACK_SIZE]
+        }
+
+        /// Keep weights in range of the quantized layers
+        pub fn clip_weights(&mut self) {
+            for stack in self.dense[HALF_DIMS..].chunks_mut(STACK_SIZE) {
+                for w in stack[L1_W..L1_B].iter_mut() {
+                    *w = w.clamp(-MAX_HIDDEN_WEIGHT, MAX_HIDDEN_WEIGHT);
+                }
+                for w in stack[L2_W..L2_B].iter_mut() {
+                    *w = w.clamp(-MAX_HIDDEN_WEIGHT, MAX_HIDDEN_WEIGHT);
+                }
+                for w in stack[OUT_W..OUT_B].iter_mut() {
+                    *w = w.clamp(-MAX_OUTPUT_WEIGHT, MAX_OUTPUT_WEIGHT);
+                }
+            }
+        }
+
+    }
+
+    /// Forward, backward
+    impl HalfKAv2Net {
+
+        /// Eval, side to move
+        pub fn evaluate(&self, s: &TrainSample) -> Score {
+            let mut sc = Scratch::new();
+            (self.forward(s, &mut sc) * NNUE2SCORE) as Score
+        }
+
+        pub(super) fn forward(&self, s: &TrainSample, sc: &mut Scratch) -> f32 {
+            let bucket = s.bucket as usize;
+
+            for p in 0..2 {
+                let x = &mut sc.x[p * HALF_DIMS..][..HALF_DIMS];
+                x.copy_from_slice(&self.dense[..HALF_DIMS]);
+                sc.psqt[p] = 0.0;
+                for f in s.features[p].iter() {
+                    let row = &self.ft[*f as usize * FT_ROW..][..FT_ROW];
+                    for (a,w) in x.iter_mut().zip(row[..HALF_DIMS].iter()) {
+                        *a += w;
+                    }
+                    sc.psqt[p] += row[HALF_DIMS + bucket];
+                }
+                x.iter_mut().for_each(|a| *a = a.clamp(0.0, 1.0));
+            }
+
+            let stack = self.stack(bucket);
+
+            for i in 0..L1_OUT {
+                let ws = &stack[L1_W + i * L1_IN..][..L1_IN];
+                let z = ws.iter().zip(sc.x.iter()).fold(stack[L1_B + i], |acc,(w,x)| acc + w * x);
+                sc.h1[i] = z.clamp(0.0, 1.0);
+            }
+
+            for i in 0..L2_OUT {
+                let ws = &stack[L2_W + i * L1_OUT..][..L1_OUT];
+                let z = ws.iter().zip(sc.h1.iter()).fold(stack[L2_B + i], |acc,(w,x)| acc + w * x);
+                sc.h2[i] = z.clamp(0.0, 1.0);
+            }
+
+            let ws = &stack[OUT_W..OUT_B];
+            let y = ws.iter().zip(sc.h2.iter()).fold(stack[OUT_B], |acc,(w,x)| acc + w * x);
+
+            y + (sc.psqt[0] - sc.psqt[1]) / 2.0
+        }
+
+        /// Adds the gradients of the dense params to grad, and writes the FT output
+        /// gradient to dx. The PSQT gradient is dy / 2 and -dy / 2
+        pub(super) fn backward(
+            &self,
+            s:        &TrainSample,
+            sc:       &Scratch,
+            dy:       f32,
+            grad:     &mut [f32],
+            dx:       &mut [f32],
+        ) {
+            let bucket = s.bucket as usize;
+            let stack  = self.stack(bucket);
+            let gs     = &mut grad[HALF_DIMS + bucket * STACK_SIZE..][..STACK_SIZE];
+
+            gs[OUT_B] += dy;
+            let mut dz2 = [0.0; L2_OUT];
+            for i in 0..L2_OUT {
+                gs[OUT_W + i] += dy * sc.h2[i];
+                if active(sc.h2[i]) {
+                    dz2[i] = dy * stack[OUT_W + i];
+                }
+            }
+
+            let mut dh1 = [0.0; L1_OUT];
+            for i in 0..L2_OUT {
+                if dz2[i] == 0.0 { continue; }
+                gs[L2_B + i] += dz2[i];
+                for j in 0..L1_OUT {
+                    gs[L2_W + i * L1_OUT + j] += dz2[i] * sc.h1[j];
+                    dh1[j] += dz2[i] * stack[L2_W + i * L1_OUT + j];
+                }
+            }
+
+            dx.fill(0.0);
+            for i in 0..L1_OUT {
+                if !active(sc.h1[i]) || dh1[i] == 0.0 { continue; }
+                let dz1 = dh1[i];
+                gs[L1_B + i] += dz1;
+                let gw = &mut gs[L1_W + i * L1_IN..][..L1_IN];
+                let ws = &stack[L1_W + i * L1_IN..][..L1_IN];
+                for j in 0..L1_IN {
+                    gw[j] += dz1 * sc.x[j];
+                    dx[j] += dz1 * ws[j];
+                }
+            }
+
+            for (d,x) in dx.iter_mut().zip(sc.x.iter()) {
+                if !active(*x) { *d = 0.0; }
+            }
+
+            for k in 0..HALF_DIMS {
+                grad[k] += dx[k] + dx[HALF_DIMS + k];
+            }
+        }
+
+    }
+
+    fn q_i8(x: f32) -> i8 { x.round().clamp(i8::MIN as f32, i8::MAX as f32) as i8 }
+    fn q_i16(x: f32) -> i16 { x.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16 }
+    fn q_i32(x: f32) -> i32 { x.round().clamp(i32::MIN as f32, i32::MAX as f32) as i32 }
+
+    /// Convert to and from NNUE4
+    impl HalfKAv2Net {
+
+        /// Scalar kernels, call set_simd before evaluating
+        pub fn to_nnue(&self) -> NNUE4 {
+            self.to_nnue_scaled(&QuantScales::default()).unwrap()
+        }
+
+        pub fn to_nnue_scaled(&self, sc: &QuantScales) -> Result<NNUE4, String> {
+            sc.check()?;
+            let (qa,qw,qo) = (sc.activation, sc.hidden, sc.output);
+            let mut nn = NNUE4::new_empty();
+
+            for k in 0..HALF_DIMS {
+                nn.ft.biases[k] = q_i16(self.dense[k] * qa);
+            }
+            for (f,row) in self.ft.chunks(FT_ROW).enumerate() {
+                for k in 0..HALF_DIMS {
+                    nn.ft.weights[f * HALF_DIMS + k] = q_i16(row[k] * qa);
+                }
+                for b in 0..BUCKETS {
+                    nn.ft.psqt_weights[f * BUCKETS + b] = q_i32(row[HALF_DIMS + b] * qo);
+                }
+            }
+
+            for (b,layer) in nn.layers.iter_mut().enumerate() {
+                let stack = self.stack(b);
+
+                layer.biases[0] = q_i32(stack[OUT_B] * qo);
+                for j in 0..L2_OUT {
+                    layer.weights[j] = q_i8(stack[OUT_W + j] * qo / qa);
+                }
+
+                let l2 = &mut layer.prev.prev;
+                let padded = l2.weights.len() / L2_OUT;
+                for i in 0..L2_OUT {
+                    l2.biases[i] = q_i32(stack[L2_B + i] * qw * qa);
+                    for j in 0..L1_OUT {
+                        l2.weights[i * padded + j] = q_i8(stack[L2_W + i * L1_OUT + j] * qw);
+                    }
+                }
+
+                let l1 = &mut l2.prev.prev;
+                let padded = l1.weights.len() / L1_OUT;
+                for i in 0..L1_OUT {
+                    l1.biases[i] = q_i32(stack[L1_B + i] * qw * qa);
+                    for j in 0..L1_IN {
+                        l1.weights[i * padded + j] = q_i8(stack[L1_W + i * L1_IN + j] * qw);
+                    }
+                }
+            }
+
+            Ok(nn)
+        }
+
+        /// Start training from an existing network
+        pub fn from_nnue(nn: &NNUE4) -> Self {
+            Self::from_nnue_scaled(nn, &QuantScales::default()).unwrap()
+        }
+
+        pub fn from_nnue_scaled(nn: &NNUE4, sc: &QuantScales) -> Result<Self, String> {
+            sc.check()?;
+            let (qa,qw,qo) = (sc.activation, sc.hidden, sc.output);
+            let mut nn = nn.clone();
+            nn.set_simd(SimdLevel::Scalar);
+
+            let mut ft    = vec![0.0; FT_IN * FT_ROW];
+            let mut dense = vec![0.0; DENSE_SIZE];
+
+            for k in 0..HALF_DIMS {
+                dense[k] = nn.ft.biases[k] as f32 / qa;
+            }
+            for (f,row) in ft.chunks_mut(FT_ROW).enumerate() {
+                for k in 0..HALF_DIMS {
+                    row[k] = nn.ft.weights[f * HALF_DIMS + k] as f32 / qa;
+                }
+                for b in 0..BUCKETS {
+                    row[HALF_DIMS + b] = nn.ft.psqt_weights[f * BUCKETS + b] as f32 / qo;
+                }
+            }
+
+            for (b,layer) in nn.layers.iter().enumerate() {
+                let stack = &mut dense[HALF_DIMS + b * STACK_SIZE..][..STACK_SIZE];
+
+                stack[OUT_B] = layer.biases[0] as f32 / qo;
+                for j in 0..L2_OUT {
+                    stack[OUT_W + j] = layer.weights[j] as f32 * qa / qo;
+                }
+
+                let l2 = &layer.prev.prev;
+                let padded = l2.weights.len() / L2_OUT;
+                for i in 0..L2_OUT {
+                    stack[L2_B + i] = l2.biases[i] as f32 / (qw * qa);
+                    for j in 0..L1_OUT {
+                        stack[L2_W + i * L1_OUT + j] = l2.weights[i * padded + j] as f32 / qw;
+                    }
+                }
+
+                let l1 = &l2.prev.prev;
+                let padded = l1.weights.len() / L1_OUT;
+                for i in 0..L1_OUT {
+                    stack[L1_B + i] = l1.biases[i] as f32 / (qw * qa);
+                    for j in 0..L1_IN {
+                        stack[L1_W + i * L1_IN + j] = l1.weights[i * padded + j] as f32 / qw;
+                    }
+                }
+            }
+
+            Ok(Self { ft, dense })
+        }
+
+    }
+
+    /// Float file: b"KA2F", u32 ft len, u32 dense len, then ft and dense as little endian f32
+    impl HalfKAv2Net {
+        const MAGIC: &'static [u8; 4] = b"KA2F";
+
+        pub fn write_f32<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+            let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+            w.write_all(Self::MAGIC)?;
+            w.write_u32::<LittleEndian>(self.ft.len() as u32)?;
+            w.write_u32::<LittleEndian>(self.dense.len() as u32)?;
+            for x in self.ft.iter().chain(self.dense.iter()) {
+                w.write_f32::<LittleEndian>(*x)?;
+            }
+            w.flush()
+        }
+
+        pub fn read_f32<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            let mut rdr = io::BufReader::new(std::fs::File::open(path)?);
+            let mut magic = [0u8; 4];
+            rdr.read_exact(&mut magic)?;
+            let n_ft    = rdr.read_u32::<LittleEndian>()? as usize;
+            let n_dense = rdr.read_u32::<LittleEndian>()? as usize;
+            if &magic != Self::MAGIC || n_ft != FT_IN * FT_ROW || n_dense != DENSE_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "not a HalfKAv2 float net, magic = {:?}, sizes = {}, {}", magic, n_ft, n_dense)));
+            }
+            let mut ft    = vec![0.0; n_ft];
+            let mut dense = vec![0.0; n_dense];
+            rdr.read_f32_into::<LittleEndian>(&mut ft)?;
+            rdr.read_f32_into::<LittleEndian>(&mut dense)?;
+            Ok(Self { ft, dense })
+        }
+
+    }
+
+}
+
+mod trainer {
+    use super::*;
+
+    #[derive(Debug,Clone,Copy)]
+    pub(crate) struct Adam {
+        pub(crate) lr: f32,
+        beta1:     f32,
+        beta2:     f32,
+        eps:       f32,
+        t:         i32,
+        step_size: f32,
+    }
+
+    impl Adam {
+        pub(crate) fn new(lr: f32) -> Self {
+            Self { lr, beta1: 0.9, beta2: 0.999, eps: 1e-8, t: 0, step_size: 0.0 }
+        }
+
+        pub(crate) fn next_step(&mut self) {
+            self.t += 1;
+            let c1 = 1.0 - self.beta1.powi(self.t);
+            let c2 = 1.0 - self.beta2.powi(self.t);
+            self.step_size = self.lr * c2.sqrt() / c1;
+        }
+
+        pub(crate) fn update(&self, ws: &mut [f32], ms: &mut [f32], vs: &mut [f32], gs: &[f32]) {
+            for (((w,m),v),g) in ws.iter_mut().zip(ms.iter_mut()).zip(vs.iter_mut()).zip(gs.iter()) {
+                *m = self.beta1 * *m + (1.0 - self.beta1) * g;
+                *v = self.beta2 * *v + (1.0 - self.beta2) * g * g;
+                *w -= self.step_size * *m / (v.sqrt() + self.eps);
+            }
+        }
+    }
+
+    /// Adam moments, FT rows are only updated when the input is used
+    struct AdamState {
+        ft_m:     Vec<f32>,
+        ft_v:     Vec<f32>,
+        dense_m:  Vec<f32>,
+        dense_v:  Vec<f32>,
+    }
+
+    /// Reused between batches
+    struct BatchBuf {
+        /// FT output gradient per sample
+        dx:       Vec<f32>,
+        dy:       Vec<f32>,
+        /// Per input, which (sample, perspective) use it, as a counting sort
+        starts:   Vec<u32>,
+        entries:  Vec<u32>,
+    }
+
+    #[derive(Debug,Clone)]
+    pub struct NNTrainer {
+        pub epochs:        usize,
+        pub batch_size:    usize,
+        pub lr:            f32,
+        /// lr is multiplied by this after each epoch
+        pub lr_decay:      f32,
+        /// 1.0 = only search score, 0.0 = only game result
+        pub lambda:        f32,
+        /// Eval that maps to a win probability of sigmoid(1)
+        pub eval_scale:    f32,
+        pub val_fraction:  f32,
+        pub num_threads:   usize,
+        pub seed:          u64,
+        pub print:         bool,
+    }
+
+    impl NNTrainer {
+        pub fn new() -> Self {
+            Self {
+                epochs:        10,
+                batch_size:    16384,
+                lr:            1e-3,
+                lr_decay:      0.99,
+                lambda:        0.8,
+                eval_scale:    410.0,
+                val_fraction:  0.05,
+                num_threads:   num_cpus::get_physical(),
+                seed:          1234,
+                print:         true,
+            }
+        }
+        builder_field!(epochs, usize);
+        builder_field!(batch_size, usize);
+        builder_field!(lr, f32);
+        builder_field!(lr_decay, f32);
+        builder_field!(lambda, f32);
+        builder_field!(eval_scale, f32);
+        builder_field!(val_fraction, f32);
+        builder_field!(num_threads, usize);
+        builder_field!(seed, u64);
+        builder_field!(print, bool);
+    }
+
+    fn sigmoid(x: f32) -> f32 { 1.0 / (1.0 + (-x).exp()) }
+
+    /// Loss
+    impl NNTrainer {
+
+        fn loss(&self, y: f32, s: &TrainSample) -> (f32,f32) {
+            self.win_loss(y, NNUE2SCORE, s.score, s.result)
+        }
+
+        /// Squared error of win probability, returns (loss, d loss / d y).
+        /// y * out_scale is the eval
+        pub(crate) fn win_loss(&self, y: f32, out_scale: f32, score: i16, result: f32) -> (f32,f32) {
+            let q = sigmoid(y * out_scale / self.eval_scale);
+            let t = self.lambda * sigmoid(score as f32 / self.eval_scale)
+                + (1.0 - self.lambda) * result;
+            let err = q - t;
+            (err * err, 2.0 * err * q * (1.0 - q) * out_scale / self.eval_scale)
+        }
+
+        pub fn mean_loss(&self, net: &HalfKAv2Net, samples: &[TrainSample]) -> f64 {
+            if samples.is_empty() { return 0.0; }
+            let sum: f64 = samples.par_chunks(256).map(|xs| {
+                let mut sc = Scratch::new();
+                xs.iter().map(|s| self.loss(net.forward(s, &mut sc), s).0 as f64).sum::<f64>()
+            }).sum();
+            sum / samples.len() as f64
+        }
+
+    }
+
+    /// Train
+    impl NNTrainer {
+
+        /// Saves the quantized net to out after each epoch
+        pub fn train(
+            &self,
+            net:       &mut HalfKAv2Net,
+            samples:   &mut [TrainSample],
+            out:       Option<&Path>,
+        ) -> io::Result<()> {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(self.num_threads)
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            pool.install(|| self._train(net, samples, out))
+        }
+
+        fn _train(
+            &self,
+            net:       &mut HalfKAv2Net,
+            samples:   &mut [TrainSample],
+            out:       Option<&Path>,
+        ) -> io::Result<()> {
+            let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed);
+
+            samples.shuffle(&mut rng);
+            let n_val = (samples.len() as f32 * self.val_fraction) as usize;
+            let (val, train) = samples.split_at_mut(n_val);
+
+            if self.print {
+                eprintln!("training on {} positions, {} validation, {} threads",
+                          train.len(), val.len(), rayon::current_num_threads());
+                eprintln!("start: val loss = {:.6}", self.mean_loss(net, val));
+            }
+
+            let mut adam = Adam::new(self.lr);
+            let mut state = AdamState {
+                ft_m:     vec![0.0; net.ft.len()],
+                ft_v:     vec![0.0; net.ft.len()],
+                dense_m:  vec![0.0; net.dense.len()],
+                dense_v:  vec![0.0; net.dense.len()],
+            };
+            let mut buf = BatchBuf {
+                dx:       vec![],
+                dy:       vec![],
+                starts:   vec![0; FT_IN + 1],
+                entries:  vec![],
+            };
+
+            for epoch in 0..self.epochs {
+                let t0 = Instant::now();
+                train.shuffle(&mut rng);
+
+                let mut loss = 0.0;
+                for batch in train.chunks(self.batch_size.max(1)) {
+                    adam.next_step();
+                    loss += self.step(net, &adam, &mut state, &mut buf, batch);
+                }
+                let loss = loss / train.len().max(1) as f64;
+
+                if self.print {
+                    let t1 = t0.elapsed().as_secs_f64();
+                    eprintln!("epoch {:>3}: loss = {:.6}, val loss = {:.6}, lr = {:.2e}, {:.1}s, {:.0} positions / sec",
+                              epoch, loss, self.mean_loss(net, val), adam.lr, t1, train.len() as f64 / t1);
+                }
+
+                if let Some(path) = out {
+                    net.to_nnue().write_nnue(path)?;
+                }
+
+                adam.lr *= self.lr_decay;
+            }
+
+            Ok(())
+        }
+
+        /// Returns the summed loss of the batch
+        fn step(
+            &self,
+            net:       &mut HalfKAv2Net,
+            adam:      &Adam,
+            state:     &mut AdamState,
+            buf:       &mut BatchBuf,
+            batch:     &[TrainSample],
+        ) -> f64 {
+            let n = batch.len();
+            buf.dx.resize(n * L1_IN, 0.0);
+            buf.dy.resize(n, 0.0);
+
+            /// Dense layers, per chunk of samples
+            let chunk = (n + rayon::current_num_threads() - 1) / rayon::current_num_threads();
+            let net2: &HalfKAv2Net = net;
+            let (grad,loss) = batch.par_chunks(chunk)
+                .zip(buf.dx.par_chunks_mut(chunk * L1_IN))
+                .zip(buf.dy.par_chunks_mut(chunk))
+                .map(|((ss,dxs),dys)| {
+                    let mut grad = vec![0.0; DENSE_SIZE];
+                    let mut sc   = Scratch::new();
+                    let mut loss = 0.0;
+                    for ((s,dx),dy) in ss.iter().zip(dxs.chunks_mut(L1_IN)).zip(dys.iter_mut()) {
+                        let y = net2.forward(s, &mut sc);
+                        let (l,d) = self.loss(y, s);
+                        loss += l as f64;
+                        *dy = d / n as f32;
+                        net2.backward(s, &sc, *dy, &mut grad, dx);
+                    }
+                    (grad,loss)
+                })
+                .reduce(|| (vec![0.0; DENSE_SIZE], 0.0), |(mut g0,l0),(g1,l1)| {
+                    g0.iter_mut().zip(g1.iter()).for_each(|(a,b)| *a += b);
+                    (g0, l0 + l1)
+                });
+
+            adam.update(&mut net.dense, &mut state.dense_m, &mut state.dense_v, &grad);
+            net.clip_weights();
+
+            /// Group the FT gradients by input
+            buf.starts.fill(0);
+            for s in batch.iter() {
+                for f in s.features.iter().flatten() {
+                    buf.starts[*f as usize + 1] += 1;
+                }
+            }
+            for f in 0..FT_IN {
+                buf.starts[f + 1] += buf.starts[f];
+            }
+            buf.entries.resize(buf.starts[FT_IN] as usize, 0);
+            let mut next = buf.starts.clone();
+            for (k,s) in batch.iter().enumerate() {
+                for (p,fs) in s.features.iter().enumerate() {
+                    for f in fs.iter() {
+                        buf.entries[next[*f as usize] as usize] = (k as u32) << 1 | p as u32;
+                        next[*f as usize] += 1;
+                    }
+                }
+            }
+
+            /// Sparse update, only rows used by the batch
+            let (dx,dy,starts,entries) = (&buf.dx, &buf.dy, &buf.starts, &buf.entries);
+            net.ft.par_chunks_mut(FT_ROW)
+                .zip(state.ft_m.par_chunks_mut(FT_ROW))
+                .zip(state.ft_v.par_chunks_mut(FT_ROW))
+                .enumerate()
+                .for_each(|(f,((ws,ms),vs))| {
+                    let es = &entries[starts[f] as usize..starts[f + 1] as usize];
+                    if es.is_empty() { return; }
+                    let mut g = [0.0f32; FT_ROW];
+                    for e in es.iter() {
+                        let k = (e >> 1) as usize;
+                        let p = (e & 1) as usize;
+                        let d = &dx[k * L1_IN + p * HALF_DIMS..][..HALF_DIMS];
+                        g[..HALF_DIMS].iter_mut().zip(d.iter()).for_each(|(a,b)| *a += b);
+                        let sign = if p == 0 { 0.5 } else { -0.5 };
+                        g[HALF_DIMS + batch[k].bucket as usize] += sign * dy[k];
+                    }
+                    adam.update(ws, ms, vs, &g);
+                });
+
+            loss
+        }
+
+    }
+
+    /// Float eval against the quantized eval
+    #[derive(Debug,Default,Clone)]
+    pub struct QuantError {
+        pub positions:  usize,
+        pub mean:       f64,
+        pub max:        Score,
+        /// FEN of the position with the max error
+        pub worst:      Option<String>,
+    }
+
+    impl std::fmt::Display for QuantError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{} positions: mean = {:.2}, max = {}", self.positions, self.mean, self.max)?;
+            if let Some(fen) = &self.worst {
+                write!(f, ", worst = {}", fen)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Check quantization
+    impl NNTrainer {
+
+        pub fn quantization_error(net: &HalfKAv2Net, nn: &mut NNUE4, gs: &[Game]) -> QuantError {
+            Self::quantization_error_scaled(net, nn, gs, &QuantScales::default())
+        }
+
+        /// nn quantized with scales, the float eval is multiplied by scales.eval_ratio()
+        pub fn quantization_error_scaled(
+            net:      &HalfKAv2Net,
+            nn:       &mut NNUE4,
+            gs:       &[Game],
+            scales:   &QuantScales,
+        ) -> QuantError {
+            let mut out = QuantError::default();
+            let mut sum: i64 = 0;
+            let mut sc = Scratch::new();
+            for g in gs.iter() {
+                let s = TrainSample::from_game(g, 0, 0.5);
+                let y = (net.forward(&s, &mut sc) * NNUE2SCORE * scales.eval_ratio()) as Score;
+                NNEvaluator::reset(nn, g);
+                let diff = (y - nn.evaluate(g, false)).abs();
+                sum += diff as i64;
+                if out.worst.is_none() || diff > out.max {
+                    out.max   = diff;
+                    out.worst = Some(g.to_fen());
+                }
+            }
+            out.positions = gs.len();
+            out.mean = sum as f64 / gs.len().max(1) as f64;
+            out
+        }
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movegen::MoveGen;
+    use crate::sf_compat::NNEval;
+    use crate::sf_compat::simd::check::randomize;
+
+    /// perft_fens.txt and every position one move later
+    fn perft_games(ts: &Tables) -> Vec<Game> {
+        let fens = crate::util::read_epd_no_bm(concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt")).unwrap();
+        let mut gs: Vec<Game> = fens.iter()
+            .flat_map(|fen| Game::from_fen(ts, fen.split(';').next().unwrap()))
+            .collect();
+        for g in gs.clone().iter() {
+            for mv in MoveGen::generate_list_legal(ts, g, None) {
+                gs.extend(g.make_move_unchecked(ts, mv).ok());
+            }
+        }
+        gs
+    }
+
+    fn nnue_eval(nn: &mut NNUE4, g: &Game) -> Score {
+        NNEvaluator::reset(nn, g);
+        nn.evaluate(g, false)
+    }
+
+    #[test]
+    fn halfkav2_quantized_matches_float() {
+        let ts = Tables::new();
+        let gs = perft_games(&ts);
+        assert!(gs.len() > 300);
+
+        let net = HalfKAv2Net::new_random(1234);
+        let mut nn = net.to_nnue();
+        nn.set_simd(SimdLevel::detect());
+
+        /// FT weights only have 7 bits of fraction
+        let (mut sum, mut max) = (0, 0);
+        for g in gs.iter() {
+            let s = TrainSample::from_game(g, 0, 0.5);
+            let diff = (net.evaluate(&s) - nnue_eval(&mut nn, g)).abs();
+            sum += diff;
+            max = max.max(diff);
+        }
+        let mean = sum as f64 / gs.len() as f64;
+        assert!(mean < 25.0 && max <= 50, "mean = {:.2}, max = {}", mean, max);
+    }
+
+    #[test]
+    fn halfkav2_write_read() {
+        let ts = Tables::new();
+        let gs = perft_games(&ts);
+
+        let mut nn = HalfKAv2Net::new_random(1234).to_nnue();
+        nn.set_simd(SimdLevel::detect());
+
+        let path = std::env::temp_dir().join(format!("rchess_halfkav2_{}.nnue", std::process::id()));
+        nn.write_nnue(&path).unwrap();
+        let nn2 = NNEval::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut nn2 = match nn2 {
+            Ok(NNEval::NNUE4(nn2)) => nn2,
+            nn2                    => panic!("expected NNUE4, got {:?}", nn2.map(|nn| nn.name())),
+        };
+
+        for g in gs.iter() {
+            assert_eq!(nnue_eval(&mut nn, g), nnue_eval(&mut nn2, g), "{}", g.to_fen());
+        }
+    }
+
+    #[test]
+    fn halfkav2_training_lowers_loss() {
+        let ts = Tables::new();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/small.binpack");
+        let mut xs = TrainSample::load_all(&ts, &[path]).unwrap();
+        assert!(xs.len() > 100);
+
+        let trainer = NNTrainer::new()
+            .epochs(10)
+            .batch_size(16)
+            .lr(1e-3)
+            .val_fraction(0.0)
+            .num_threads(2)
+            .print(false);
+
+        let mut net = HalfKAv2Net::new_random(1234);
+        let loss0 = trainer.mean_loss(&net, &xs);
+        trainer.train(&mut net, &mut xs, None).unwrap();
+        let loss1 = trainer.mean_loss(&net, &xs);
+        assert!(loss1 < loss0 * 0.9, "{} -> {}", loss0, loss1);
+    }
+
+
+    #[test]
+    fn halfkav2_dequantize_quantize() {
+        let ts = Tables::new();
+        let gs = perft_games(&ts);
+
+        let mut nn = NNUE4::new_empty();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        randomize(&mut nn, &mut rng);
+
+        /// Integer weights survive the round trip exactly
+        let net = HalfKAv2Net::from_nnue(&nn);
+        let mut nn2 = net.to_nnue();
+        nn2.set_simd(SimdLevel::detect());
+        for g in gs.iter() {
+            assert_eq!(nnue_eval(&mut nn, g), nnue_eval(&mut nn2, g), "{}", g.to_fen());
+        }
+        let net2 = HalfKAv2Net::from_nnue(&nn2);
+        assert!(net2.ft == net.ft && net2.dense == net.dense);
+
+        /// Same weights as the quantized net, so only rounding of the activations
+        let net = HalfKAv2Net::from_nnue(&HalfKAv2Net::new_random(1234).to_nnue());
+        let mut nn = net.to_nnue();
+        nn.set_simd(SimdLevel::detect());
+        let err = NNTrainer::quantization_error(&net, &mut nn, &gs);
+        assert_eq!(err.positions, gs.len());
+        assert!(err.worst.is_some());
+        assert!(err.mean < 3.0 && err.max <= 8, "{}", err);
+    }
+
+    #[test]
+    fn halfkav2_quant_scales() {
+        let ts = Tables::new();
+        let gs = perft_games(&ts);
+        let net = HalfKAv2Net::new_random(1234);
+
+        let mut nn = net.to_nnue();
+        nn.set_simd(SimdLevel::detect());
+        let err0 = NNTrainer::quantization_error(&net, &mut nn, &gs);
+
+        /// Output scale only rescales the eval
+        let sc = QuantScales { output: QO * 2.0, ..Default::default() };
+        let mut nn2 = net.to_nnue_scaled(&sc).unwrap();
+        nn2.set_simd(SimdLevel::detect());
+        let err = NNTrainer::quantization_error_scaled(&net, &mut nn2, &gs, &sc);
+        assert!(err.mean < err0.mean * 2.0 + 1.0, "{} / {}", err0, err);
+
+        let net2 = HalfKAv2Net::from_nnue_scaled(&nn2, &sc).unwrap();
+        let psqt = |n: &HalfKAv2Net| n.ft.chunks(FT_ROW).map(|r| r[HALF_DIMS]).collect::<Vec<_>>();
+        for (a,b) in psqt(&net).iter().zip(psqt(&net2).iter()) {
+            assert!((a - b).abs() <= 0.5 / sc.output, "{} {}", a, b);
+        }
+
+        /// The engine's clamp and shift fix the other scales
+        let bad = [
+            QuantScales { activation: 255.0, ..Default::default() },
+            QuantScales { hidden: 128.0, ..Default::default() },
+            QuantScales { output: 0.0, ..Default::default() },
+            QuantScales { output: f32::NAN, ..Default::default() },
+        ];
+        for sc in bad.iter() {
+            assert!(sc.check().is_err(), "{:?}", sc);
+            assert!(net.to_nnue_scaled(sc).is_err(), "{:?}", sc);
+            assert!(HalfKAv2Net::from_nnue_scaled(&nn, sc).is_err(), "{:?}", sc);
+        }
+    }
+
+}
@@ -0,0 +1,412 @@
+use crate::tables::*;
+use crate::types::*;
+use crate::brain::gensfen::{TrainingData,TDOutcome};
+use crate::brain::nnue_trainer::{NNTrainer,TrainSample,QuantError,Adam};
+use crate::sf_compat::PerspectiveNet;
+
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use arrayvec::ArrayVec;
+use rand::{prelude::{StdRng,SliceRandom},Rng,SeedableRng};
+use rayon::prelude::*;
+
+/// Activations, 1.0 = QA
+const QA: f32 = PerspectiveNet::QA as f32;
+/// Output weights, 1.0 = QB
+const QB: f32 = PerspectiveNet::QB as f32;
+/// Float output to eval
+const OUT_SCALE: f32 = PerspectiveNet::SCALE as f32;
+
+/// Accumulators are i16, so 32 pieces plus the bias can't overflow
+const MAX_FT_WEIGHT: f32 = i16::MAX as f32 / (33.0 * QA);
+/// Output weights are i16 too
+const MAX_OUT_WEIGHT: f32 = i16::MAX as f32 / QB;
+
+/// One position, as the inputs to PerspectiveNet
+#[derive(Debug,Clone)]
+pub struct PerspectiveSample {
+    /// PerspectiveNet::index, side to move first
+    pub features: [ArrayVec<u16, 32>; 2],
+    /// Search score, side to move
+    pub score:    i16,
+    /// Game result, side to move, 1.0 = win
+    pub result:   f32,
+}
+
+impl PerspectiveSample {
+
+    pub fn from_game(g: &Game, score: Score, result: f32) -> Self {
+        let persps = [g.state.side_to_move, !g.state.side_to_move];
+        let mut features = [ArrayVec::new(), ArrayVec::new()];
+        for (p,persp) in persps.iter().enumerate() {
+            for side in [White,Black] {
+                for pc in Piece::iter_pieces() {
+                    g.get(pc, side).into_iter().for_each(|sq| {
+                        features[p].push(PerspectiveNet::index(*persp, pc, side, sq) as u16);
+                    });
+                }
+            }
+        }
+        Self {
+            features,
+            score:  score.clamp(-i16::MAX as Score, i16::MAX as Score) as i16,
+            result,
+        }
+    }
+
+    pub fn from_training_data(ts: &Tables, td: &TrainingData) -> Vec<Self> {
+        let mut out = vec![];
+        td.for_each_position(ts, |g, te| {
+            if te.skip { return; }
+            let result = match td.result {
+                TDOutcome::Win(c) if c == g.state.side_to_move => 1.0,
+                TDOutcome::Win(_)                              => 0.0,
+                TDOutcome::Draw | TDOutcome::Stalemate         => 0.5,
+            };
+            out.push(Self::from_game(g, te.eval, result));
+        });
+        out
+    }
+
+    /// Same files as TrainSample::load_all
+    pub fn load_all<P: AsRef<Path>>(ts: &Tables, paths: &[P]) -> io::Result<Vec<Self>> {
+        let mut out = vec![];
+        for path in TrainSample::data_files(paths)?.iter() {
+            let tds = TrainSample::read_file(ts, path)?;
+            out.par_extend(tds.par_iter().flat_map_iter(|td| Self::from_training_data(ts, td)));
+        }
+        Ok(out)
+    }
+
+}
+
+/// Float version of PerspectiveNet, for training
+#[derive(Debug,Clone)]
+pub struct PerspectiveF32Net {
+    pub hidden:  usize,
+    /// [feature][hidden]
+    pub ft:      Vec<f32>,
+    /// FT biases, output weights (side to move half first), output bias
+    pub dense:   Vec<f32>,
+}
+
+/// New
+impl PerspectiveF32Net {
+
+    pub fn new_random(hidden: usize, seed: u64) -> Self {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+        let mut out = Self {
+            hidden,
+            ft:     vec![0.0; PerspectiveNet::INPUTS * hidden],
+            dense:  vec![0.0; 3 * hidden + 1],
+        };
+        out.ft.iter_mut().for_each(|w| *w = rng.gen_range(-0.05..0.05));
+        out.dense[..hidden].fill(0.5);
+        let k = 1.0 / (2.0 * hidden as f32).sqrt();
+        out.dense[hidden..3 * hidden].iter_mut().for_each(|w| *w = rng.gen_range(-k..k));
+        out
+    }
+
+    /// Keep weights in range of the quantized net
+    pub fn clip_weights(&mut self) {
+        let h = self.hidden;
+        self.ft.iter_mut().chain(self.dense[..h].iter_mut()).for_each(|w| {
+            *w = w.clamp(-MAX_FT_WEIGHT, MAX_FT_WEIGHT);
+        });
+        self.dense[h..3 * h].iter_mut().for_each(|w| {
+            *w = w.clamp(-MAX_OUT_WEIGHT, MAX_OUT_WEIGHT);
+        });
+    }
+
+}
+
+fn active(x: f32) -> bool { x > 0.0 && x < 1.0 }
+
+/// Forward, backward
+impl PerspectiveF32Net {
+
+    /// Eval, side to move
+    pub fn evaluate(&self, s: &PerspectiveSample) -> Score {
+        let mut x = vec![0.0; 2 * self.hidden];
+        (self.forward(s, &mut x) * OUT_SCALE) as Score
+    }
+
+    /// x is the clamped accumulators, side to move first
+    fn forward(&self, s: &PerspectiveSample, x: &mut [f32]) -> f32 {
+        let h = self.hidden;
+        for p in 0..2 {
+            let acc = &mut x[p * h..][..h];
+            acc.copy_from_slice(&self.dense[..h]);
+            for f in s.features[p].iter() {
+                let row = &self.ft[*f as usize * h..][..h];
+                acc.iter_mut().zip(row.iter()).for_each(|(a,w)| *a += w);
+            }
+            acc.iter_mut().for_each(|a| *a = a.clamp(0.0, 1.0));
+        }
+        let ws = &self.dense[h..3 * h];
+        ws.iter().zip(x.iter()).fold(self.dense[3 * h], |acc,(w,x)| acc + w * x)
+    }
+
+    /// Adds the gradients to grad_ft and grad_dense, dx is scratch
+    fn backward(
+        &self,
+        s:           &PerspectiveSample,
+        x:           &[f32],
+        dy:          f32,
+        grad_ft:     &mut [f32],
+        grad_dense:  &mut [f32],
+        dx:          &mut [f32],
+    ) {
+        let h = self.hidden;
+        grad_dense[3 * h] += dy;
+        for k in 0..2 * h {
+            grad_dense[h + k] += dy * x[k];
+            dx[k] = if active(x[k]) { dy * self.dense[h + k] } else { 0.0 };
+        }
+        for k in 0..h {
+            grad_dense[k] += dx[k] + dx[h + k];
+        }
+        for p in 0..2 {
+            let d = &dx[p * h..][..h];
+            for f in s.features[p].iter() {
+                let g = &mut grad_ft[*f as usize * h..][..h];
+                g.iter_mut().zip(d.iter()).for_each(|(a,b)| *a += b);
+            }
+        }
+    }
+
+}
+
+fn q_i16(x: f32) -> i16 { x.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16 }
+fn q_i32(x: f32) -> i32 { x.round().clamp(i32::MIN as f32, i32::MAX as f32) as i32 }
+
+/// Convert to and from PerspectiveNet
+impl PerspectiveF32Net {
+
+    pub fn to_net(&self) -> PerspectiveNet {
+        let h = self.hidden;
+        let mut nn = PerspectiveNet::new_empty(h);
+        nn.ft_weights.iter_mut().zip(self.ft.iter()).for_each(|(q,w)| *q = q_i16(w * QA));
+        nn.ft_biases.iter_mut().zip(self.dense[..h].iter()).for_each(|(q,w)| *q = q_i16(w * QA));
+        nn.out_weights.iter_mut().zip(self.dense[h..3 * h].iter()).for_each(|(q,w)| *q = q_i16(w * QB));
+        nn.out_bias = q_i32(self.dense[3 * h] * QA * QB);
+        nn
+    }
+
+    /// Start training from an existing network
+    pub fn from_net(nn: &PerspectiveNet) -> Self {
+        let h = nn.hidden;
+        let mut dense = Vec::with_capacity(3 * h + 1);
+        dense.extend(nn.ft_biases.iter().map(|q| *q as f32 / QA));
+        dense.extend(nn.out_weights.iter().map(|q| *q as f32 / QB));
+        dense.push(nn.out_bias as f32 / (QA * QB));
+        Self {
+            hidden: h,
+            ft:     nn.ft_weights.iter().map(|q| *q as f32 / QA).collect(),
+            dense,
+        }
+    }
+
+    pub fn quantization_error(&self, nn: &mut PerspectiveNet, gs: &[Game]) -> QuantError {
+        let mut out = QuantError::default();
+        let mut sum: i64 = 0;
+        for g in gs.iter() {
+            let s = PerspectiveSample::from_game(g, 0, 0.5);
+            nn.refresh(g);
+            let diff = (self.evaluate(&s) - nn.evaluate(g)).abs();
+            sum += diff as i64;
+            if out.worst.is_none() || diff > out.max {
+                out.max   = diff;
+                out.worst = Some(g.to_fen());
+            }
+        }
+        out.positions = gs.len();
+        out.mean = sum as f64 / gs.len().max(1) as f64;
+        out
+    }
+
+}
+
+/// Train
+impl NNTrainer {
+
+    /// Same settings as NNTrainer::train, saves the quantized net to out after each epoch
+    pub fn train_perspective(
+        &self,
+        net:       &mut PerspectiveF32Net,
+        samples:   &mut [PerspectiveSample],
+        out:       Option<&Path>,
+    ) -> io::Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        pool.install(|| self._train_perspective(net, samples, out))
+    }
+
+    pub fn mean_loss_perspective(&self, net: &PerspectiveF32Net, samples: &[PerspectiveSample]) -> f64 {
+        if samples.is_empty() { return 0.0; }
+        let sum: f64 = samples.par_chunks(256).map(|xs| {
+            let mut x = vec![0.0; 2 * net.hidden];
+            xs.iter().map(|s| {
+                let y = net.forward(s, &mut x);
+                self.win_loss(y, OUT_SCALE, s.score, s.result).0 as f64
+            }).sum::<f64>()
+        }).sum();
+        sum / samples.len() as f64
+    }
+
+    fn _train_perspective(
+        &self,
+        net:       &mut PerspectiveF32Net,
+        samples:   &mut [PerspectiveSample],
+        out:       Option<&Path>,
+    ) -> io::Result<()> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed);
+
+        samples.shuffle(&mut rng);
+        let n_val = (samples.len() as f32 * self.val_fraction) as usize;
+        let (val, train) = samples.split_at_mut(n_val);
+
+        if self.print {
+            eprintln!("training on {} positions, {} validation, {} threads",
+                      train.len(), val.len(), rayon::current_num_threads());
+            eprintln!("start: val loss = {:.6}", self.mean_loss_perspective(net, val));
+        }
+
+        let mut adam = Adam::new(self.lr);
+        let (mut ft_m, mut ft_v)       = (vec![0.0; net.ft.len()], vec![0.0; net.ft.len()]);
+        let (mut dense_m, mut dense_v) = (vec![0.0; net.dense.len()], vec![0.0; net.dense.len()]);
+
+        for epoch in 0..self.epochs {
+            let t0 = Instant::now();
+            train.shuffle(&mut rng);
+
+            let mut loss = 0.0;
+            for batch in train.chunks(self.batch_size.max(1)) {
+                adam.next_step();
+                let (grad_ft,grad_dense,l) = self.gradient_perspective(net, batch);
+                adam.update(&mut net.ft, &mut ft_m, &mut ft_v, &grad_ft);
+                adam.update(&mut net.dense, &mut dense_m, &mut dense_v, &grad_dense);
+                net.clip_weights();
+                loss += l;
+            }
+            let loss = loss / train.len().max(1) as f64;
+
+            if self.print {
+                let t1 = t0.elapsed().as_secs_f64();
+                eprintln!("epoch {:>3}: loss = {:.6}, val loss = {:.6}, lr = {:.2e}, {:.1}s, {:.0} positions / sec",
+                          epoch, loss, self.mean_loss_perspective(net, val), adam.lr, t1, train.len() as f64 / t1);
+            }
+
+            if let Some(path) = out {
+                net.to_net().write(path)?;
+            }
+
+            adam.lr *= self.lr_decay;
+        }
+
+        Ok(())
+    }
+
+    /// Mean gradients of the batch, and the summed loss
+    fn gradient_perspective(
+        &self,
+        net:    &PerspectiveF32Net,
+        batch:  &[PerspectiveSample],
+    ) -> (Vec<f32>,Vec<f32>,f64) {
+        let n = batch.len();
+        let chunk = (n + rayon::current_num_threads() - 1) / rayon::current_num_threads();
+        let zero = || (vec![0.0; net.ft.len()], vec![0.0; net.dense.len()], 0.0);
+        batch.par_chunks(chunk.max(1))
+            .map(|ss| {
+                let (mut grad_ft, mut grad_dense, mut loss) = zero();
+                let mut x  = vec![0.0; 2 * net.hidden];
+                let mut dx = vec![0.0; 2 * net.hidden];
+                for s in ss.iter() {
+                    let y = net.forward(s, &mut x);
+                    let (l,d) = self.win_loss(y, OUT_SCALE, s.score, s.result);
+                    loss += l as f64;
+                    net.backward(s, &x, d / n as f32, &mut grad_ft, &mut grad_dense, &mut dx);
+                }
+                (grad_ft, grad_dense, loss)
+            })
+            .reduce(zero, |(mut f0,mut d0,l0),(f1,d1,l1)| {
+                f0.iter_mut().zip(f1.iter()).for_each(|(a,b)| *a += b);
+                d0.iter_mut().zip(d1.iter()).for_each(|(a,b)| *a += b);
+                (f0, d0, l0 + l1)
+            })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brain::binpack::read_training_data;
+    use crate::movegen::MoveGen;
+
+    fn samples(ts: &Tables) -> Vec<PerspectiveSample> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/small.binpack");
+        let tds = read_training_data(ts, path).unwrap();
+        tds.iter().flat_map(|td| PerspectiveSample::from_training_data(ts, td)).collect()
+    }
+
+    #[test]
+    fn perspective_quantized_matches_float() {
+        let ts = Tables::new();
+        let net = PerspectiveF32Net::new_random(32, 1234);
+        let mut nn = net.to_net();
+
+        let fens = crate::util::read_epd_no_bm(concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt")).unwrap();
+        let mut gs: Vec<Game> = fens.iter()
+            .flat_map(|fen| Game::from_fen(&ts, fen.split(';').next().unwrap()))
+            .collect();
+        for g in gs.clone().iter() {
+            for mv in MoveGen::generate_list_legal(&ts, g, None) {
+                gs.extend(g.make_move_unchecked(&ts, mv).ok());
+            }
+        }
+        assert!(gs.len() > 300);
+
+        /// Output weights only have 6 bits of fraction
+        let err = net.quantization_error(&mut nn, &gs);
+        assert!(err.mean < 15.0, "{}", err);
+
+        /// Same weights as the quantized net, so only rounding of the output
+        let net2 = PerspectiveF32Net::from_net(&nn);
+        let err = net2.quantization_error(&mut nn, &gs);
+        assert!(err.mean < 1.0, "{}", err);
+        assert!(err.max <= 1, "{}", err);
+
+        let nn2 = net2.to_net();
+        assert_eq!(nn.ft_weights, nn2.ft_weights);
+        assert_eq!(nn.ft_biases, nn2.ft_biases);
+        assert_eq!(nn.out_weights, nn2.out_weights);
+        assert_eq!(nn.out_bias, nn2.out_bias);
+    }
+
+    #[test]
+    fn perspective_training_lowers_loss() {
+        let ts = Tables::new();
+        let mut xs = samples(&ts);
+        assert!(xs.len() > 100);
+
+        let trainer = NNTrainer::new()
+            .epochs(20)
+            .batch_size(16)
+            .lr(1e-2)
+            .val_fraction(0.0)
+            .num_threads(2)
+            .print(false);
+
+        let mut net = PerspectiveF32Net::new_random(16, 1234);
+        let loss0 = trainer.mean_loss_perspective(&net, &xs);
+        trainer.train_perspective(&mut net, &mut xs, None).unwrap();
+        let loss1 = trainer.mean_loss_perspective(&net, &xs);
+        assert!(loss1 < loss0 * 0.9, "{} -> {}", loss0, loss1);
+    }
+
+}
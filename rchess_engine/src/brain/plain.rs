@@ -0,0 +1,145 @@
+//! Stockfish .plain training data, one position per record:
+//!
+//! fen <fen>
+//! move <uci move>
+//! score <score>
+//! ply <ply>
+//! result <1, 0, -1>
+//! e
+//!
+//! Scores and results are from the side to move's perspective, same as binpack.
+
+use crate::types::*;
+use crate::tables::*;
+use crate::movegen::MoveGen;
+use crate::parsing::FenMode;
+use crate::brain::binpack::BPEntry;
+
+use std::io::{self,BufRead,Write,BufReader,BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+fn invalid<T>(line: usize, msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("plain, line {}: {}", line, msg)))
+}
+
+pub struct PlainReader<'a, R: BufRead> {
+    ts:          &'a Tables,
+    rdr:         R,
+    line:        usize,
+    buf:         String,
+}
+
+impl<'a> PlainReader<'a, BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(ts: &'a Tables, path: P) -> io::Result<Self> {
+        Ok(Self::new(ts, BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<'a, R: BufRead> PlainReader<'a, R> {
+
+    pub fn new(ts: &'a Tables, rdr: R) -> Self {
+        Self { ts, rdr, line: 0, buf: String::new() }
+    }
+
+    /// None at the end of the file, blank lines are skipped
+    fn next_line(&mut self) -> io::Result<Option<(String,String)>> {
+        loop {
+            self.buf.clear();
+            if self.rdr.read_line(&mut self.buf)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            let l = self.buf.trim();
+            if l.is_empty() { continue; }
+            let (key,val) = l.split_once(' ').unwrap_or((l, ""));
+            return Ok(Some((key.to_string(), val.trim().to_string())));
+        }
+    }
+
+    pub fn next_entry(&mut self) -> io::Result<Option<BPEntry>> {
+        let (mut game, mut mv, mut score, mut ply, mut result) = (None, None, 0, 0, 0);
+        let mut started = false;
+
+        loop {
+            let (key,val) = match self.next_line()? {
+                Some(kv) => kv,
+                None if started => return invalid(self.line, "record without 'e'"),
+                None            => return Ok(None),
+            };
+            started = true;
+            let num = |line: usize| -> io::Result<i64> {
+                val.parse().or_else(|_| invalid(line, &format!("bad number: {}", val)))
+            };
+            match key.as_str() {
+                "fen"    => {
+                    let g = Game::from_fen_mode(self.ts, &val, FenMode::Lenient)
+                        .or_else(|e| invalid(self.line, &format!("bad fen: {}", e)))?;
+                    game = Some(g);
+                },
+                "move"   => {
+                    let g = match game.as_ref() {
+                        Some(g) => g,
+                        None    => return invalid(self.line, "move before fen"),
+                    };
+                    let m = MoveGen::generate_list_legal(self.ts, g, None).into_iter()
+                        .find(|m| m.to_long_algebraic() == val);
+                    match m {
+                        Some(m) => mv = Some(m),
+                        None    => return invalid(self.line, &format!("illegal move: {}", val)),
+                    }
+                },
+                "score"  => score = num(self.line)?.clamp(i16::MIN as i64, i16::MAX as i64) as i16,
+                "ply"    => ply = num(self.line)?.clamp(0, u16::MAX as i64) as u16,
+                "result" => result = num(self.line)?.signum() as i8,
+                "e"      => break,
+                _        => return invalid(self.line, &format!("unknown key: {}", key)),
+            }
+        }
+
+        match (game,mv) {
+            (Some(game),Some(mv)) => Ok(Some(BPEntry { game, mv, score, ply, result })),
+            _                     => invalid(self.line, "record without fen or move"),
+        }
+    }
+
+}
+
+impl<'a, R: BufRead> Iterator for PlainReader<'a, R> {
+    type Item = io::Result<BPEntry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+pub struct PlainWriter<W: Write> {
+    w:           W,
+}
+
+impl PlainWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> PlainWriter<W> {
+
+    pub fn new(w: W) -> Self {
+        Self { w }
+    }
+
+    pub fn write_entry(&mut self, e: &BPEntry) -> io::Result<()> {
+        writeln!(self.w, "fen {} {} {}", e.game.to_fen(), e.game.halfmove, e.ply / 2 + 1)?;
+        writeln!(self.w, "move {}", e.mv.to_long_algebraic())?;
+        writeln!(self.w, "score {}", e.score)?;
+        writeln!(self.w, "ply {}", e.ply)?;
+        writeln!(self.w, "result {}", e.result)?;
+        writeln!(self.w, "e")
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.w.flush()?;
+        Ok(self.w)
+    }
+
+}
@@ -0,0 +1,786 @@
+
+use crate::types::*;
+use crate::tables::*;
+use crate::explore::*;
+use crate::alphabeta::ABResult;
+use crate::searchstats::SearchStats;
+use crate::sf_compat::NNEval;
+use crate::timer::*;
+use crate::builder_field;
+use crate::brain::gensfen::{TrainingData,SfenFormat,SfenDir,SfenWriter,dedup_key};
+#[cfg(feature = "syzygy")]
+use crate::brain::gensfen::probe_wdl_exact;
+use crate::brain::binpack::{BPEntry,BPReader,BPWriter};
+use crate::brain::plain::PlainReader;
+use crate::brain::nnue_trainer::TrainSample;
+
+#[cfg(feature = "lockless_hashmap")]
+use crate::lockless_map::TransTable;
+
+use std::io::{self,BufReader};
+use std::fs::File;
+use std::path::{Path,PathBuf};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{Ordering,AtomicU64,AtomicBool};
+use std::time::{Instant,Duration};
+
+use crossbeam::channel::{Sender,Receiver};
+use rand::{prelude::{StdRng,SliceRandom},Rng,SeedableRng};
+
+/// Positions per message between threads
+const BATCH_SIZE: usize = 1024;
+
+/// Temporary files for merge rounds, inside the output dir
+const MERGE_DIR: &'static str = "merge_tmp";
+
+/// Streams training data through a set of steps, in this order:
+/// ply and piece count bounds, dedup, quiet, syzygy relabel, rescore, score bound.
+/// Every step is off by default.
+/// Memory is bounded by the channels, the shuffle buffer and the dedup table.
+#[derive(Debug,Clone)]
+pub struct TDPipeline {
+    min_ply:            Option<u16>,
+    max_ply:            Option<u16>,
+    min_pieces:         Option<u32>,
+    max_pieces:         Option<u32>,
+    /// Checked after rescoring
+    max_score:          Option<Score>,
+    /// Size of the dedup table
+    dedup_mb:           Option<usize>,
+    /// Drop positions that aren't quiet, see TrainingData::is_quiet
+    quiet:              bool,
+    /// Replace scores with a fixed depth search
+    rescore_depth:      Option<Depth>,
+    /// Relabel results of positions in the tables with their WDL
+    syzygy_path:        Option<PathBuf>,
+    /// Used by quiet and rescore
+    nnue_path:          Option<PathBuf>,
+    /// Interleave the inputs, and mix the output through a buffer of this many positions
+    shuffle:            Option<usize>,
+    /// Most inputs open at once when shuffling, more are merged in rounds
+    merge_width:        usize,
+    num_threads:        usize,
+    hash_size_mb:       usize,
+    positions_per_file: u64,
+    format:             SfenFormat,
+    seed:               u64,
+    print:              bool,
+}
+
+impl TDPipeline {
+    pub fn new() -> Self {
+        Self {
+            min_ply:            None,
+            max_ply:            None,
+            min_pieces:         None,
+            max_pieces:         None,
+            max_score:          None,
+            dedup_mb:           None,
+            quiet:              false,
+            rescore_depth:      None,
+            syzygy_path:        None,
+            nnue_path:          None,
+            shuffle:            None,
+            merge_width:        32,
+            num_threads:        1,
+            hash_size_mb:       16,
+            positions_per_file: 1_000_000,
+            format:             SfenFormat::Binpack,
+            seed:               1234,
+            print:              true,
+        }
+    }
+    builder_field!(min_ply, Option<u16>);
+    builder_field!(max_ply, Option<u16>);
+    builder_field!(min_pieces, Option<u32>);
+    builder_field!(max_pieces, Option<u32>);
+    builder_field!(max_score, Option<Score>);
+    builder_field!(dedup_mb, Option<usize>);
+    builder_field!(quiet, bool);
+    builder_field!(rescore_depth, Option<Depth>);
+    builder_field!(syzygy_path, Option<PathBuf>);
+    builder_field!(nnue_path, Option<PathBuf>);
+    builder_field!(shuffle, Option<usize>);
+    builder_field!(merge_width, usize);
+    builder_field!(num_threads, usize);
+    builder_field!(hash_size_mb, usize);
+    builder_field!(positions_per_file, u64);
+    builder_field!(format, SfenFormat);
+    builder_field!(seed, u64);
+    builder_field!(print, bool);
+}
+
+/// Counts of positions, shared between threads
+#[derive(Debug,Default)]
+pub struct TDPipelineStats {
+    pub read:           AtomicU64,
+    /// By ply, piece count or score
+    pub out_of_bounds:  AtomicU64,
+    pub duplicates:     AtomicU64,
+    pub not_quiet:      AtomicU64,
+    pub relabeled:      AtomicU64,
+    pub rescored:       AtomicU64,
+    pub written:        AtomicU64,
+}
+
+impl TDPipelineStats {
+    fn inc(x: &AtomicU64) {
+        x.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn print(&self, t0: Instant) {
+        let get = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        let t1 = t0.elapsed().as_secs_f64();
+        eprintln!("{:>9} read, {:>9} written, {:.1}s, {:.1} positions / sec",
+                  get(&self.read), get(&self.written), t1, get(&self.read) as f64 / t1);
+        eprintln!("    dropped: {} out of bounds, {} duplicates, {} not quiet",
+                  get(&self.out_of_bounds), get(&self.duplicates), get(&self.not_quiet));
+        eprintln!("    {} rescored, {} relabeled", get(&self.rescored), get(&self.relabeled));
+    }
+}
+
+/// Fixed size, lossy: a key can be replaced by another with the same slot,
+/// so duplicates far apart are sometimes kept
+struct DedupTable {
+    keys:    Vec<AtomicU64>,
+    /// Any key is valid, including 0
+    used:    Vec<AtomicBool>,
+}
+
+impl DedupTable {
+    fn new_mb(mb: usize) -> Self {
+        let slot = std::mem::size_of::<u64>() + std::mem::size_of::<bool>();
+        let n = (mb * 1024 * 1024 / slot).max(1);
+        Self {
+            keys: (0..n).map(|_| AtomicU64::new(0)).collect(),
+            used: (0..n).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// True if key wasn't in the table
+    fn insert(&self, key: u64) -> bool {
+        let idx = ((key as u128 * self.keys.len() as u128) >> 64) as usize;
+        let used = self.used[idx].swap(true, Ordering::Relaxed);
+        let prev = self.keys[idx].swap(key, Ordering::Relaxed);
+        !used || prev != key
+    }
+}
+
+/// One input file, as positions
+enum TDInput<'a> {
+    Binpack(BPReader<'a, BufReader<File>>),
+    Plain(PlainReader<'a, BufReader<File>>),
+    /// Positions left from the last TrainingData
+    Bincode(&'a Tables, BufReader<File>, VecDeque<BPEntry>),
+}
+
+impl<'a> TDInput<'a> {
+    fn open(ts: &'a Tables, path: &Path) -> io::Result<Self> {
+        match SfenFormat::from_path(path) {
+            Some(SfenFormat::Binpack) => Ok(Self::Binpack(BPReader::open(ts, path)?)),
+            Some(SfenFormat::Plain)   => Ok(Self::Plain(PlainReader::open(ts, path)?)),
+            Some(SfenFormat::Bincode) => Ok(Self::Bincode(ts, BufReader::new(File::open(path)?), VecDeque::new())),
+            None                      => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown training data format: {:?}", path))),
+        }
+    }
+
+    fn next_entry(&mut self) -> io::Result<Option<BPEntry>> {
+        match self {
+            Self::Binpack(r) => r.next_entry(),
+            Self::Plain(r)   => r.next_entry(),
+            Self::Bincode(ts, rdr, pending) => loop {
+                if let Some(e) = pending.pop_front() {
+                    return Ok(Some(e));
+                }
+                /// Same as TrainingData::load_all, the file ends at the first bad record
+                match bincode::deserialize_from::<_,TrainingData>(&mut *rdr) {
+                    Ok(td) => pending.extend(BPEntry::from_training_data(ts, &td)),
+                    Err(_) => return Ok(None),
+                }
+            },
+        }
+    }
+}
+
+/// Run
+impl TDPipeline {
+
+    /// Inputs are files or directories of them, in any SfenFormat.
+    /// out_dir is numbered files like gensfen, and must not already have any.
+    /// Returns the number of positions written
+    pub fn run<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        ts:         &Tables,
+        inputs:     &[P],
+        out_dir:    Q,
+    ) -> io::Result<u64> {
+
+        let files = TrainSample::data_files(inputs)?;
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no input files"));
+        }
+
+        std::fs::create_dir_all(&out_dir)?;
+        let dir = SfenDir {
+            dir:        out_dir.as_ref().to_path_buf(),
+            format:     self.format,
+            next:       0,
+            positions:  0,
+        };
+        if dir.file_name(0).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already has training data", dir.dir)));
+        }
+
+        let nnue = match &self.nnue_path {
+            Some(path) => Some(NNEval::read(path).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+            })?),
+            None       => None,
+        };
+
+        if self.syzygy_path.is_some() && !cfg!(feature = "syzygy") {
+            eprintln!("warning: relabeling needs feature syzygy, skipping");
+        }
+
+        if self.print {
+            eprintln!("pipeline: {} files -> {:?}, {} threads", files.len(), dir.dir, self.num_threads);
+        }
+
+        let dedup = self.dedup_mb.map(DedupTable::new_mb);
+        let stats = TDPipelineStats::default();
+
+        let (tx_in,rx_in): (Sender<Vec<BPEntry>>, Receiver<Vec<BPEntry>>) =
+            crossbeam::channel::bounded(self.num_threads * 2);
+        let (tx_out,rx_out): (Sender<Vec<BPEntry>>, Receiver<Vec<BPEntry>>) =
+            crossbeam::channel::bounded(self.num_threads * 2);
+
+        let t0 = Instant::now();
+        let mut read_result = Ok(());
+        let mut result = Ok(0);
+
+        crossbeam::scope(|s| {
+            let (files, stats, dedup) = (&files, &stats, dedup.as_ref());
+
+            let dir = &dir;
+            let reader = s.spawn(move |_| self.read_inputs(ts, files, &dir.dir, stats, tx_in));
+
+            for _ in 0..self.num_threads {
+                let (rx,tx) = (rx_in.clone(), tx_out.clone());
+                let nnue = nnue.clone();
+                /// 4 MB is needed to prevent stack overflow
+                s.builder()
+                    .stack_size(1024 * 1024 * 4)
+                    .spawn(move |_| self.worker(ts, nnue, dedup, stats, rx, tx))
+                    .unwrap();
+            }
+            drop(rx_in);
+            drop(tx_out);
+
+            result = self.write_output(ts, &dir, t0, stats, rx_out);
+            read_result = reader.join().unwrap();
+        }).unwrap();
+
+        if self.print {
+            eprintln!("pipeline done");
+            stats.print(t0);
+        }
+
+        read_result?;
+        result
+    }
+
+    /// When shuffling up to merge_width files are open at once, and each batch is from
+    /// a random one. With more files than that, they're first merged in rounds into
+    /// temporary files in out_dir, so every input is mixed with every other
+    fn read_inputs(
+        &self,
+        ts:         &Tables,
+        files:      &[PathBuf],
+        out_dir:    &Path,
+        stats:      &TDPipelineStats,
+        tx:         Sender<Vec<BPEntry>>,
+    ) -> io::Result<()> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed);
+
+        if self.shuffle.is_none() {
+            return self.interleave(ts, files, 1, &mut rng, |batch| {
+                stats.read.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                Ok(tx.send(batch).is_ok())
+            });
+        }
+
+        let width = self.merge_width.max(2);
+        let tmp = out_dir.join(MERGE_DIR);
+        let mut files = files.to_vec();
+        let mut round = 0;
+        while files.len() > width {
+            std::fs::create_dir_all(&tmp)?;
+            let n_out = (files.len() + width - 1) / width;
+            let mut merged = vec![];
+            for k in 0..n_out {
+                /// Strided, so each output mixes files from across the whole input
+                let group = files.iter().skip(k).step_by(n_out).cloned().collect::<Vec<_>>();
+                let path = tmp.join(format!("round_{}_{:05}.binpack", round, k));
+                let mut w = BPWriter::create(ts, &path)?;
+                self.interleave(ts, &group, width, &mut rng, |batch| {
+                    batch.iter().try_for_each(|e| w.write_entry(e))?;
+                    Ok(true)
+                })?;
+                w.finish()?;
+                merged.push(path);
+            }
+            /// Only the previous round's files, never the inputs
+            if round > 0 {
+                files.iter().try_for_each(std::fs::remove_file)?;
+            }
+            if self.print {
+                eprintln!("merge round {}: {} files -> {}", round, files.len(), merged.len());
+            }
+            files = merged;
+            round += 1;
+        }
+
+        self.interleave(ts, &files, width, &mut rng, |batch| {
+            stats.read.fetch_add(batch.len() as u64, Ordering::Relaxed);
+            Ok(tx.send(batch).is_ok())
+        })?;
+
+        if round > 0 {
+            std::fs::remove_dir_all(&tmp)?;
+        }
+        Ok(())
+    }
+
+    /// Batches from a random one of up to max_open files at a time, stops when f returns false
+    fn interleave<F: FnMut(Vec<BPEntry>) -> io::Result<bool>>(
+        &self,
+        ts:         &Tables,
+        files:      &[PathBuf],
+        max_open:   usize,
+        rng:        &mut StdRng,
+        mut f:      F,
+    ) -> io::Result<()> {
+        let mut files = files.iter();
+        let mut open: Vec<TDInput> = vec![];
+        loop {
+            while open.len() < max_open {
+                match files.next() {
+                    Some(path) => open.push(TDInput::open(ts, path)?),
+                    None       => break,
+                }
+            }
+            if open.is_empty() { break; }
+
+            let k = rng.gen_range(0..open.len());
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            while batch.len() < BATCH_SIZE {
+                match open[k].next_entry()? {
+                    Some(e) => batch.push(e),
+                    None    => {
+                        open.swap_remove(k);
+                        break;
+                    },
+                }
+            }
+
+            if !batch.is_empty() && !f(batch)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn new_explorer(&self, ts: &Tables, nnue: Option<NNEval>) -> Explorer {
+        let g = Game::from_fen(ts, STARTPOS).unwrap();
+        let depth = self.rescore_depth.unwrap_or(1);
+        let mut ex = Explorer::new(White, g, depth, TimeSettings::new_infinite());
+        ex.cfg.num_threads = Some(1);
+        #[cfg(feature = "lockless_hashmap")]
+        {
+            ex.ptr_tt = Arc::new(TransTable::new_mb(self.hash_size_mb));
+        }
+        if let Some(nn) = nnue {
+            ex.add_nnue(nn);
+        }
+        if let Some(path) = &self.syzygy_path {
+            if let Err(e) = ex.load_syzygy(path) {
+                eprintln!("pipeline: couldn't load syzygy from {:?}: {:?}", path, e);
+            }
+        }
+        ex.new_game(ts, g);
+        ex
+    }
+
+    fn worker(
+        &self,
+        ts:         &Tables,
+        nnue:       Option<NNEval>,
+        dedup:      Option<&DedupTable>,
+        stats:      &TDPipelineStats,
+        rx:         Receiver<Vec<BPEntry>>,
+        tx:         Sender<Vec<BPEntry>>,
+    ) {
+        let mut ex = self.new_explorer(ts, nnue);
+        let mut helper = ex.build_exhelper(0, PerThreadData::default());
+        let mut sstats = SearchStats::default();
+
+        while let Ok(batch) = rx.recv() {
+            let out = batch.into_iter()
+                .flat_map(|e| self.process(ts, &mut ex, &mut helper, &mut sstats, dedup, stats, e))
+                .collect::<Vec<_>>();
+            if !out.is_empty() && tx.send(out).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// None if the position is dropped
+    fn process(
+        &self,
+        ts:         &Tables,
+        ex:         &mut Explorer,
+        helper:     &mut ExHelper,
+        sstats:     &mut SearchStats,
+        dedup:      Option<&DedupTable>,
+        stats:      &TDPipelineStats,
+        mut e:      BPEntry,
+    ) -> Option<BPEntry> {
+        let pieces = e.game.all_occupied().popcount() as u32;
+        if self.min_ply.map_or(false, |n| e.ply < n)
+            || self.max_ply.map_or(false, |n| e.ply > n)
+            || self.min_pieces.map_or(false, |n| pieces < n)
+            || self.max_pieces.map_or(false, |n| pieces > n) {
+                TDPipelineStats::inc(&stats.out_of_bounds);
+                return None;
+            }
+
+        if let Some(dedup) = dedup {
+            if !dedup.insert(dedup_key(ts, &e.game)) {
+                TDPipelineStats::inc(&stats.duplicates);
+                return None;
+            }
+        }
+
+        if self.quiet && !TrainingData::is_quiet(ts, helper, sstats, &e.game, e.mv) {
+            TDPipelineStats::inc(&stats.not_quiet);
+            return None;
+        }
+
+        #[cfg(feature = "syzygy")]
+        if let Some(result) = Self::probe_result(ts, ex, &e.game) {
+            if result != e.result {
+                TDPipelineStats::inc(&stats.relabeled);
+                e.result = result;
+            }
+        }
+
+        /// Keeps the old score if the search has no result
+        if self.rescore_depth.is_some() {
+            ex.update_game(e.game);
+            let (res,_,_) = ex.lazy_smp_2(ts);
+            if let Some(ABResult { score, .. }) = res.get_result() {
+                e.score = score.clamp(i16::MIN as Score, i16::MAX as Score) as i16;
+                TDPipelineStats::inc(&stats.rescored);
+            }
+        }
+
+        if self.max_score.map_or(false, |m| (e.score as Score).abs() > m) {
+            TDPipelineStats::inc(&stats.out_of_bounds);
+            return None;
+        }
+
+        Some(e)
+    }
+
+    /// Side to move, cursed wins and blessed losses are draws.
+    /// Same as gensfen, only right after a capture or pawn move
+    #[cfg(feature = "syzygy")]
+    fn probe_result(ts: &Tables, ex: &Explorer, g: &Game) -> Option<i8> {
+        use crate::syzygy::Wdl;
+        match probe_wdl_exact(ts, ex, g)? {
+            Wdl::Win  => Some(1),
+            Wdl::Loss => Some(-1),
+            _         => Some(0),
+        }
+    }
+
+    fn write_output(
+        &self,
+        ts:         &Tables,
+        dir:        &SfenDir,
+        t0:         Instant,
+        stats:      &TDPipelineStats,
+        rx:         Receiver<Vec<BPEntry>>,
+    ) -> io::Result<u64> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed ^ 1);
+        let mut w = SfenWriter::create(ts, dir, 0)?;
+        let mut n_file = 0;
+        let mut buf: Vec<BPEntry> = vec![];
+        let mut t_print = Instant::now();
+
+        while let Ok(batch) = rx.recv() {
+            for e in batch.into_iter() {
+                match self.shuffle {
+                    Some(size) => {
+                        buf.push(e);
+                        if buf.len() > size {
+                            let k = rng.gen_range(0..buf.len());
+                            let e = buf.swap_remove(k);
+                            self.write_one(ts, dir, &mut w, &mut n_file, stats, &e)?;
+                        }
+                    },
+                    None => self.write_one(ts, dir, &mut w, &mut n_file, stats, &e)?,
+                }
+            }
+
+            if self.print && t_print.elapsed() > Duration::from_secs(10) {
+                stats.print(t0);
+                t_print = Instant::now();
+            }
+        }
+
+        buf.shuffle(&mut rng);
+        for e in buf.iter() {
+            self.write_one(ts, dir, &mut w, &mut n_file, stats, e)?;
+        }
+        w.finish()?;
+
+        Ok(stats.written.load(Ordering::SeqCst))
+    }
+
+    fn write_one<'a>(
+        &self,
+        ts:         &'a Tables,
+        dir:        &SfenDir,
+        w:          &mut SfenWriter<'a>,
+        n_file:     &mut usize,
+        stats:      &TDPipelineStats,
+        e:          &BPEntry,
+    ) -> io::Result<()> {
+        w.write_entry(e)?;
+        TDPipelineStats::inc(&stats.written);
+        if w.positions() >= self.positions_per_file {
+            *n_file += 1;
+            let w2 = SfenWriter::create(ts, dir, *n_file)?;
+            std::mem::replace(w, w2).finish()?;
+        }
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rchess_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read_packed(ts: &Tables, dir: &Path) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        for path in TrainSample::data_files(&[dir]).unwrap().iter() {
+            let mut r = BPReader::open(ts, path).unwrap();
+            while let Some(e) = r.next_entry().unwrap() {
+                out.push(e.pack(ts).to_vec());
+            }
+        }
+        out.sort();
+        out
+    }
+
+    fn entry(ts: &Tables, fen: &str, from: &str, to: &str, score: i16, ply: u16) -> BPEntry {
+        let game = Game::from_fen(ts, fen).unwrap();
+        let mv = crate::movegen::MoveGen::generate_list_legal(ts, &game, None).into_iter()
+            .find(|mv| mv.sq_from() == Coord::from(from) && mv.sq_to() == Coord::from(to))
+            .unwrap();
+        BPEntry { game, mv, score, ply, result: 0 }
+    }
+
+    fn get(x: &AtomicU64) -> u64 { x.load(Ordering::SeqCst) }
+
+    /// Every step of one worker, with the same stack size as run
+    fn process_all(ts: &Tables, p: &TDPipeline, entries: Vec<BPEntry>) -> (Vec<BPEntry>, TDPipelineStats) {
+        let stats = TDPipelineStats::default();
+        let dedup = p.dedup_mb.map(DedupTable::new_mb);
+        let out = std::thread::scope(|s| {
+            std::thread::Builder::new()
+                .stack_size(1024 * 1024 * 4)
+                .spawn_scoped(s, || {
+                    let mut ex = p.new_explorer(ts, None);
+                    let mut helper = ex.build_exhelper(0, PerThreadData::default());
+                    let mut sstats = SearchStats::default();
+                    entries.into_iter()
+                        .flat_map(|e| p.process(ts, &mut ex, &mut helper, &mut sstats, dedup.as_ref(), &stats, e))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+        (out, stats)
+    }
+
+    const MIDDLEGAME: &'static str = "r2q1rk1/pp2bppp/3p1n2/3Np3/4P1b1/5N2/PPP1QPPP/R3KB1R w KQ - 0 1";
+
+    #[test]
+    fn pipeline_bounds() {
+        let ts = Tables::new();
+        let p = TDPipeline::new()
+            .min_ply(Some(5))
+            .max_ply(Some(40))
+            .min_pieces(Some(4))
+            .max_pieces(Some(30))
+            .max_score(Some(300));
+
+        let entries = vec![
+            entry(&ts, STARTPOS, "E2", "E4", 0, 10),
+            entry(&ts, MIDDLEGAME, "A2", "A3", 100, 10),
+            entry(&ts, MIDDLEGAME, "A2", "A3", 100, 2),
+            entry(&ts, MIDDLEGAME, "A2", "A3", 100, 50),
+            entry(&ts, "8/8/8/4k3/8/8/8/R3K3 w - - 0 1", "A1", "A2", 100, 10),
+            entry(&ts, MIDDLEGAME, "A2", "A3", -500, 10),
+            entry(&ts, MIDDLEGAME, "A2", "A3", -300, 11),
+        ];
+        let (out,stats) = process_all(&ts, &p, entries);
+
+        assert_eq!(get(&stats.out_of_bounds), 5);
+        assert_eq!(out.iter().map(|e| (e.score,e.ply)).collect::<Vec<_>>(), vec![(100,10), (-300,11)]);
+    }
+
+    #[test]
+    fn pipeline_dedup() {
+        let ts = Tables::new();
+        let p = TDPipeline::new().dedup_mb(Some(1));
+
+        let entries = vec![
+            entry(&ts, STARTPOS, "E2", "E4", 0, 0),
+            entry(&ts, MIDDLEGAME, "A2", "A3", 100, 10),
+            entry(&ts, STARTPOS, "D2", "D4", 20, 0),
+            /// Same position, the en passant square can't be taken
+            entry(&ts, "rnbqkbnr/pppppppp/8/8/2P5/8/PP1PPPPP/RNBQKBNR b KQkq c3 0 1", "A7", "A5", 0, 1),
+            entry(&ts, "rnbqkbnr/pppppppp/8/8/2P5/8/PP1PPPPP/RNBQKBNR b KQkq - 0 1", "B7", "B5", 0, 1),
+        ];
+        let (out,stats) = process_all(&ts, &p, entries);
+
+        assert_eq!(get(&stats.duplicates), 2);
+        assert_eq!(out.iter().map(|e| e.mv).collect::<Vec<_>>(),
+                   vec![entry(&ts, STARTPOS, "E2", "E4", 0, 0).mv,
+                        entry(&ts, MIDDLEGAME, "A2", "A3", 0, 0).mv,
+                        entry(&ts, "rnbqkbnr/pppppppp/8/8/2P5/8/PP1PPPPP/RNBQKBNR b KQkq - 0 1",
+                              "A7", "A5", 0, 1).mv]);
+    }
+
+    #[test]
+    fn pipeline_quiet() {
+        let ts = Tables::new();
+        let p = TDPipeline::new().quiet(true);
+
+        let entries = vec![
+            entry(&ts, STARTPOS, "E2", "E4", 0, 0),
+            /// In check
+            entry(&ts, "4k3/8/8/8/8/8/3q4/4K3 w - - 0 1", "E1", "F1", 0, 0),
+            /// A capture
+            entry(&ts, "4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1", "E4", "D5", 0, 0),
+            /// Not a capture, but the queen is hanging
+            entry(&ts, "4k3/8/8/3q4/4P3/8/8/4K3 w - - 0 1", "E1", "F1", 0, 0),
+        ];
+        let (out,stats) = process_all(&ts, &p, entries);
+
+        assert_eq!(get(&stats.not_quiet), 3);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].game, Game::from_fen(&ts, STARTPOS).unwrap());
+    }
+
+    #[test]
+    fn pipeline_rescore() {
+        let ts = Tables::new();
+
+        /// White is a queen up, scores are from the side to move
+        let entries = || vec![
+            entry(&ts, "rnb1kbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "E2", "E4", 0, 0),
+            entry(&ts, "rnb1kbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1", "E7", "E5", 0, 1),
+        ];
+
+        let p = TDPipeline::new().rescore_depth(Some(2));
+        let (out,stats) = process_all(&ts, &p, entries());
+        assert_eq!(get(&stats.rescored), 2);
+        assert_eq!(out.len(), 2);
+        assert!(out[0].score > 500, "{}", out[0].score);
+        assert!(out[1].score < -500, "{}", out[1].score);
+
+        /// The score bound is checked against the new scores
+        let (out,stats) = process_all(&ts, &p.clone().max_score(Some(500)), entries());
+        assert_eq!(get(&stats.rescored), 2);
+        assert_eq!(get(&stats.out_of_bounds), 2);
+        assert!(out.is_empty());
+    }
+
+    /// Needs the 3 and 4 piece tables in SYZYGY_PATH
+    #[cfg(feature = "syzygy")]
+    #[test]
+    #[ignore]
+    fn pipeline_syzygy_relabel() {
+        let ts = Tables::new();
+        let path = std::env::var("SYZYGY_PATH").expect("SYZYGY_PATH");
+        let p = TDPipeline::new().syzygy_path(Some(path.into()));
+
+        let entries = vec![
+            entry(&ts, "8/8/8/4k3/8/8/8/Q3K3 w - - 0 1", "A1", "A2", 0, 10),
+            entry(&ts, "8/8/8/4k3/8/8/8/Q3K3 b - - 0 1", "E5", "E6", 0, 11),
+            /// The 50-move counter isn't 0, the tables can't tell if it's still a win
+            entry(&ts, "8/8/8/4k3/8/8/8/Q3K3 w - - 20 1", "A1", "A2", 0, 10),
+            entry(&ts, MIDDLEGAME, "A2", "A3", 0, 10),
+        ];
+        let (out,stats) = process_all(&ts, &p, entries);
+
+        assert_eq!(get(&stats.relabeled), 2);
+        assert_eq!(out.iter().map(|e| e.result).collect::<Vec<_>>(), vec![1, -1, 0, 0]);
+    }
+
+    #[test]
+    fn dedup_table_key_zero() {
+        let t = DedupTable::new_mb(1);
+        assert!(t.insert(0));
+        assert!(!t.insert(0));
+        assert!(t.insert(1));
+        assert!(!t.insert(1));
+    }
+
+    #[test]
+    fn shuffle_merges_in_rounds() {
+        let ts = Tables::new();
+        let small = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/small.binpack");
+
+        let (input,out) = (temp_dir("pipeline_in"), temp_dir("pipeline_out"));
+        std::fs::create_dir_all(&input).unwrap();
+        for k in 0..7 {
+            std::fs::copy(small, input.join(format!("sfen_{:05}.binpack", k))).unwrap();
+        }
+
+        /// 7 -> 4 -> 2 files
+        let n = TDPipeline::new()
+            .shuffle(Some(50))
+            .merge_width(2)
+            .print(false)
+            .run(&ts, &[&input], &out)
+            .unwrap();
+
+        let expected = read_packed(&ts, &input);
+        assert_eq!(expected.len(), 7 * 129);
+        assert_eq!(n as usize, expected.len());
+        assert_eq!(read_packed(&ts, &out), expected);
+        assert!(!out.join(MERGE_DIR).exists());
+
+        std::fs::remove_dir_all(&input).unwrap();
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+
+}
@@ -1,4 +1,3 @@
-
 #![allow(non_camel_case_types)]
 
 use crate::movegen::MoveGen;
@@ -12,16 +11,14 @@ use std::collections::HashMap;
 
 use once_cell::sync::OnceCell;
 
-// static ENDGAME_MAPS: OnceCell<EndGameMaps> = OnceCell::new();
+static ENDGAME_MAPS: OnceCell<EndGameMaps> = OnceCell::new();
 
 pub trait EndGame {
 
-    // const NPM: Score;
-    // const PAWNS: u8;
-
-    fn evaluate(self, ts: &Tables, g: &Game) -> Score;
+    /// From the strong side's perspective
+    fn evaluate(self, ts: &Tables, g: &Game, strong: Color) -> Score;
 
-    fn verify_material(self, g: &Game, side: Color) -> bool;
+    fn verify_material(self, g: &Game, strong: Color) -> bool;
 
 }
 
@@ -39,7 +36,7 @@ pub mod helpers {
     }
 
     fn edge_distance(rank_or_file: u8) -> u8 {
-        rank_or_file.min(8 - rank_or_file)
+        rank_or_file.min(7 - rank_or_file)
     }
 
     pub fn push_king_to_edge(ksq: Coord) -> Score {
@@ -50,6 +47,7 @@ pub mod helpers {
         90 - (7 * fd * fd / 2 + 7 * rd * rd / 2)
     }
 
+    /// Highest in the A1 and H8 corners
     pub fn push_king_to_corner(ksq: Coord) -> Score {
         let x = 7 - ksq.rank() as Score - ksq.file() as Score;
         x.abs()
@@ -60,6 +58,15 @@ pub mod helpers {
         140 - 20 * d as Score
     }
 
+    pub fn push_away(c0: Coord, c1: Coord) -> Score {
+        120 - push_close(c0, c1)
+    }
+
+    /// Flip ranks so the strong side is White
+    pub fn normalize(strong: Color, sq: Coord) -> Coord {
+        if strong == White { sq } else { sq.flip_vertical() }
+    }
+
 }
 
 /// from stockfish
@@ -71,110 +78,196 @@ pub enum ScaleFactor {
     None   = 255,
 }
 
+/// Specialized evaluators, by material key.
+/// KXK isn't here, it matches any material where the weak side only has a king,
+/// so it's only tried when the material key misses (KNNvK has to be found first)
 #[derive(Debug,Clone)]
 pub struct EndGameMaps {
+    value:          HashMap<Zobrist, (EndGameType, Color)>,
+}
 
-    // value:          HashMap<Score, EndGame>,
-    // scalefactors:   HashMap<ScaleFactor, EndGame>,
+impl EndGameMaps {
 
-    // value:          HashMap<Score, Box<dyn EndGame>>,
-    // scalefactors:   HashMap<ScaleFactor, Box<dyn EndGame>>,
+    pub fn get(ts: &Tables) -> &'static Self {
+        ENDGAME_MAPS.get_or_init(|| Self::init(ts))
+    }
 
-}
+    pub fn init(ts: &Tables) -> Self {
+        let mut out = Self { value: HashMap::default() };
 
-impl EndGameMaps {
-    pub fn init() -> Self {
-        // Self {}
-        unimplemented!()
+        out.add(ts, "KPvK",   EndGameType::KPvK);
+        out.add(ts, "KNNvK",  EndGameType::KNNvK);
+        out.add(ts, "KNNvKP", EndGameType::KNNvKP);
+        out.add(ts, "KBNvK",  EndGameType::KBNvK);
+        out.add(ts, "KRvKP",  EndGameType::KRvKP);
+        out.add(ts, "KQvKP",  EndGameType::KQvKP);
+        out.add(ts, "KRvKB",  EndGameType::KRvKB);
+        out.add(ts, "KRvKN",  EndGameType::KRvKN);
+
+        out
     }
 
-    // pub fn get_value(&self, score: Score) -> Option<EndGame> {
-    //     unimplemented!()
-    // }
+    /// Both colors, mat is strong side first
+    fn add(&mut self, ts: &Tables, mat: &str, eg: EndGameType) {
+        let mat = Material::from_str(mat).unwrap();
+        self.value.insert(Zobrist::from_material(ts, &mat), (eg, White));
+        self.value.insert(Zobrist::from_material(ts, &mat.into_flipped()), (eg, Black));
+    }
 
-    // pub fn get_scalefactor(&self, scale: ScaleFactor) -> Option<EndGame> {
-    //     unimplemented!()
-    // }
+    /// (evaluator, strong side)
+    pub fn get_value(&self, g: &Game) -> Option<(EndGameType, Color)> {
+        if let Some(eg) = self.value.get(&g.mat_zb) {
+            return Some(*eg);
+        }
+        for side in [White,Black] {
+            if is_kx_vs_k(g, side) {
+                return Some((EndGameType::KXvK, side));
+            }
+        }
+        None
+    }
 
-    // pub fn get_endgame(g: &Game) -> Option<EndGame> {
-    //     unimplemented!()
-    // }
+    /// Scaling function for side, only depending on material.
+    /// ScaleType::OppositeBishops is checked for every position when this doesn't apply.
+    pub fn get_scale(g: &Game, side: Color) -> Option<ScaleType> {
+        let mat = &g.state.material;
+        if mat.non_pawn_value(side) == Bishop.score()
+            && mat.get(Bishop, side) == 1
+            && mat.get(Pawn, side) >= 1 {
+                Some(ScaleType::KBPsK)
+            } else {
+                None
+            }
+    }
 
 }
 
-#[derive(Debug,PartialEq,PartialOrd,Clone,Copy)]
+#[derive(Debug,PartialEq,Eq,PartialOrd,Clone,Copy)]
 pub enum EndGameType {
     KXvK,
     KPvK,
+    KNNvK,
+    KNNvKP,
+    KBNvK,
+    KRvKP,
+    KQvKP,
+    KRvKB,
+    KRvKN,
 }
 
-impl EndGameType {
+impl EndGame for EndGameType {
 
-    fn get_eg(self) -> Box<dyn EndGame> {
+    fn evaluate(self, ts: &Tables, g: &Game, strong: Color) -> Score {
+        debug_assert!(self.verify_material(g, strong));
         match self {
-            Self::KXvK => Box::new(KX_VS_K),
-            Self::KPvK => Box::new(KP_VS_K),
-            // _          => unimplemented!(),
+            Self::KXvK   => endgame_kx_vs_k(ts, g, strong),
+            Self::KPvK   => endgame_kp_vs_k(ts, g, strong),
+            Self::KNNvK  => DRAW_VALUE,
+            Self::KNNvKP => endgame_knn_vs_kp(ts, g, strong),
+            Self::KBNvK  => endgame_kbn_vs_k(ts, g, strong),
+            Self::KRvKP  => endgame_kr_vs_kp(ts, g, strong),
+            Self::KQvKP  => endgame_kq_vs_kp(ts, g, strong),
+            Self::KRvKB  => endgame_kr_vs_kb(ts, g, strong),
+            Self::KRvKN  => endgame_kr_vs_kn(ts, g, strong),
         }
     }
 
-    pub fn check(ts: &Tables, g: &Game) -> Option<Self> {
-
-
-
-        unimplemented!()
-    }
-
-    pub fn evaluate(self, ts: &Tables, g: &Game) -> Score {
+    fn verify_material(self, g: &Game, strong: Color) -> bool {
+        let weak = !strong;
         match self {
-            Self::KXvK => endgame_kx_vs_k(ts, g),
-            _          => unimplemented!(),
+            Self::KXvK   => is_kx_vs_k(g, strong),
+            Self::KPvK   => _verify_material(g, strong, 0, 1)
+                && _verify_material(g, weak, 0, 0),
+            Self::KNNvK  => _verify_material(g, strong, 2 * Knight.score(), 0)
+                && _verify_material(g, weak, 0, 0),
+            Self::KNNvKP => _verify_material(g, strong, 2 * Knight.score(), 0)
+                && _verify_material(g, weak, 0, 1),
+            Self::KBNvK  => _verify_material(g, strong, Knight.score() + Bishop.score(), 0)
+                && _verify_material(g, weak, 0, 0),
+            Self::KRvKP  => _verify_material(g, strong, Rook.score(), 0)
+                && _verify_material(g, weak, 0, 1),
+            Self::KQvKP  => _verify_material(g, strong, Queen.score(), 0)
+                && _verify_material(g, weak, 0, 1),
+            Self::KRvKB  => _verify_material(g, strong, Rook.score(), 0)
+                && _verify_material(g, weak, Bishop.score(), 0),
+            Self::KRvKN  => _verify_material(g, strong, Rook.score(), 0)
+                && _verify_material(g, weak, Knight.score(), 0),
         }
     }
-}
 
-pub struct KX_VS_K;
+}
 
-impl EndGame for KX_VS_K {
-    fn evaluate(self, ts: &Tables, g: &Game) -> Score {
-        unimplemented!()
-    }
-    fn verify_material(self, g: &Game, side: Color) -> bool {
-        // _verify_material(g, side, npm, pawns)
-        unimplemented!()
+impl EndGameType {
+    /// From the side to move's perspective
+    pub fn evaluate_stm(self, ts: &Tables, g: &Game, strong: Color) -> Score {
+        let score = self.evaluate(ts, g, strong);
+        if g.state.side_to_move == strong { score } else { -score }
     }
 }
 
-pub struct KP_VS_K;
-
-impl EndGame for KP_VS_K {
-    fn evaluate(self, ts: &Tables, g: &Game) -> Score {
-        unimplemented!()
-    }
-    fn verify_material(self, g: &Game, side: Color) -> bool {
-        // _verify_material(g, side, npm, pawns)
-        unimplemented!()
-    }
+#[derive(Debug,PartialEq,Eq,PartialOrd,Clone,Copy)]
+pub enum ScaleType {
+    /// Bishop and pawns on a rook file, with the wrong colored corner
+    KBPsK,
+    OppositeBishops,
 }
 
-fn endgame_kx_vs_k(ts: &Tables, g: &Game) -> Score {
+impl ScaleType {
 
-    // let weak_side: Color = unimplemented!();
-    let weak_side: Color = White;
-    // XXX: nope
-    debug!("nope");
+    /// Out of ScaleFactor::Normal, for the strong side's score.
+    /// passed is the number of passed pawns of the strong side.
+    pub fn scale(self, g: &Game, strong: Color, passed: u8) -> Score {
+        let weak = !strong;
+        match self {
+            Self::KBPsK  => {
+                let pawns = g.get(Pawn, strong);
+                let file = if (pawns & !BitBoard::mask_file(0)).is_empty() {
+                    0
+                } else if (pawns & !BitBoard::mask_file(7)).is_empty() {
+                    7
+                } else {
+                    return ScaleFactor::None as Score;
+                };
+
+                let queening = Coord::new(file, strong.fold(7, 0));
+                let bishop   = g.get(Bishop, strong);
+                let ksq_weak = g.get(King, weak).bitscan();
+
+                let dark_corner = DARK_SQUARES.is_one_at(queening);
+                let dark_bishop = (bishop & DARK_SQUARES).is_not_empty();
+
+                if dark_corner != dark_bishop && ksq_weak.square_dist(queening) <= 1 {
+                    ScaleFactor::Draw as Score
+                } else {
+                    ScaleFactor::None as Score
+                }
+            },
+            Self::OppositeBishops => {
+                let mat = &g.state.material;
+                let bishops = g.get_piece(Bishop);
+                if mat.get(Bishop, White) != 1 || mat.get(Bishop, Black) != 1
+                    || (bishops & DARK_SQUARES).popcount() != 1 {
+                        return ScaleFactor::None as Score;
+                    }
+                if mat.non_pawn_value(White) == Bishop.score() && mat.non_pawn_value(Black) == Bishop.score() {
+                    18 + 4 * passed as Score
+                } else {
+                    22 + 3 * g.get_color(strong).popcount() as Score
+                }
+            },
+        }
+    }
 
-    // assert!(EndGame::KXvK.verify_material(g, side));
-    assert!(!g.state.in_check);
+}
 
-    if g.state.side_to_move == weak_side {
-        if MoveGen::generate_list_legal(ts, g, Some(MoveGenType::Captures)).is_empty()
-            && MoveGen::generate_list_legal(ts, g, Some(MoveGenType::Quiets)).is_empty() {
-                return DRAW_VALUE;
-            }
-    }
+/// Mate with enough material against a lone king
+fn endgame_kx_vs_k(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
 
-    let strong_side = !weak_side;
+    if g.state.side_to_move == weak_side
+        && MoveGen::generate_list_legal(ts, g, None).is_empty() {
+            return DRAW_VALUE;
+        }
 
     let ksq_strong = g.get(King, strong_side).bitscan();
     let ksq_weak   = g.get(King, weak_side).bitscan();
@@ -188,24 +281,303 @@ fn endgame_kx_vs_k(ts: &Tables, g: &Game) -> Score {
 
     if mat.has_piece_side(Queen, strong_side)
         || mat.has_piece_side(Rook, strong_side)
-        || (mat.has_piece_side(Bishop, strong_side) && mat.has_piece_side(Bishop, strong_side))
+        || (mat.has_piece_side(Bishop, strong_side) && mat.has_piece_side(Knight, strong_side))
         || ((g.get(Bishop, strong_side) & DARK_SQUARES).is_not_empty()
             && (g.get(Bishop, strong_side) & LIGHT_SQUARES).is_not_empty())
     {
-        score = score + KNOWN_WIN_VALUE;
+        score += KNOWN_WIN_VALUE;
     }
 
-    if strong_side == g.state.side_to_move {
-        score
-    } else {
-        -score
+    score
+}
+
+/// Draw unless the bitbase says it's won
+fn endgame_kp_vs_k(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
+
+    let ksq_strong = normalize(strong_side, g.get(King, strong_side).bitscan());
+    let ksq_weak   = normalize(strong_side, g.get(King, weak_side).bitscan());
+    let pawn       = normalize(strong_side, g.get(Pawn, strong_side).bitscan());
+
+    let stm = if g.state.side_to_move == strong_side { White } else { Black };
+
+    if !ts.kpk_bitbase.probe(ksq_strong, pawn, ksq_weak, stm) {
+        return DRAW_VALUE;
     }
+
+    KNOWN_WIN_VALUE + Pawn.score_endgame() + 10 * pawn.rank() as Score
 }
 
-fn endgame_kp_vs_k(ts: &Tables, g: &Game, side: Color) -> Score {
-    unimplemented!()
+/// Can't force mate, but the pawn might allow it, so keep the weak king on the edge
+fn endgame_knn_vs_kp(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
+
+    let ksq_weak = g.get(King, weak_side).bitscan();
+    let pawn     = g.get(Pawn, weak_side).bitscan();
+
+    Pawn.score_endgame()
+        + 2 * push_king_to_edge(ksq_weak)
+        - 10 * BitBoard::relative_rank(weak_side, pawn) as Score
 }
 
-fn endgame_kbn_vs_k(ts: &Tables, g: &Game, side: Color) -> Score {
-    unimplemented!()
+/// Push the weak king toward a corner the bishop can attack
+fn endgame_kbn_vs_k(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
+
+    let ksq_strong = g.get(King, strong_side).bitscan();
+    let mut ksq_weak = g.get(King, weak_side).bitscan();
+
+    /// push_king_to_corner goes to A1 and H8, which are dark
+    if (g.get(Bishop, strong_side) & DARK_SQUARES).is_empty() {
+        ksq_weak = ksq_weak.flip_horizontal();
+    }
+
+    KNOWN_WIN_VALUE + 3520
+        + push_close(ksq_strong, ksq_weak)
+        + 420 * push_king_to_corner(ksq_weak)
+}
+
+/// Winning if the strong king is in front of the pawn, or the weak king is far away.
+/// Drawish if the pawn is advanced and supported by its king
+fn endgame_kr_vs_kp(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
+
+    let ksq_strong = normalize(strong_side, g.get(King, strong_side).bitscan());
+    let ksq_weak   = normalize(strong_side, g.get(King, weak_side).bitscan());
+    let rook       = normalize(strong_side, g.get(Rook, strong_side).bitscan());
+    let pawn       = normalize(strong_side, g.get(Pawn, weak_side).bitscan());
+
+    let queening = Coord::new(pawn.file(), 0);
+    let push     = Coord::new(pawn.file(), pawn.rank() - 1);
+
+    let stm_weak   = if g.state.side_to_move == weak_side { 1 } else { 0 };
+    let stm_strong = 1 - stm_weak;
+
+    let rook_value = Rook.score_endgame();
+
+    if ksq_strong.file() == pawn.file() && ksq_strong.rank() < pawn.rank() {
+        rook_value - ksq_strong.square_dist(pawn) as Score
+    } else if ksq_weak.square_dist(pawn) >= 3 + stm_weak && ksq_weak.square_dist(rook) >= 3 {
+        rook_value - ksq_strong.square_dist(pawn) as Score
+    } else if ksq_weak.rank() <= 2
+        && ksq_weak.square_dist(pawn) == 1
+        && ksq_strong.rank() >= 3
+        && ksq_strong.square_dist(pawn) > 2 + stm_strong {
+            80 - 8 * ksq_strong.square_dist(pawn) as Score
+        } else {
+            200 - 8 * (ksq_strong.square_dist(push) as Score
+                       - ksq_weak.square_dist(push) as Score
+                       - pawn.square_dist(queening) as Score)
+        }
+}
+
+/// Winning, unless the pawn is on the 7th supported by the king on a bishop or rook file
+fn endgame_kq_vs_kp(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let weak_side = !strong_side;
+
+    let ksq_strong = g.get(King, strong_side).bitscan();
+    let ksq_weak   = g.get(King, weak_side).bitscan();
+    let pawn       = g.get(Pawn, weak_side).bitscan();
+
+    let mut score = push_close(ksq_strong, ksq_weak);
+
+    let drawish_file = matches!(pawn.file(), 0 | 2 | 5 | 7);
+    if BitBoard::relative_rank(weak_side, pawn) != 6
+        || ksq_weak.square_dist(pawn) != 1
+        || !drawish_file {
+            score += Queen.score_endgame() - Pawn.score_endgame();
+        }
+
+    score
+}
+
+/// Drawish, slightly better with the weak king on the edge
+fn endgame_kr_vs_kb(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let ksq_weak = g.get(King, !strong_side).bitscan();
+    push_king_to_edge(ksq_weak)
+}
+
+/// Drawish, better with the weak king on the edge and away from its knight
+fn endgame_kr_vs_kn(ts: &Tables, g: &Game, strong_side: Color) -> Score {
+    let ksq_weak = g.get(King, !strong_side).bitscan();
+    let knight   = g.get(Knight, !strong_side).bitscan();
+    push_king_to_edge(ksq_weak) + push_away(ksq_weak, knight)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(ts: &Tables, fen: &str) -> Game {
+        Game::from_fen(ts, fen).unwrap()
+    }
+
+    #[test]
+    fn endgame_registry() {
+        let ts = Tables::new();
+        let egs = EndGameMaps::get(&ts);
+
+        let cases: &[(&str, Option<EndGameType>)] = &[
+            ("8/8/8/4k3/8/8/8/R3K3 w - - 0 1",        Some(EndGameType::KXvK)),
+            ("8/8/8/4k3/8/8/3P4/Q3K3 b - - 0 1",      Some(EndGameType::KXvK)),
+            ("8/8/8/4k3/8/8/4P3/4K3 w - - 0 1",       Some(EndGameType::KPvK)),
+            /// Has to be found before KXvK
+            ("8/8/8/4k3/8/8/8/3NKN2 w - - 0 1",       Some(EndGameType::KNNvK)),
+            ("8/8/8/4k3/4p3/8/8/3NKN2 w - - 0 1",     Some(EndGameType::KNNvKP)),
+            ("8/8/8/4k3/8/8/8/2B1KN2 w - - 0 1",      Some(EndGameType::KBNvK)),
+            ("8/8/8/4k3/4p3/8/8/R3K3 w - - 0 1",      Some(EndGameType::KRvKP)),
+            ("8/8/8/4k3/4p3/8/8/Q3K3 w - - 0 1",      Some(EndGameType::KQvKP)),
+            ("8/8/8/4kb2/8/8/8/R3K3 w - - 0 1",       Some(EndGameType::KRvKB)),
+            ("8/8/8/4kn2/8/8/8/R3K3 w - - 0 1",       Some(EndGameType::KRvKN)),
+            ("8/8/8/4k3/8/8/8/2B1K3 w - - 0 1",       None),
+            ("8/8/8/4k3/4p3/8/8/4KN2 w - - 0 1",      None),
+            (STARTPOS,                                None),
+        ];
+
+        for &(fen, eg) in cases.iter() {
+            let g  = game(&ts, fen);
+            let g2 = g.flip_sides(&ts);
+
+            assert_eq!(Zobrist::from_material(&ts, &g.state.material), g.mat_zb, "{}", fen);
+            assert_eq!(Zobrist::from_material(&ts, &g2.state.material), g2.mat_zb, "{}", fen);
+
+            assert_eq!(egs.get_value(&g), eg.map(|eg| (eg, White)), "{}", fen);
+            assert_eq!(egs.get_value(&g2), eg.map(|eg| (eg, Black)), "{}", g2.to_fen());
+
+            if let Some(eg) = eg {
+                assert!(eg.verify_material(&g, White) && !eg.verify_material(&g, Black));
+                assert!(eg.verify_material(&g2, Black) && !eg.verify_material(&g2, White));
+                assert_eq!(eg.evaluate(&ts, &g, White), eg.evaluate(&ts, &g2, Black), "{}", fen);
+                assert_eq!(eg.evaluate_stm(&ts, &g, White), eg.evaluate_stm(&ts, &g2, Black), "{}", fen);
+            }
+        }
+    }
+
+    #[test]
+    fn endgame_mat_zb_lookup() {
+        let ts = Tables::new();
+        let egs = EndGameMaps::get(&ts);
+
+        let cases = [
+            ("KNNvK",  "8/8/8/4k3/8/8/8/3NKN2 w - - 0 1",   EndGameType::KNNvK),
+            ("KRvKN",  "8/8/8/4kn2/8/8/8/R3K3 w - - 0 1",   EndGameType::KRvKN),
+            ("KBNvK",  "8/8/8/4k3/8/8/8/2B1KN2 w - - 0 1",  EndGameType::KBNvK),
+        ];
+
+        for (mat, fen, eg) in cases {
+            let mat = Material::from_str(mat).unwrap();
+            let g   = game(&ts, fen);
+            assert_eq!(Zobrist::from_material(&ts, &mat), g.mat_zb);
+            assert_eq!(egs.value.get(&Zobrist::from_material(&ts, &mat)), Some(&(eg, White)));
+            assert_eq!(egs.value.get(&Zobrist::from_material(&ts, &mat.into_flipped())), Some(&(eg, Black)));
+        }
+    }
+
+    /// The material key only depends on piece counts, and stays in sync through
+    /// captures and promotions
+    #[test]
+    fn mat_zb_counts_only() {
+        use rand::prelude::{StdRng,SeedableRng,SliceRandom};
+        let ts = Tables::new();
+
+        let g0 = game(&ts, "8/8/8/4k3/8/8/8/3NKN2 w - - 0 1");
+        let g1 = game(&ts, "8/1N6/8/4k3/8/8/N7/4K3 w - - 0 1");
+        assert_eq!(g0.mat_zb, g1.mat_zb);
+        assert_ne!(g0.mat_zb, game(&ts, "8/8/8/4k3/8/8/8/3BKN2 w - - 0 1").mat_zb);
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        let fens = [
+            STARTPOS,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+        ];
+        for fen in fens {
+            for _ in 0..4 {
+                let mut g = game(&ts, fen);
+                for _ in 0..100 {
+                    assert_eq!(g.mat_zb, Zobrist::new_material(&ts, &g), "{}", g.to_fen());
+                    assert_eq!(g.mat_zb, Zobrist::from_material(&ts, &g.state.material), "{}", g.to_fen());
+
+                    let mvs = MoveGen::generate_list_legal(&ts, &g, None);
+                    let caps = mvs.iter().filter(|mv| mv.filter_capture_or_promotion())
+                        .cloned().collect::<Vec<_>>();
+                    let mv = match caps.choose(&mut rng).or_else(|| mvs.choose(&mut rng)) {
+                        Some(&mv) => mv,
+                        None      => break,
+                    };
+                    g = match g.make_move_unchecked(&ts, mv) {
+                        Ok(g2) => g2,
+                        Err(_) => break,
+                    };
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn endgame_scores() {
+        let ts = Tables::new();
+        let egs = EndGameMaps::get(&ts);
+
+        let score = |fen: &str| {
+            let g = game(&ts, fen);
+            let (eg,strong) = egs.get_value(&g).unwrap();
+            eg.evaluate(&ts, &g, strong)
+        };
+
+        assert_eq!(score("8/8/8/4k3/8/8/8/3NKN2 w - - 0 1"), DRAW_VALUE);
+        assert_eq!(score("8/8/8/4k3/8/8/8/3NKN2 b - - 0 1"), DRAW_VALUE);
+        assert!(score("8/8/8/4k3/4p3/8/8/3NKN2 w - - 0 1").abs() < KNOWN_WIN_VALUE);
+
+        assert!(score("8/8/8/4k3/8/8/8/R3K3 w - - 0 1") > KNOWN_WIN_VALUE);
+        assert!(score("8/8/8/4k3/8/8/8/2B1KN2 w - - 0 1") > KNOWN_WIN_VALUE);
+        /// Stalemate
+        assert_eq!(score("k7/8/1Q6/8/8/8/8/4K3 b - - 0 1"), DRAW_VALUE);
+
+        /// The weak king in the right corner is worse for it
+        assert!(score("k7/8/8/8/8/8/8/2B1KN2 w - - 0 1") < score("7k/8/8/8/8/8/8/2B1KN2 w - - 0 1"));
+
+        /// Opposition draws, king in front of the pawn on the 6th wins
+        assert_eq!(score("8/8/8/8/8/4k3/4P3/4K3 w - - 0 1"), DRAW_VALUE);
+        assert!(score("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1") > KNOWN_WIN_VALUE);
+    }
+
+    #[test]
+    fn endgame_scale_factors() {
+        let ts = Tables::new();
+
+        let scale = |g: &Game, strong: Color| {
+            EndGameMaps::get_scale(g, strong).map(|st| st.scale(g, strong, 0))
+        };
+
+        let cases = [
+            /// Wrong bishop, weak king on the queening square
+            ("k7/8/8/8/8/8/P7/2B1K3 w - - 0 1",  Some(ScaleFactor::Draw as Score)),
+            /// Right bishop
+            ("k7/8/8/8/8/8/P7/4KB2 w - - 0 1",   Some(ScaleFactor::None as Score)),
+            /// Wrong bishop, weak king too far
+            ("8/8/8/5k2/8/8/P7/2B1K3 w - - 0 1", Some(ScaleFactor::None as Score)),
+            ("k7/8/8/8/8/8/8/2B1KN2 w - - 0 1",  None),
+        ];
+
+        for (fen, sf) in cases {
+            let g  = game(&ts, fen);
+            let g2 = g.flip_sides(&ts);
+            assert_eq!(scale(&g, White), sf, "{}", fen);
+            assert_eq!(scale(&g2, Black), sf, "{}", fen);
+            assert_eq!(scale(&g, Black), None, "{}", fen);
+            assert_eq!(scale(&g2, White), None, "{}", fen);
+        }
+
+        let g  = game(&ts, "2b1k3/8/8/8/8/8/PP6/2B1K3 w - - 0 1");
+        let g2 = g.flip_sides(&ts);
+        assert_eq!(ScaleType::OppositeBishops.scale(&g, White, 0), 18);
+        assert_eq!(ScaleType::OppositeBishops.scale(&g, White, 2), 26);
+        assert_eq!(ScaleType::OppositeBishops.scale(&g2, Black, 0), 18);
+
+        /// Same colored bishops
+        let g = game(&ts, "4kb2/8/8/8/8/8/PP6/2B1K3 w - - 0 1");
+        assert_eq!(ScaleType::OppositeBishops.scale(&g, White, 0), ScaleFactor::None as Score);
+    }
+
 }
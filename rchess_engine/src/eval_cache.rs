@@ -0,0 +1,122 @@
+
+use crate::types::Score;
+use crate::hashing::Zobrist;
+
+use std::sync::atomic::{AtomicU64,Ordering};
+
+pub const DEFAULT_EVAL_CACHE_SIZE_MB: usize = 8;
+
+const ENTRIES_PER_BUCKET: usize = 4;
+
+const KILOBYTE: usize = 1024;
+const MEGABYTE: usize = 1024 * KILOBYTE;
+
+const LOW_FOUR_BYTES: u64 = 0x00_00_00_00_FF_FF_FF_FF;
+const SHIFT_TO_UPPER: u64 = 32;
+
+/// Static evals by full Zobrist, shared between threads and kept across iterations.
+/// Same buckets and index as lockless_map::TransTable, but each entry is a single AtomicU64
+/// with the ver in the high half and the score in the low half, so there are no locks and
+/// no torn reads.
+/// Ver 0 is an empty entry, so those positions aren't cached
+#[derive(Debug)]
+pub struct EvalCache {
+    buf:           Vec<EvBucket>,
+    num_buckets:   usize,
+}
+
+#[derive(Debug,Default)]
+#[repr(align(32))]
+struct EvBucket {
+    entries:       [AtomicU64; ENTRIES_PER_BUCKET],
+}
+
+/// New, Insert, Probe
+impl EvalCache {
+
+    pub fn new_mb(megabytes: usize) -> Self {
+
+        let mut num_buckets: usize = (megabytes * MEGABYTE) / std::mem::size_of::<EvBucket>();
+        num_buckets = num_buckets.next_power_of_two() / 2;
+
+        let mut buf = vec![];
+        for _ in 0..num_buckets {
+            buf.push(EvBucket::default());
+        }
+
+        Self {
+            buf,
+            num_buckets,
+        }
+    }
+
+    /// Side to move
+    pub fn insert(&self, zb: Zobrist, eval: Score) {
+        let (idx,ver) = self.calc_index(zb);
+        if ver == 0 { return; }
+
+        let entry = (ver as u64) << SHIFT_TO_UPPER | (eval as u32 as u64);
+        let bucket = &self.buf[idx].entries;
+
+        /// Same position, then an empty slot, otherwise replace by ver
+        let slot = bucket.iter()
+            .position(|e| Self::get_ver(e.load(Ordering::Relaxed)) == ver)
+            .or_else(|| bucket.iter().position(|e| e.load(Ordering::Relaxed) == 0))
+            .unwrap_or(ver as usize % ENTRIES_PER_BUCKET);
+
+        bucket[slot].store(entry, Ordering::Relaxed);
+    }
+
+    /// Side to move
+    pub fn probe(&self, zb: Zobrist) -> Option<Score> {
+        let (idx,ver) = self.calc_index(zb);
+        if ver == 0 { return None; }
+
+        for e in self.buf[idx].entries.iter() {
+            let e = e.load(Ordering::Relaxed);
+            if Self::get_ver(e) == ver {
+                return Some((e & LOW_FOUR_BYTES) as u32 as i32 as Score);
+            }
+        }
+        None
+    }
+
+    fn get_ver(entry: u64) -> u32 {
+        (entry >> SHIFT_TO_UPPER) as u32
+    }
+
+}
+
+/// Calc index
+impl EvalCache {
+    /// https://en.wikipedia.org/wiki/Hash_function#Multiplicative_hashing
+    pub fn calc_index(&self, zb: Zobrist) -> (usize, u32) {
+        let key = (zb.0 as u128 * self.num_buckets as u128).overflowing_shr(64).0;
+        let ver = (zb.0 & LOW_FOUR_BYTES) as u32;
+        (key as usize, ver)
+    }
+}
+
+/// clear, queries
+impl EvalCache {
+
+    /// Entries depend on the eval params and NNUE, clear after changing them
+    pub fn clear_table(&self) {
+        for bucket in self.buf.iter() {
+            for e in bucket.entries.iter() {
+                e.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn total_entries(&self) -> usize {
+        self.num_buckets * ENTRIES_PER_BUCKET
+    }
+
+    pub fn used_entries(&self) -> usize {
+        self.buf.iter()
+            .map(|b| b.entries.iter().filter(|e| e.load(Ordering::Relaxed) != 0).count())
+            .sum()
+    }
+
+}
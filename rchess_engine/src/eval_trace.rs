@@ -0,0 +1,377 @@
+
+use crate::types::*;
+use crate::tables::*;
+use crate::explore::*;
+use crate::evaluate::TaperedScore;
+use crate::endgame::{EndGameMaps,ScaleFactor};
+use crate::sf_compat::{NNEval,NNEvaluator};
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+/// One term of the classical eval
+#[derive(Debug,Clone,Copy,Serialize)]
+pub struct EvalTerm {
+    pub name:          &'static str,
+    /// [White, Black], None for terms only computed for both sides together
+    pub sides:         Option<[TaperedScore; 2]>,
+    /// White side
+    pub total:         TaperedScore,
+}
+
+/// Every term of ExHelper::_evaluate_classical, see ExHelper::trace_classical
+#[derive(Debug,Default,Clone,Serialize)]
+pub struct EvalTrace {
+    pub terms:         Vec<EvalTerm>,
+    /// 0 is the start of the game, 256 the end
+    pub phase:         Phase,
+    /// Out of ScaleFactor::Normal, only for the endgame part
+    pub scale_factor:  Score,
+    /// Specialized evaluator and the strong side, replaces all the terms with a single "endgame" term
+    pub endgame:       Option<String>,
+    /// White side, same as evaluate_classical
+    pub score:         Score,
+}
+
+/// build
+impl EvalTrace {
+    pub fn push(&mut self, name: &'static str, white: TaperedScore, black: TaperedScore) {
+        self.terms.push(EvalTerm { name, sides: Some([white, black]), total: white - black });
+    }
+    pub fn push_total(&mut self, name: &'static str, total: TaperedScore) {
+        self.terms.push(EvalTerm { name, sides: None, total });
+    }
+}
+
+/// query
+impl EvalTrace {
+    /// White side, before scaling
+    pub fn total(&self) -> TaperedScore {
+        self.terms.iter().map(|t| t.total).sum()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EvalTerm> {
+        self.terms.iter().find(|t| t.name == name)
+    }
+}
+
+/// print
+impl EvalTrace {
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// In pawns, like Stockfish's eval command
+    pub fn to_table(&self) -> String {
+        let mut out = String::new();
+        let pawns = |x: Score| x as f64 / 100.0;
+        let cell = |s: Option<TaperedScore>| match s {
+            Some(s) => format!(" {:>+6.2}  {:>+6.2} ", pawns(s.mid), pawns(s.end)),
+            None    => format!(" {:>6}  {:>6} ", "----", "----"),
+        };
+
+        writeln!(out, "Classical terms (White side)").unwrap();
+        if let Some(eg) = &self.endgame {
+            writeln!(out, "Specialized endgame evaluator: {}, score {:>+.2}", eg, pawns(self.score)).unwrap();
+            return out;
+        }
+
+        let line = "+------------+-----------------+-----------------+-----------------+";
+        writeln!(out, "{}", line).unwrap();
+        writeln!(out, "|    Term    |      White      |      Black      |      Total      |").unwrap();
+        writeln!(out, "|            |   MG       EG   |   MG       EG   |   MG       EG   |").unwrap();
+        writeln!(out, "{}", line).unwrap();
+        for t in self.terms.iter() {
+            let (w,b) = match t.sides {
+                Some([w,b]) => (Some(w), Some(b)),
+                None        => (None, None),
+            };
+            writeln!(out, "| {:>10} |{}|{}|{}|", t.name, cell(w), cell(b), cell(Some(t.total))).unwrap();
+        }
+        writeln!(out, "{}", line).unwrap();
+        writeln!(out, "| {:>10} |{}|{}|{}|", "total", cell(None), cell(None), cell(Some(self.total()))).unwrap();
+        writeln!(out, "{}", line).unwrap();
+        writeln!(out, "phase {}/256, scale factor {}/{}",
+                 self.phase, self.scale_factor, ScaleFactor::Normal as Score).unwrap();
+
+        out
+    }
+
+}
+
+/// Like Stockfish's eval command, White side, in pawns
+pub fn trace_eval(ts: &Tables, ex: &Explorer, g: &Game) -> String {
+    let mut out = String::new();
+    let mut nn = ex.nnue.clone();
+    let mut helper = ex.build_exhelper(0, PerThreadData::default());
+
+    let white = |g: &Game, x: Score| if g.state.side_to_move == White { x } else { -x };
+    let pawns = |x: Score| x as f64 / 100.0;
+
+    /// NNUE buckets
+    match nn.as_mut() {
+        Some(NNEval::NNUE4(nn4)) => {
+            let tr = nn4.trace_eval(g);
+            writeln!(out, "NNUE network contributions (White side)").unwrap();
+            writeln!(out, "+--------+----------+------------+---------+").unwrap();
+            writeln!(out, "| Bucket | Material | Positional |  Total  |").unwrap();
+            writeln!(out, "|        |  (PSQT)  |  (Layers)  |         |").unwrap();
+            writeln!(out, "+--------+----------+------------+---------+").unwrap();
+            for b in 0..8 {
+                let (psqt,pos) = (white(g, tr.psqt[b]), white(g, tr.positional[b]));
+                writeln!(out, "|  {:>4}  | {:>+8.2} | {:>+10.2} | {:>+7.2} |{}",
+                         b, pawns(psqt), pawns(pos), pawns(psqt + pos),
+                         if b == tr.bucket { " <-- this bucket is used" } else { "" }).unwrap();
+            }
+            writeln!(out, "+--------+----------+------------+---------+").unwrap();
+            writeln!(out).unwrap();
+        },
+        Some(nn) => {
+            writeln!(out, "{} network: {}", nn.name(), nn.description()).unwrap();
+            writeln!(out).unwrap();
+        },
+        None => {
+            writeln!(out, "No network loaded").unwrap();
+            writeln!(out).unwrap();
+        },
+    }
+
+    /// Classical terms
+    let trace = helper.trace_classical(ts, g);
+    writeln!(out, "{}", trace.to_table()).unwrap();
+
+    /// Piece values, from removing each piece
+    let mut eval_white = |g: &Game| -> Score {
+        match nn.as_mut() {
+            Some(nn) => {
+                nn.reset(g);
+                white(g, nn.evaluate(g))
+            },
+            None     => helper.evaluate_classical(ts, g, &mut SearchStats::default()),
+        }
+    };
+
+    let base = eval_white(g);
+
+    /// Removing a piece would compare against a known win or draw
+    let egs = EndGameMaps::get(ts);
+    if egs.get_value(g).is_some() {
+        writeln!(out, "No piece values, the specialized endgame evaluator is used instead").unwrap();
+        writeln!(out).unwrap();
+    } else {
+        writeln!(out, "Piece values (White side), from removing each piece").unwrap();
+        let line = " +-------+-------+-------+-------+-------+-------+-------+-------+";
+        writeln!(out, "{}", line).unwrap();
+        for rank in (0..8).rev() {
+            let mut names  = String::from(" |");
+            let mut values = String::from(" |");
+            for file in 0..8 {
+                let sq = Coord::new(file, rank);
+                match g.get_at(sq) {
+                    Some((side,pc)) => {
+                        let ch = pc.print_char().to_ascii_uppercase();
+                        write!(names, "   {}   |", side.fold(ch, ch.to_ascii_lowercase())).unwrap();
+                        match without_piece(ts, g, sq) {
+                            Some(g2) if egs.get_value(&g2).is_none() => {
+                                write!(values, " {:>+5.2} |", pawns(base - eval_white(&g2))).unwrap();
+                            },
+                            _ => values.push_str("       |"),
+                        }
+                    },
+                    None => {
+                        names.push_str("       |");
+                        values.push_str("       |");
+                    },
+                }
+            }
+            writeln!(out, "{}", names).unwrap();
+            writeln!(out, "{}", values).unwrap();
+            writeln!(out, "{}", line).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+
+    if nn.is_some() {
+        writeln!(out, "NNUE evaluation        {:>+.2} (White side)", pawns(base)).unwrap();
+    }
+    /// Includes endgame evaluators and scaling
+    writeln!(out, "Classical evaluation   {:>+.2} (White side), phase {}/256",
+             pawns(trace.score), g.state.phase).unwrap();
+
+    out
+}
+
+/// None for an empty square or a king
+fn without_piece(ts: &Tables, g: &Game, sq: Coord) -> Option<Game> {
+    let (side,pc) = g.get_at(sq)?;
+    if pc == King { return None; }
+
+    let mut g2 = g.clone();
+    g2.delete_piece_mut_unchecked(ts, sq, pc, side, false);
+
+    /// Same as FenMode::Lenient, drop rights that no longer have a rook,
+    /// and en passant without the pawn that double pushed
+    let y = side.fold(0, 7);
+    if pc == Rook && sq == Coord::new(7,y) { g2.state.castling.set_king(side, false); }
+    if pc == Rook && sq == Coord::new(0,y) { g2.state.castling.set_queen(side, false); }
+    if let Some(ep) = g2.state.en_passant {
+        if pc == Pawn && ep.file() == sq.file() && sq.rank() == side.fold(3, 4) {
+            g2.state.en_passant = None;
+        }
+    }
+
+    // Not a move, so phase is from scratch
+    g2.last_move = None;
+    g2.recalc_gameinfo_mut(ts).ok()?;
+    g2.init_gameinfo_mut(ts).ok()?;
+    g2.zobrist = Zobrist::new(ts, &g2);
+    Some(g2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::FenMode;
+    use crate::timer::TimeSettings;
+
+    fn perft_games(ts: &Tables) -> Vec<Game> {
+        let fens = crate::util::read_epd_no_bm(concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt")).unwrap();
+        fens.iter().flat_map(|fen| Game::from_fen(ts, fen.split(';').next().unwrap())).collect()
+    }
+
+    fn explorer(ts: &Tables) -> Explorer {
+        let g = Game::from_fen(ts, STARTPOS).unwrap();
+        Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0))
+    }
+
+    const ENDGAME_FENS: [&'static str; 3] = [
+        "8/8/8/4k3/8/8/8/R3K3 w - - 0 1",
+        "8/8/8/4k3/8/8/8/3NKN2 b - - 0 1",
+        "8/8/8/4kr2/8/8/8/4K1N1 w - - 0 1",
+    ];
+
+    #[test]
+    fn without_piece_matches_fen() {
+        let ts = Tables::new();
+        let mut n = 0;
+        for g in perft_games(&ts) {
+            for sq in g.all_occupied().into_iter() {
+                let g2 = match without_piece(&ts, &g, sq) {
+                    Some(g2) => g2,
+                    None     => {
+                        assert_eq!(g.get_at(sq).map(|x| x.1), Some(King));
+                        continue;
+                    },
+                };
+                assert!(g2.get_at(sq).is_none());
+                assert_eq!(g2.all_occupied().popcount() + 1, g.all_occupied().popcount());
+
+                /// Same as building the position from scratch
+                let g3 = Game::from_fen_mode(&ts, &g2.to_fen(), FenMode::Lenient).unwrap();
+                assert_eq!(g2.to_fen(), g3.to_fen());
+                assert_eq!(g2.zobrist, g3.zobrist, "{}", g2.to_fen());
+                assert_eq!(g2.pawn_zb, g3.pawn_zb, "{}", g2.to_fen());
+                assert_eq!(g2.mat_zb, g3.mat_zb, "{}", g2.to_fen());
+                assert_eq!(g2.state.material, g3.state.material);
+                assert_eq!(g2.state.phase, g3.state.phase);
+                assert_eq!(g2.state.in_check, g3.state.in_check);
+                assert_eq!(g2.psqt_score, g3.psqt_score);
+                assert_eq!(g2.npm, g3.npm);
+                n += 1;
+            }
+        }
+        assert!(n > 100);
+    }
+
+    #[test]
+    fn trace_terms_sum_to_classical() {
+        let ts = Tables::new();
+        let ex = explorer(&ts);
+        let mut helper = ex.build_exhelper(0, PerThreadData::default());
+
+        let mut gs = perft_games(&ts);
+        gs.extend(ENDGAME_FENS.iter().map(|fen| Game::from_fen(&ts, fen).unwrap()));
+
+        for g in gs.iter() {
+            let trace = helper.trace_classical(&ts, g);
+            let score = helper.evaluate_classical(&ts, g, &mut SearchStats::default());
+
+            let mut total = trace.total();
+            /// i64 for known wins
+            let end = total.end as i64 * trace.scale_factor as i64 / ScaleFactor::Normal as i64;
+            total.end = end as Score;
+            assert_eq!(total.taper(g), score, "{}", g.to_fen());
+            assert_eq!(trace.score, score, "{}", g.to_fen());
+            assert_eq!(trace.phase, g.state.phase);
+        }
+    }
+
+    #[test]
+    fn trace_to_json_round_trip() {
+        use serde_json::Value;
+        let ts = Tables::new();
+        let ex = explorer(&ts);
+        let mut helper = ex.build_exhelper(0, PerThreadData::default());
+
+        let mut gs = perft_games(&ts);
+        gs.push(Game::from_fen(&ts, ENDGAME_FENS[0]).unwrap());
+
+        let ts_from = |v: &Value| -> TaperedScore { serde_json::from_value(v.clone()).unwrap() };
+
+        for g in gs.iter() {
+            let trace = helper.trace_classical(&ts, g);
+            let v: Value = serde_json::from_str(&trace.to_json()).unwrap();
+
+            assert_eq!(v["phase"].as_i64(), Some(trace.phase as i64));
+            assert_eq!(v["scale_factor"].as_i64(), Some(trace.scale_factor as i64));
+            assert_eq!(v["score"].as_i64(), Some(trace.score as i64));
+            assert_eq!(v["endgame"].as_str(), trace.endgame.as_deref());
+
+            let terms = v["terms"].as_array().unwrap();
+            assert_eq!(terms.len(), trace.terms.len());
+            for (t,v) in trace.terms.iter().zip(terms.iter()) {
+                assert_eq!(v["name"].as_str(), Some(t.name));
+                assert_eq!(ts_from(&v["total"]), t.total);
+                match t.sides {
+                    Some([w,b]) => {
+                        assert_eq!(ts_from(&v["sides"][0]), w);
+                        assert_eq!(ts_from(&v["sides"][1]), b);
+                    },
+                    None => assert!(v["sides"].is_null()),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trace_eval_endgame_evaluator() {
+        let ts = Tables::new();
+        let ex = explorer(&ts);
+
+        for (fen,eg) in ENDGAME_FENS.iter().zip(["KXvK, White", "KNNvK, White", "KRvKN, Black"]) {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            let out = trace_eval(&ts, &ex, &g);
+            assert!(out.contains(&format!("Specialized endgame evaluator: {}, score", eg)), "{}", out);
+            assert!(!out.contains("Piece values"), "{}", out);
+        }
+
+        let g = Game::from_fen(&ts, "8/8/8/4k3/8/8/8/3NKN2 w - - 0 1").unwrap();
+        let out = trace_eval(&ts, &ex, &g);
+        assert!(out.contains("Classical evaluation   +0.00"), "{}", out);
+
+        /// Removing the pawn leaves KXvK, removing the knight KRvKP
+        let g = Game::from_fen(&ts, "8/8/8/4k3/4p3/8/8/R3K1N1 w - - 0 1").unwrap();
+        let out = trace_eval(&ts, &ex, &g);
+        let values = out.lines()
+            .skip_while(|l| !l.starts_with("Piece values"))
+            .filter(|l| l.starts_with(" |") && l.contains('.'))
+            .collect::<Vec<_>>();
+        /// Only the rook has a value
+        assert_eq!(values.len(), 1, "{}", out);
+        assert_eq!(values[0].matches('.').count(), 1, "{}", out);
+        assert!(values[0].starts_with(" | +"), "{}", out);
+    }
+
+}
@@ -1,10 +1,13 @@
 
 use crate::explore::*;
 // use crate::material_table::{MatEval,PawnEval,MaterialTable,PawnTable};
-use crate::material::{MatEval,PawnEval,MaterialTable,PawnTable};
+use crate::material::{MatEval,PawnEval,MaterialTable,PawnTable,pawn_attacks};
 use crate::types::*;
 use crate::tables::*;
 use crate::endgame::*;
+use crate::sf_compat::NNEvaluator;
+use crate::tuning::{EvalParams,EPPawns};
+use crate::eval_trace::EvalTrace;
 
 pub use self::tapered::TaperedScore;
 
@@ -44,9 +47,10 @@ mod tapered {
 
     /// convert TaperedScore to Score
     impl TaperedScore {
+        /// i64 so known wins from endgame evaluators don't overflow
         pub fn taper(&self, g: &Game) -> Score {
-            let p = g.state.phase as Score;
-            ((self.mid * (256 - p)) + (self.end * p)) / 256
+            let p = g.state.phase as i64;
+            (((self.mid as i64 * (256 - p)) + (self.end as i64 * p)) / 256) as Score
         }
     }
 
@@ -55,16 +59,24 @@ mod tapered {
 /// Evaluate
 impl ExHelper {
 
-    // fn use_nnue_imbalance(g: &Game) -> bool {
-    //     let imbalance = g.state.npm[White]
-    //         + Pawn.score_tapered() * g.state.material.get(Pawn, White) as Score
-    //         - g.state.npm[Black]
-    //         - Pawn.score_tapered() * g.state.material.get(Pawn, Black) as Score;
-    //     imbalance.taper(g) < 4 * Pawn.score()
-    // }
+    /// Classical eval for lopsided positions, where the NNUE isn't needed to find the winner.
+    /// The threshold is higher with more pieces on the board, and grows with the 50 move
+    /// counter, since the classical eval doesn't know shuffling positions are drawish
+    pub fn use_classical_hybrid(&self, g: &Game, me: &MatEval) -> bool {
+        let sp = &self.params;
+        if sp.hybrid_threshold <= 0 { return false; }
+
+        let lazy = (me.material_score + g.psqt_score[White] - g.psqt_score[Black]).taper(g);
+
+        let mid       = 256 - (g.state.phase as i64).min(256);
+        let threshold = sp.hybrid_threshold as i64 + sp.hybrid_phase as i64 * mid / 256;
+        let rule50    = sp.hybrid_rule50 as i64;
+
+        (lazy as i64).abs() * rule50 > threshold * (rule50 + g.halfmove as i64)
+    }
 
     /// NNUE eval is ~18x slower than classic (only material and psqt)
-    /// so fallback to classic for large material imbalance
+    /// so fallback to classic for large material imbalance, see use_classical_hybrid
     pub fn evaluate(
         &mut self,
         ts:       &Tables,
@@ -77,23 +89,58 @@ impl ExHelper {
         // /// evaluate is only called from quiet positions
         // assert!(!g.state.in_check);
 
-        let use_nnue = cfg!(feature = "nnue")
-            && self.nnue.is_some()
-            // && Self::use_nnue_imbalance(g)
-            ;
+        let use_nnue = cfg!(feature = "nnue") && self.nnue.is_some();
 
         if use_nnue {
-            stats.eval_nnue += 1;
-            if let Some(nnue) = self.nnue.as_mut() {
-                let score = nnue.evaluate(&g, true);
-                score
-            } else { unreachable!() }
-        } else {
-            let stand_pat = self.evaluate_classical(ts, g, stats);
-            let score = if g.state.side_to_move == Black { -stand_pat } else { stand_pat };
-            stats.eval_classical += 1;
-            score
+            let me = self.material(ts, g, stats);
+            if let Some((eg,strong)) = me.eg_val {
+                stats.eval_endgame += 1;
+                return eg.evaluate_stm(ts, g, strong);
+            }
+
+            if !self.use_classical_hybrid(g, &me) {
+                stats.eval_nnue += 1;
+                let score = if let Some(nnue) = self.nnue.as_mut() {
+                    nnue.evaluate(&g)
+                } else { unreachable!() };
+
+                let strong = if (score >= 0) == (g.state.side_to_move == White) { White } else { Black };
+                let sf = self.scale_factor(ts, g, &me, strong);
+                return score * sf / ScaleFactor::Normal as Score;
+            }
+        }
+
+        let stand_pat = self.evaluate_classical(ts, g, stats);
+        let score = if g.state.side_to_move == Black { -stand_pat } else { stand_pat };
+        stats.eval_classical += 1;
+        score
+    }
+
+    /// evaluate, through the eval cache shared by all threads
+    pub fn evaluate_cached(
+        &mut self,
+        ts:       &Tables,
+        stats:    &mut SearchStats,
+        g:        &Game,
+        ply:      Depth,
+        quiesce:  bool,
+    ) -> Score {
+        let key = Self::eval_cache_key(g);
+        if let Some(score) = self.eval_cache.probe(key) {
+            stats.ec_hits += 1;
+            return score;
         }
+        stats.ec_misses += 1;
+        let score = self.evaluate(ts, stats, g, ply, quiesce);
+        self.eval_cache.insert(key, score);
+        score
+    }
+
+    /// use_classical_hybrid depends on the 50 move counter, so the same position with a
+    /// different counter can get a different eval
+    pub fn eval_cache_key(g: &Game) -> Zobrist {
+        const HALFMOVE_KEY: u64 = 0x9e37_79b9_7f4a_7c15;
+        Zobrist(g.zobrist.0 ^ (g.halfmove as u64).wrapping_mul(HALFMOVE_KEY))
     }
 
 }
@@ -102,23 +149,38 @@ impl ExHelper {
 impl ExHelper {
 
     pub fn evaluate_classical(&mut self, ts: &Tables, g: &Game, stats: &mut SearchStats) -> Score {
-        let score = self._evaluate_classical::<false>(ts, g, stats);
+        let score = self._evaluate_classical::<false>(ts, g, stats, &mut EvalTrace::default());
         score.taper(g)
     }
 
+    /// Every term of _evaluate_classical, White side
+    pub fn trace_classical(&mut self, ts: &Tables, g: &Game) -> EvalTrace {
+        let mut trace = EvalTrace::default();
+        let score = self._evaluate_classical::<true>(ts, g, &mut SearchStats::default(), &mut trace);
+        trace.phase = g.state.phase;
+        trace.score = score.taper(g);
+        trace
+    }
+
+    /// The terms are only added to trace if TR
     pub fn _evaluate_classical<const TR: bool>(
-        &mut self, ts: &Tables, g: &Game, stats: &mut SearchStats) -> TaperedScore {
+        &mut self, ts: &Tables, g: &Game, stats: &mut SearchStats, trace: &mut EvalTrace,
+    ) -> TaperedScore {
 
         let mut score = TaperedScore::default();
 
-        let (me,overwritten, hit) = self.material_table.get_or_insert(ts, g);
-        if overwritten { stats.mt_overwrites += 1; }
-        if hit { stats.mt_hits += 1; } else { stats.mt_misses += 1; }
+        let me = self.material(ts, g, stats);
 
-        /// TODO: endgames
-        if let Some(eg) = me.eg_val {
-            // return eg.evaluate(ts, g);
-            unimplemented!()
+        if let Some((eg,strong)) = me.eg_val {
+            let score = eg.evaluate(ts, g, strong);
+            let score = strong.fold(score, -score);
+            let score = TaperedScore::new(score, score);
+            if TR {
+                trace.endgame      = Some(format!("{:?}, {:?}", eg, strong));
+                trace.scale_factor = ScaleFactor::Normal as Score;
+                trace.push_total("endgame", score);
+            }
+            return score;
         }
 
         // let psqt = (g.sum_psqt_score(ts, White), g.sum_psqt_score(ts, Black));
@@ -126,7 +188,7 @@ impl ExHelper {
         // if TR { eprintln!("psqt = {:?}", (psqt.0, psqt.1)); }
 
         score += g.psqt_score[White] - g.psqt_score[Black];
-        if TR { eprintln!("psqt = {:?}", (g.psqt_score[White],g.psqt_score[Black])); }
+        if TR { trace.push("psqt", g.psqt_score[White], g.psqt_score[Black]); }
 
         // let material_score = g.state.npm[White]
         //     + Pawn.score_tapered() * g.state.material.get(Pawn, White) as Score
@@ -134,17 +196,341 @@ impl ExHelper {
         //     - Pawn.score_tapered() * g.state.material.get(Pawn, Black) as Score;
 
         score += me.material_score;
-        if TR { eprintln!("material = {:?}", me.material_score); }
+        if TR { trace.push_total("material", me.material_score); }
+
+        score += me.imbalance;
+        if TR { trace.push_total("imbalance", me.imbalance); }
 
         // score += material_score;
         // if TR { eprintln!("material = {:?}", material_score); }
 
-        // let pawns = self.pawn_table.get_or_insert(ts, g);
-        // score += pawns.scores[White] - pawns.scores[Black];
-        // if TR { eprintln!("pawns = {:?}", (pawns.scores[White], pawns.scores[Black])); }
+        let (pawns,overwritten,hit) = self.pawn_table.get_or_insert(
+            ts, g, &self.cfg.eval_params_mid, &self.cfg.eval_params_end);
+        if hit { stats.ph_hits += 1; } else { stats.ph_misses += 1; }
+
+        score += pawns.scores[White] - pawns.scores[Black];
+        if TR { trace.push("pawns", pawns.scores[White], pawns.scores[Black]); }
+
+        let passed = (self.passed_pawns(g, &pawns, White), self.passed_pawns(g, &pawns, Black));
+        score += passed.0 - passed.1;
+        if TR { trace.push("passed", passed.0, passed.1); }
+
+        /// Mobility fills in the attacks used by king safety and threats
+        let mut att = EvalAttacks::new(ts, g);
+        let mob = (self.mobility(ts, g, &pawns, &mut att, White),
+                   self.mobility(ts, g, &pawns, &mut att, Black));
+        if cfg!(feature = "mobility_scoring") {
+            score += mob.0 - mob.1;
+            if TR { trace.push("mobility", mob.0, mob.1); }
+        }
+
+        let pieces = (self.pieces(ts, g, &pawns, White), self.pieces(ts, g, &pawns, Black));
+        score += pieces.0 - pieces.1;
+        if TR { trace.push("pieces", pieces.0, pieces.1); }
+
+        let king = (self.king_safety(ts, g, &att, White), self.king_safety(ts, g, &att, Black));
+        score += king.0 - king.1;
+        if TR { trace.push("king", king.0, king.1); }
+
+        let threats = (self.threats(g, &att, White), self.threats(g, &att, Black));
+        score += threats.0 - threats.1;
+        if TR { trace.push("threats", threats.0, threats.1); }
+
+        /// Only the endgame part is scaled
+        let strong = if score.end >= 0 { White } else { Black };
+        let sf = self.scale_factor(ts, g, &me, strong);
+        score.end = score.end * sf / ScaleFactor::Normal as Score;
+        if TR { trace.scale_factor = sf; }
 
         score
     }
+
+    fn material(&mut self, ts: &Tables, g: &Game, stats: &mut SearchStats) -> MatEval {
+        let (me,overwritten, hit) = self.material_table.get_or_insert(
+            ts, g, &self.cfg.eval_params_mid, &self.cfg.eval_params_end);
+        if overwritten { stats.mt_overwrites += 1; }
+        if hit { stats.mt_hits += 1; } else { stats.mt_misses += 1; }
+        me
+    }
+
+    /// Out of ScaleFactor::Normal, for the strong side's score
+    pub fn scale_factor(&mut self, ts: &Tables, g: &Game, me: &MatEval, strong: Color) -> Score {
+        let mut sf = match me.scale[strong] {
+            Some(st) => st.scale(g, strong, 0),
+            None     => ScaleFactor::None as Score,
+        };
+
+        if sf == ScaleFactor::None as Score
+            && g.state.material.get(Bishop, White) == 1
+            && g.state.material.get(Bishop, Black) == 1 {
+                let (pawns,_,_) = self.pawn_table.get_or_insert(
+                    ts, g, &self.cfg.eval_params_mid, &self.cfg.eval_params_end);
+                let passed = (pawns.passed & g.get(Pawn, strong)).popcount();
+                sf = ScaleType::OppositeBishops.scale(g, strong, passed);
+            }
+
+        if sf == ScaleFactor::None as Score {
+            ScaleFactor::Normal as Score
+        } else {
+            sf
+        }
+    }
+
+}
+
+/// Passed pawns
+impl ExHelper {
+
+    /// The parts that depend on more than pawns, so aren't in the pawn table.
+    /// Only for pawns past the 3rd rank, weighted by how far
+    pub fn passed_pawns(&self, g: &Game, pawns: &PawnEval, side: Color) -> TaperedScore {
+        let (ev_mid,ev_end) = (&self.cfg.eval_params_mid.pawns, &self.cfg.eval_params_end.pawns);
+
+        let king_us   = g.get(King, side).bitscan();
+        let king_them = g.get(King, !side).bitscan();
+
+        let mut score = TaperedScore::default();
+        for sq in (pawns.passed & g.get(Pawn, side)).into_iter() {
+            let r = BitBoard::relative_rank(side, sq);
+            if r < 3 { continue; }
+            let w = (r - 2) as Score;
+
+            let block  = BitBoard::single(sq).shift_dir(side.fold(N, S));
+            let free   = (block & g.all_occupied()).is_empty();
+            let block  = block.bitscan();
+            let d_us   = king_us.square_dist(block) as Score;
+            let d_them = king_them.square_dist(block) as Score;
+
+            let term = |ev: &EPPawns| {
+                let mut x = (ev.passed_king_us * d_us + ev.passed_king_them * d_them) * w;
+                if free { x += ev.passed_free * w; }
+                x
+            };
+            score += TaperedScore::new(term(ev_mid), term(ev_end));
+        }
+        score
+    }
+
+}
+
+/// Attacks by each side, filled in by mobility
+#[derive(Debug,Default,Clone,Copy)]
+pub struct EvalAttacks {
+    pub by_piece:         [[BitBoard; 6]; 2],
+    pub all:              [BitBoard; 2],
+    /// Attacked at least twice
+    pub double:           [BitBoard; 2],
+    /// King and the squares around it
+    pub king_zone:        [BitBoard; 2],
+    /// Pieces attacking the zone of side's king
+    pub king_attackers:   [u8; 2],
+    /// Zone squares attacked, by piece, of side's king
+    pub king_attacks:     [[u8; 6]; 2],
+}
+
+impl EvalAttacks {
+    /// Only pawns and kings, other pieces are added by ExHelper::mobility
+    pub fn new(ts: &Tables, g: &Game) -> Self {
+        let mut out = Self::default();
+        for side in [White,Black] {
+            let (dw,de) = side.fold((NW,NE), (SW,SE));
+            let pawns = g.get(Pawn, side);
+            out.add(side, Pawn, pawns.shift_dir(dw));
+            out.add(side, Pawn, pawns.shift_dir(de));
+
+            let king  = g.get(King, side);
+            let katts = ts.get_king(king.bitscan());
+            out.add(side, King, katts);
+            out.king_zone[side] = katts | king;
+        }
+        out
+    }
+
+    pub fn add(&mut self, side: Color, pc: Piece, bb: BitBoard) {
+        self.double[side]       |= self.all[side] & bb;
+        self.all[side]          |= bb;
+        self.by_piece[side][pc] |= bb;
+    }
+}
+
+/// Mobility
+impl ExHelper {
+
+    fn param(&self, f: impl Fn(&EvalParams) -> Score) -> TaperedScore {
+        TaperedScore::new(f(&self.cfg.eval_params_mid), f(&self.cfg.eval_params_end))
+    }
+
+    /// Squares not attacked by enemy pawns, and not occupied by our king, queen,
+    /// blocked pawns or pawns on the first two ranks
+    pub fn mobility_area(g: &Game, pawns: &PawnEval, side: Color) -> BitBoard {
+        let low_ranks = side.fold(BitBoard(0x0000000000ffff00), BitBoard(0x00ffff0000000000));
+        let pawns_us  = g.get(Pawn, side);
+        let blocked   = pawns_us & g.all_occupied().shift_dir(side.fold(S, N));
+
+        !(g.get(King, side)
+          | g.get(Queen, side)
+          | (pawns_us & (low_ranks | blocked))
+          | pawns.attacks[!side])
+    }
+
+    /// Also adds the attacks of each piece to att
+    pub fn mobility(
+        &self,
+        ts:       &Tables,
+        g:        &Game,
+        pawns:    &PawnEval,
+        att:      &mut EvalAttacks,
+        side:     Color,
+    ) -> TaperedScore {
+        let area = Self::mobility_area(g, pawns, side);
+        let occ  = g.all_occupied();
+        let ksq  = g.get(King, side).bitscan();
+        let pins = g.get_pins(side) & g.get_color(side);
+
+        let mut score = TaperedScore::default();
+        for pc in [Knight,Bishop,Rook,Queen] {
+            for sq in g.get(pc, side).into_iter() {
+                /// Sliders see through our own queens, rooks through our own rooks
+                let mut a = match pc {
+                    Knight => ts.get_knight(sq),
+                    Bishop => ts.attacks_bishop(sq, occ & !g.get(Queen, side)),
+                    Rook   => ts.attacks_rook(sq, occ & !g.get(Queen, side) & !g.get(Rook, side)),
+                    Queen  => ts.attacks_bishop(sq, occ) | ts.attacks_rook(sq, occ),
+                    _      => unreachable!(),
+                };
+                /// Pinned pieces can only move along the pin
+                if pins.is_one_at(sq) {
+                    a &= ts.line(ksq, sq);
+                }
+
+                att.add(side, pc, a);
+                let zone = (a & att.king_zone[!side]).popcount();
+                if zone > 0 {
+                    att.king_attackers[!side] += 1;
+                    att.king_attacks[!side][pc] += zone;
+                }
+
+                let n = (a & area).popcount() as usize;
+                score += self.param(|ev| ev.pieces.mobility.get(pc, n));
+            }
+        }
+        score
+    }
+
+}
+
+/// Outposts, rooks on open files
+impl ExHelper {
+
+    /// Squares on ranks 4 to 6 defended by our pawns, that enemy pawns can never attack
+    pub fn outposts(pawns: &PawnEval, side: Color) -> BitBoard {
+        let ranks = side.fold(BitBoard(0x0000ffffff000000), BitBoard(0x000000ffffff0000));
+        ranks & pawns.attacks[side] & !pawns.attacks_span[!side]
+    }
+
+    pub fn pieces(&self, ts: &Tables, g: &Game, pawns: &PawnEval, side: Color) -> TaperedScore {
+        let mut score = TaperedScore::default();
+
+        let outposts = Self::outposts(pawns, side);
+        let knights  = g.get(Knight, side);
+        let n_out    = (knights & outposts).popcount() as Score;
+        let b_out    = (g.get(Bishop, side) & outposts).popcount() as Score;
+        score += self.param(|ev| ev.pieces.outpost.outpost_knight) * n_out;
+        score += self.param(|ev| ev.pieces.outpost.outpost_bishop) * b_out;
+
+        /// Knights that can reach an empty outpost in one move
+        let reachable = (knights & !outposts).into_iter()
+            .filter(|&sq| (ts.get_knight(sq) & outposts & !g.all_occupied()).is_not_empty())
+            .count() as Score;
+        score += self.param(|ev| ev.pieces.outpost.reachable_knight) * reachable;
+
+        for sq in g.get(Rook, side).into_iter() {
+            let file = sq.mask_file();
+            if (file & g.get(Pawn, side)).is_not_empty() { continue; }
+            if (file & g.get(Pawn, !side)).is_not_empty() {
+                score += self.param(|ev| ev.pieces.rook_open_file[0]);
+            } else {
+                score += self.param(|ev| ev.pieces.rook_open_file[1]);
+            }
+        }
+
+        score
+    }
+
+}
+
+/// King Safety
+impl ExHelper {
+
+    /// Penalty for side, needs att from mobility for both sides
+    pub fn king_safety(&self, ts: &Tables, g: &Game, att: &EvalAttacks, side: Color) -> TaperedScore {
+        let mut score = TaperedScore::default();
+        let them = !side;
+
+        /// A single attacker isn't much of a threat
+        if att.king_attackers[side] >= 2 {
+            for pc in [Knight,Bishop,Rook,Queen] {
+                let n = att.king_attacks[side][pc] as Score;
+                score += self.param(|ev| ev.pieces.king_safety.zone_attack[pc.index() - 1]) * n;
+            }
+        }
+
+        /// Not defended, or attacked twice and only defended once
+        let safe = !g.get_color(them)
+            & (!att.all[side] | (att.double[them] & !att.double[side]));
+
+        let ksq = g.get(King, side).bitscan();
+        let occ = g.all_occupied();
+        let rook_checks   = ts.attacks_rook(ksq, occ);
+        let bishop_checks = ts.attacks_bishop(ksq, occ);
+
+        let checks = [
+            ts.get_knight(ksq),
+            bishop_checks,
+            rook_checks,
+            bishop_checks | rook_checks,
+        ];
+        for (i,&pc) in [Knight,Bishop,Rook,Queen].iter().enumerate() {
+            let n = (checks[i] & att.by_piece[them][pc] & safe).popcount() as Score;
+            score += self.param(|ev| ev.pieces.king_safety.safe_check[i]) * n;
+        }
+
+        score
+    }
+
+}
+
+/// Threats
+impl ExHelper {
+
+    /// Bonus for side, needs att from mobility for both sides
+    pub fn threats(&self, g: &Game, att: &EvalAttacks, side: Color) -> TaperedScore {
+        let mut score = TaperedScore::default();
+        let them = !side;
+
+        let targets = g.get_color(them) & !g.get(Pawn, them) & !g.get(King, them);
+
+        let hanging = targets & att.all[side] & !att.all[them];
+        score += self.param(|ev| ev.pieces.threats.hanging) * hanging.popcount() as Score;
+
+        /// Pawns that are defended, or not attacked
+        let safe = att.all[side] | !att.all[them];
+
+        let safe_pawns = g.get(Pawn, side) & safe;
+        let n = (pawn_attacks(side, safe_pawns) & targets).popcount() as Score;
+        score += self.param(|ev| ev.pieces.threats.safe_pawn) * n;
+
+        let up    = side.fold(N, S);
+        let empty = !g.all_occupied();
+        let mut push = g.get(Pawn, side).shift_dir(up) & empty;
+        push |= (push & BitBoard::mask_rank(side.fold(2, 5))).shift_dir(up) & empty;
+        push &= !att.by_piece[them][Pawn] & safe;
+        let n = (pawn_attacks(side, push) & targets).popcount() as Score;
+        score += self.param(|ev| ev.pieces.threats.pawn_push) * n;
+
+        score
+    }
+
 }
 
 /// psqt scores
@@ -277,3 +663,135 @@ impl Game {
 
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::TimeSettings;
+
+    #[test]
+    fn eval_cache_key_halfmove() {
+        let ts = Tables::new();
+        /// White is a queen up
+        let g0 = Game::from_fen(&ts, "rnb1kbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").unwrap();
+        let mut g1 = g0.clone();
+        g1.halfmove = 90;
+
+        let ex = Explorer::new(White, g0, 1, TimeSettings::new_f64(0.0, 1.0));
+        let mut h = ex.build_exhelper(0, PerThreadData::default());
+
+        /// Classical at first, but not after shuffling for a while
+        let (me,_,_) = h.material_table.get_or_insert(
+            &ts, &g0, &h.cfg.eval_params_mid, &h.cfg.eval_params_end);
+        assert!(h.use_classical_hybrid(&g0, &me));
+        assert!(!h.use_classical_hybrid(&g1, &me));
+
+        assert_ne!(ExHelper::eval_cache_key(&g0), ExHelper::eval_cache_key(&g1));
+        assert_eq!(ExHelper::eval_cache_key(&g0), g0.zobrist);
+
+        let mut stats = SearchStats::default();
+        let mut stats2 = SearchStats::default();
+        for g in [&g0, &g1] {
+            let score = h.evaluate_cached(&ts, &mut stats, g, 0, false);
+            assert_eq!(score, h.evaluate(&ts, &mut stats2, g, 0, false));
+            assert_eq!(h.evaluate_cached(&ts, &mut stats, g, 0, false), score);
+        }
+        assert_eq!(stats.ec_misses, 2);
+        assert_eq!(stats.ec_hits, 2);
+    }
+
+    #[cfg(feature = "nnue")]
+    #[test]
+    fn eval_counts_endgame() {
+        use crate::sf_compat::{NNUE4,simd::check::randomize};
+        use rand::prelude::{StdRng,SeedableRng};
+
+        let ts = Tables::new();
+        let mut nn = NNUE4::new_empty();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        randomize(&mut nn, &mut rng);
+
+        let g0 = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g0, 1, TimeSettings::new_f64(0.0, 1.0));
+        ex.add_nnue(nn);
+        let mut h = ex.build_exhelper(0, PerThreadData::default());
+
+        /// KRvK has its own evaluator, it isn't NNUE or classical
+        let g1 = Game::from_fen(&ts, "8/8/8/4k3/8/8/8/R3K3 w - - 0 1").unwrap();
+        let mut stats = SearchStats::default();
+        h.evaluate(&ts, &mut stats, &g0, 0, false);
+        h.evaluate(&ts, &mut stats, &g1, 0, false);
+        assert_eq!((stats.eval_nnue, stats.eval_classical, stats.eval_endgame), (1, 0, 1));
+    }
+
+    /// Mobility for both sides, which fills in the attacks for king safety and threats
+    fn eval_terms(ts: &Tables, fen: &str) -> (ExHelper, Game, PawnEval, EvalAttacks, [TaperedScore; 2]) {
+        let g = Game::from_fen(ts, fen).unwrap();
+        let ex = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+        let mut h = ex.build_exhelper(0, PerThreadData::default());
+        let (pawns,_,_) = h.pawn_table.get_or_insert(
+            ts, &g, &h.cfg.eval_params_mid, &h.cfg.eval_params_end);
+        let mut att = EvalAttacks::new(ts, &g);
+        let mob = [h.mobility(ts, &g, &pawns, &mut att, White),
+                   h.mobility(ts, &g, &pawns, &mut att, Black)];
+        (h, g, pawns, att, mob)
+    }
+
+    #[test]
+    fn mobility_trapped_bishop() {
+        let ts = Tables::new();
+        let (_,_,_,_,free)    = eval_terms(&ts, "4k3/8/8/8/8/8/8/2B1K3 w - - 0 1");
+        /// Boxed in by its own unmoved pawns
+        let (_,_,_,_,trapped) = eval_terms(&ts, "4k3/8/8/8/8/8/1P1P4/2B1K3 w - - 0 1");
+        assert!(free[White].mid > trapped[White].mid);
+        assert!(free[White].end > trapped[White].end);
+    }
+
+    #[test]
+    fn king_safety_safe_check() {
+        let ts = Tables::new();
+        /// Ra1+ is safe
+        let (h,g,_,att,_) = eval_terms(&ts, "r5k1/8/8/8/8/8/5PPP/6K1 w - - 0 1");
+        let check = h.king_safety(&ts, &g, &att, White);
+        assert_eq!(check, h.param(|ev| ev.pieces.king_safety.safe_check[2]));
+
+        /// Same check, but the knight covers a1
+        let (h,g,_,att,_) = eval_terms(&ts, "r5k1/8/8/8/8/1N6/5PPP/6K1 w - - 0 1");
+        let covered = h.king_safety(&ts, &g, &att, White);
+        assert_eq!(covered, TaperedScore::default());
+
+        assert!(check.mid < covered.mid);
+    }
+
+    #[test]
+    fn threats_hanging_piece() {
+        let ts = Tables::new();
+        let (h,g,_,att,_) = eval_terms(&ts, "4k3/8/8/3n4/8/8/8/3RK3 w - - 0 1");
+        assert_eq!(h.threats(&g, &att, White), h.param(|ev| ev.pieces.threats.hanging));
+
+        /// Defended by the e6 pawn
+        let (h,g,_,att,_) = eval_terms(&ts, "4k3/8/4p3/3n4/8/8/8/3RK3 w - - 0 1");
+        assert_eq!(h.threats(&g, &att, White), TaperedScore::default());
+    }
+
+    #[test]
+    fn pieces_outpost_open_file() {
+        let ts = Tables::new();
+        let (h,g,pawns,_,_) = eval_terms(&ts, "4k3/8/8/3N4/2P5/8/8/4K3 w - - 0 1");
+        assert_eq!(h.pieces(&ts, &g, &pawns, White), h.param(|ev| ev.pieces.outpost.outpost_knight));
+
+        /// Nothing supports the knight
+        let (h,g,pawns,_,_) = eval_terms(&ts, "4k3/8/8/3N4/8/8/8/4K3 w - - 0 1");
+        assert_eq!(h.pieces(&ts, &g, &pawns, White), TaperedScore::default());
+
+        let (h,g,pawns,_,_) = eval_terms(&ts, "4k3/8/8/8/8/8/P7/1R2K3 w - - 0 1");
+        assert_eq!(h.pieces(&ts, &g, &pawns, White), h.param(|ev| ev.pieces.rook_open_file[1]));
+
+        let (h,g,pawns,_,_) = eval_terms(&ts, "4k3/p7/8/8/8/8/8/R3K3 w - - 0 1");
+        assert_eq!(h.pieces(&ts, &g, &pawns, White), h.param(|ev| ev.pieces.rook_open_file[0]));
+
+        let (h,g,pawns,_,_) = eval_terms(&ts, "4k3/8/8/8/8/8/P7/R3K3 w - - 0 1");
+        assert_eq!(h.pieces(&ts, &g, &pawns, White), TaperedScore::default());
+    }
+
+}
@@ -1,15 +1,19 @@
 
 use crate::evmap_tables::*;
 use crate::lockless_map::*;
+use crate::eval_cache::*;
 use crate::material::{MaterialTable,PawnTable};
 use crate::movegen::MoveGen;
 use crate::searchstats;
 use crate::types::*;
 use crate::tables::*;
 use crate::evaluate::*;
+use crate::tuning::{EvalParams,ParamFile};
 use crate::pruning::*;
 use crate::alphabeta::*;
 use crate::opening_book::*;
+use crate::game_record::{GameRecord,GameRecordError};
+use crate::parsing::FenMode;
 // use crate::pawn_hash_table::*;
 // use crate::heuristics::*;
 pub use crate::stack::{ABStack,ABStackPly};
@@ -17,7 +21,7 @@ pub use crate::options::*;
 
 #[cfg(feature = "syzygy")]
 use crate::syzygy::SyzygyTB;
-use crate::sf_compat::NNUE4;
+use crate::sf_compat::{NNEval,NNEvaluator,NNUEError};
 
 pub use crate::move_ordering::*;
 pub use crate::timer::*;
@@ -29,7 +33,7 @@ use std::cell::RefCell;
 use std::path::Path;
 use std::collections::{VecDeque,HashMap,HashSet};
 use std::hash::BuildHasher;
-use std::sync::atomic::{Ordering,Ordering::SeqCst,Ordering::Relaxed,AtomicU8,AtomicI8,AtomicI16,AtomicBool};
+use std::sync::atomic::{Ordering,Ordering::SeqCst,Ordering::Relaxed,AtomicU8,AtomicI8,AtomicI16,AtomicU64,AtomicBool};
 use std::time::{Instant,Duration};
 use std::sync::Arc;
 
@@ -79,6 +83,8 @@ pub struct Explorer {
     // pub best_mate:         Arc<RwLock<Option<Depth>>>,
     pub best_mate:         Arc<CachePadded<AtomicI16>>,
     pub best_depth:        Arc<CachePadded<AtomicI16>>,
+    /// Nodes searched by all threads, only counted with cfg.max_nodes
+    pub nodes:             Arc<CachePadded<AtomicU64>>,
 
     pub tx:                ExSender,
     pub rx:                ExReceiver,
@@ -92,7 +98,7 @@ pub struct Explorer {
     pub syzygy:            Option<Arc<SyzygyTB>>,
     pub opening_book:      Option<Arc<OpeningBook>>,
 
-    pub nnue:              Option<NNUE4>,
+    pub nnue:              Option<NNEval>,
 
     #[cfg(feature = "lockless_hashmap")]
     pub ptr_tt:            Arc<TransTable>,
@@ -102,6 +108,8 @@ pub struct Explorer {
     #[cfg(not(feature = "lockless_hashmap"))]
     pub tt_w:              TTWrite,
 
+    pub eval_cache:        Arc<EvalCache>,
+
     // pub eval_hashmap:      (EVReadFactory<Score>,EVWrite<Score>),
 
     // pub ph_rw:             (PHReadFactory,PHWrite),
@@ -156,6 +164,7 @@ impl Explorer {
             // best_mate:      Arc::new(RwLock::new(None)),
             best_mate:     Arc::new(CachePadded::new(AtomicI16::new(-1))),
             best_depth:     Arc::new(CachePadded::new(AtomicI16::new(0))),
+            nodes:          Arc::new(CachePadded::new(AtomicU64::new(0))),
 
             tx,
             rx,
@@ -180,6 +189,8 @@ impl Explorer {
             #[cfg(not(feature = "lockless_hashmap"))]
             tt_w,
 
+            eval_cache:     Arc::new(EvalCache::new_mb(DEFAULT_EVAL_CACHE_SIZE_MB)),
+
             // ph_rw:          (ph_rf,ph_w),
             // ph_rw,
 
@@ -197,6 +208,8 @@ impl Explorer {
 #[derive(Debug,Clone)]
 pub struct ExConfig {
     pub max_depth:             Depth,
+    /// Nodes searched by all threads, checked at every node
+    pub max_nodes:             Option<u64>,
     pub num_threads:           Option<u16>,
 
     pub blocked_moves:         HashSet<Move>,
@@ -209,14 +222,17 @@ pub struct ExConfig {
     pub clear_table:           bool,
     pub hash_size_mb:          Option<usize>,
 
-    // pub eval_params_mid:       EvalParams,
-    // pub eval_params_end:       EvalParams,
+    /// Pawn table entries depend on these, clear it after changing them
+    pub eval_params_mid:       EvalParams,
+    pub eval_params_end:       EvalParams,
 }
 
 impl Default for ExConfig {
     fn default() -> Self {
+        let (eval_params_mid,eval_params_end) = EvalParams::new_mid_end();
         Self {
             max_depth:             10,
+            max_nodes:             None,
             num_threads:           None,
 
             blocked_moves:         HashSet::default(),
@@ -230,8 +246,8 @@ impl Default for ExConfig {
             clear_table:           false,
             hash_size_mb:          None,
 
-            // eval_params_mid:       EvalParams::default(),
-            // eval_params_end:       EvalParams::default(),
+            eval_params_mid,
+            eval_params_end,
         }
     }
 }
@@ -249,11 +265,12 @@ pub struct ExHelper {
 
     pub stop:            Arc<CachePadded<AtomicBool>>,
     pub best_mate:       Arc<CachePadded<AtomicI16>>,
+    pub nodes:           Arc<CachePadded<AtomicU64>>,
 
     #[cfg(feature = "syzygy")]
     pub syzygy:          Option<Arc<SyzygyTB>>,
     // pub nnue:            Option<RefCell<NNUE4>>,
-    pub nnue:            Option<NNUE4>,
+    pub nnue:            Option<NNEval>,
 
     pub cfg:             ExConfig,
     pub params:          SParams,
@@ -270,6 +287,8 @@ pub struct ExHelper {
     #[cfg(not(feature = "lockless_hashmap"))]
     pub tt_w:            TTWrite,
 
+    pub eval_cache:      Arc<EvalCache>,
+
     // pub ph_rw:         (PHRead,PHWrite),
     // pub ph_rw:           PHTable,
 
@@ -305,6 +324,7 @@ impl Explorer {
 
             stop:            self.stop.clone(),
             best_mate:       self.best_mate.clone(),
+            nodes:           self.nodes.clone(),
 
             cfg:             self.cfg.clone(),
             params:          self.search_params.clone(),
@@ -327,6 +347,8 @@ impl Explorer {
             #[cfg(not(feature = "lockless_hashmap"))]
             tt_w:            self.tt_w.clone(),
 
+            eval_cache:      self.eval_cache.clone(),
+
             // ph_rw:           (self.ph_rw.0.handle(),self.ph_rw.1.clone()),
             // ph_rw:           self.ph_rw.handle(),
 
@@ -437,7 +459,7 @@ impl Explorer {
             helper.move_history = self.move_history.clone();
 
             if let Some(nn) = helper.nnue.as_mut() {
-                nn.ft.reset_accum(&self.game);
+                nn.reset(&self.game);
             }
 
         }
@@ -465,19 +487,7 @@ impl Explorer {
     pub fn update_game(&mut self, g: Game) {
         #[cfg(feature = "nnue")]
         if let Some(ref mut nnue) = self.nnue {
-            // nnue.ft.accum.needs_refresh = [true; 2];
-
-            #[cfg(feature = "prev_accum")]
-            // nnue.ft.accum.undo_stack_copies.clear();
-            nnue.ft.accum.stack_copies.clear();
-            #[cfg(feature = "prev_accum")]
-            // nnue.ft.accum.undo_stack_delta.clear();
-            nnue.ft.accum.stack_delta.clear();
-
-            #[cfg(feature = "prev_accum")]
-            nnue.ft.reset_accum(&g);
-            #[cfg(not(feature = "prev_accum"))]
-            nnue.ft.reset_feature_trans(&g);
+            nnue.reset(&g);
         }
         self.side = g.state.side_to_move;
         self.game = g;
@@ -493,23 +503,22 @@ impl Explorer {
         self.update_game(g);
     }
 
+    /// Replaces the move history
     pub fn update_game_movelist<'a>(
         &mut self,
         ts:          &Tables,
         fen:         &str,
-        mut moves:   impl Iterator<Item = &'a str>
-    ) {
-        let mut g = Game::from_fen(&ts, &fen).unwrap();
-        for m in moves {
-            let from = &m[0..2];
-            let to = &m[2..4];
-            let other = &m[4..];
-            let mm = g.convert_move(from, to, other).unwrap();
-            g = g.make_move_unchecked(&ts, mm).unwrap();
-            self.move_history.push((g.zobrist,mm));
-        }
+        moves:       impl Iterator<Item = &'a str>
+    ) -> Result<(), GameRecordError> {
+        let g = Game::from_fen_mode(&ts, &fen, FenMode::Lenient).map_err(GameRecordError::Fen)?;
+        let record = GameRecord::from_uci_moves(ts, g, moves)?;
+        self.update_game_record(&record);
+        Ok(())
+    }
 
-        self.update_game(g);
+    pub fn update_game_record(&mut self, record: &GameRecord) {
+        self.move_history = record.move_history();
+        self.update_game(*record.current());
     }
 
 }
@@ -517,33 +526,68 @@ impl Explorer {
 /// Load nnue, syzygy, openings
 impl Explorer {
 
-    pub fn add_nnue(&mut self, mut nn: NNUE4) {
+    pub fn add_nnue<N: Into<NNEval>>(&mut self, nn: N) {
+        self.eval_cache.clear_table();
         #[cfg(feature = "nnue")]
         {
-            #[cfg(feature = "prev_accum")]
-            nn.ft.reset_accum(&self.game);
-            #[cfg(not(feature = "prev_accum"))]
-            nn.ft.reset_feature_trans(&self.game);
-
+            let mut nn = nn.into();
+            nn.reset(&self.game);
             self.nnue = Some(nn);
         }
     }
 
-    pub fn load_nnue<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+    /// Any network type, see NNEval::read.
+    /// On error, drops any loaded net and falls back to the classical eval
+    pub fn load_nnue<P: AsRef<Path>>(&mut self, path: P) -> Result<(), NNUEError> {
+        self.eval_cache.clear_table();
         #[cfg(feature = "nnue")]
         {
-            let mut nn = NNUE4::read_nnue(path)?;
-
-            #[cfg(feature = "prev_accum")]
-            nn.ft.reset_accum(&self.game);
-            #[cfg(not(feature = "prev_accum"))]
-            nn.ft.reset_feature_trans(&self.game);
-
+            let mut nn = match NNEval::read(path) {
+                Ok(nn) => nn,
+                Err(e) => {
+                    self.nnue = None;
+                    return Err(e);
+                },
+            };
+            nn.reset(&self.game);
             self.nnue = Some(nn);
         }
         Ok(())
     }
 
+    /// JSON, see ParamFile. The PSQTs are also set in ts, and the current game is re-scored.
+    /// On error, nothing is changed
+    pub fn load_params<P: AsRef<Path>>(&mut self, ts: &mut Tables, path: P) -> std::io::Result<()> {
+        let pf = ParamFile::read(path)?;
+        self.set_params(ts, &pf);
+        Ok(())
+    }
+
+    pub fn set_params(&mut self, ts: &mut Tables, pf: &ParamFile) {
+        ts.eval_params_mid = pf.eval_mid;
+        ts.eval_params_end = pf.eval_end;
+        self.cfg.eval_params_mid = pf.eval_mid;
+        self.cfg.eval_params_end = pf.eval_end;
+        self.search_params = pf.search;
+
+        /// Cached entries depend on the params
+        self.eval_cache.clear_table();
+        for helper in self.helpers.iter() {
+            let mut helper = helper.lock();
+            helper.material_table = MaterialTable::default();
+            helper.pawn_table     = PawnTable::default();
+        }
+
+        let mut g = self.game;
+        g.recalc_psqt_mut(ts);
+        self.update_game(g);
+    }
+
+    /// The params in use, ts's PSQTs are the same unless changed elsewhere
+    pub fn dump_params(&self) -> ParamFile {
+        ParamFile::new(self.cfg.eval_params_mid, self.cfg.eval_params_end, self.search_params)
+    }
+
     pub fn load_syzygy<P: AsRef<Path>>(&mut self, dir: P) -> std::io::Result<()> {
         #[cfg(feature = "syzygy")]
         {
@@ -658,6 +702,16 @@ impl ExHelper {
     }
 }
 
+/// Params
+impl ExHelper {
+    /// After changing cfg.eval_params_mid or _end, anything evaluated with the old ones is stale
+    pub fn clear_eval_caches(&mut self) {
+        self.eval_cache.clear_table();
+        self.material_table = MaterialTable::default();
+        self.pawn_table     = PawnTable::default();
+    }
+}
+
 /// Entry points
 impl Explorer {
 
@@ -691,6 +745,7 @@ impl Explorer {
         // let mut w = self.best_mate.write();
         // *w = None;
         self.best_mate.store(-1, SeqCst);
+        self.nodes.store(0, SeqCst);
     }
 
     #[allow(unused_labels,unused_doc_comments)]
@@ -1567,3 +1622,108 @@ impl ExHelper {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_nodes_stops_mid_iteration() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g, 64, TimeSettings::new_infinite());
+        ex.cfg.num_threads = Some(1);
+        ex.cfg.max_nodes   = Some(3000);
+
+        let (res,stats) = ex.explore(&ts);
+        assert!(res.is_some());
+        assert_eq!(ex.nodes.load(SeqCst), 3000);
+        /// stats only include finished iterations
+        assert!(stats.nodes <= 3000, "{}", stats.nodes);
+    }
+
+    #[test]
+    #[cfg(feature = "nnue")]
+    fn load_nnue_bad_file() {
+        use crate::sf_compat::{NNUE4,NNUEError};
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+
+        let path = std::env::temp_dir().join(format!("rchess_load_nnue_{}.nnue", std::process::id()));
+        NNUE4::new_empty().write_nnue(&path).unwrap();
+        ex.load_nnue(&path).unwrap();
+        assert!(ex.nnue.is_some());
+
+        /// A failed load drops the previous net too, so the eval is classical
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(ex.load_nnue(&path), Err(NNUEError::Truncated)));
+        assert!(ex.nnue.is_none());
+
+        std::fs::write(&path, b"not a net").unwrap();
+        assert!(ex.load_nnue(&path).is_err());
+        assert!(ex.nnue.is_none());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(ex.load_nnue(&path), Err(NNUEError::Io(_))));
+        assert!(ex.nnue.is_none());
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let mut ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+
+        let mut pf = ParamFile::default();
+        pf.eval_mid.psqt.knight[27] += 13;
+        pf.eval_end.psqt.pawn[52]   -= 7;
+        pf.eval_mid.pieces.mobility.knight[3] += 5;
+        pf.eval_end.imbalance.ours[2][1]      += 3;
+        pf.search.rfp_margin       += 11;
+        pf.search.hybrid_threshold += 100;
+        ex.set_params(&mut ts, &pf);
+
+        let path = std::env::temp_dir().join(format!("rchess_params_{}.json", std::process::id()));
+        ex.dump_params().save(&path).unwrap();
+
+        let mut ts2 = Tables::new();
+        let mut ex2 = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+        ex2.load_params(&mut ts2, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let pf2 = ex2.dump_params();
+        assert_eq!(pf2.eval_mid, pf.eval_mid);
+        assert_eq!(pf2.eval_end, pf.eval_end);
+        assert_eq!(pf2.search, pf.search);
+        assert_eq!(ts2.eval_params_mid.psqt, pf.eval_mid.psqt);
+        assert_eq!(ts2.eval_params_end.psqt, pf.eval_end.psqt);
+        assert_eq!(ex2.game.psqt_score, ex.game.psqt_score);
+    }
+
+    #[test]
+    fn params_bad_file_unchanged() {
+        let mut ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+        let pf0 = ex.dump_params();
+
+        let path = std::env::temp_dir().join(format!("rchess_params_bad_{}.json", std::process::id()));
+        let mut pf = ParamFile::default();
+        pf.search.lmr_reduction = 0;
+        std::fs::write(&path, pf.to_json()).unwrap();
+        assert!(ex.load_params(&mut ts, &path).is_err());
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(ex.load_params(&mut ts, &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(ex.load_params(&mut ts, &path).is_err());
+
+        let pf1 = ex.dump_params();
+        assert_eq!(pf1.eval_mid, pf0.eval_mid);
+        assert_eq!(pf1.eval_end, pf0.eval_end);
+        assert_eq!(pf1.search, pf0.search);
+    }
+
+}
@@ -0,0 +1,389 @@
+
+use crate::types::*;
+use crate::tables::*;
+use crate::movegen::*;
+use crate::parsing::FenError;
+
+use rand::{Rng,SeedableRng};
+use rand::prelude::{StdRng,SliceRandom};
+
+pub const FUZZ_SEED: u64 = 0x2f6b_1d3a_9c4e_8075;
+
+const SEED_FENS: [&'static str; 5] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+];
+
+#[derive(Debug,Clone)]
+pub enum FuzzError {
+    /// moves missing from or extra in generate_list_legal, vs the reference.
+    /// Duplicates count as extra
+    Movegen       { fen: String, missing: Vec<Move>, extra: Vec<Move> },
+    FenRoundTrip  { fen: String, fen2: Result<String,FenError> },
+    /// Zobrist::update_move_unchecked didn't match make_move
+    ZobristUpdate { fen: String, mv: Move },
+}
+
+#[derive(Debug,Default,Clone,Copy)]
+pub struct FuzzStats {
+    pub games:       u64,
+    pub positions:   u64,
+    pub moves:       u64,
+}
+
+/// Reference move generator, slow but simple
+pub mod reference {
+    use super::*;
+
+    const KNIGHT_DIRS: [(i8,i8); 8] = [(1,2),(2,1),(2,-1),(1,-2),(-1,-2),(-2,-1),(-2,1),(-1,2)];
+    const KING_DIRS: [(i8,i8); 8]   = [(0,1),(1,1),(1,0),(1,-1),(0,-1),(-1,-1),(-1,0),(-1,1)];
+    const ROOK_DIRS: [(i8,i8); 4]   = [(0,1),(1,0),(0,-1),(-1,0)];
+    const BISHOP_DIRS: [(i8,i8); 4] = [(1,1),(1,-1),(-1,-1),(-1,1)];
+
+    fn offset(sq: Coord, (dx,dy): (i8,i8)) -> Option<Coord> {
+        let x = sq.file() as i8 + dx;
+        let y = sq.rank() as i8 + dy;
+        if x < 0 || x > 7 || y < 0 || y > 7 { return None; }
+        Some(Coord::new(x as u8, y as u8))
+    }
+
+    fn targets(g: &Game, sq: Coord, dirs: &[(i8,i8)], slide: bool) -> Vec<Coord> {
+        let mut out = vec![];
+        for &d in dirs.iter() {
+            let mut c0 = sq;
+            while let Some(c1) = offset(c0, d) {
+                out.push(c1);
+                if !slide || g.get_at(c1).is_some() { break; }
+                c0 = c1;
+            }
+        }
+        out
+    }
+
+    /// Whether side `by` attacks sq
+    pub fn square_attacked(g: &Game, sq: Coord, by: Color) -> bool {
+        let is = |c0: Coord, pcs: &[Piece]| match g.get_at(c0) {
+            Some((col,pc)) => col == by && pcs.contains(&pc),
+            None           => false,
+        };
+
+        if targets(g, sq, &KNIGHT_DIRS, false).into_iter().any(|c0| is(c0, &[Knight])) { return true; }
+        if targets(g, sq, &KING_DIRS, false).into_iter().any(|c0| is(c0, &[King])) { return true; }
+        if targets(g, sq, &ROOK_DIRS, true).into_iter().any(|c0| is(c0, &[Rook,Queen])) { return true; }
+        if targets(g, sq, &BISHOP_DIRS, true).into_iter().any(|c0| is(c0, &[Bishop,Queen])) { return true; }
+
+        /// pawns of `by` attack diagonally forward, so look backward from sq
+        let dy = if by == White { -1 } else { 1 };
+        [(-1,dy),(1,dy)].iter().any(|&d| offset(sq, d).map_or(false, |c0| is(c0, &[Pawn])))
+    }
+
+    pub fn pseudo_legal_moves(g: &Game) -> Vec<Move> {
+        let side = g.state.side_to_move;
+        let mut out = vec![];
+
+        for sq in g.get_color(side).into_iter() {
+            let (_,pc) = g.get_at(sq).unwrap();
+
+            match pc {
+                Pawn => {
+                    let (dy,start,last) = if side == White { (1,1,7) } else { (-1,6,0) };
+
+                    if let Some(to) = offset(sq, (0,dy)) {
+                        if g.get_at(to).is_none() {
+                            if to.rank() == last {
+                                for new_piece in [Queen,Knight,Bishop,Rook] {
+                                    out.push(Move::new_promotion(sq, to, new_piece));
+                                }
+                            } else {
+                                out.push(Move::new_quiet(sq, to, Pawn));
+                                if sq.rank() == start {
+                                    let to2 = offset(to, (0,dy)).unwrap();
+                                    if g.get_at(to2).is_none() {
+                                        out.push(Move::new_double(sq, to2));
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    for dx in [-1,1] {
+                        if let Some(to) = offset(sq, (dx,dy)) {
+                            match g.get_at(to) {
+                                Some((col,victim)) if col != side => {
+                                    if to.rank() == last {
+                                        for new_piece in [Queen,Knight,Bishop,Rook] {
+                                            out.push(Move::new_promotion_cap(sq, to, new_piece, victim));
+                                        }
+                                    } else {
+                                        out.push(Move::new_capture(sq, to, Pawn, victim));
+                                    }
+                                },
+                                None if g.state.en_passant == Some(to) => {
+                                    let capture = offset(sq, (dx,0)).unwrap();
+                                    if g.get_at(capture) == Some((!side,Pawn)) {
+                                        out.push(Move::EnPassant { from: sq, to, capture });
+                                    }
+                                },
+                                _ => {},
+                            }
+                        }
+                    }
+                },
+                _ => {
+                    let tgts = match pc {
+                        Knight => targets(g, sq, &KNIGHT_DIRS, false),
+                        King   => targets(g, sq, &KING_DIRS, false),
+                        Bishop => targets(g, sq, &BISHOP_DIRS, true),
+                        Rook   => targets(g, sq, &ROOK_DIRS, true),
+                        Queen  => {
+                            let mut tgts = targets(g, sq, &BISHOP_DIRS, true);
+                            tgts.extend(targets(g, sq, &ROOK_DIRS, true));
+                            tgts
+                        },
+                        Pawn   => unreachable!(),
+                    };
+                    for to in tgts {
+                        match g.get_at(to) {
+                            None                             => out.push(Move::new_quiet(sq, to, pc)),
+                            Some((col,victim)) if col != side => out.push(Move::new_capture(sq, to, pc, victim)),
+                            _                                => {},
+                        }
+                    }
+                },
+            }
+        }
+
+        /// Castling, the destination square is checked after making the move
+        let (kingside,queenside) = g.state.castling.get_color(side);
+        let y = if side == White { 0 } else { 7 };
+        let king_home = g.get_at(Coord::new(4,y)) == Some((side,King));
+        let in_check = square_attacked(g, Coord::new(4,y), !side);
+        if king_home && !in_check {
+            let empty = |xs: &[u8]| xs.iter().all(|&x| g.get_at(Coord::new(x,y)).is_none());
+            if kingside && g.get_at(Coord::new(7,y)) == Some((side,Rook))
+                && empty(&[5,6]) && !square_attacked(g, Coord::new(5,y), !side) {
+                    out.push(Move::new_castle(side, true));
+                }
+            if queenside && g.get_at(Coord::new(0,y)) == Some((side,Rook))
+                && empty(&[1,2,3]) && !square_attacked(g, Coord::new(3,y), !side) {
+                    out.push(Move::new_castle(side, false));
+                }
+        }
+
+        out
+    }
+
+    /// Make each pseudo-legal move, keep it if the king isn't attacked afterwards
+    pub fn legal_moves(ts: &Tables, g: &Game) -> Vec<Move> {
+        let side = g.state.side_to_move;
+        pseudo_legal_moves(g).into_iter().filter(|&mv| {
+            match g._apply_move_unchecked(ts, mv, false) {
+                Some(g2) => !square_attacked(&g2, g2.get(King,side).bitscan(), !side),
+                None     => false,
+            }
+        }).collect()
+    }
+
+}
+
+/// Random legal placement, side not to move is never in check
+pub fn random_fen(ts: &Tables, rng: &mut StdRng) -> String {
+    loop {
+        let mut board: [Option<(Color,Piece)>; 64] = [None; 64];
+
+        let wk = rng.gen_range(0..64u8);
+        let bk = rng.gen_range(0..64u8);
+        let (wk,bk) = (Coord::new_int(wk), Coord::new_int(bk));
+        if wk.file_dist(bk) <= 1 && wk.rank_dist(bk) <= 1 { continue; }
+        board[wk] = Some((White,King));
+        board[bk] = Some((Black,King));
+
+        let n = rng.gen_range(0..=16);
+        for _ in 0..n {
+            let side = if rng.gen_bool(0.5) { White } else { Black };
+            let pc = *[Pawn,Pawn,Pawn,Knight,Bishop,Rook,Queen].choose(rng).unwrap();
+            let sq = Coord::new_int(rng.gen_range(0..64u8));
+            if board[sq].is_some() { continue; }
+            if pc == Pawn && (sq.rank() == 0 || sq.rank() == 7) { continue; }
+            board[sq] = Some((side,pc));
+        }
+
+        let side = if rng.gen_bool(0.5) { White } else { Black };
+
+        let mut castling = String::new();
+        for (col,y,ks) in [(White,0,"KQ"),(Black,7,"kq")] {
+            if board[Coord::new(4,y)] != Some((col,King)) { continue; }
+            let ks: Vec<char> = ks.chars().collect();
+            if board[Coord::new(7,y)] == Some((col,Rook)) && rng.gen_bool(0.75) { castling.push(ks[0]); }
+            if board[Coord::new(0,y)] == Some((col,Rook)) && rng.gen_bool(0.75) { castling.push(ks[1]); }
+        }
+        if castling.is_empty() { castling.push('-'); }
+
+        let mut fen = String::new();
+        for y in (0..8).rev() {
+            let mut n = 0;
+            for x in 0..8 {
+                match board[Coord::new(x,y)] {
+                    None => n += 1,
+                    Some((col,pc)) => {
+                        if n != 0 { fen.push_str(&format!("{}", n)); }
+                        n = 0;
+                        let c = pc.print_char().to_ascii_lowercase();
+                        fen.push(if col == White { c.to_ascii_uppercase() } else { c });
+                    },
+                }
+            }
+            if n != 0 { fen.push_str(&format!("{}", n)); }
+            if y != 0 { fen.push('/'); }
+        }
+        let s = if side == White { 'w' } else { 'b' };
+        fen.push_str(&format!(" {} {} - 0 1", s, castling));
+
+        /// reject if the side not to move is in check
+        let g = Game::from_fen(ts, &fen).unwrap();
+        let ksq = g.get(King, !side).bitscan();
+        if reference::square_attacked(&g, ksq, side) { continue; }
+
+        return fen;
+    }
+}
+
+/// Compare generate_list_legal with the reference, check zobrist updates,
+/// and round trip through FEN
+pub fn check_position(ts: &Tables, g: &Game) -> Result<usize, FuzzError> {
+
+    let legal     = MoveGen::generate_list_legal(ts, g, None);
+    let reference = reference::legal_moves(ts, g);
+    compare_moves(g, legal.to_vec(), reference)?;
+
+    for &mv in legal.iter() {
+        let zb = g.zobrist.update_move_unchecked(ts, g, mv);
+        if g.make_move_unchecked(ts, mv).map_or(true, |g2| g2.zobrist != zb) {
+            return Err(FuzzError::ZobristUpdate { fen: g.to_fen(), mv });
+        }
+    }
+
+    let fen = g.to_fen();
+    match Game::from_fen_strict(ts, &fen) {
+        Ok(g2) if g2.to_fen() == fen && g2.state.game_equal(g.state) && g2.zobrist == g.zobrist => {},
+        Ok(g2) => return Err(FuzzError::FenRoundTrip { fen, fen2: Ok(g2.to_fen()) }),
+        Err(e) => return Err(FuzzError::FenRoundTrip { fen, fen2: Err(e) }),
+    }
+
+    Ok(legal.len())
+}
+
+/// Compared as sorted lists, so duplicate moves are caught
+pub fn compare_moves(g: &Game, mut legal: Vec<Move>, mut reference: Vec<Move>) -> Result<(), FuzzError> {
+    legal.sort_by_cached_key(|mv| mv.to_long_algebraic());
+    reference.sort_by_cached_key(|mv| mv.to_long_algebraic());
+    if legal == reference { return Ok(()); }
+
+    /// xs - ys, as multisets
+    let diff = |xs: &[Move], ys: &[Move]| {
+        let mut ys = ys.to_vec();
+        let mut out = vec![];
+        for mv in xs.iter() {
+            match ys.iter().position(|m| m == mv) {
+                Some(i) => { ys.swap_remove(i); },
+                None    => out.push(*mv),
+            }
+        }
+        out
+    };
+
+    Err(FuzzError::Movegen {
+        fen:      g.to_fen(),
+        missing:  diff(&reference, &legal),
+        extra:    diff(&legal, &reference),
+    })
+}
+
+/// Play random games, checking every position along the way.
+/// Games start from the perft positions and from random placements.
+pub fn fuzz_movegen(
+    ts:         &Tables,
+    seed:       u64,
+    games:      u64,
+    max_plies:  usize,
+) -> Result<FuzzStats, FuzzError> {
+    let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+    let mut stats = FuzzStats::default();
+
+    for k in 0..games {
+        let fen = if (k as usize) < SEED_FENS.len() {
+            SEED_FENS[k as usize].to_string()
+        } else if rng.gen_bool(0.5) {
+            SEED_FENS.choose(&mut rng).unwrap().to_string()
+        } else {
+            random_fen(ts, &mut rng)
+        };
+        let mut g = Game::from_fen(ts, &fen).unwrap();
+
+        for _ in 0..max_plies {
+            stats.moves += check_position(ts, &g)? as u64;
+            stats.positions += 1;
+
+            let moves = MoveGen::generate_list_legal(ts, &g, None);
+            let mv = match moves.choose(&mut rng) {
+                Some(mv) => *mv,
+                None     => break,
+            };
+            g = match g.make_move_unchecked(ts, mv) {
+                Ok(g2) => g2,
+                Err(_) => break,
+            };
+        }
+        stats.games += 1;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzz_movegen_fixed_seed() {
+        let ts = Tables::new();
+        match fuzz_movegen(&ts, FUZZ_SEED, 50, 100) {
+            Ok(stats) => assert_eq!(stats.games, 50),
+            Err(e)    => panic!("fuzz failed, seed {:#x}: {:?}", FUZZ_SEED, e),
+        }
+    }
+
+    #[test]
+    fn compare_moves_catches_duplicates() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, SEED_FENS[1]).unwrap();
+        let reference = reference::legal_moves(&ts, &g);
+
+        let mut moves = reference.clone();
+        moves.reverse();
+        assert!(compare_moves(&g, moves.clone(), reference.clone()).is_ok());
+
+        moves.push(moves[0]);
+        match compare_moves(&g, moves.clone(), reference.clone()) {
+            Err(FuzzError::Movegen { missing, extra, .. }) => {
+                assert!(missing.is_empty());
+                assert_eq!(extra, vec![moves[0]]);
+            },
+            r => panic!("duplicate not caught: {:?}", r),
+        }
+
+        moves.pop();
+        let mv = moves.pop().unwrap();
+        match compare_moves(&g, moves, reference) {
+            Err(FuzzError::Movegen { missing, extra, .. }) => {
+                assert_eq!(missing, vec![mv]);
+                assert!(extra.is_empty());
+            },
+            r => panic!("missing move not caught: {:?}", r),
+        }
+    }
+
+}
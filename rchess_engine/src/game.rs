@@ -240,6 +240,12 @@ impl Material {
         self.has_piece(Pawn)
     }
 
+    /// KvK, KNvK or KBvK
+    pub fn is_insufficient(&self) -> bool {
+        !self.has_piece(Pawn) && !self.has_piece(Rook) && !self.has_piece(Queen)
+            && self.count_piece(Knight) + self.count_piece(Bishop) <= 1
+    }
+
     pub fn has_piece_side(&self, pc: Piece, side: Color) -> bool {
         self.buf[side][pc.index()] != 0
     }
@@ -813,6 +819,11 @@ impl Game {
         match next.recalc_gameinfo_mut(&ts) {
             Err(win) => Err(win),
             Ok(_)    => {
+                #[cfg(feature = "validate_game")]
+                if let Err(e) = next.validate(ts) {
+                    panic!("validate failed after {:?}: {:?}\n{:?}\n{:?}",
+                           mv, e, self.to_fen(), next.to_fen());
+                }
                 Ok(next)
             },
         }
@@ -862,6 +873,19 @@ impl Game {
         }
     }
 
+    /// From scratch, after the PSQTs in ts have changed
+    pub fn recalc_psqt_mut(&mut self, ts: &Tables) {
+        for side in [White,Black] {
+            let mut psqt = TaperedScore::default();
+            for pc in Piece::iter_pieces() {
+                for sq in self.get(pc, side).into_iter() {
+                    psqt += ts.get_psqt_tapered(pc, side, sq);
+                }
+            }
+            self.psqt_score[side] = psqt;
+        }
+    }
+
     pub fn recalc_gameinfo_mut(&mut self, ts: &Tables) -> GameResult<()> {
 
         let king = self.get(King, self.state.side_to_move);
@@ -1048,6 +1072,21 @@ impl Game {
             _ => {},
         }
 
+        /// Capturing a rook on its starting square removes that castling right
+        if let Move::Capture { to, .. } | Move::PromotionCapture { to, .. } = m {
+            let col = !self.state.side_to_move;
+            let y = if col == White { 0 } else { 7 };
+            let (kingside,queenside) = x.state.castling.get_color(col);
+            let kingside  = kingside && to == Coord::new_const(7,y);
+            let queenside = queenside && to == Coord::new_const(0,y);
+            if kingside || queenside {
+                if calc_zb { x.zobrist = x.zobrist.update_castling(&ts, x.state.castling); }
+                if kingside { x.state.castling.set_king(col,false); }
+                if queenside { x.state.castling.set_queen(col,false); }
+                if calc_zb { x.zobrist = x.zobrist.update_castling(&ts, x.state.castling); }
+            }
+        }
+
     }
 
 }
@@ -1089,9 +1128,6 @@ impl Game {
             self.pawn_zb = self.pawn_zb.update_piece(ts, pc, side, to);
         }
 
-        self.mat_zb = self.mat_zb.update_piece(ts, pc, side, from);
-        self.mat_zb = self.mat_zb.update_piece(ts, pc, side, to);
-
     }
 
     #[cfg(feature = "nope")]
@@ -1103,7 +1139,7 @@ impl Game {
         self._insert_piece_mut_unchecked(&ts, to, pc, side, false, calc_zb);
     }
 
-    fn delete_piece_mut_unchecked(
+    pub fn delete_piece_mut_unchecked(
         &mut self, ts: &Tables, at: Coord, pc: Piece, side: Color, calc_zb: bool) {
 
         let mut bc = self.get_color_mut(side);
@@ -1138,7 +1174,8 @@ impl Game {
         } else {
             self.npm[side] -= pc.score_tapered();
         }
-        self.mat_zb = self.mat_zb.update_piece(ts, pc, side, at);
+        let n = self.get(pc, side).popcount();
+        self.mat_zb = self.mat_zb.update_material(ts, pc, side, n);
     }
 
     pub fn insert_piece_mut_unchecked(
@@ -1167,7 +1204,8 @@ impl Game {
         } else {
             self.npm[side] += pc.score_tapered();
         }
-        self.mat_zb = self.mat_zb.update_piece(ts, pc, side, at);
+        let n = self.get(pc, side).popcount() - 1;
+        self.mat_zb = self.mat_zb.update_material(ts, pc, side, n);
 
     }
 
@@ -1193,7 +1231,8 @@ impl Game {
         if pc == Pawn {
             self.pawn_zb = self.pawn_zb.update_piece(ts, pc, side, at);
         }
-        self.mat_zb = self.mat_zb.update_piece(ts, pc, side, at);
+        let n = self.get(pc, side).popcount() - 1;
+        self.mat_zb = self.mat_zb.update_material(ts, pc, side, n);
 
     }
 
@@ -1758,38 +1797,206 @@ impl Game {
         g.zobrist
     }
 
+    /// Mirror vertically and swap colors, including side to move, castling and en passant.
+    /// Evaluation should be the same from the side to move's perspective
     pub fn flip_sides(&self, ts: &Tables) -> Self {
-        let mut st = self.state.clone();
-        st.side_to_move = !st.side_to_move;
-
-        // let mw = st.white;
-        // let mb = st.black;
-        // st.black = mw;
-        // st.white = mb;
-        // std::mem::swap(st.colors[0],st.colors[1]);
-
-        // st.white   = st.white.rotate_180().mirror_horiz();
-        // st.black   = st.black.rotate_180().mirror_horiz();
-        // st.pawns   = st.pawns.rotate_180().mirror_horiz();
-        // st.rooks   = st.rooks.rotate_180().mirror_horiz();
-        // st.knights = st.knights.rotate_180().mirror_horiz();
-        // st.bishops = st.bishops.rotate_180().mirror_horiz();
-        // st.queens  = st.queens.rotate_180().mirror_horiz();
-        // st.kings   = st.kings.rotate_180().mirror_horiz();
-
-        st.castling = st.castling.mirror_sides();
-
+        let ep = self.state.en_passant.map(|ep| ep.flip_vertical());
+        self._rebuild(
+            ts,
+            |sq,side| (sq.flip_vertical(), !side),
+            !self.state.side_to_move,
+            self.state.castling.mirror_sides(),
+            ep,
+        )
+    }
+
+    /// Mirror horizontally, None if any castling rights are left
+    pub fn mirror_horizontal(&self, ts: &Tables) -> Option<Self> {
+        if self.state.castling.any() { return None; }
+        let ep = self.state.en_passant.map(|ep| ep.flip_horizontal());
+        Some(self._rebuild(
+            ts,
+            |sq,side| (sq.flip_horizontal(), side),
+            self.state.side_to_move,
+            self.state.castling,
+            ep,
+        ))
+    }
+
+    fn _rebuild<F: Fn(Coord,Color) -> (Coord,Color)>(
+        &self,
+        ts:         &Tables,
+        f:          F,
+        side:       Color,
+        castling:   Castling,
+        ep:         Option<Coord>,
+    ) -> Self {
+        let mut pieces = vec![];
+        for col in [White,Black] {
+            for pc in Piece::iter_pieces() {
+                for sq in self.get(pc, col).into_iter() {
+                    let (sq2,col2) = f(sq,col);
+                    pieces.push((sq2,pc,col2));
+                }
+            }
+        }
+        Self::from_parts(ts, &pieces, side, castling, ep, self.halfmove).unwrap()
+    }
+
+    /// Position from a list of pieces, without checking legality.
+    /// Each side must have a king
+    pub fn from_parts(
+        ts:         &Tables,
+        pieces:     &[(Coord,Piece,Color)],
+        side:       Color,
+        castling:   Castling,
+        ep:         Option<Coord>,
+        halfmove:   Depth,
+    ) -> GameResult<Self> {
         let mut out = Game::default();
 
-        out.state = st;
+        for &(sq,pc,col) in pieces.iter() {
+            out.insert_piece_mut_unchecked_nohash(ts, sq, pc, col);
+        }
+
+        out.state.side_to_move = side;
+        out.state.castling     = castling;
+        out.state.en_passant   = ep;
+        out.halfmove           = halfmove;
+
+        out.recalc_gameinfo_mut(ts)?;
+        out.init_gameinfo_mut(ts)?;
+
         out.zobrist = Zobrist::new(&ts, &out);
         out.pawn_zb = Zobrist::new_pawns(&ts, &out);
 
-        out.init_gameinfo_mut(ts).unwrap();
-        out.recalc_gameinfo_mut(ts).unwrap();
+        Ok(out)
+    }
 
-        // out
-        unimplemented!()
+}
+
+/// Incrementally updated field that didn't match a full recalculation
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum GameInvalid {
+    Zobrist       { expected: Zobrist, found: Zobrist },
+    PawnZb        { expected: Zobrist, found: Zobrist },
+    MatZb         { expected: Zobrist, found: Zobrist },
+    Material      { expected: Material, found: Material },
+    Npm           { side: Color, expected: TaperedScore, found: TaperedScore },
+    Psqt          { side: Color, expected: TaperedScore, found: TaperedScore },
+    Pieces        { sq: Coord, expected: Option<Piece>, found: Option<Piece> },
+    Checkers      { expected: BitBoard, found: BitBoard },
+    InCheck       { expected: bool, found: bool },
+    Pins          { side: Color, expected: BitBoard, found: BitBoard },
+    CheckSquares  { pc: Piece, expected: BitBoard, found: BitBoard },
+    /// Squares set in more than one color or piece bitboard, or in a color but no piece
+    Overlap(BitBoard),
+    MissingKing(Color),
+}
+
+/// Validation
+impl Game {
+
+    /// Recompute everything that make_move updates incrementally, and compare.
+    /// Slow, only for debugging and perft validation.
+    pub fn validate(&self, ts: &Tables) -> Result<(), GameInvalid> {
+
+        let mut seen    = BitBoard::empty();
+        let mut overlap = self.get_color(White) & self.get_color(Black);
+        for pc in Piece::iter_pieces() {
+            overlap |= seen & self.get_piece(pc);
+            seen    |= self.get_piece(pc);
+        }
+        overlap |= seen ^ (self.get_color(White) | self.get_color(Black));
+        if overlap.is_not_empty() {
+            return Err(GameInvalid::Overlap(overlap));
+        }
+
+        for side in [White,Black] {
+            if self.get(King, side).popcount() != 1 {
+                return Err(GameInvalid::MissingKing(side));
+            }
+        }
+
+        let zb = Zobrist::new(ts, self);
+        if zb != self.zobrist {
+            return Err(GameInvalid::Zobrist { expected: zb, found: self.zobrist });
+        }
+
+        let zb = Zobrist::new_pawns(ts, self);
+        if zb != self.pawn_zb {
+            return Err(GameInvalid::PawnZb { expected: zb, found: self.pawn_zb });
+        }
+
+        let zb = Zobrist::new_material(ts, self);
+        if zb != self.mat_zb {
+            return Err(GameInvalid::MatZb { expected: zb, found: self.mat_zb });
+        }
+
+        /// King count is only set by init_gameinfo_mut, ignore it
+        let mut mat = self.count_material();
+        mat.buf[White][King.index()] = self.state.material.buf[White][King.index()];
+        mat.buf[Black][King.index()] = self.state.material.buf[Black][King.index()];
+        if mat != self.state.material {
+            return Err(GameInvalid::Material { expected: mat, found: self.state.material });
+        }
+
+        for sq in 0..64u8 {
+            let sq = Coord::new_int(sq);
+            /// From the bitboards, get_at reads self.pieces
+            let expected = Piece::iter_pieces().find(|&pc| self.get_piece(pc).is_one_at(sq));
+            if expected != self.pieces[sq] {
+                return Err(GameInvalid::Pieces { sq, expected, found: self.pieces[sq] });
+            }
+        }
+
+        for side in [White,Black] {
+            let npm = self.count_npm(side);
+            if npm != self.npm[side] {
+                return Err(GameInvalid::Npm { side, expected: npm, found: self.npm[side] });
+            }
+
+            let mut psqt = TaperedScore::default();
+            for pc in Piece::iter_pieces() {
+                for sq in self.get(pc, side).into_iter() {
+                    psqt += ts.get_psqt_tapered(pc, side, sq);
+                }
+            }
+            if psqt != self.psqt_score[side] {
+                return Err(GameInvalid::Psqt { side, expected: psqt, found: self.psqt_score[side] });
+            }
+        }
+
+        let checkers = self.find_checkers(ts);
+        if checkers != self.state.checkers {
+            return Err(GameInvalid::Checkers { expected: checkers, found: self.state.checkers });
+        }
+        if checkers.is_not_empty() != self.state.in_check {
+            return Err(GameInvalid::InCheck { expected: !self.state.in_check, found: self.state.in_check });
+        }
+
+        let pins_w = self.find_slider_blockers(ts, self.get(King,White).bitscan(), White);
+        if pins_w != self.state.king_blocks_w {
+            return Err(GameInvalid::Pins { side: White, expected: pins_w, found: self.state.king_blocks_w });
+        }
+        let pins_b = self.find_slider_blockers(ts, self.get(King,Black).bitscan(), Black);
+        if pins_b != self.state.king_blocks_b {
+            return Err(GameInvalid::Pins { side: Black, expected: pins_b, found: self.state.king_blocks_b });
+        }
+
+        let mut x = *self;
+        x.update_check_squares_mut(ts);
+        for pc in [Pawn,Knight,Bishop,Rook,Queen] {
+            if x.state.check_squares[pc] != self.state.check_squares[pc] {
+                return Err(GameInvalid::CheckSquares {
+                    pc,
+                    expected: x.state.check_squares[pc],
+                    found:    self.state.check_squares[pc],
+                });
+            }
+        }
+
+        Ok(())
     }
 
 }
@@ -2089,3 +2296,85 @@ impl std::fmt::Debug for Game {
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::movegen::MoveGen;
+
+    /// Perft positions 2-5, https://www.chessprogramming.org/Perft_Results
+    const FENS: [&str; 5] = [
+        STARTPOS,
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    ];
+
+    #[test]
+    fn validate_perft_positions() {
+        let ts = Tables::new();
+        let counts: [u64; 5] = [400, 2039, 191, 264, 1486];
+        for (fen,count) in FENS.iter().zip(counts) {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            assert_eq!(g.validate(&ts), Ok(()), "{}", fen);
+            match MoveGen::perft_validate(&ts, &g, 2) {
+                Ok(n)          => assert_eq!(n, count, "{}", fen),
+                Err((g2,mv,e)) => panic!("{}: invalid after {:?}: {:?}", g2.to_fen(), mv, e),
+            }
+            // make_move is copy-make, the original must be untouched
+            assert_eq!(g.validate(&ts), Ok(()), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_corrupted() {
+        let ts = Tables::new();
+        let g0 = Game::from_fen(&ts, FENS[1]).unwrap();
+
+        let mut g = g0;
+        let bp = g.get(Pawn, Black);
+        *g.get_color_mut(White) |= bp;
+        assert_eq!(g.validate(&ts), Err(GameInvalid::Overlap(bp)));
+
+        let mut g = g0;
+        let wn = g.get(Knight, White);
+        *g.get_piece_mut(Bishop) |= wn;
+        assert_eq!(g.validate(&ts), Err(GameInvalid::Overlap(wn)));
+
+        let mut g = g0;
+        let sq = BitBoard::single(Coord::new(3, 3));
+        *g.get_piece_mut(Rook) |= sq;
+        assert_eq!(g.validate(&ts), Err(GameInvalid::Overlap(sq)));
+
+        let mut g = g0;
+        let wk = g.get(King, White);
+        *g.get_piece_mut(King) &= !wk;
+        *g.get_color_mut(White) &= !wk;
+        assert_eq!(g.validate(&ts), Err(GameInvalid::MissingKing(White)));
+
+        let mut g = g0;
+        g.zobrist = Zobrist(g.zobrist.0 ^ 1);
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::Zobrist { .. })));
+
+        let mut g = g0;
+        g.pawn_zb = Zobrist(g.pawn_zb.0 ^ 1);
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::PawnZb { .. })));
+
+        let mut g = g0;
+        g.mat_zb = Zobrist(g.mat_zb.0 ^ 1);
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::MatZb { .. })));
+
+        let mut g = g0;
+        g.psqt_score[Black].end += 1;
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::Psqt { side: Black, .. })));
+
+        let mut g = g0;
+        g.pieces[Coord::new(0, 0)] = Some(Queen);
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::Pieces { .. })));
+
+        let mut g = g0;
+        g.state.in_check = true;
+        assert!(matches!(g.validate(&ts), Err(GameInvalid::InCheck { .. })));
+    }
+
+}
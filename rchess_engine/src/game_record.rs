@@ -0,0 +1,362 @@
+
+use crate::types::*;
+use crate::tables::*;
+use crate::movegen::MoveGen;
+use crate::parsing::FenError;
+
+/// Full game, from a start position.
+/// The position after each move is hashed into history, for repetitions
+#[derive(Debug,Clone)]
+pub struct GameRecord {
+    start:      Game,
+    current:    Game,
+    moves:      Vec<Move>,
+    /// history[0] is the start position, history[n] is after moves[n-1]
+    history:    Vec<Zobrist>,
+    /// same as history, without en passant squares that can't be captured
+    rep_keys:   Vec<Zobrist>,
+}
+
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum DrawReason {
+    Stalemate,
+    /// Claimable
+    ThreefoldRepetition,
+    FivefoldRepetition,
+    /// Claimable
+    FiftyMoves,
+    SeventyFiveMoves,
+    InsufficientMaterial,
+}
+
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum GameOver {
+    Checkmate { win: Color },
+    Draw(DrawReason),
+}
+
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub enum GameRecordError {
+    Fen(FenError),
+    /// couldn't parse a UCI move
+    BadMove(String),
+    IllegalMove(Move),
+    /// no moves after the game is over
+    GameOver(GameOver),
+}
+
+impl std::fmt::Display for GameRecordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fen(e)          => write!(f, "bad fen, {}", e),
+            Self::BadMove(s)      => write!(f, "bad move '{}'", s),
+            Self::IllegalMove(mv) => write!(f, "illegal move {:?}", mv),
+            Self::GameOver(end)   => write!(f, "game is over, {:?}", end),
+        }
+    }
+}
+
+impl std::error::Error for GameRecordError {}
+
+impl GameOver {
+    pub fn winner(&self) -> Option<Color> {
+        match self {
+            Self::Checkmate { win } => Some(*win),
+            Self::Draw(_)           => None,
+        }
+    }
+}
+
+/// New
+impl GameRecord {
+
+    pub fn new(ts: &Tables, g: Game) -> Self {
+        Self {
+            start:    g,
+            current:  g,
+            moves:    vec![],
+            history:  vec![g.zobrist],
+            rep_keys: vec![repetition_key(ts, &g)],
+        }
+    }
+
+    pub fn from_fen(ts: &Tables, fen: &str) -> Result<Self, GameRecordError> {
+        let g = Game::from_fen_strict(ts, fen).map_err(GameRecordError::Fen)?;
+        Ok(Self::new(ts, g))
+    }
+
+    /// e.g. from "position fen ... moves e2e4 e7e5"
+    pub fn from_uci_moves<'a>(
+        ts:      &Tables,
+        g:       Game,
+        moves:   impl Iterator<Item = &'a str>,
+    ) -> Result<Self, GameRecordError> {
+        let mut out = Self::new(ts, g);
+        for mv in moves.filter(|s| !s.is_empty()) {
+            out.push_uci(ts, mv)?;
+        }
+        Ok(out)
+    }
+
+}
+
+/// Moves
+impl GameRecord {
+
+    /// Errors if the move is illegal, or the game is already over.
+    /// Claimable draws don't end the game
+    pub fn push(&mut self, ts: &Tables, mv: Move) -> Result<(), GameRecordError> {
+        if let Some(end) = self.game_over(ts) {
+            return Err(GameRecordError::GameOver(end));
+        }
+        if !MoveGen::generate_list_legal(ts, &self.current, None).contains(&mv) {
+            return Err(GameRecordError::IllegalMove(mv));
+        }
+        let g2 = self.current.make_move_unchecked(ts, mv)
+            .map_err(|_| GameRecordError::IllegalMove(mv))?;
+        self.current = g2;
+        self.moves.push(mv);
+        self.history.push(g2.zobrist);
+        self.rep_keys.push(repetition_key(ts, &g2));
+        Ok(())
+    }
+
+    pub fn push_uci(&mut self, ts: &Tables, s: &str) -> Result<(), GameRecordError> {
+        let mv = self.parse_uci(s).ok_or_else(|| GameRecordError::BadMove(s.to_string()))?;
+        self.push(ts, mv)
+    }
+
+    fn parse_uci(&self, s: &str) -> Option<Move> {
+        let cs = s.as_bytes();
+        let sq = |f: u8, r: u8| (b'a'..=b'h').contains(&f) && (b'1'..=b'8').contains(&r);
+        if !(4..=5).contains(&cs.len()) || !sq(cs[0],cs[1]) || !sq(cs[2],cs[3]) {
+            return None;
+        }
+        /// convert_move panics on these
+        let (from,to): (Coord,Coord) = (s[0..2].into(), s[2..4].into());
+        match (self.current.get_at(from), self.current.get_at(to)) {
+            (None,_)                                  => return None,
+            (Some((c0,_)),Some((c1,_))) if c0 == c1   => return None,
+            (Some((col,Pawn)),_) if to.rank() == col.fold(7,0)
+                && !["q","r","b","n"].contains(&&s[4..]) => return None,
+            _                                         => {},
+        }
+        self.current.convert_move(&s[0..2], &s[2..4], &s[4..])
+    }
+
+    /// Undo the last move, replays from the start position
+    pub fn pop(&mut self, ts: &Tables) -> Option<Move> {
+        let mv = self.moves.pop()?;
+        self.history.pop();
+        self.rep_keys.pop();
+        let mut g = self.start;
+        for &mv in self.moves.iter() {
+            g = g.make_move_unchecked(ts, mv).unwrap();
+        }
+        self.current = g;
+        Some(mv)
+    }
+
+}
+
+/// Queries
+impl GameRecord {
+
+    pub fn start(&self) -> &Game { &self.start }
+    pub fn current(&self) -> &Game { &self.current }
+    pub fn moves(&self) -> &[Move] { &self.moves }
+    pub fn history(&self) -> &[Zobrist] { &self.history }
+
+    /// (zobrist after move, move), as used by Explorer and ABStack
+    pub fn move_history(&self) -> Vec<(Zobrist, Move)> {
+        self.history[1..].iter().cloned().zip(self.moves.iter().cloned()).collect()
+    }
+
+    /// Times the current position has occurred, including now.
+    /// Only positions since the last irreversible move can repeat
+    pub fn repetitions(&self) -> usize {
+        let zb = self.rep_keys[self.rep_keys.len() - 1];
+        let n = (self.current.halfmove.max(0) as usize).min(self.rep_keys.len() - 1);
+        self.rep_keys.iter()
+            .rev()
+            .take(n + 1)
+            .step_by(2)
+            .filter(|&&zb2| zb2 == zb)
+            .count()
+    }
+
+    /// Game over without any claim: checkmate, stalemate,
+    /// fivefold repetition, 75 move rule, or insufficient material
+    pub fn game_over(&self, ts: &Tables) -> Option<GameOver> {
+        let g = &self.current;
+
+        /// Mate on the last move takes precedence over the 75 move rule
+        if MoveGen::generate_list_legal(ts, g, None).is_empty() {
+            if g.state.in_check {
+                return Some(GameOver::Checkmate { win: !g.state.side_to_move });
+            } else {
+                return Some(GameOver::Draw(DrawReason::Stalemate));
+            }
+        }
+
+        if self.repetitions() >= 5 {
+            Some(GameOver::Draw(DrawReason::FivefoldRepetition))
+        } else if g.halfmove >= 150 {
+            Some(GameOver::Draw(DrawReason::SeventyFiveMoves))
+        } else if insufficient_material(g) {
+            Some(GameOver::Draw(DrawReason::InsufficientMaterial))
+        } else {
+            None
+        }
+    }
+
+    /// Draw that either side may claim: threefold repetition or 50 move rule
+    pub fn claimable_draw(&self) -> Option<DrawReason> {
+        if self.repetitions() >= 3 {
+            Some(DrawReason::ThreefoldRepetition)
+        } else if self.current.halfmove >= 100 {
+            Some(DrawReason::FiftyMoves)
+        } else {
+            None
+        }
+    }
+
+    /// For adjudicating engine games, where draws are always claimed
+    pub fn adjudicate(&self, ts: &Tables) -> Option<GameOver> {
+        self.game_over(ts).or_else(|| self.claimable_draw().map(GameOver::Draw))
+    }
+
+}
+
+/// Zobrist, but en passant is only part of the position if a capture is legal
+fn repetition_key(ts: &Tables, g: &Game) -> Zobrist {
+    match g.state.en_passant {
+        Some(ep) if !MoveGen::generate_list_legal(ts, g, None).iter()
+                      .any(|mv| matches!(mv, Move::EnPassant { .. })) => g.zobrist.update_ep(ts, ep),
+        _ => g.zobrist,
+    }
+}
+
+/// Neither side can mate.
+/// From Material: KvK, KNvK, KBvK.
+/// Also any number of bishops all on the same color, which needs the board
+pub fn insufficient_material(g: &Game) -> bool {
+    if g.state.material.is_insufficient() {
+        return true;
+    }
+
+    let m = &g.state.material;
+    let others = [Pawn,Knight,Rook,Queen].iter().any(|&pc| m.has_piece(pc));
+    if others { return false; }
+
+    let bishops = g.get_piece(Bishop);
+    (bishops & LIGHT_SQUARES).is_empty() || (bishops & DARK_SQUARES).is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ts: &Tables, fen: &str, moves: &str) -> GameRecord {
+        let g = Game::from_fen(ts, fen).unwrap();
+        GameRecord::from_uci_moves(ts, g, moves.split(' ')).unwrap()
+    }
+
+    #[test]
+    fn checkmate_stalemate() {
+        let ts = Tables::new();
+        /// fool's mate
+        let r = record(&ts, STARTPOS, "f2f3 e7e5 g2g4 d8h4");
+        assert_eq!(r.game_over(&ts), Some(GameOver::Checkmate { win: Black }));
+        let mut r2 = r.clone();
+        assert!(r2.push_uci(&ts, "e1f2").is_err());
+        assert_eq!(r2.pop(&ts), Some(r.moves()[3]));
+        assert_eq!(r2.current().zobrist, r2.history()[3]);
+        assert_eq!(r2.game_over(&ts), None);
+
+        let r = record(&ts, "7k/5Q2/6K1/8/8/8/8/8 w - - 0 1", "f7f8");
+        assert_eq!(r.game_over(&ts), Some(GameOver::Checkmate { win: White }));
+        let r = record(&ts, "7k/8/5QK1/8/8/8/8/8 w - - 0 1", "f6f7");
+        assert_eq!(r.game_over(&ts), Some(GameOver::Draw(DrawReason::Stalemate)));
+    }
+
+    #[test]
+    fn repetitions() {
+        let ts = Tables::new();
+        /// knight shuffles, the start position occurs again every 4 plies
+        let shuffle = "g1f3 g8f6 f3g1 f6g8";
+        let r = record(&ts, STARTPOS, &[shuffle; 2].join(" "));
+        assert_eq!(r.repetitions(), 3);
+        assert_eq!(r.claimable_draw(), Some(DrawReason::ThreefoldRepetition));
+        assert_eq!(r.game_over(&ts), None);
+        let r = record(&ts, STARTPOS, &[shuffle; 4].join(" "));
+        assert_eq!(r.repetitions(), 5);
+        assert_eq!(r.game_over(&ts), Some(GameOver::Draw(DrawReason::FivefoldRepetition)));
+        assert!(r.clone().push_uci(&ts, "g1f3").is_err());
+
+        /// a pawn move in between resets the count.
+        /// e6 can't be captured, so is ignored after e7e5
+        let r = record(&ts, STARTPOS, &format!("{} e2e4 e7e5 {}", shuffle, shuffle));
+        assert_eq!(r.repetitions(), 2);
+        assert_eq!(r.claimable_draw(), None);
+    }
+
+    #[test]
+    fn move_rules() {
+        let ts = Tables::new();
+        let fen = "4k3/8/8/8/8/8/4P3/R3K3 w - - 99 80";
+        assert_eq!(record(&ts, fen, "a1a2").claimable_draw(), Some(DrawReason::FiftyMoves));
+        assert_eq!(record(&ts, fen, "e2e3").claimable_draw(), None);
+        let fen = "4k3/8/8/8/8/8/4P3/R3K3 w - - 149 100";
+        assert_eq!(record(&ts, fen, "a1a2").game_over(&ts),
+                   Some(GameOver::Draw(DrawReason::SeventyFiveMoves)));
+        /// mate on the 150th ply stands
+        let fen = "6k1/5ppp/8/8/8/8/8/R5K1 w - - 149 100";
+        assert_eq!(record(&ts, fen, "a1a8").game_over(&ts), Some(GameOver::Checkmate { win: White }));
+    }
+
+    #[test]
+    fn insufficient_material() {
+        let ts = Tables::new();
+        for (fen,draw) in [
+            ("4k3/8/8/8/8/8/8/4K3 w - - 0 1", true),
+            ("4k3/8/8/8/8/8/8/4KN2 w - - 0 1", true),
+            ("4k3/8/8/8/8/8/8/4KB2 w - - 0 1", true),
+            ("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1", true),
+            ("4k3/8/8/8/8/8/8/2B1KB2 w - - 0 1", false),
+            ("4k3/8/8/8/8/8/8/3BKB2 w - - 0 1", true),
+            ("4kb2/8/8/8/8/8/8/3BK3 w - - 0 1", false),
+            ("4kn2/8/8/8/8/8/8/4KN2 w - - 0 1", false),
+            ("4k3/8/8/8/8/8/8/4KNN1 w - - 0 1", false),
+            ("4k3/8/8/8/8/8/8/4KR2 w - - 0 1", false),
+            ("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", false),
+        ] {
+            let r = record(&ts, fen, "");
+            let expected = if draw { Some(GameOver::Draw(DrawReason::InsufficientMaterial)) } else { None };
+            assert_eq!(r.game_over(&ts), expected, "{}", fen);
+        }
+
+        /// capture down to KvK
+        let r = record(&ts, "4k3/8/8/8/8/8/3r4/4K3 w - - 0 1", "e1d2");
+        assert_eq!(r.game_over(&ts), Some(GameOver::Draw(DrawReason::InsufficientMaterial)));
+    }
+
+    #[test]
+    fn bad_moves() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        assert!(matches!(GameRecord::from_uci_moves(&ts, g, "e2e5".split(' ')),
+                         Err(GameRecordError::IllegalMove(_))));
+        assert!(matches!(GameRecord::from_uci_moves(&ts, g, "e2".split(' ')),
+                         Err(GameRecordError::BadMove(_))));
+        for moves in ["e2e4 e2e4", "e1e2", "e2e4 d7d5 d1d2", "a2a8"] {
+            assert!(matches!(GameRecord::from_uci_moves(&ts, g, moves.split(' ')),
+                             Err(GameRecordError::BadMove(_))), "{}", moves);
+        }
+        let g = Game::from_fen(&ts, "4k3/P7/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        assert!(matches!(GameRecord::from_uci_moves(&ts, g, "a7a8".split(' ')),
+                         Err(GameRecordError::BadMove(_))));
+        assert!(GameRecord::from_uci_moves(&ts, g, "a7a8q".split(' ')).is_ok());
+    }
+
+}
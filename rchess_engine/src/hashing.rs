@@ -45,6 +45,35 @@ impl Zobrist {
         Zobrist(out)
     }
 
+    /// Only depends on the number of each piece, not where they are
+    pub fn new_material(ts: &Tables, g: &Game) -> Self {
+        let mut out = Zobrist(0);
+
+        for &side in [White,Black].iter() {
+            for pc in Piece::iter_pieces() {
+                for n in 0..g.get(pc, side).popcount() {
+                    out = out.update_material(ts, pc, side, n);
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Material key from piece counts, kings included
+    pub fn from_material(ts: &Tables, mat: &Material) -> Self {
+        let mut out = Zobrist(0);
+        for &side in [White,Black].iter() {
+            out = out.update_material(ts, King, side, 0);
+            for pc in Piece::iter_nonking_pieces() {
+                for n in 0..mat.get(pc, side) {
+                    out = out.update_material(ts, pc, side, n);
+                }
+            }
+        }
+        out
+    }
+
     pub fn new(ts: &Tables, g: &Game) -> Self {
         let mut out = 0u64;
         let zb = &ts.zobrist_tables;
@@ -124,14 +153,15 @@ impl Zobrist {
                 // .update_piece(ts, victim, !g.state.side_to_move, to)
                 // .update_piece(ts, new_piece, g.state.side_to_move, to),
                 .update_piece(ts, pcs.second(), !g.state.side_to_move, to)
-                .update_piece(ts, pcs.first(), g.state.side_to_move, to),
+                .update_piece(ts, pcs.first(), g.state.side_to_move, to)
+                .update_move_castles(ts, g, mv),
             Move::NullMove                          => self,
         }
     }
 
     fn update_move_castles(mut self, ts: &Tables, g: &Game, mv: Move) -> Self {
         let mut castling = g.state.castling.clone();
-        match mv {
+        self = match mv {
             // Move::Quiet { from, pc, .. } | Move::Capture { from, pc, .. } => {
             Move::Quiet { from, .. } | Move::Capture { from, .. } => {
                 let pc = mv.piece().unwrap();
@@ -165,7 +195,24 @@ impl Zobrist {
                 self.update_castling(ts, castling)
             },
             _ => self
+        };
+
+        /// Capturing a rook on its starting square
+        if let Move::Capture { to, .. } | Move::PromotionCapture { to, .. } = mv {
+            let col = !g.state.side_to_move;
+            let y = if col == White { 0 } else { 7 };
+            let (kingside,queenside) = castling.get_color(col);
+            let kingside  = kingside && to == Coord::new_const(7,y);
+            let queenside = queenside && to == Coord::new_const(0,y);
+            if kingside || queenside {
+                self = self.update_castling(ts, castling);
+                if kingside { castling.set_king(col,false); }
+                if queenside { castling.set_queen(col,false); }
+                self = self.update_castling(ts, castling);
+            }
         }
+
+        self
     }
 
     #[must_use]
@@ -202,6 +249,13 @@ impl Zobrist {
         Self(out)
     }
 
+    /// For the material key, n is the number of other pieces of the same type
+    pub fn update_material(&self, ts: &Tables, pc: Piece, col: Color, n: u8) -> Self {
+        let mut out = self.0;
+        out ^= ts.zobrist_tables.pieces[col][pc][n as usize];
+        Self(out)
+    }
+
 }
 
 #[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy)]
@@ -59,21 +59,26 @@ pub mod coords;
 pub mod tables;
 pub mod magics;
 pub mod game;
+pub mod game_record;
 pub mod parsing;
 
 pub mod see;
 pub mod qsearch;
 // pub mod search;
 pub mod movegen;
+pub mod fuzz;
+pub mod symmetry;
 pub mod explore;
 pub mod alphabeta;
 pub mod evaluate;
+pub mod eval_trace;
 pub mod timer;
 pub mod tuning;
 pub mod hashing;
 pub mod trans_table;
 pub mod stack;
 pub mod endgame;
+pub mod bitbase;
 pub mod options;
 
 // pub mod threading;
@@ -94,9 +99,9 @@ pub mod evmap_tables;
 pub mod cuckoo;
 
 
-// pub mod brain;
+pub mod brain;
 // pub mod pgn;
-// pub mod texel;
+pub mod texel;
 
 pub mod sf_compat;
 
@@ -108,6 +113,7 @@ pub mod move_ordering;
 pub mod heuristics;
 
 pub mod lockless_map;
+pub mod eval_cache;
 
 pub mod prefetch;
 
@@ -78,18 +78,15 @@ fn main() {
         "sample"    => main_sample(),
         "endgame"   => main_endgame(),
         // "nn"        => main_nn(),
-        "nnue"      => main_nnue3(),
-        // "train"     => main_nnue_train(),
+        "nnue"      => main_nnue(&args[2..]),
+        "train"     => main_train(&args[2..]),
         // "simd"      => main_simd(),
-        // "tuning"    => main_tuning(),
+        "texel"     => main_texel(&args[2..]),
+        "params"    => main_params(&args[2..]),
         "eval"      => main_eval(),
-        "gensfen"   => {
-            let count = u64::from_str(&args[2]).unwrap();
-            let time = f64::from_str(&args[3]).unwrap();
-            let path = &args[4];
-
-            main_gensfen(count, path);
-        },
+        "gensfen"   => main_gensfen(&args[2..]),
+        "filter"    => main_filter(&args[2..]),
+        "binpack"   => main_binpack(args.get(2).map(|x| x.as_str()), args.get(3).map(|x| x.as_str())),
         "wac"       => match args.get(2).map(|x| u64::from_str(x).ok()) {
             Some(n) => main_wac(n, false),
             _       => main_wac(None, false),
@@ -98,6 +95,17 @@ fn main() {
             Some(n) => main_perft(n),
             _       => main_perft(None),
         },
+        "fuzz"      => {
+            let games = args.get(2).and_then(|x| u64::from_str(x).ok());
+            let seed  = args.get(3).and_then(|x| u64::from_str(x).ok());
+            main_fuzz(games, seed);
+        },
+        "validate"  => match args.get(2).map(|x| u64::from_str(x).ok()) {
+            Some(n) => main_validate(n),
+            _       => main_validate(None),
+        },
+        "symmetry"  => main_symmetry(args.get(2).map(|x| x.as_str()), args.get(3).map(|x| x.as_str())),
+        "simd"      => main_simd(args.get(2).map(|x| x.as_str()), args.get(3).map(|x| x.as_str())),
         _           => main9(),
     }
 
@@ -1512,181 +1520,622 @@ fn main_mnist() {
     return;
 }
 
-#[allow(unreachable_code)]
-#[cfg(feature = "nope")]
-fn main_tuning() {
+/// texel <out.bin> <labeled.epd>... [--init evparams.bin] [--count n] [--loops n] [--k x]
+///     [--step n] [--threads n]
+/// Rerun with --init set to the output to continue
+fn main_texel(args: &[String]) {
     use rchess_engine_lib::texel::*;
-    use rchess_engine_lib::qsearch::*;
-    use rchess_engine_lib::pawn_hash_table::*;
-    use rchess_engine_lib::pgn::*;
-
-    // let ts = Tables::read_from_file_def().unwrap();
-    // let ts = &_TABLES;
-    let ts = Tables::new();
-
-    let ob = OpeningBook::read_from_file(&ts, "tables/Perfect_2021/BIN/Perfect2021.bin").unwrap();
-
-    // let path = "/home/me/code/rust/rchess/training_data/test_5.bin";
-    // let path = "/home/me/code/rust/rchess/training_data/set1/depth5_games500_4.bin";
-    // let path = "/home/me/code/rust/rchess/training_data/depth5_games100_1.bin";
-    // let path = "/home/me/code/rust/rchess/training_data/depth5_test_1.bin";
-    // let path = "/home/me/code/rust/rchess/training_data/set2/depth5_games500_1.bin";
-
-    let evpath = "/home/me/code/rust/rchess/evparams.bin";
-
-    if !true {
+    use rchess_engine_lib::material::*;
 
-        // let mut ev = EvalParams::default();
-        // ev.psqt.print_table(Rook).unwrap();
-        // println!();
+    if args.len() < 2 {
+        eprintln!("usage: texel <out.bin|out.json> <labeled.epd>... [--init evparams.bin|params.json] [--count n] [--loops n] [--k x] [--step n] [--threads n]\n       [--method local|adam] [--epochs n] [--lr x]");
+        return;
+    }
+    let out = &args[0];
 
-        let (ev_mid,ev_end) = EvalParams::read_evparams(evpath).unwrap();
+    let ts = Tables::new();
 
-        for pc in Piece::iter_pieces() {
-            eprintln!("{:?}", pc);
-            // ev_mid.psqt.print_table(pc).unwrap();
-            ev_end.psqt.print_table(pc).unwrap();
-            println!();
+    let mut tuner = TexelTuner::new()
+        .num_threads(num_cpus::get_physical());
+    let mut paths = vec![];
+    let mut init  = None;
+    let mut count = None;
+    let mut adam  = false;
+
+    let mut xs = args[1..].iter();
+    while let Some(arg) = xs.next() {
+        if !arg.starts_with("--") {
+            paths.push(arg.clone());
+            continue;
         }
-        // ev_mid.psqt.print_table(Rook).unwrap();
+        let val = match xs.next() {
+            Some(val) => val.as_str(),
+            None      => panic!("texel: missing value for {}", arg),
+        };
+        let n = || u64::from_str(val).unwrap_or_else(|_| panic!("texel: bad number for {}: {}", arg, val));
+        match arg.as_str() {
+            "--init"    => init = Some(val.to_string()),
+            "--count"   => count = Some(n() as usize),
+            "--loops"   => tuner = tuner.max_loops(Some(n() as usize)),
+            "--k"       => tuner = tuner.k(Some(f64::from_str(val)
+                                             .unwrap_or_else(|_| panic!("texel: bad k: {}", val)))),
+            "--step"    => tuner = tuner.step(n() as Score),
+            "--threads" => tuner = tuner.num_threads(n() as usize),
+            "--method"  => adam = match val {
+                "local" => false,
+                "adam"  => true,
+                _       => panic!("texel: unknown method {}", val),
+            },
+            "--epochs"  => tuner = tuner.epochs(n() as usize),
+            "--lr"      => tuner = tuner.learning_rate(f64::from_str(val)
+                                             .unwrap_or_else(|_| panic!("texel: bad lr: {}", val))),
+            _           => panic!("texel: unknown flag {}", arg),
+        }
+    }
 
-        // eprintln!("ev == ev_mid = {:?}", ev == ev_mid);
+    let mut ps = vec![];
+    for path in paths.iter() {
+        let left = count.map(|c: usize| c - ps.len());
+        ps.extend(load_labeled_fens(&ts, left, path).unwrap());
+        if count.map_or(false, |c| ps.len() >= c) { break; }
+    }
+    eprintln!("loaded {} positions", ps.len());
+
+    let g = Game::from_fen(&ts, STARTPOS).unwrap();
+    let ex = Explorer::new(g.state.side_to_move, g.clone(), 1, TimeSettings::new_f64(0.0,1.0));
+    let thread_data = PerThreadData::new(MaterialTable::default(), PawnTable::default());
+    let mut helper = ex.build_exhelper(0, thread_data);
+
+    if let Some(init) = init {
+        let (ev_mid,ev_end) = if init.ends_with(".json") {
+            let pf = ParamFile::read(&init).unwrap();
+            helper.params = pf.search;
+            (pf.eval_mid,pf.eval_end)
+        } else {
+            EvalParams::read_evparams(&init).unwrap()
+        };
+        helper.cfg.eval_params_mid = ev_mid;
+        helper.cfg.eval_params_end = ev_end;
+    }
 
-        return;
+    let out_path = std::path::Path::new(out);
+    let (e0,e1) = if adam {
+        tuner.tune_adam(&ts, &mut helper, &ps, out_path).unwrap()
+    } else {
+        tuner.tune(&ts, &mut helper, &ps, out_path).unwrap()
+    };
+    println!("error before = {:.6}, after = {:.6}, wrote {}", e0, e1, out);
+}
+
+/// params dump <out.json> [--init evparams.bin|params.json]
+/// params check <params.json>
+fn main_params(args: &[String]) {
+    let usage = "usage: params dump <out.json> [--init evparams.bin|params.json]\n       params check <params.json>";
+
+    match (args.get(0).map(|x| x.as_str()), args.get(1)) {
+        (Some("dump"), Some(out)) => {
+            let pf = match (args.get(2).map(|x| x.as_str()), args.get(3)) {
+                (Some("--init"), Some(init)) if init.ends_with(".json") => ParamFile::read(init).unwrap(),
+                (Some("--init"), Some(init)) => {
+                    let (ev_mid,ev_end) = EvalParams::read_evparams(init).unwrap();
+                    ParamFile::new(ev_mid, ev_end, SParams::default())
+                },
+                (None, _) => ParamFile::default(),
+                _         => {
+                    eprintln!("{}", usage);
+                    return;
+                },
+            };
+            if let Err(e) = pf.validate() {
+                eprintln!("warning: {}", e);
+            }
+            pf.save(out).unwrap();
+            eprintln!("wrote {}", out);
+        },
+        (Some("check"), Some(path)) => match ParamFile::read(path) {
+            Ok(_)  => println!("{}: ok", path),
+            Err(e) => println!("{}: {}", path, e),
+        },
+        _ => eprintln!("{}", usage),
     }
+}
 
-    // // let mut ev_mid = EvalParams::default();
-    // // let mut ev_end = EvalParams::default();
-    // let mut ev_mid = EvalParams::empty();
-    // let mut ev_end = EvalParams::empty();
-    // ev_mid.mid = true;
-    // ev_end.mid = false;
+/// gensfen <dir> [--positions n] [--depth n] [--nodes n] [--threads n] [--book path]
+///     [--book-plies n] [--random-plies n] [--syzygy dir] [--nnue path] [--seed n] [--bincode]
+/// Rerun with the same dir to continue
+fn main_gensfen(args: &[String]) {
+    use rchess_engine_lib::brain::gensfen::*;
+
+    let dir = match args.get(0) {
+        Some(dir) => dir.clone(),
+        None      => {
+            eprintln!("usage: gensfen <dir> [--positions n] [--depth n] [--nodes n] [--threads n] [--book path] [--book-plies n] [--random-plies n] [--syzygy dir] [--nnue path] [--seed n] [--bincode]");
+            return;
+        },
+    };
 
-    // EvalParams::save_evparams(&ev_mid, &ev_end, evpath).unwrap();
-    // return;
+    let ts = Tables::new();
 
-    // let ev0 = EvalParams::default();
-    // ev0.psqt.print_table(Knight).unwrap();
-    // println!();
+    let mut builder = TDBuilder::new()
+        .num_threads(num_cpus::get_physical());
+    let mut book = None;
 
-    let (ev_mid,ev_end) = EvalParams::read_evparams(evpath).unwrap();
+    let mut xs = args[1..].iter();
+    while let Some(flag) = xs.next() {
+        if flag == "--bincode" {
+            builder = builder.format(SfenFormat::Bincode);
+            continue;
+        }
+        let val = match xs.next() {
+            Some(val) => val.as_str(),
+            None      => panic!("gensfen: missing value for {}", flag),
+        };
+        let n = || u64::from_str(val).unwrap_or_else(|_| panic!("gensfen: bad number for {}: {}", flag, val));
+        builder = match flag.as_str() {
+            "--positions"    => builder.num_positions(Some(n())),
+            "--depth"        => builder.max_depth(n() as Depth),
+            "--nodes"        => builder.nodes_per_pos(Some(n())).max_depth(MAX_SEARCH_PLY as Depth - 1),
+            "--threads"      => builder.num_threads(n() as usize),
+            "--book-plies"   => builder.book_plies(n() as usize),
+            "--random-plies" => builder.random_plies(n() as usize),
+            "--seed"         => builder.seed(n()),
+            "--syzygy"       => builder.syzygy_path(Some(val.into())),
+            "--nnue"         => builder.nnue_path(Some(val.into())),
+            "--book"         => {
+                book = Some(OpeningBook::read_from_file(&ts, val).unwrap());
+                builder
+            },
+            _                => panic!("gensfen: unknown flag {}", flag),
+        };
+    }
 
-    // ev_mid.psqt.print_table(Knight).unwrap();
-    // return;
+    let n = builder.do_explore(&ts, book.as_ref(), &dir).unwrap();
+    println!("wrote {} positions to {}", n, dir);
+}
 
-    // filter quiet TrainingData
-    if !true {
-        let path = "/home/me/code/rust/rchess/training_data/set2/depth5_games500_1.bin";
-        let path2 = &format!("{}-tmp", path);
+/// filter <out dir> <data>... [flags], see TDPipeline
+/// data is .binpack, .bin or .plain files, or directories of them
+fn main_filter(args: &[String]) {
+    use rchess_engine_lib::brain::gensfen::SfenFormat;
+    use rchess_engine_lib::brain::td_pipeline::*;
 
-        eprintln!("path = {:?}", path);
-        eprintln!("path2 = {:?}", path2);
+    if args.len() < 2 {
+        eprintln!("usage: filter <out dir> <data>... [--dedup mb] [--min-ply n] [--max-ply n] [--min-pieces n] [--max-pieces n] [--max-score n] [--quiet] [--rescore depth] [--syzygy dir] [--nnue path] [--shuffle n] [--merge-width n] [--threads n] [--seed n] [--per-file n] [--format binpack|bin|plain]");
+        return;
+    }
+    let out = &args[0];
 
-        let t0 = std::time::Instant::now();
-        let tds = TrainingData::load_all(path, None).unwrap();
-        eprintln!("finished in {:.3} seconds", t0.elapsed().as_secs_f64());
+    let ts = Tables::new();
 
-        eprintln!("tds.len() = {:?}", tds.len());
+    let mut pipeline = TDPipeline::new()
+        .num_threads(num_cpus::get_physical());
+    let mut paths = vec![];
 
-        // let t0 = std::time::Instant::now();
-        // let tds2 = TrainingData::filter_quiet(&ts, &(ev_mid,ev_end), tds);
-        // eprintln!("finished in {:.3} seconds", t0.elapsed().as_secs_f64());
+    let mut xs = args[1..].iter();
+    while let Some(arg) = xs.next() {
+        if !arg.starts_with("--") {
+            paths.push(arg.clone());
+            continue;
+        }
+        if arg == "--quiet" {
+            pipeline = pipeline.quiet(true);
+            continue;
+        }
+        let val = match xs.next() {
+            Some(val) => val.as_str(),
+            None      => panic!("filter: missing value for {}", arg),
+        };
+        let n = || u64::from_str(val).unwrap_or_else(|_| panic!("filter: bad number for {}: {}", arg, val));
+        pipeline = match arg.as_str() {
+            "--dedup"      => pipeline.dedup_mb(Some(n() as usize)),
+            "--min-ply"    => pipeline.min_ply(Some(n() as u16)),
+            "--max-ply"    => pipeline.max_ply(Some(n() as u16)),
+            "--min-pieces" => pipeline.min_pieces(Some(n() as u32)),
+            "--max-pieces" => pipeline.max_pieces(Some(n() as u32)),
+            "--max-score"  => pipeline.max_score(Some(n() as Score)),
+            "--rescore"    => pipeline.rescore_depth(Some(n() as Depth)),
+            "--syzygy"     => pipeline.syzygy_path(Some(val.into())),
+            "--nnue"       => pipeline.nnue_path(Some(val.into())),
+            "--shuffle"    => pipeline.shuffle(Some(n() as usize)),
+            "--merge-width" => pipeline.merge_width(n() as usize),
+            "--threads"    => pipeline.num_threads(n() as usize),
+            "--seed"       => pipeline.seed(n()),
+            "--per-file"   => pipeline.positions_per_file(n()),
+            "--format"     => match val {
+                "binpack" => pipeline.format(SfenFormat::Binpack),
+                "bin"     => pipeline.format(SfenFormat::Bincode),
+                "plain"   => pipeline.format(SfenFormat::Plain),
+                _         => panic!("filter: unknown format {}", val),
+            },
+            _              => panic!("filter: unknown flag {}", arg),
+        };
+    }
 
-        // eprintln!("tds2.len() = {:?}", tds2.len());
+    let n = pipeline.run(&ts, &paths, out).unwrap();
+    println!("wrote {} positions to {}", n, out);
+}
 
-        // return;
+/// train <out.nnue> <data>... [--init net.nnue] [--epochs n] [--batch n] [--lr x] [--lambda x]
+///     [--threads n] [--seed n]
+/// data is .binpack or .bin files, or directories of them
+fn main_train(args: &[String]) {
+    use rchess_engine_lib::brain::nnue_trainer::*;
+    use rchess_engine_lib::sf_compat::NNUE4;
 
-        unimplemented!()
+    if args.len() < 2 {
+        eprintln!("usage: train <out.nnue> <data>... [--arch halfkav2|perspective] [--size n] [--init net.nnue] [--epochs n] [--batch n] [--lr x] [--lambda x] [--threads n] [--seed n]");
+        return;
     }
+    let out = &args[0];
 
-    // let pgn_path = "./training_data/ficsgamesdb_2020_standard_nomovetimes_233317.pgn";
-    // let pgn_path = "./training_data/fics_test2.pgn";
-    // let pgn_path = "./training_data/fics_test.pgn";
-
-    // let ps = load_pgns_tx(&ts, &mut exhelper, count, pgn_path).unwrap();
+    let ts = Tables::new();
 
-    let ph_factory = PHTableFactory::new();
-    let ph_rw = ph_factory.handle();
+    let mut trainer = NNTrainer::new();
+    let mut init  = None;
+    let mut arch  = "halfkav2".to_string();
+    let mut size  = 256;
+    let mut paths = vec![];
+
+    let mut xs = args[1..].iter();
+    while let Some(arg) = xs.next() {
+        if !arg.starts_with("--") {
+            paths.push(arg.clone());
+            continue;
+        }
+        let val = match xs.next() {
+            Some(val) => val.as_str(),
+            None      => panic!("train: missing value for {}", arg),
+        };
+        let n = || f64::from_str(val).unwrap_or_else(|_| panic!("train: bad number for {}: {}", arg, val));
+        trainer = match arg.as_str() {
+            "--epochs"  => trainer.epochs(n() as usize),
+            "--batch"   => trainer.batch_size(n() as usize),
+            "--lr"      => trainer.lr(n() as f32),
+            "--lambda"  => trainer.lambda(n() as f32),
+            "--threads" => trainer.num_threads(n() as usize),
+            "--seed"    => trainer.seed(n() as u64),
+            "--init"    => {
+                init = Some(val.to_string());
+                trainer
+            },
+            "--arch"    => {
+                arch = val.to_string();
+                trainer
+            },
+            "--size"    => {
+                size = n() as usize;
+                trainer
+            },
+            _           => panic!("train: unknown flag {}", arg),
+        };
+    }
 
-    let mut g = Game::from_fen(&ts, STARTPOS).unwrap();
-    let mut exhelper = exhelper_once(&ts, &g, g.state.side_to_move, &ev_mid, &ev_end, Some(&ph_rw), None);
+    match arch.as_str() {
+        "halfkav2"    => {},
+        "perspective" => {
+            main_train_perspective(&ts, &trainer, out, &paths, init, size);
+            return;
+        },
+        _             => panic!("train: unknown arch {}", arch),
+    }
 
-    let fen_path = "./training_data/tuner/quiet-labeled.epd";
+    let t0 = Instant::now();
+    let mut samples = TrainSample::load_all(&ts, &paths).unwrap();
+    eprintln!("loaded {} positions in {:.1}s", samples.len(), t0.elapsed().as_secs_f64());
 
-    // let count = Some(100000);
-    let count = None; // 725 k
+    let mut net = match init {
+        Some(path) => HalfKAv2Net::from_nnue(&NNUE4::read_nnue(path).unwrap()),
+        None       => HalfKAv2Net::new_random(trainer.seed),
+    };
 
-    let ps = load_labeled_fens(&ts, &mut exhelper, count, fen_path).unwrap();
+    trainer.train(&mut net, &mut samples, Some(std::path::Path::new(out))).unwrap();
 
-    eprintln!("ps.len() = {:?}", ps.len());
+    /// Check the saved net against the float net
+    let mut nn = NNUE4::read_nnue(out).unwrap();
+    let mut gs = vec![];
+    if let Some(path) = TrainSample::data_files(&paths).unwrap().first() {
+        let tds = TrainSample::read_file(&ts, path).unwrap();
+        for td in tds.iter().take(100) {
+            td.for_each_position(&ts, |g, _| gs.push(*g));
+        }
+    }
+    let err = NNTrainer::quantization_error(&net, &mut nn, &gs);
+    eprintln!("saved {}, quantization error over {}", out, err);
+}
 
-    let k = 1.0;
-    // let k: f64 = -0.1111f64;
-    // let k = 1.3;
-    // let k = find_k(&ts, &ps, &exhelper, true);
-    eprintln!("k = {:?}", k);
+fn main_train_perspective(
+    ts:      &Tables,
+    trainer: &rchess_engine_lib::brain::nnue_trainer::NNTrainer,
+    out:     &str,
+    paths:   &[String],
+    init:    Option<String>,
+    size:    usize,
+) {
+    use rchess_engine_lib::brain::nnue_trainer::*;
+    use rchess_engine_lib::brain::perspective_trainer::*;
+    use rchess_engine_lib::sf_compat::PerspectiveNet;
 
-    let t0 = std::time::Instant::now();
-    let error = average_eval_error(&ts, &ps, &exhelper, Some(k));
-    eprintln!("error = {:.5}", error);
-    let t1 = t0.elapsed().as_secs_f64();
-    eprintln!("finished one eval_error in {:.3} seconds", t1);
+    let t0 = Instant::now();
+    let mut samples = PerspectiveSample::load_all(ts, paths).unwrap();
+    eprintln!("loaded {} positions in {:.1}s", samples.len(), t0.elapsed().as_secs_f64());
 
-    // return;
+    let mut net = match init {
+        Some(path) => PerspectiveF32Net::from_net(&PerspectiveNet::read(path).unwrap()),
+        None       => PerspectiveF32Net::new_random(size, trainer.seed),
+    };
 
-    // if std::path::Path::new(&evpath).exists() {
-    //     std::fs::rename(&evpath, &format!("{}.bak", evpath)).unwrap();
-    //     eprintln!("evparams.bin already exists, renaming to evparams.bin.bak");
-    //     // return;
-    // }
+    trainer.train_perspective(&mut net, &mut samples, Some(std::path::Path::new(out))).unwrap();
 
-    let count = 10;
+    /// Check the saved net against the float net
+    let mut nn = PerspectiveNet::read(out).unwrap();
+    let mut gs = vec![];
+    if let Some(path) = TrainSample::data_files(paths).unwrap().first() {
+        let tds = TrainSample::read_file(ts, path).unwrap();
+        for td in tds.iter().take(100) {
+            td.for_each_position(ts, |g, _| gs.push(*g));
+        }
+    }
+    let err = net.quantization_error(&mut nn, &gs);
+    eprintln!("saved {}, quantization error over {}", out, err);
+}
 
-    let (ev_mid2,ev_end2) = texel_optimize(
-        &ts,
-        &ps,
-        &mut exhelper,
-        &vec![],
-        Some(count),
-        Some(k),
-        evpath);
+/// nnue inspect <net.nnue>
+/// nnue dequantize <net.nnue> <out.f32>
+/// nnue quantize <net.f32> <out.nnue> [--output x] [--desc s]
+/// nnue compare <net.nnue|net.f32> [fens] [--output x]
+/// --output is the output and PSQT scale, see QuantScales. --activation and --hidden are
+/// fixed by the engine, other values are rejected
+/// nnue perspective <out.nnue> [--size n] [--seed n] [--desc s], random weights
+/// nnue check <net> [fens], incremental updates against a full refresh, for any network type
+/// nnue eval <net|-> [fen] [--format table|json] [--params params.json], same as the UCI
+/// eval command, "-" for classical only
+fn main_nnue(args: &[String]) {
+    use rchess_engine_lib::brain::nnue_trainer::*;
+    use rchess_engine_lib::sf_compat::{NNUE4,SimdLevel,NNEval,NNEvaluator,PerspectiveNet,WeightStats};
+    use rchess_engine_lib::util::read_epd_no_bm;
+    use rchess_engine_lib::parsing::FenMode;
+    use rand::prelude::{StdRng,SeedableRng};
+
+    let cmd = match args.get(0) {
+        Some(cmd) => cmd.as_str(),
+        None      => {
+            main_nnue3();
+            return;
+        },
+    };
 
-    let error = average_eval_error(&ts, &ps, &exhelper, Some(k));
-    eprintln!("error = {:.5}", error);
+    let mut scales = QuantScales::default();
+    let mut desc   = None;
+    let mut size   = 256;
+    let mut seed   = 1234;
+    let mut format = "table".to_string();
+    let mut params = None;
+    let mut pos    = vec![];
+    let mut xs = args[1..].iter();
+    while let Some(arg) = xs.next() {
+        if !arg.starts_with("--") {
+            pos.push(arg.as_str());
+            continue;
+        }
+        let val = xs.next().unwrap_or_else(|| panic!("nnue: missing value for {}", arg));
+        let x = || f32::from_str(val).unwrap_or_else(|_| panic!("nnue: bad number for {}: {}", arg, val));
+        match arg.as_str() {
+            "--activation" => scales.activation = x(),
+            "--hidden"     => scales.hidden = x(),
+            "--output"     => scales.output = x(),
+            "--desc"       => desc = Some(val.clone()),
+            "--size"       => size = x() as usize,
+            "--seed"       => seed = x() as u64,
+            "--format"     => format = val.clone(),
+            "--params"     => params = Some(val.clone()),
+            _              => panic!("nnue: unknown flag {}", arg),
+        }
+    }
+    let path = |n: usize| *pos.get(n).unwrap_or_else(|| panic!("nnue {}: missing argument {}", cmd, n + 1));
+    if let Err(e) = scales.check() {
+        eprintln!("nnue {}: {}", cmd, e);
+        return;
+    }
 
+    match cmd {
+        "inspect"    => {
+            let size   = std::fs::metadata(path(0)).unwrap().len();
+            println!("file:        {} ({} bytes)", path(0), size);
+            let header = match NNUE4::read_header(path(0)) {
+                Ok(header) => header,
+                Err(e)     => {
+                    println!("error:       bad header, {}", e);
+                    return;
+                },
+            };
+            if header.version == PerspectiveNet::VERSION {
+                println!("type:        Perspective");
+                let nn = match PerspectiveNet::read(path(0)) {
+                    Ok(nn) => nn,
+                    Err(e) => {
+                        println!("error:       {}", e);
+                        return;
+                    },
+                };
+                println!("size:        (768 -> {}) x 2 -> 1", nn.hidden);
+                println!("description: {}", nn.desc);
+                let i16_lim = i16::MAX as i64;
+                println!("{:<22} {}", "ft.weights", WeightStats::new(nn.ft_weights.iter().map(|x| *x as i64), i16_lim));
+                println!("{:<22} {}", "ft.biases", WeightStats::new(nn.ft_biases.iter().map(|x| *x as i64), i16_lim));
+                println!("{:<22} {}", "out.weights", WeightStats::new(nn.out_weights.iter().map(|x| *x as i64), i16_lim));
+                println!("{:<22} {}", "out.bias", nn.out_bias);
+                return;
+            }
+            println!("type:        HalfKAv2");
+            println!("version:     {:#010x} (expected {:#010x})", header.version, NNUE4::VERSION);
+            println!("hash:        {:#010x} (expected {:#010x})", header.hash, NNUE4::HASH);
+            println!("description: {}", header.desc);
+            let nn = match NNUE4::read_nnue_simd(path(0), SimdLevel::Scalar) {
+                Ok(nn) => nn,
+                Err(e) => {
+                    println!("error:       {}", e);
+                    return;
+                },
+            };
+            for (name,stats) in nn.weight_stats() {
+                println!("{:<22} {}", name, stats);
+            }
+        },
+        "dequantize" => {
+            let nn = NNUE4::read_nnue(path(0)).unwrap();
+            HalfKAv2Net::from_nnue_scaled(&nn, &scales).unwrap().write_f32(path(1)).unwrap();
+            println!("wrote {}", path(1));
+        },
+        "quantize"   => {
+            let net = HalfKAv2Net::read_f32(path(0)).unwrap();
+            let mut nn = net.to_nnue_scaled(&scales).unwrap();
+            if let Some(desc) = desc {
+                nn.desc = desc;
+            }
+            nn.write_nnue(path(1)).unwrap();
+            println!("wrote {}", path(1));
+        },
+        "compare"    => {
+            let ts = Tables::new();
+            let (net,mut nn) = if path(0).ends_with(".f32") {
+                let net = HalfKAv2Net::read_f32(path(0)).unwrap();
+                let mut nn = net.to_nnue_scaled(&scales).unwrap();
+                nn.set_simd(SimdLevel::detect());
+                (net, nn)
+            } else {
+                let nn = NNUE4::read_nnue(path(0)).unwrap();
+                (HalfKAv2Net::from_nnue_scaled(&nn, &scales).unwrap(), nn)
+            };
+            let fens = read_epd_no_bm(pos.get(1).copied().unwrap_or("perft_fens.txt")).unwrap();
+            let gs = fens.iter()
+                .filter(|l| !l.trim().is_empty() && !l.starts_with("//"))
+                .flat_map(|l| Game::from_fen_mode(&ts, l.split(';').next().unwrap_or(""), FenMode::Lenient).ok())
+                .collect::<Vec<_>>();
+            println!("quantization error over {}", NNTrainer::quantization_error_scaled(&net, &mut nn, &gs, &scales));
+        },
+        "perspective" => {
+            let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+            let mut nn = PerspectiveNet::new_random(size, &mut rng);
+            if let Some(desc) = desc {
+                nn.desc = desc;
+            }
+            nn.write(path(0)).unwrap();
+            println!("wrote {}", path(0));
+        },
+        "check"      => {
+            let ts = Tables::new();
+            let mut nn = NNEval::read(path(0)).unwrap();
+            println!("{}: {}", nn.name(), nn.description());
+            let fens = read_epd_no_bm(pos.get(1).copied().unwrap_or("perft_fens.txt")).unwrap();
+            let gs = fens.iter()
+                .filter(|l| !l.trim().is_empty() && !l.starts_with("//"))
+                .flat_map(|l| Game::from_fen_mode(&ts, l.split(';').next().unwrap_or(""), FenMode::Lenient).ok())
+                .collect::<Vec<_>>();
+            let mut reference = nn.clone();
+            let (mut moves, mut errors) = (0, 0);
+            for g in gs.iter() {
+                nn.reset(g);
+                for mv in MoveGen::generate_list_legal(&ts, g, None) {
+                    let g2 = match g.make_move_unchecked(&ts, mv) {
+                        Ok(g2) => g2,
+                        Err(_) => continue,
+                    };
+                    moves += 1;
+                    nn.make_move(&g2, mv);
+                    reference.reset(&g2);
+                    let (x0,x1) = (reference.evaluate(&g2), nn.evaluate(&g2));
+                    nn.unmake_move();
+                    reference.reset(g);
+                    let (y0,y1) = (reference.evaluate(g), nn.evaluate(g));
+                    if x0 != x1 || y0 != y1 {
+                        errors += 1;
+                        if errors <= 20 {
+                            println!("{}, {:?}: make {} != {}, unmake {} != {}", g.to_fen(), mv, x1, x0, y1, y0);
+                        }
+                    }
+                }
+            }
+            println!("{} positions, {} moves, {} mismatches", gs.len(), moves, errors);
+        },
+        "eval"       => {
+            use rchess_engine_lib::eval_trace::trace_eval;
+            let mut ts = Tables::new();
+            let fen = if pos.len() > 1 { pos[1..].join(" ") } else { STARTPOS.to_string() };
+            let g = Game::from_fen_mode(&ts, &fen, FenMode::Lenient).unwrap();
+            let timesettings = TimeSettings::new_f64(0.0,1.0);
+            let mut ex = Explorer::new(g.state.side_to_move, g.clone(), 1, timesettings);
+            if path(0) != "-" {
+                ex.load_nnue(path(0)).unwrap();
+            }
+            if let Some(params) = params {
+                ex.load_params(&mut ts, &params).unwrap();
+            }
+            let g = ex.game;
+            match format.as_str() {
+                "json" => {
+                    let mut helper = ex.build_exhelper(0, PerThreadData::default());
+                    println!("{}", helper.trace_classical(&ts, &g).to_json());
+                },
+                _      => print!("{}", trace_eval(&ts, &ex, &g)),
+            }
+        },
+        _            => main_nnue3(),
+    }
 }
 
-fn main_gensfen(count: u64, path: &str) {
-    let mut rng: StdRng = SeedableRng::seed_from_u64(1234u64);
+/// binpack <in> [out]
+/// Reads a .binpack, .plain or TrainingData .bin, and converts to the format of out
+fn main_binpack(input: Option<&str>, output: Option<&str>) {
+    use rchess_engine_lib::brain::gensfen::*;
+    use rchess_engine_lib::brain::binpack::*;
+    use rchess_engine_lib::brain::plain::*;
 
-    // let ts = Tables::read_from_file_def().unwrap();
-    // let ts = &_TABLES;
-    let ts = Tables::new();
-    let ob = OpeningBook::read_from_file(&ts, "tables/Perfect_2021/BIN/Perfect2021.bin").unwrap();
+    let input = input.unwrap_or_else(|| panic!("usage: binpack <in> [out]"));
 
+    let ts = Tables::new();
     let t0 = Instant::now();
 
-    // let count = 100;
-
-    // let path = "/home/me/code/rust/rchess/training_data/test_6.bin";
-
-    if std::path::Path::new(&path).exists() {
-        eprintln!("path exists, exiting");
-        return;
+    let tds = match SfenFormat::from_path(input) {
+        Some(SfenFormat::Binpack) => read_training_data(&ts, input).unwrap(),
+        Some(SfenFormat::Bincode) => TrainingData::load_all(input, None).unwrap(),
+        Some(SfenFormat::Plain)   => {
+            let entries = PlainReader::open(&ts, input).unwrap().collect::<std::io::Result<Vec<_>>>().unwrap();
+            BPEntry::to_training_data(&ts, entries.into_iter())
+        },
+        None                      => panic!("binpack: unknown extension, {}", input),
+    };
+    let positions: usize = tds.iter().map(|td| td.moves.iter().filter(|te| !te.skip).count()).sum();
+    println!("{}: {} games, {} positions, read in {:.3}s",
+             input, tds.len(), positions, t0.elapsed().as_secs_f64());
+
+    if let Some(output) = output {
+        match SfenFormat::from_path(output) {
+            Some(SfenFormat::Binpack) => {
+                let n = write_training_data(&ts, &tds, output).unwrap();
+                println!("wrote {} positions to {}", n, output);
+            },
+            Some(SfenFormat::Bincode) => {
+                let mut f = std::fs::File::create(output).unwrap();
+                for td in tds.iter() {
+                    TrainingData::save_into(true, &mut f, td).unwrap();
+                }
+                println!("wrote {} games to {}", tds.len(), output);
+            },
+            Some(SfenFormat::Plain)   => {
+                let mut w = PlainWriter::create(output).unwrap();
+                let mut n = 0;
+                for td in tds.iter() {
+                    for e in BPEntry::from_training_data(&ts, td) {
+                        w.write_entry(&e).unwrap();
+                        n += 1;
+                    }
+                }
+                w.finish().unwrap();
+                println!("wrote {} positions to {}", n, output);
+            },
+            None                      => panic!("binpack: unknown extension, {}", output),
+        }
     }
-
-    // let ts = TDBuilder::new()
-    //     .max_depth(5)
-    //     .time(0.2)
-    //     .num_threads(num_cpus::get())
-    //     // .num_threads(12)
-    //     // .num_positions(Some(1000))
-    //     .num_positions(None)
-    //     .do_explore(&ts, &ob, count, true, rng, true, path)
-    //     .unwrap();
-    // println!("finished in {:.3} seconds", t0.elapsed().as_secs_f64());
-
-    unimplemented!()
 }
 
 #[allow(unreachable_code)]
@@ -3582,8 +4031,6 @@ fn main9() {
 
     // eprintln!("stats0.eval_nnue      = {}", pretty_print_si(stats0.eval_nnue as i64));
     // eprintln!("stats0.eval_classical = {}", pretty_print_si(stats0.eval_classical as i64));
-    eprintln!("% nnue eval = {:.1}%",
-              (stats0.eval_nnue as f64 / (stats0.eval_classical + stats0.eval_nnue) as f64) * 100.0);
 
     // return;
 
@@ -4287,6 +4734,151 @@ fn main_perft(depth: Option<u64>) {
 
 }
 
+fn main_validate(depth: Option<u64>) {
+
+    let ts = Tables::new();
+
+    let fen2 = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - "; // Position 2
+    let fen3 = "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - "; // Position 3
+    let fen4 = "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1"; // Position 4
+    let fen5 = "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8  "; // Position 5
+
+    /// Known perft node counts, depth 1..
+    let fens: Vec<(&str,&[u64])> = vec![
+        (STARTPOS, &[20, 400, 8902, 197281, 4865609]),
+        (fen2,     &[48, 2039, 97862, 4085603]),
+        (fen3,     &[14, 191, 2812, 43238, 674624]),
+        (fen4,     &[6, 264, 9467, 422333]),
+        (fen5,     &[44, 1486, 62379, 2103487]),
+    ];
+
+    let n = depth.unwrap_or(3) as usize;
+
+    let t0 = std::time::Instant::now();
+    for (k,(fen,counts)) in fens.iter().enumerate() {
+        let g = Game::from_fen(&ts, fen).unwrap();
+        g.validate(&ts).unwrap();
+
+        for d in 1..=n.min(counts.len()) {
+            match MoveGen::perft_validate(&ts, &g, d as Depth) {
+                Ok(tot) => {
+                    println!("fen #{}, depth {}: {:>10} {}",
+                             k + 1, d, tot, if tot == counts[d-1] { "ok" } else { "WRONG" });
+                    assert_eq!(tot, counts[d-1]);
+                },
+                Err((g2,mv,e)) => {
+                    println!("fen #{}, depth {}: invalid after {:?}: {:?}\n{}\n{:?}",
+                             k + 1, d, mv, e, g2.to_fen(), g2);
+                    panic!();
+                },
+            }
+        }
+    }
+    println!("validate done in {:.3} seconds.", t0.elapsed().as_secs_f64());
+
+}
+
+fn main_fuzz(games: Option<u64>, seed: Option<u64>) {
+    use rchess_engine_lib::fuzz::*;
+
+    let ts = Tables::new();
+
+    /// short fixed seed run by default, pass a game count and seed for longer runs
+    let games = games.unwrap_or(200);
+    let seed  = seed.unwrap_or(FUZZ_SEED);
+
+    let t0 = std::time::Instant::now();
+    match fuzz_movegen(&ts, seed, games, 200) {
+        Ok(stats) => {
+            println!("fuzz ok, seed {:#x}: {:?}", seed, stats);
+        },
+        Err(e) => {
+            println!("fuzz failed, seed {:#x}: {:?}", seed, e);
+            panic!();
+        },
+    }
+    println!("fuzz done in {:.3} seconds.", t0.elapsed().as_secs_f64());
+
+}
+
+fn main_symmetry(epd: Option<&str>, nnue: Option<&str>) {
+    use rchess_engine_lib::symmetry::*;
+    use rchess_engine_lib::sf_compat::NNUE4;
+    use rchess_engine_lib::material::*;
+
+    let ts = Tables::new();
+
+    let epd = epd.unwrap_or("perft_fens.txt");
+
+    let g = Game::from_fen(&ts, STARTPOS).unwrap();
+    let ex = Explorer::new(g.state.side_to_move, g.clone(), 1, TimeSettings::new_f64(0.0,1.0));
+    let thread_data = PerThreadData::new(MaterialTable::default(), PawnTable::default());
+    let mut helper = ex.build_exhelper(0, thread_data);
+
+    let mut nn = nnue.map(|path| NNUE4::read_nnue(path).unwrap());
+
+    let t0 = std::time::Instant::now();
+    let (stats, errors) = check_epd(&ts, &mut helper, nn.as_mut(), epd).unwrap();
+    println!("symmetry, {}: {:?}", epd, stats);
+    for e in errors.iter() {
+        println!("{}", e);
+    }
+    println!("symmetry done in {:.3} seconds.", t0.elapsed().as_secs_f64());
+    assert!(errors.is_empty(), "{} asymmetric evals", errors.len());
+
+}
+
+/// Without a net, checks random weights
+fn main_simd(epd: Option<&str>, nnue: Option<&str>) {
+    use rchess_engine_lib::sf_compat::{NNUE4,SimdLevel};
+    use rchess_engine_lib::sf_compat::simd::check::*;
+    use rchess_engine_lib::util::read_epd_no_bm;
+    use rand::prelude::{StdRng,SeedableRng};
+
+    let ts = Tables::new();
+
+    let epd = epd.unwrap_or("perft_fens.txt");
+    let fens = read_epd_no_bm(epd).unwrap().into_iter()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with("//"))
+        .collect::<Vec<_>>();
+
+    let nn = match nnue {
+        Some(path) => NNUE4::read_nnue(path).unwrap(),
+        None       => {
+            let mut nn = NNUE4::new_empty();
+            let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+            randomize(&mut nn, &mut rng);
+            nn
+        },
+    };
+
+    println!("detected: {:?}, supported: {:?}", SimdLevel::detect(), SimdLevel::supported());
+
+    let t0 = std::time::Instant::now();
+    let (stats, errors) = check_positions(&ts, &nn, &fens);
+    println!("simd, {}: {:?}", epd, stats);
+    for e in errors.iter().take(20) {
+        println!("{}", e);
+    }
+    println!("simd done in {:.3} seconds.", t0.elapsed().as_secs_f64());
+    assert!(errors.is_empty(), "{} mismatches", errors.len());
+
+    /// weights are written in file order, whatever the layout in memory
+    let path = std::env::temp_dir().join("rchess_simd_check.nnue");
+    let mut nn = nn;
+    nn.set_simd(SimdLevel::detect());
+    nn.write_nnue(&path).unwrap();
+    let nn2 = NNUE4::read_nnue_simd(&path, SimdLevel::Scalar).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    nn.set_simd(SimdLevel::Scalar);
+    for (l0,l1) in nn.layers.iter().zip(nn2.layers.iter()) {
+        assert!(l0.weights[..] == l1.weights[..]);
+        assert!(l0.prev.prev.prev.prev.weights[..] == l1.prev.prev.prev.prev.weights[..]);
+    }
+    println!("round trip ok");
+
+}
+
 #[allow(unreachable_code)]
 fn init_logger() {
 
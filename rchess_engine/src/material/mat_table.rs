@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use crate::endgame::*;
 // use crate::endgame::helpers::is_kx_vs_k;
 use crate::evaluate::TaperedScore;
+use crate::tuning::{EvalParams,EPImbalance};
 use crate::types::*;
 use crate::tables::*;
 
@@ -23,24 +24,33 @@ pub type MaterialTable = VecTable<MatEval, 512>;
 pub struct MatEval {
 
     pub material_score: TaperedScore,
+    /// White side
+    pub imbalance:      TaperedScore,
     pub phase:          Phase,
 
-    // pub scaling_func:   
-
-    pub eg_val:         Option<EndGameType>,
+    /// Specialized evaluator and the strong side
+    pub eg_val:         Option<(EndGameType, Color)>,
+    /// Scaling function, by strong side
+    pub scale:          [Option<ScaleType>; 2],
 
 }
 
 impl MaterialTable {
 
     /// (entry, overwrite, hit)
-    pub fn get_or_insert(&mut self, ts: &Tables, g: &Game) -> (MatEval, bool, bool) {
+    pub fn get_or_insert(
+        &mut self,
+        ts:       &Tables,
+        g:        &Game,
+        ev_mid:   &EvalParams,
+        ev_end:   &EvalParams,
+    ) -> (MatEval, bool, bool) {
         // if let Some(me) = self.0.get(&g.zobrist) {
         if let Some(me) = self.get(g.mat_zb) {
             return (*me, false, true);
         }
 
-        let me = MatEval::new(ts, g);
+        let me = MatEval::new(ts, g, ev_mid, ev_end);
 
         // self.0.insert(g.zobrist, me);
         let ow = self.insert(g.mat_zb, me);
@@ -51,20 +61,31 @@ impl MaterialTable {
 
 impl MatEval {
 
-    /// TODO: sf quadratics?
-    #[cfg(feature = "nope")]
-    pub fn imbalance(mat: &Material, side: Color) -> Score {
-        // let mut score = 0;
-        // for pc in Piece::iter_nonking_pieces() {
-        //     let n = mat.get(pc, side);
-        //     if n == 0 { continue; }
-        //     score += n as Score * pc.score();
-        // }
-        // score
-        unimplemented!()
+    /// Quadratic in the piece counts, see EPImbalance
+    pub fn imbalance(mat: &Material, ev: &EPImbalance, side: Color) -> Score {
+        let counts = |c: Color| [
+            if mat.get(Bishop, c) > 1 { 1 } else { 0 },
+            mat.get(Pawn, c) as Score,
+            mat.get(Knight, c) as Score,
+            mat.get(Bishop, c) as Score,
+            mat.get(Rook, c) as Score,
+            mat.get(Queen, c) as Score,
+        ];
+        let (us,them) = (counts(side), counts(!side));
+
+        let mut score = 0;
+        for pt1 in 0..6 {
+            if us[pt1] == 0 { continue; }
+            let mut v = 0;
+            for pt2 in 0..=pt1 {
+                v += ev.ours[pt1][pt2] * us[pt2] + ev.theirs[pt1][pt2] * them[pt2];
+            }
+            score += us[pt1] * v;
+        }
+        score
     }
 
-    pub fn imbalance(g: &Game) -> TaperedScore {
+    pub fn material(g: &Game) -> TaperedScore {
 
         g.count_npm(White)
             + Pawn.score_tapered() * g.state.material.get(Pawn, White) as Score
@@ -77,25 +98,27 @@ impl MatEval {
         //     - Pawn.score_tapered() * g.state.material.get(Pawn, Black) as Score
     }
 
-    pub fn new(ts: &Tables, g: &Game) -> Self {
+    pub fn new(ts: &Tables, g: &Game, ev_mid: &EvalParams, ev_end: &EvalParams) -> Self {
 
         // let score = g.sum_evaluate(ts, &ts.eval_params_mid, &ts.eval_params_mid, None);
-        let mut material_score = Self::imbalance(g);
+        let mut material_score = Self::material(g);
 
-        // if is_kx_vs_k(g, g.state.side_to_move) {
-        //     unimplemented!()
-        // }
+        let mat = &g.state.material;
+        let imbalance = |ev: &EPImbalance| {
+            (Self::imbalance(mat, ev, White) - Self::imbalance(mat, ev, Black)) / 16
+        };
+        let imbalance = TaperedScore::new(
+            imbalance(&ev_mid.imbalance), imbalance(&ev_end.imbalance));
 
-        // if g.state.npm[White] + g.state.npm[Black] == 0 && g.state.material.get_both(Pawn) > 0 {
-        //     unimplemented!()
-        // }
-
-        let eg_val = None;
+        let eg_val = EndGameMaps::get(ts).get_value(g);
+        let scale  = [EndGameMaps::get_scale(g, White), EndGameMaps::get_scale(g, Black)];
 
         Self {
             material_score,
+            imbalance,
             phase:     g.state.phase,
             eg_val,
+            scale,
         }
 
     }
@@ -113,3 +136,46 @@ impl MatEval {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imbalance_symmetric() {
+        let ts = Tables::new();
+        let (ev_mid,ev_end) = EvalParams::new_mid_end();
+
+        let fens = [
+            "r2q1rk1/pp3ppp/3p1n2/3Np3/4P3/5N2/PPP1QPPP/R3K2R w KQ - 0 1",
+            "2r3k1/5ppp/8/8/8/8/2B2PPP/2B3K1 b - - 0 1",
+            "4k3/pppppppp/8/8/8/8/8/QQ2K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K2R w K - 0 1",
+            "1q2k3/8/8/8/8/8/8/RR2K3 w - - 0 1",
+        ];
+
+        for fen in fens {
+            let g  = Game::from_fen(&ts, fen).unwrap();
+            let g2 = g.flip_sides(&ts);
+            let me  = MatEval::new(&ts, &g, &ev_mid, &ev_end);
+            let me2 = MatEval::new(&ts, &g2, &ev_mid, &ev_end);
+            assert_ne!(me.imbalance, TaperedScore::default(), "{}", fen);
+            assert_eq!(me.imbalance, -me2.imbalance, "{}", fen);
+        }
+
+        /// Equal material, also with the pieces on different squares
+        let fens = [
+            STARTPOS,
+            "r1bqkb1r/pppp1ppp/2n2n2/4p3/4P3/2N2N2/PPPP1PPP/R1BQKB1R w KQkq - 4 4",
+            "4k3/1n3b2/8/8/8/8/8/2N1KB2 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+        ];
+
+        for fen in fens {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            let me = MatEval::new(&ts, &g, &ev_mid, &ev_end);
+            assert_eq!(me.imbalance, TaperedScore::default(), "{}", fen);
+        }
+    }
+
+}
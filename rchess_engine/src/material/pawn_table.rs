@@ -2,83 +2,182 @@
 use crate::types::*;
 use crate::tables::*;
 use crate::evaluate::TaperedScore;
+use crate::tuning::{EvalParams,EPPawns};
 
 use crate::material::vec_table::VecTable;
 
-pub type PawnTable = VecTable<PawnEval, 8>;
+/// 64 kB
+pub type PawnTable = VecTable<PawnEval, 64>;
 
+/// Everything that only depends on the pawns.
+/// Terms that depend on other pieces, like passed pawn king distances, are in ExHelper::passed_pawns
 #[derive(Debug,Clone,Copy)]
 pub struct PawnEval {
     pub scores:          [TaperedScore; 2],
+    /// Both sides
     pub passed:          BitBoard,
-    pub attacks:         BitBoard,
-    pub attacks_span:    BitBoard,
+    pub attacks:         [BitBoard; 2],
+    /// Squares that can be attacked by pawns as they advance
+    pub attacks_span:    [BitBoard; 2],
 }
 
 impl PawnTable { /// get_or_insert
-    pub fn get_or_insert(&mut self, ts: &Tables, g: &Game) -> PawnEval {
-        if let Some(ev) = self.get(g.zobrist) {
-            return *ev;
+    /// (entry, overwrite, hit)
+    pub fn get_or_insert(
+        &mut self,
+        ts:       &Tables,
+        g:        &Game,
+        ev_mid:   &EvalParams,
+        ev_end:   &EvalParams,
+    ) -> (PawnEval, bool, bool) {
+        if let Some(ev) = self.get(g.pawn_zb) {
+            return (*ev, false, true);
         }
 
-        let ev = PawnEval::new(ts, g);
+        let ev = PawnEval::new(ts, g, ev_mid, ev_end);
 
-        self.insert(g.zobrist, ev);
+        let ow = self.insert(g.pawn_zb, ev);
 
-        ev
+        (ev, ow, false)
     }
 }
 
 impl PawnEval { /// build, evaluate
-    pub fn new(ts: &Tables, g: &Game) -> Self {
-
-        let mut passed       = BitBoard::empty();
-        let mut attacks      = BitBoard::empty();
-        let mut attacks_span = BitBoard::empty();
+    pub fn new(ts: &Tables, g: &Game, ev_mid: &EvalParams, ev_end: &EvalParams) -> Self {
 
         let mut out = Self {
-            scores: [TaperedScore::default(); 2],
-            passed,
-            attacks,
-            attacks_span,
+            scores:        [TaperedScore::default(); 2],
+            passed:        BitBoard::empty(),
+            attacks:       [BitBoard::empty(); 2],
+            attacks_span:  [BitBoard::empty(); 2],
         };
 
-        out.evaluate(ts, g, White);
-        out.evaluate(ts, g, Black);
+        for side in [White,Black] {
+            let pawns = g.get(Pawn, side);
+            out.attacks[side] = pawn_attacks(side, pawns);
+            out.attacks_span[side] = pawns.into_iter()
+                .fold(BitBoard::empty(), |acc, sq| acc | pawn_attacks_span(side, sq));
+        }
+
+        out.scores[White] = out.evaluate(g, ev_mid, ev_end, White);
+        out.scores[Black] = out.evaluate(g, ev_mid, ev_end, Black);
 
         out
     }
 
-    fn evaluate(&mut self, ts: &Tables, g: &Game, side: Color) -> TaperedScore {
+    fn evaluate(&mut self, g: &Game, ev_mid: &EvalParams, ev_end: &EvalParams, side: Color) -> TaperedScore {
 
-        let score = TaperedScore::default();
+        let mut score = TaperedScore::default();
 
         let pawns_us   = g.get(Pawn, side);
         let pawns_them = g.get(Pawn, !side);
 
-        let up = if side == White { N } else { S };
+        let up = side.fold(N, S);
 
-        // let neighbors: BitBoard;
+        let term = |f: &dyn Fn(&EPPawns) -> Score| TaperedScore::new(f(&ev_mid.pawns), f(&ev_end.pawns));
 
         for sq in pawns_us.into_iter() {
 
             let s = BitBoard::single(sq);
-
             let r = BitBoard::relative_rank(side, sq);
 
-            let opposed  = pawns_them & forward_file_bb(side, sq);
-            let blocked  = pawns_them & (s.shift_dir(up));
-            let stoppers = pawns_them & pawn_passed_span(side, sq);
-            // let lever    = pawns_them & pawn_attacks_bb
+            let opposed    = (pawns_them & forward_file_bb(side, sq)).is_not_empty();
+            let blocked    = (pawns_them & s.shift_dir(up)).is_not_empty();
+            let neighbours = pawns_us & adjacent_files_bb(sq);
+            let phalanx    = neighbours & BitBoard::mask_rank(sq.rank());
+            let support    = neighbours & BitBoard::mask_rank(sq.rank()).shift_dir(side.fold(S, N));
+
+            if Self::passed(side, sq, pawns_us, pawns_them) {
+                self.passed.set_one_mut(sq);
+                /// Candidates that need more than one push, or are blocked, get half
+                let front = s.shift_dir(up);
+                let full = (pawns_them & pawn_passed_span(side, front.bitscan())).is_empty()
+                    && ((pawns_us | pawns_them) & front).is_empty();
+                let div = if full { 1 } else { 2 };
+                score += term(&|ev| ev.passed_rank[r as usize] / div);
+            }
+
+            if phalanx.is_not_empty() || support.is_not_empty() {
+                if r != 0 && r != 7 {
+                    let px = if phalanx.is_not_empty() { 1 } else { 0 };
+                    let op = if opposed { 1 } else { 0 };
+                    let su = support.popcount() as Score;
+                    score += term(&|ev| ev.connected_ranks[r as usize] * (2 + px - op) + ev.supported * su);
+                }
+            } else if neighbours.is_empty() {
+                score += term(&|ev| ev.isolated);
+            } else if Self::backward(side, sq, pawns_us, pawns_them) {
+                score += term(&|ev| ev.backward);
+            }
+
+            /// Pawn behind on the same file, and no support to recapture with
+            if (pawns_us & forward_file_bb(!side, sq)).is_not_empty() && support.is_empty() {
+                score += term(&|ev| ev.doubled);
+            }
+
+            if blocked {
+                match r {
+                    4 => score += term(&|ev| ev.blocked_r5),
+                    5 => score += term(&|ev| ev.blocked_r6),
+                    _ => {},
+                }
+            }
 
-            // unimplemented!()
         }
 
         score
     }
 
+    /// Passed, or a candidate: the only stoppers are pawns that can be traded with one of ours.
+    /// Either every stopper is a lever, or every stopper attacks the square in front and the
+    /// phalanx outnumbers them, or a single blocker on rank 5 or higher can be levered by a
+    /// supporting pawn. Never the rear pawn of a doubled pair.
+    pub fn passed(side: Color, sq: Coord, pawns_us: BitBoard, pawns_them: BitBoard) -> bool {
+        let s          = BitBoard::single(sq);
+        let up         = side.fold(N, S);
+        let r          = BitBoard::relative_rank(side, sq);
+        let stoppers   = pawns_them & pawn_passed_span(side, sq);
+        let lever      = pawns_them & pawn_attacks(side, s);
+        let lever_push = pawns_them & pawn_attacks(side, s.shift_dir(up));
+        let blocked    = pawns_them & s.shift_dir(up);
+        let neighbours = pawns_us & adjacent_files_bb(sq);
+        let phalanx    = neighbours & BitBoard::mask_rank(sq.rank());
+        let support    = neighbours & BitBoard::mask_rank(sq.rank()).shift_dir(side.fold(S, N));
+
+        let double_them = pawn_double_attacks(!side, pawns_them);
+
+        let passed = (stoppers ^ lever).is_empty()
+            || ((stoppers ^ lever_push).is_empty() && phalanx.popcount() >= lever_push.popcount())
+            || (stoppers == blocked && r >= 4
+                && (support.shift_dir(up) & !(pawns_them | double_them)).is_not_empty());
+
+        passed && (pawns_us & forward_file_bb(side, sq)).is_empty()
+    }
+
+    /// No friendly pawn on an adjacent file behind or level, and the square in front
+    /// is blocked or attacked by an enemy pawn
+    fn backward(side: Color, sq: Coord, pawns_us: BitBoard, pawns_them: BitBoard) -> bool {
+        let behind = pawns_us & adjacent_files_bb(sq) & !forward_ranks_bb(side, sq);
+        if behind.is_not_empty() { return false; }
+
+        let stop = BitBoard::single(sq).shift_dir(side.fold(N, S));
+        let (dw,de) = side.fold((NW,NE), (SW,SE));
+        let stop_attacks = stop.shift_dir(dw) | stop.shift_dir(de);
+        (pawns_them & (stop | stop_attacks)).is_not_empty()
+    }
+
+}
+
+pub fn pawn_attacks(side: Color, pawns: BitBoard) -> BitBoard {
+    let (dw,de) = side.fold((NW,NE), (SW,SE));
+    pawns.shift_dir(dw) | pawns.shift_dir(de)
 }
 
+/// Attacked by two pawns
+pub fn pawn_double_attacks(side: Color, pawns: BitBoard) -> BitBoard {
+    let (dw,de) = side.fold((NW,NE), (SW,SE));
+    pawns.shift_dir(dw) & pawns.shift_dir(de)
+}
 
 pub fn pawn_attacks_span(side: Color, sq: Coord) -> BitBoard {
     forward_ranks_bb(side, sq) & adjacent_files_bb(sq)
@@ -103,3 +202,91 @@ pub fn pawn_passed_span(side: Color, sq: Coord) -> BitBoard {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirrored vertically with the colours swapped
+    fn flip_fen(fen: &str) -> String {
+        let fields = fen.split_whitespace().collect::<Vec<_>>();
+        let swap = |c: char| if c.is_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() };
+        let board = fields[0].split('/').rev().map(|r| r.chars().map(swap).collect::<String>())
+            .collect::<Vec<_>>().join("/");
+        let side = if fields[1] == "w" { "b" } else { "w" };
+        format!("{} {} - - 0 1", board, side)
+    }
+
+    fn squares(bb: BitBoard) -> Vec<String> {
+        let mut out = bb.into_iter().map(|sq| format!("{:?}", sq).to_lowercase()).collect::<Vec<_>>();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn passed_and_candidate_pawns() {
+        let ts = Tables::new();
+        let (ev_mid,ev_end) = EvalParams::new_mid_end();
+
+        let cases: &[(&str, &[&str])] = &[
+            ("4k3/8/8/3P4/8/8/8/4K3 w - - 0 1",       &["d5"]),
+            /// Blocked on rank 5 with no support
+            ("4k3/8/3p4/3P4/8/8/8/4K3 w - - 0 1",     &[]),
+            /// The only stopper is a lever, for both sides
+            ("4k3/8/4p3/3P4/8/8/8/4K3 w - - 0 1",     &["d5", "e6"]),
+            /// Stopper attacks the push square, outnumbered by the phalanx
+            ("4k3/8/4p3/8/2PP4/8/8/4K3 w - - 0 1",    &["c4", "d4"]),
+            ("4k3/8/4p3/8/3P4/8/8/4K3 w - - 0 1",     &[]),
+            /// Single blocker, and the supporting pawn can lever it
+            ("4k3/8/3p4/3P4/2P5/8/8/4K3 w - - 0 1",   &["d5"]),
+            /// Lever square is attacked twice
+            ("4k3/8/1p1p4/3P4/2P5/8/8/4K3 w - - 0 1", &[]),
+            /// Rear doubled pawn
+            ("4k3/8/8/3P4/3P4/8/8/4K3 w - - 0 1",     &["d5"]),
+            ("4k3/pp6/8/8/8/8/P7/4K3 w - - 0 1",      &[]),
+            ("4k3/1p6/8/8/8/8/P7/4K3 w - - 0 1",      &[]),
+            ("4k3/2p5/8/8/8/8/P7/4K3 w - - 0 1",      &["a2", "c7"]),
+        ];
+
+        for (fen,expected) in cases.iter() {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            let ev = PawnEval::new(&ts, &g, &ev_mid, &ev_end);
+            assert_eq!(squares(ev.passed), *expected, "{}", fen);
+
+            let fen2 = flip_fen(fen);
+            let g2 = Game::from_fen(&ts, &fen2).unwrap();
+            let ev2 = PawnEval::new(&ts, &g2, &ev_mid, &ev_end);
+            assert_eq!(ev2.passed, ev.passed.mirror_vert(), "{}", fen2);
+            assert_eq!(ev2.scores[White], ev.scores[Black], "{}", fen2);
+            assert_eq!(ev2.scores[Black], ev.scores[White], "{}", fen2);
+        }
+    }
+
+    #[test]
+    fn candidate_gets_half_bonus() {
+        let ts = Tables::new();
+        let (ev_mid,ev_end) = EvalParams::new_mid_end();
+        let (mut ev_mid0, mut ev_end0) = (ev_mid, ev_end);
+        ev_mid0.pawns.passed_rank = [0; 8];
+        ev_end0.pawns.passed_rank = [0; 8];
+
+        /// Only the passed_rank terms
+        let bonus = |fen: &str| {
+            let g = Game::from_fen(&ts, fen).unwrap();
+            PawnEval::new(&ts, &g, &ev_mid, &ev_end).scores[White]
+                - PawnEval::new(&ts, &g, &ev_mid0, &ev_end0).scores[White]
+        };
+        let rank = |r: usize, div: Score| TaperedScore::new(
+            ev_mid.pawns.passed_rank[r] / div, ev_end.pawns.passed_rank[r] / div);
+
+        /// Free path
+        assert_eq!(bonus("4k3/8/8/3P4/8/8/8/4K3 w - - 0 1"), rank(4, 1));
+        /// Passed after one push, through the lever
+        assert_eq!(bonus("4k3/8/4p3/3P4/8/8/8/4K3 w - - 0 1"), rank(4, 1));
+        /// c4 is passed, d4 is still stopped by e6 after the push
+        assert_eq!(bonus("4k3/8/4p3/8/2PP4/8/8/4K3 w - - 0 1"), rank(3, 1) + rank(3, 2));
+        /// Blocked
+        assert_eq!(bonus("4k3/8/3p4/3P4/2P5/8/8/4K3 w - - 0 1"), rank(4, 2));
+    }
+
+}
@@ -943,6 +943,19 @@ impl<'a> MoveGen<'a> {
             self.gen_sliding(gen, Rook, target);
             self.gen_sliding(gen, Queen, target);
 
+            /// Queen promotions are generated with captures and under-promotions with quiets,
+            /// so blocking with a queen promotion or capturing the checker with an
+            /// under-promotion falls outside the targets above
+            match gen {
+                MoveGenType::Captures => {
+                    let ksq     = self.game.get(King, self.side).bitscan();
+                    let between = self.ts.between(ksq, checkers.bitscan());
+                    self.gen_promotions(gen, Some(between & self.game.all_empty()));
+                },
+                MoveGenType::Quiets   => self.gen_promotions(gen, Some(checkers)),
+                _                     => {},
+            }
+
         } else {
             // double check, only generate king moves
 
@@ -1015,6 +1028,33 @@ impl<'a> MoveGen<'a> {
         sum
     }
 
+    /// perft, calling Game::validate on every node
+    pub fn perft_validate(ts: &'a Tables, g: &'a Game, depth: Depth) -> Result<u64,(Game,Move,GameInvalid)> {
+        let stack = ABStack::new();
+        Self::_perft_validate(ts, &stack, *g, depth.max(1))
+    }
+
+    fn _perft_validate(ts: &'a Tables, st: &ABStack, g: Game, depth: Depth) -> Result<u64,(Game,Move,GameInvalid)> {
+        if depth == 0 { return Ok(1); }
+
+        let mut gen = MoveGen::new(ts, &g, None, st, depth, 0);
+
+        let mut moves = gen._generate_list(None);
+        moves.retain(|mv| gen.move_is_legal(*mv));
+
+        let mut sum = 0;
+        for mv in moves {
+            if let Ok(g2) = g.make_move_unchecked(&ts, mv) {
+                if let Err(e) = g2.validate(ts) {
+                    return Err((g,mv,e));
+                }
+                sum += Self::_perft_validate(ts, st, g2, depth - 1)?;
+            }
+        }
+
+        Ok(sum)
+    }
+
 }
 
 /// SEE
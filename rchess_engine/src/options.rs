@@ -1,5 +1,5 @@
 
-use crate::{explore::{Explorer, ExConfig}, tables::SParams};
+use crate::{explore::{Explorer, ExConfig}, tables::SParams, types::Score};
 
 use std::{str::FromStr, collections::HashMap};
 use log::debug;
@@ -57,7 +57,13 @@ impl Explorer {
         let val = i64::from_str(val).unwrap();
 
         if let Some(opt) = self.options.get(name) {
+            let hybrid = |sp: &SParams| (sp.hybrid_threshold, sp.hybrid_phase, sp.hybrid_rule50);
+            /// Cached evals depend on the hybrid params, see use_classical_hybrid
+            let prev = hybrid(&self.search_params);
             (opt.func)(&mut self.search_params, &mut self.cfg, val);
+            if hybrid(&self.search_params) != prev {
+                self.eval_cache.clear_table();
+            }
             self.sync_threads();
         } else {
             debug!("no option: {:?} = {:?}", name, val);
@@ -131,6 +137,30 @@ impl EngineOptions {
             func:    opt_lmr_ply_const,
         });
 
+        out.insert(EngineOption {
+            name:    "hybrid_threshold",
+            default: Some(400),
+            min:     Some(0),
+            max:     Some(5000),
+            func:    opt_hybrid_threshold,
+        });
+
+        out.insert(EngineOption {
+            name:    "hybrid_phase",
+            default: Some(100),
+            min:     Some(0),
+            max:     Some(5000),
+            func:    opt_hybrid_phase,
+        });
+
+        out.insert(EngineOption {
+            name:    "hybrid_rule50",
+            default: Some(5),
+            min:     Some(1),
+            max:     Some(100),
+            func:    opt_hybrid_rule50,
+        });
+
         out
     }
 }
@@ -159,5 +189,44 @@ fn opt_lmr_ply_const(sp: &mut SParams, cfg: &mut ExConfig, val: i64) {
     sp.lmr_ply_const = val as i16;
 }
 
+fn opt_hybrid_threshold(sp: &mut SParams, cfg: &mut ExConfig, val: i64) {
+    sp.hybrid_threshold = val as Score;
+}
+
+fn opt_hybrid_phase(sp: &mut SParams, cfg: &mut ExConfig, val: i64) {
+    sp.hybrid_phase = val as Score;
+}
+
+fn opt_hybrid_rule50(sp: &mut SParams, cfg: &mut ExConfig, val: i64) {
+    sp.hybrid_rule50 = val as i16;
+}
 
+#[cfg(test)]
+mod tests {
+    use crate::explore::*;
+    use crate::tables::*;
+    use crate::types::*;
+    use crate::hashing::Zobrist;
+    use crate::timer::TimeSettings;
+
+    #[test]
+    fn hybrid_options_clear_eval_cache() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let mut ex = Explorer::new(White, g, 1, TimeSettings::new_f64(0.0, 1.0));
+
+        let zb = Zobrist(0x1234_5678_9abc_def0);
+        for (name,val,cleared) in [
+            ("lmr_min_ply",      "4",   false),
+            ("hybrid_threshold", "400", false),
+            ("hybrid_threshold", "900", true),
+            ("hybrid_phase",     "50",  true),
+            ("hybrid_rule50",    "10",  true),
+        ] {
+            ex.eval_cache.insert(zb, 17);
+            ex.set_option(name, val);
+            assert_eq!(ex.eval_cache.probe(zb).is_none(), cleared, "{} = {}", name, val);
+        }
+    }
 
+}
@@ -1,32 +1,194 @@
-
 use crate::types::*;
 use crate::tables::*;
 
 use std::str::FromStr;
-use nom::{
-    IResult,
-    bytes::complete::tag,
-    character::complete::{one_of,alphanumeric1},
-};
+
+/// FEN field, for error reporting
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum FenField {
+    Placement,
+    SideToMove,
+    Castling,
+    EnPassant,
+    Halfmove,
+    Fullmove,
+}
+
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub enum FenError {
+    Missing              (FenField),
+    /// rank as written, 8 first
+    BadPiece             { rank: u8, ch: char },
+    RankLength           { rank: u8, len: usize },
+    RankCount            (usize),
+    KingCount            { side: Color, count: u32 },
+    PawnOnBackRank       (Coord),
+    BadSideToMove        (String),
+    BadCastling          (String),
+    CastlingRights       { side: Color, kingside: bool },
+    BadEnPassant         (String),
+    ImpossibleEnPassant  (Coord),
+    BadCounter           { field: FenField, s: String },
+    TrailingInput        (String),
+    OpponentInCheck      (Color),
+}
+
+/// Strict rejects anything that isn't a legal position.
+/// Lenient drops castling rights and en passant squares that don't match the board,
+/// allows the side not to move to be in check, and ignores trailing input such as EPD ops.
+/// Both modes reject positions the engine can't handle: bad syntax, king counts and
+/// pawns on the back ranks.
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum FenMode {
+    Strict,
+    Lenient,
+}
+
+impl FenError {
+    pub fn field(&self) -> FenField {
+        use self::FenError::*;
+        match self {
+            Missing(field)                  => *field,
+            BadPiece { .. }                 => FenField::Placement,
+            RankLength { .. }               => FenField::Placement,
+            RankCount(_)                    => FenField::Placement,
+            KingCount { .. }                => FenField::Placement,
+            PawnOnBackRank(_)               => FenField::Placement,
+            BadSideToMove(_)                => FenField::SideToMove,
+            OpponentInCheck(_)              => FenField::SideToMove,
+            BadCastling(_)                  => FenField::Castling,
+            CastlingRights { .. }           => FenField::Castling,
+            BadEnPassant(_)                 => FenField::EnPassant,
+            ImpossibleEnPassant(_)          => FenField::EnPassant,
+            BadCounter { field, .. }        => *field,
+            TrailingInput(_)                => FenField::Fullmove,
+        }
+    }
+}
+
+impl std::fmt::Display for FenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use self::FenError::*;
+        let reason = match self {
+            Missing(_)                      => format!("missing"),
+            BadPiece { rank, ch }           => format!("bad piece '{}' on rank {}", ch, rank),
+            RankLength { rank, len }        => format!("rank {} has {} squares", rank, len),
+            RankCount(n)                    => format!("{} ranks", n),
+            KingCount { side, count }       => format!("{:?} has {} kings", side, count),
+            PawnOnBackRank(sq)              => format!("pawn on back rank at {:?}", sq),
+            BadSideToMove(s)                => format!("bad side to move '{}'", s),
+            BadCastling(s)                  => format!("bad castling rights '{}'", s),
+            CastlingRights { side, kingside } => format!(
+                "{:?} {} castling, but king or rook has moved",
+                side, if *kingside { "kingside" } else { "queenside" }),
+            BadEnPassant(s)                 => format!("bad en passant square '{}'", s),
+            ImpossibleEnPassant(sq)         => format!("impossible en passant square {:?}", sq),
+            BadCounter { s, .. }            => format!("bad counter '{}'", s),
+            TrailingInput(s)                => format!("trailing input '{}'", s),
+            OpponentInCheck(side)           => format!("{:?} is not to move, but is in check", side),
+        };
+        write!(f, "{:?}: {}", self.field(), reason)
+    }
+}
+
+impl std::error::Error for FenError {}
 
 impl Game {
 
+    /// Lenient, None on any FenError
     pub fn from_fen(ts: &Tables, s: &str) -> Option<Game> {
+        Self::from_fen_mode(ts, s, FenMode::Lenient).ok()
+    }
+
+    pub fn from_fen_strict(ts: &Tables, s: &str) -> Result<Game, FenError> {
+        Self::from_fen_mode(ts, s, FenMode::Strict)
+    }
+
+    pub fn from_fen_mode(ts: &Tables, s: &str, mode: FenMode) -> Result<Game, FenError> {
+        let strict = mode == FenMode::Strict;
+
+        let mut fields = s.split_ascii_whitespace();
+        let mut next = |field| fields.next().ok_or(FenError::Missing(field));
+
+        let ss        = parse_piece_lines(next(FenField::Placement)?)?;
+        let side      = parse_side(next(FenField::SideToMove)?)?;
+        let mut castle = parse_castle(next(FenField::Castling)?)?;
+        let mut ep    = parse_enpassant(next(FenField::EnPassant)?)?;
 
-        let (s,ss) = parse_piece_lines(&s).unwrap();
-        // eprintln!("ss = {:?}", ss);
+        /// Counters are optional, as in EPD
+        let mut halfmove = 0;
+        if let Ok(hm) = next(FenField::Halfmove) {
+            match Depth::from_str(hm) {
+                Ok(hm) => {
+                    halfmove = hm;
+                    match next(FenField::Fullmove) {
+                        Ok(fm) => if u32::from_str(fm).is_err() && strict {
+                            return Err(FenError::BadCounter { field: FenField::Fullmove, s: fm.to_string() });
+                        },
+                        Err(e) => if strict { return Err(e); },
+                    }
+                },
+                Err(_) if strict => {
+                    return Err(FenError::BadCounter { field: FenField::Halfmove, s: hm.to_string() });
+                },
+                Err(_) => {},
+            }
+        }
+        if strict {
+            if let Ok(rest) = next(FenField::Fullmove) {
+                return Err(FenError::TrailingInput(rest.to_string()));
+            }
+        }
 
-        let (s,side) = parse_side(&s).unwrap();
-        // let (s,castle) = parse_castle(&s).unwrap();
-        // let (s,ep) = parse_enpassant(&s).unwrap();
+        /// Placement, checked in both modes
+        for side in [White,Black] {
+            let count = ss.iter().flatten().filter(|&&(_,pc,c)| pc == King && c == side).count();
+            if count != 1 {
+                return Err(FenError::KingCount { side, count: count as u32 });
+            }
+        }
+        if let Some(&(sq,_,_)) = ss.iter()
+            .flatten()
+            .find(|&&(sq,pc,_)| pc == Pawn && (sq.rank() == 0 || sq.rank() == 7)) {
+                return Err(FenError::PawnOnBackRank(sq));
+            }
 
-        let (s,castle) = parse_castle(&s).unwrap();
-        let (s,ep) = parse_enpassant(&s).unwrap();
+        let at = |c0: Coord| ss.iter().flatten().find(|&&(sq,_,_)| sq == c0).map(|&(_,pc,col)| (pc,col));
+
+        /// Castling rights need the king and rook on their starting squares
+        for col in [White,Black] {
+            let y = if col == White { 0 } else { 7 };
+            let (kingside,queenside) = castle.get_color(col);
+            let king = at(Coord::new(4,y)) == Some((King,col));
+            for (right,x,is_king) in [(kingside,7,true),(queenside,0,false)] {
+                if right && !(king && at(Coord::new(x,y)) == Some((Rook,col))) {
+                    if strict {
+                        return Err(FenError::CastlingRights { side: col, kingside: is_king });
+                    } else if is_king {
+                        castle.set_king(col, false);
+                    } else {
+                        castle.set_queen(col, false);
+                    }
+                }
+            }
+        }
 
-        let (s,halfmove) = parse_halfmove_fullmove(&s).unwrap_or((s,0));
+        /// En passant square must be behind a pawn that just double pushed
+        if let Some(sq) = ep {
+            let (y,dy) = if side == White { (5,-1) } else { (2,1) };
+            let possible = sq.rank() == y
+                && at(sq).is_none()
+                && at(Coord::new(sq.file(), (y as i8 - dy) as u8)).is_none()
+                && at(Coord::new(sq.file(), (y as i8 + dy) as u8)) == Some((Pawn,!side));
+            if !possible {
+                if strict {
+                    return Err(FenError::ImpossibleEnPassant(sq));
+                }
+                ep = None;
+            }
+        }
 
-        let mut g = build_from_fen(ts, ss, side, castle, ep, halfmove);
-        // g.recalc_gameinfo_mut();
+        let mut g = build_from_fen(ts, &ss, side, castle, ep, halfmove);
 
         let _ = g.recalc_gameinfo_mut(&ts);
         let _ = g.init_gameinfo_mut(&ts);
@@ -34,13 +196,21 @@ impl Game {
         g.zobrist = Zobrist::new(&ts, &g);
         g.pawn_zb = Zobrist::new_pawns(ts, &g);
 
-        Some(g)
+        if strict {
+            let ksq = g.get(King, !side).bitscan();
+            if g.find_attacks_by_side(ts, ksq, side, false) {
+                return Err(FenError::OpponentInCheck(!side));
+            }
+        }
+
+        Ok(g)
     }
 
 }
+
 fn build_from_fen(
     ts:         &Tables,
-    v:          Vec<Vec<Option<(Piece,Color)>>>,
+    v:          &[Vec<(Coord,Piece,Color)>],
     col:        Color,
     castling:   Castling,
     ep:         Option<Coord>,
@@ -49,122 +219,83 @@ fn build_from_fen(
     // let mut out = Game::empty();
     let mut out = Game::default();
 
-    for (rank,y) in v.iter().rev().zip(0..8) {
-        for (sq,x) in rank.iter().zip(0..8) {
-            match sq {
-                Some((p,c)) => {
-                    out.insert_piece_mut_unchecked_nohash(ts, Coord::new(x,y), *p, *c);
-                },
-                None => {},
-            }
-        }
+    for &(sq,p,c) in v.iter().flatten() {
+        out.insert_piece_mut_unchecked_nohash(ts, sq, p, c);
     }
     out.state.side_to_move = col;
     out.state.castling = castling;
     out.state.en_passant = ep;
+    out.halfmove = halfmove;
     out
 }
 
-fn parse_halfmove_fullmove(s: &str) -> IResult<&str, Depth> {
-    let (s,_) = tag(" ")(s)?;
-    let (s,hm) = nom::character::complete::digit1(s)?;
-    let (s,_) = tag(" ")(s)?;
-    let (s,fm) = nom::character::complete::digit1(s)?;
-
-    let hm: Depth = Depth::from_str(&hm).unwrap();
-    let fm: Depth = Depth::from_str(&fm).unwrap();
-
-    Ok((s,hm))
+fn parse_side(s: &str) -> Result<Color, FenError> {
+    match s {
+        "w" => Ok(White),
+        "b" => Ok(Black),
+        _   => Err(FenError::BadSideToMove(s.to_string())),
+    }
 }
 
-fn parse_side(s: &str) -> IResult<&str, Color> {
-    let (s,_) = tag(" ")(s)?;
-    let (s,c) = one_of("w,b")(s)?;
+fn parse_castle(s: &str) -> Result<Castling, FenError> {
+    let mut out = Castling::new_with(false, false);
+    if s == "-" { return Ok(out); }
 
-    match c {
-        'w' => Ok((s,White)),
-        'b' => Ok((s,Black)),
-        _   => panic!(),
+    for c in s.chars() {
+        match c {
+            'K' => out.set_king(White,true),
+            'Q' => out.set_queen(White,true),
+            'k' => out.set_king(Black,true),
+            'q' => out.set_queen(Black,true),
+            _   => return Err(FenError::BadCastling(s.to_string())),
+        }
     }
+    Ok(out)
 }
 
-fn parse_castle(s: &str) -> IResult<&str, Castling> {
-    let (s,_) = tag(" ")(s)?;
-
-    let (s,cs) = nom::branch::alt((
-        nom::multi::many1(one_of("-")),
-        nom::multi::many1(one_of("KQkq")),
-    ))(s)?;
-
-    match cs.get(0) {
-        Some('-') => Ok((s,Castling::new_with(false, false))),
-        None      => panic!("parse_castle?"),
-        _         => {
-            let mut out = Castling::new_with(false, false);
-            if cs.contains(&'K') { out.set_king(White,true); }
-            if cs.contains(&'Q') { out.set_queen(White,true); }
-            if cs.contains(&'k') { out.set_king(Black, true); }
-            if cs.contains(&'q') { out.set_queen(Black,true); }
-            Ok((s,out))
-        },
-    }
-}
+fn parse_enpassant(s: &str) -> Result<Option<Coord>, FenError> {
+    if s == "-" { return Ok(None); }
 
-fn parse_enpassant(s: &str) -> IResult<&str, Option<Coord>> {
-    let (s,_) = tag(" ")(s)?;
-    let (s,cs) = nom::branch::alt((
-        tag("-"),
-        alphanumeric1,
-    ))(s)?;
-
-    match cs {
-        "-" => {
-            Ok((s,None))
-        },
-        _   => {
-            Ok((s, Some(Coord::from_str(cs).expect("parse_enpassant"))))
-            // unimplemented!()
-        },
+    let cs = s.as_bytes();
+    if cs.len() != 2 || !(b'a'..=b'h').contains(&cs[0]) || !(b'1'..=b'8').contains(&cs[1]) {
+        return Err(FenError::BadEnPassant(s.to_string()));
     }
+    Ok(Some(Coord::new(cs[0] - b'a', cs[1] - b'1')))
 }
 
-fn parse_piece_line(s: &str) -> Vec<Option<(Piece,Color)>> {
-    let mut out = vec![];
-    for c in s.chars() {
-        let x = match parse_piece(c) {
-            Ok((p,c)) => {
-                out.push(Some((p,c)));
-            },
-            Err(n) => {
-                for _ in 0..n {
-                    out.push(None);
-                }
-            },
-        };
+/// Returns (square, piece, color) for each occupied square
+fn parse_piece_lines(s: &str) -> Result<Vec<Vec<(Coord,Piece,Color)>>, FenError> {
+    let lines = s.split('/').collect::<Vec<&str>>();
+    if lines.len() != 8 {
+        return Err(FenError::RankCount(lines.len()));
     }
-    out
-}
-
-fn parse_piece_lines(s0: &str) -> IResult<&str, Vec<Vec<Option<(Piece,Color)>>>> {
-
-    let (s, ss) = nom::multi::separated_list1(
-        tag("/"),
-        // nom::character::complete::one_of("0123456789PNBRQKpnbrqk"),
-        alphanumeric1,
-        )(s0)?;
 
     let mut out = vec![];
-    for line in ss.iter() {
-        let xs = parse_piece_line(line);
+    for (line,y) in lines.iter().zip((0..8).rev()) {
+        let mut xs = vec![];
+        let mut x = 0;
+        for c in line.chars() {
+            match parse_piece(c) {
+                Some(Ok((p,col))) => {
+                    if x < 8 { xs.push((Coord::new(x as u8,y),p,col)); }
+                    x += 1;
+                },
+                Some(Err(n)) => x += n as usize,
+                None         => return Err(FenError::BadPiece { rank: y + 1, ch: c }),
+            }
+        }
+        if x != 8 {
+            return Err(FenError::RankLength { rank: y + 1, len: x });
+        }
         out.push(xs);
     }
-    Ok((s, out))
+    Ok(out)
 }
 
-fn parse_piece(c: char) -> std::result::Result<(Piece,Color), u8> {
-    // nom::character::complete::one_of("0123456789PNBRQKpnbrqk")(s)
-    if c.is_digit(10) {
-        Err(u8::from_str(&format!("{}", c)).unwrap())
+/// Some(Err(n)) for n empty squares
+fn parse_piece(c: char) -> Option<std::result::Result<(Piece,Color), u8>> {
+    if ('1'..='8').contains(&c) {
+        Some(Err(c as u8 - b'0'))
     } else {
         let col = if c.is_ascii_uppercase() { White } else { Black };
         let p = match c.to_ascii_lowercase() {
@@ -174,10 +305,161 @@ fn parse_piece(c: char) -> std::result::Result<(Piece,Color), u8> {
             'r' => Rook,
             'q' => Queen,
             'k' => King,
-            _ => panic!(),
+            _   => return None,
         };
-        Ok((p,col))
+        Some(Ok((p,col)))
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR";
+
+    /// (fen, strict, lenient), None for Ok
+    fn cases() -> Vec<(String, Option<FenError>, Option<FenError>)> {
+        use self::FenError::*;
+        let both = |fen: &str, e: FenError| (fen.to_string(), Some(e.clone()), Some(e));
+        let strict = |fen: &str, e: FenError| (fen.to_string(), Some(e), None);
+        vec![
+            /// Placement
+            both("rnbqkbnr/ppppxppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                 BadPiece { rank: 7, ch: 'x' }),
+            both("rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                 BadPiece { rank: 6, ch: '9' }),
+            both("rnbqkbnr/ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                 RankLength { rank: 7, len: 7 }),
+            both("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNRR w KQkq - 0 1",
+                 RankLength { rank: 1, len: 9 }),
+            both("rnbqkbnr/pppppppp/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                 RankCount(7)),
+            both("8/8/8/8/8/8/8/4K3 w - - 0 1",
+                 KingCount { side: Black, count: 0 }),
+            both("4k3/8/8/8/8/8/8/3KK3 w - - 0 1",
+                 KingCount { side: White, count: 2 }),
+            both("P3k3/8/8/8/8/8/8/4K3 w - - 0 1",
+                 PawnOnBackRank(Coord::new(0,7))),
+            both("4k3/8/8/8/8/8/8/4K2p w - - 0 1",
+                 PawnOnBackRank(Coord::new(7,0))),
+            /// Castling rights without the king or rook in place
+            strict("4k3/8/8/8/8/8/8/4K3 w KQkq - 0 1",
+                   CastlingRights { side: White, kingside: true }),
+            strict("r3k2r/8/8/8/8/8/8/R3K3 w K - 0 1",
+                   CastlingRights { side: White, kingside: true }),
+            strict("r3k2r/8/8/8/8/8/8/R2K3R w Q - 0 1",
+                   CastlingRights { side: White, kingside: false }),
+            strict("r4k1r/8/8/8/8/8/8/R3K2R w KQq - 0 1",
+                   CastlingRights { side: Black, kingside: false }),
+            /// En passant
+            strict("4k3/8/8/8/8/8/8/4K3 w - e6 0 1",
+                   ImpossibleEnPassant(Coord::new(4,5))),
+            strict("4k3/8/8/4p3/8/8/8/4K3 w - e3 0 1",
+                   ImpossibleEnPassant(Coord::new(4,2))),
+            strict("4k3/8/4p3/4p3/8/8/8/4K3 w - e6 0 1",
+                   ImpossibleEnPassant(Coord::new(4,5))),
+            both("4k3/8/8/4p3/8/8/8/4K3 w - e9 0 1",
+                 BadEnPassant("e9".to_string())),
+            /// Side not to move in check
+            strict("4k3/8/8/8/8/8/8/4R1K1 w - - 0 1",
+                   OpponentInCheck(Black)),
+            strict("4k3/8/8/8/8/5n2/8/6K1 b - - 0 1",
+                   OpponentInCheck(White)),
+            /// Truncated and garbage input
+            both("", Missing(FenField::Placement)),
+            both("   ", Missing(FenField::Placement)),
+            both(START, Missing(FenField::SideToMove)),
+            both(&format!("{} w", START), Missing(FenField::Castling)),
+            both(&format!("{} w KQkq", START), Missing(FenField::EnPassant)),
+            strict(&format!("{} w KQkq - 0", START), Missing(FenField::Fullmove)),
+            strict(&format!("{} w KQkq - x 1", START),
+                   BadCounter { field: FenField::Halfmove, s: "x".to_string() }),
+            strict(&format!("{} w KQkq - 0 x", START),
+                   BadCounter { field: FenField::Fullmove, s: "x".to_string() }),
+            strict(&format!("{} w KQkq - 0 1 bm e4;", START),
+                   TrailingInput("bm".to_string())),
+            both(&format!("{} x KQkq - 0 1", START), BadSideToMove("x".to_string())),
+            both(&format!("{} w KQkx - 0 1", START), BadCastling("KQkx".to_string())),
+            both("not a fen at all", RankCount(1)),
+            both("////////", RankCount(9)),
+            both("ééééééé/8/8/8/8/8/8/8 w - - 0 1", BadPiece { rank: 8, ch: 'é' }),
+        ]
+    }
+
+    #[test]
+    fn fen_errors() {
+        let ts = Tables::new();
+        for (fen,strict,lenient) in cases() {
+            for (mode,expected) in [(FenMode::Strict,strict),(FenMode::Lenient,lenient)] {
+                let result = Game::from_fen_mode(&ts, &fen, mode);
+                match expected {
+                    None    => assert!(result.is_ok(), "{:?} {:?}: {:?}", mode, fen, result.err()),
+                    Some(e) => {
+                        let err = result.err();
+                        assert_eq!(err.as_ref(), Some(&e), "{:?} {:?}", mode, fen);
+                        assert_eq!(err.unwrap().field(), e.field(), "{:?} {:?}", mode, fen);
+                    },
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fen_error_fields() {
+        let ts = Tables::new();
+        let field = |fen: &str| Game::from_fen_strict(&ts, fen).err().map(|e| e.field());
+        assert_eq!(field("rnbqkbnr/ppppxppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+                   Some(FenField::Placement));
+        assert_eq!(field("4k3/8/8/8/8/8/8/4R1K1 w - - 0 1"), Some(FenField::SideToMove));
+        assert_eq!(field("4k3/8/8/8/8/8/8/4K3 w KQkq - 0 1"), Some(FenField::Castling));
+        assert_eq!(field("4k3/8/8/8/8/8/8/4K3 w - e6 0 1"), Some(FenField::EnPassant));
+        assert_eq!(field(&format!("{} w KQkq - x 1", START)), Some(FenField::Halfmove));
+        assert_eq!(field(&format!("{} w KQkq - 0 1 x", START)), Some(FenField::Fullmove));
+    }
+
+    /// Lenient drops what doesn't match the board instead of failing
+    #[test]
+    fn fen_lenient_repairs() {
+        let ts = Tables::new();
+
+        let g = Game::from_fen(&ts, "4k3/8/8/8/8/8/8/4K3 w KQkq - 0 1").unwrap();
+        assert!(!g.state.castling.any());
+
+        let g = Game::from_fen(&ts, "r3k2r/8/8/8/8/8/8/R3K3 w KQkq - 0 1").unwrap();
+        assert_eq!(g.state.castling.get_color(White), (false,true));
+        assert_eq!(g.state.castling.get_color(Black), (true,true));
+
+        let g = Game::from_fen(&ts, "4k3/8/8/8/8/8/8/4K3 w - e6 0 1").unwrap();
+        assert_eq!(g.state.en_passant, None);
+
+        let g = Game::from_fen_strict(&ts, "4k3/8/8/3Pp3/8/8/8/4K3 w - e6 0 1").unwrap();
+        assert_eq!(g.state.en_passant, Some(Coord::new(4,5)));
+    }
+
+    /// Every prefix of a valid FEN, and random printable bytes, give Ok or Err
+    #[test]
+    fn fen_truncated_no_panic() {
+        use rand::prelude::*;
+        let ts = Tables::new();
+        let fen = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1";
+        for n in 0..fen.len() {
+            let _ = Game::from_fen_mode(&ts, &fen[..n], FenMode::Strict);
+            let _ = Game::from_fen_mode(&ts, &fen[..n], FenMode::Lenient);
+        }
+
+        let mut rng = StdRng::seed_from_u64(1234);
+        let alphabet = b"pnbrqkPNBRQK012345678 /-wbKQkqa-h";
+        for _ in 0..2000 {
+            let mut s: Vec<u8> = fen.bytes().collect();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..s.len());
+                s[i] = alphabet[rng.gen_range(0..alphabet.len())];
+            }
+            let s = String::from_utf8(s).unwrap();
+            let _ = Game::from_fen_mode(&ts, &s, FenMode::Strict);
+            let _ = Game::from_fen_mode(&ts, &s, FenMode::Lenient);
+        }
+    }
+
+}
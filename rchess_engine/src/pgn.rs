@@ -218,6 +218,7 @@ pub fn process_pgns_td(
                 opening: vec![],
                 moves,
                 result: pgn.result,
+                start: None,
             };
             td
         }).collect::<Vec<_>>()
@@ -278,6 +279,7 @@ pub fn _process_pgn_td(
         opening: vec![],
         moves,
         result: pgn.result,
+        start: None,
     };
     td
 }
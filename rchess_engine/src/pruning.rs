@@ -6,6 +6,7 @@ use crate::types::*;
 use crate::tables::*;
 use crate::evaluate::*;
 use crate::explore::*;
+use crate::endgame::helpers::normalize;
 
 /// Null Move
 impl ExHelper {
@@ -66,4 +67,24 @@ impl ExHelper {
 
 }
 
+/// KPK bitbase
+impl ExHelper {
+
+    /// Drawn KPK positions, the eval alone can't tell them apart from slow wins
+    pub fn prune_kpk_draw(&self, ts: &Tables, g: &Game) -> bool {
+        if g.all_occupied().popcount() != 3 || g.get_piece(Pawn).popcount() != 1 {
+            return false;
+        }
+
+        let strong = if g.get(Pawn, White).is_not_empty() { White } else { Black };
+
+        let ksq_strong = normalize(strong, g.get(King, strong).bitscan());
+        let ksq_weak   = normalize(strong, g.get(King, !strong).bitscan());
+        let pawn       = normalize(strong, g.get(Pawn, strong).bitscan());
+        let stm = if g.state.side_to_move == strong { White } else { Black };
+
+        !ts.kpk_bitbase.probe(ksq_strong, pawn, ksq_weak, stm)
+    }
+
+}
 
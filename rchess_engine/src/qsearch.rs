@@ -109,10 +109,10 @@ impl ExHelper {
         let (alpha,beta) = (alpha + 200,beta - 200);
         self.game = g.clone();
         self.side = g.state.side_to_move;
-        let mut stack = ABStack::new();
+        let mut stack = ABStack::new_with_plies();
         // self.qsearch(ts, g, (0,0), (alpha,beta), &mut stack, stats, ABNodeType::Root)
         // self.qsearch2::<{ABNodeType::Root}>(ts, g, (0,0), (alpha,beta), &mut stack, stats)
-        unimplemented!()
+        self.qsearch::<{ABNodeType::PV}>(ts, g, (0,0,0), (alpha,beta), &mut stack, stats)
     }
 
     pub fn qsearch_once(
@@ -588,7 +588,7 @@ impl ExHelper {
         let mut allow_stand_pat = true;
 
         /// XXX: for some reason, not evaluating when in check gives invalid mates
-        let stand_pat = self.evaluate(ts, stats, g, ply, true);
+        let stand_pat = self.evaluate_cached(ts, stats, g, ply, true);
 
         // /// XXX: should be correct, need to verify
         // let stand_pat = if g.state.in_check {
@@ -83,6 +83,11 @@ mod ss {
 
         pub eval_nnue:          u32,
         pub eval_classical:     u32,
+        /// Specialized endgame evals, instead of NNUE
+        pub eval_endgame:       u32,
+
+        pub ec_hits:            u32,
+        pub ec_misses:          u32,
 
         pub tt_hits:            u32,
         pub tt_halfmiss:        u32,
@@ -110,6 +115,7 @@ mod ss {
 
         pub null_prunes:        u32,
         pub fut_prunes:         u32,
+        pub kpk_draws:          u32,
         pub lmrs:               u32,
         pub sing_exts:          SSSingularExtensions,
 
@@ -210,6 +216,18 @@ mod ss {
             // println!("qt_misses    = {}", Self::_print(self.qt_misses as i32));
 
             // eprintln!("nodes/qt nodes = {:.1?}", self.qt_nodes as f64 / self.nodes as f64);
+            let evals = (self.eval_nnue + self.eval_classical + self.eval_endgame).max(1) as f64;
+            eprintln!("evals nnue/classical/endgame = {}, {}, {}, ({:.1}% nnue)",
+                      pretty_print_si(self.eval_nnue as i64),
+                      pretty_print_si(self.eval_classical as i64),
+                      pretty_print_si(self.eval_endgame as i64),
+                      self.eval_nnue as f64 / evals * 100.0);
+
+            eprintln!("eval cache hits/miss = {}, {}, ({:.1}% hits)",
+                      pretty_print_si(self.ec_hits as i64),
+                      pretty_print_si(self.ec_misses as i64),
+                      self.ec_hits as f64 / (self.ec_hits + self.ec_misses).max(1) as f64 * 100.0);
+
             eprintln!("qt nodes    = {}", pretty_print_si(self.qt_nodes as i64));
             eprintln!("q_max_depth = {:?}", self.q_max_depth.0);
             // eprintln!("q_tt_returns = {}", pretty_print_si(self.qs_tt_returns as i64));
@@ -252,6 +270,7 @@ mod ss {
                      self.fut_prunes, self.null_prunes as f64 / self.nodes as f64);
             println!("lmrs        = {:?}, {:.3}",
                      self.lmrs, self.lmrs as f64 / self.nodes as f64);
+            println!("kpk draws   = {:?}", self.kpk_draws);
 
             // println!("fut prunes    = {:?}", self.fut_prunes);
             // println!("counter_moves = {:?}", self.counter_moves);
@@ -396,5 +396,70 @@ mod old {
 
     }
 
-}
+    /// Accumulator for one perspective and king square, with the pieces it was computed from
+    #[derive(Debug,Clone)]
+    pub struct NNRefreshEntry {
+        pub accum:      Aligned<A32,[i16; 1024]>,
+        pub psqt:       [i32; 8],
+        pub pieces:     [[BitBoard; 6]; 2],
+        pub valid:      bool,
+    }
+
+    /// "Finny tables": on a king move, refresh from the cached accumulator for the new
+    /// king square and only apply the pieces that changed since then
+    #[derive(Debug,Clone)]
+    pub struct NNRefreshTable {
+        entries:        Vec<NNRefreshEntry>,
+    }
+
+    impl NNRefreshTable {
+        pub fn new() -> Self {
+            let entry = NNRefreshEntry {
+                accum:  Aligned([0; 1024]),
+                psqt:   [0; 8],
+                pieces: [[BitBoard::empty(); 6]; 2],
+                valid:  false,
+            };
+            Self { entries: vec![entry; 2 * 64] }
+        }
+
+        /// Must be called whenever the weights change
+        pub fn clear(&mut self) {
+            self.entries.iter_mut().for_each(|e| e.valid = false);
+        }
+
+        pub fn get_mut(&mut self, persp: Color, king_sq: Coord) -> &mut NNRefreshEntry {
+            &mut self.entries[persp.fold(0, 64) + king_sq.inner() as usize]
+        }
+    }
 
+    impl NNRefreshEntry {
+
+        /// Features added and removed since the entry was stored, and store the new pieces
+        pub fn diff(
+            &mut self,
+            g:           &Game,
+            persp:       Color,
+            removed: &mut ArrayVec<NNIndex, 32>,
+            added:   &mut ArrayVec<NNIndex, 32>,
+        ) {
+            let king_sq = g.get(King,persp).bitscan();
+
+            for side in [White,Black] {
+                for pc in Piece::iter_pieces() {
+                    let prev = self.pieces[side][pc.index()];
+                    let cur  = g.get(pc,side);
+                    (prev & !cur).into_iter().for_each(|sq| {
+                        removed.push(crate::sf_compat::NNUE4::make_index_half_ka_v2(king_sq, persp, pc, side, sq));
+                    });
+                    (cur & !prev).into_iter().for_each(|sq| {
+                        added.push(crate::sf_compat::NNUE4::make_index_half_ka_v2(king_sq, persp, pc, side, sq));
+                    });
+                    self.pieces[side][pc.index()] = cur;
+                }
+            }
+        }
+
+    }
+
+}
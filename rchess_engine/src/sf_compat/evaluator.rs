@@ -0,0 +1,195 @@
+
+use crate::types::*;
+use crate::sf_compat::{NNUE4,NNUEError,PerspectiveNet};
+
+use std::path::Path;
+
+/// What the search needs from a network
+pub trait NNEvaluator {
+    /// Full refresh, also drops anything left from a previous search
+    fn reset(&mut self, g: &Game);
+
+    /// g2 is the position after mv
+    fn make_move(&mut self, g2: &Game, mv: Move);
+
+    fn unmake_move(&mut self);
+
+    /// Relative to the side to move
+    fn evaluate(&mut self, g: &Game) -> Score;
+
+    fn description(&self) -> &str;
+}
+
+/// Network used by the search, the type is detected from the file header
+#[derive(Debug,Clone)]
+pub enum NNEval {
+    /// HalfKAv2, Stockfish compatible. Boxed, it's much larger than the other nets
+    NNUE4(Box<NNUE4>),
+    Perspective(PerspectiveNet),
+}
+
+impl NNEval {
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, NNUEError> {
+        let header = NNUE4::read_header(&path)?;
+        match header.version {
+            NNUE4::VERSION          => Ok(Self::NNUE4(Box::new(NNUE4::read_nnue(path)?))),
+            PerspectiveNet::VERSION => Ok(Self::Perspective(PerspectiveNet::read(path)?)),
+            version                 => Err(NNUEError::Version { found: version, expected: NNUE4::VERSION }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NNUE4(_)       => "HalfKAv2",
+            Self::Perspective(_) => "Perspective",
+        }
+    }
+
+}
+
+impl From<NNUE4> for NNEval {
+    fn from(nn: NNUE4) -> Self { Self::NNUE4(Box::new(nn)) }
+}
+
+impl From<PerspectiveNet> for NNEval {
+    fn from(nn: PerspectiveNet) -> Self { Self::Perspective(nn) }
+}
+
+impl NNEvaluator for NNEval {
+    fn reset(&mut self, g: &Game) {
+        match self {
+            Self::NNUE4(nn)       => nn.reset(g),
+            Self::Perspective(nn) => nn.reset(g),
+        }
+    }
+
+    fn make_move(&mut self, g2: &Game, mv: Move) {
+        match self {
+            Self::NNUE4(nn)       => NNEvaluator::make_move(&mut **nn, g2, mv),
+            Self::Perspective(nn) => NNEvaluator::make_move(nn, g2, mv),
+        }
+    }
+
+    fn unmake_move(&mut self) {
+        match self {
+            Self::NNUE4(nn)       => nn.unmake_move(),
+            Self::Perspective(nn) => nn.unmake_move(),
+        }
+    }
+
+    fn evaluate(&mut self, g: &Game) -> Score {
+        match self {
+            Self::NNUE4(nn)       => NNEvaluator::evaluate(&mut **nn, g),
+            Self::Perspective(nn) => NNEvaluator::evaluate(nn, g),
+        }
+    }
+
+    fn description(&self) -> &str {
+        match self {
+            Self::NNUE4(nn)       => nn.description(),
+            Self::Perspective(nn) => nn.description(),
+        }
+    }
+}
+
+impl NNEvaluator for NNUE4 {
+    fn reset(&mut self, g: &Game) {
+        #[cfg(feature = "prev_accum")]
+        {
+            self.ft.accum.stack_copies.clear();
+            self.ft.accum.stack_delta.clear();
+            self.ft.reset_accum(g);
+        }
+        #[cfg(not(feature = "prev_accum"))]
+        self.ft.reset_feature_trans(g);
+    }
+
+    fn make_move(&mut self, g2: &Game, mv: Move) {
+        self.ft.make_move(g2, mv);
+    }
+
+    fn unmake_move(&mut self) {
+        self.ft.accum_pop();
+    }
+
+    fn evaluate(&mut self, g: &Game) -> Score {
+        NNUE4::evaluate(self, g, true)
+    }
+
+    fn description(&self) -> &str {
+        &self.desc
+    }
+}
+
+impl NNEvaluator for PerspectiveNet {
+    fn reset(&mut self, g: &Game) {
+        self.refresh(g);
+    }
+
+    fn make_move(&mut self, g2: &Game, mv: Move) {
+        self.push_move(g2, mv);
+    }
+
+    fn unmake_move(&mut self) {
+        self.pop_move();
+    }
+
+    fn evaluate(&mut self, g: &Game) -> Score {
+        PerspectiveNet::evaluate(self, g)
+    }
+
+    fn description(&self) -> &str {
+        &self.desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::*;
+    use rand::prelude::{StdRng,SeedableRng};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rchess_{}_{}.nnue", name, std::process::id()))
+    }
+
+    #[test]
+    fn nneval_detects_header_version() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+
+        let path = temp_path("halfkav2");
+        NNUE4::new_empty().write_nnue(&path).unwrap();
+        let nn = NNEval::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(nn, Ok(NNEval::NNUE4(_))));
+
+        let path = temp_path("perspective");
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        let mut nn0 = PerspectiveNet::new_random(16, &mut rng);
+        nn0.write(&path).unwrap();
+        let nn = NNEval::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut nn = match nn {
+            Ok(NNEval::Perspective(nn)) => nn,
+            nn                          => panic!("expected a perspective net, got {:?}", nn.map(|nn| nn.name())),
+        };
+        assert_eq!(nn.ft_weights, nn0.ft_weights);
+        assert_eq!(nn.out_bias, nn0.out_bias);
+        nn0.refresh(&g);
+        nn.refresh(&g);
+        assert_eq!(PerspectiveNet::evaluate(&nn, &g), PerspectiveNet::evaluate(&nn0, &g));
+
+        /// Anything else is rejected by version, not read as one or the other
+        let path = temp_path("unknown");
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&0x1234_5678u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 64]);
+        std::fs::write(&path, &bytes).unwrap();
+        let nn = NNEval::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(nn, Err(NNUEError::Version { found: 0x1234_5678, .. })));
+    }
+
+}
@@ -10,7 +10,7 @@ mod new {
     use crate::tables::MAX_SEARCH_PLY;
     use crate::types::*;
     use crate::sf_compat::accumulator::new::*;
-    use crate::sf_compat::{NNIndex,HALF_DIMS,NNUE4, NNStats};
+    use crate::sf_compat::{NNIndex,HALF_DIMS,NNUE4, NNStats, NNUEError};
 
     use std::io::{self, Read,BufReader, BufWriter};
     use std::fs::File;
@@ -42,11 +42,11 @@ mod new {
     impl NNFeatureTrans {
         // const HALF_DIMS: usize = 1024;
 
-        const DIMS_IN: usize = 64 * 11 * 64 / 2;
+        pub const DIMS_IN: usize = 64 * 11 * 64 / 2;
         const DIMS_OUT: usize = HALF_DIMS * 2;
 
-        const PSQT_BUCKETS: usize = 8;
-        const LAYER_STACKS: usize = 8;
+        pub const PSQT_BUCKETS: usize = 8;
+        pub const LAYER_STACKS: usize = 8;
 
         pub const HASH: u32 = 0x7f234cb8 ^ Self::DIMS_OUT as u32;
 
@@ -73,11 +73,13 @@ mod new {
             }
         }
 
-        pub fn read_parameters(&mut self, mut rdr: &mut BufReader<File>) -> io::Result<()> {
+        pub fn read_parameters(&mut self, mut rdr: &mut BufReader<File>) -> Result<(), NNUEError> {
             // println!("wat NNFeatureTrans");
 
             let hash = rdr.read_u32::<LittleEndian>()?;
-            assert_eq!(hash, Self::HASH);
+            if hash != Self::HASH {
+                return Err(NNUEError::FeatureHash { found: hash, expected: Self::HASH });
+            }
 
             for mut x in self.biases.iter_mut() {
                 *x = rdr.read_i16::<LittleEndian>()?;
@@ -1630,8 +1632,9 @@ mod old {
     use crate::sf_compat::accumulator::*;
     use crate::sf_compat::NNIndex;
 
-    use crate::sf_compat::{HALF_DIMS, NNUE4};
+    use crate::sf_compat::{HALF_DIMS, NNUE4, NNUEError};
     use crate::sf_compat::accumulator::NNAccum;
+    use crate::sf_compat::simd::{self, SimdLevel};
 
     use std::io::{self, Read,BufReader, BufWriter};
     use std::fs::File;
@@ -1658,19 +1661,24 @@ mod old {
 
         pub accum:          NNAccum,
 
+        /// None always does a full refresh
+        pub refresh_table:  Option<Box<NNRefreshTable>>,
+
         pub stats:          NNStats,
 
+        pub simd:           SimdLevel,
+
     }
 
     /// Consts, Init
     impl NNFeatureTrans {
         // const HALF_DIMS: usize = 1024;
 
-        const DIMS_IN: usize = 64 * 11 * 64 / 2;
+        pub const DIMS_IN: usize = 64 * 11 * 64 / 2;
         const DIMS_OUT: usize = HALF_DIMS * 2;
 
-        const PSQT_BUCKETS: usize = 8;
-        const LAYER_STACKS: usize = 8;
+        pub const PSQT_BUCKETS: usize = 8;
+        pub const LAYER_STACKS: usize = 8;
 
         pub const HASH: u32 = 0x7f234cb8 ^ Self::DIMS_OUT as u32;
 
@@ -1687,15 +1695,21 @@ mod old {
 
                 accum:          NNAccum::new(),
 
+                refresh_table:  Some(Box::new(NNRefreshTable::new())),
+
                 stats:          NNStats::default(),
+
+                simd:           SimdLevel::Scalar,
             }
         }
 
-        pub fn read_parameters(&mut self, mut rdr: &mut BufReader<File>) -> io::Result<()> {
+        pub fn read_parameters(&mut self, mut rdr: &mut BufReader<File>) -> Result<(), NNUEError> {
             // println!("wat NNFeatureTrans");
 
             let hash = rdr.read_u32::<LittleEndian>()?;
-            assert_eq!(hash, Self::HASH);
+            if hash != Self::HASH {
+                return Err(NNUEError::FeatureHash { found: hash, expected: Self::HASH });
+            }
 
             for mut x in self.biases.iter_mut() {
                 *x = rdr.read_i16::<LittleEndian>()?;
@@ -1709,6 +1723,8 @@ mod old {
                 *x = rdr.read_i32::<LittleEndian>()?;
             }
 
+            self.clear_refresh_table();
+
             // eprintln!("FT Read");
             // eprintln!("HALF_DIMS = {:?}", HALF_DIMS);
             // eprintln!("Self::DIMS_IN = {:?}", Self::DIMS_IN);
@@ -1781,458 +1797,43 @@ mod old {
 
     }
 
-    /// SIMD, AVX2
-    #[cfg(target_feature = "avx2")]
+    /// SIMD, dispatched at runtime on self.simd
     impl NNFeatureTrans {
 
-        const NUM_REGS: usize = 16; // AVX2
-        const NUM_REGS_PSQT: usize = 1; // AVX2
-
-        /// AVX2 = 256
-        const TILE_HEIGHT: usize = Self::NUM_REGS * std::mem::size_of::<safe_arch::m256i>() / 2;
-        /// AVX2 = 8
-        const TILE_HEIGHT_PSQT: usize = Self::NUM_REGS_PSQT * std::mem::size_of::<safe_arch::m256i>() / 4;
-
-        // #[no_panic]
-        #[cfg(feature = "nope")]
-        pub fn _update_accum_simd(&mut self, g: &Game, persp: Color) {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            unsafe {
-                let src: *const i16 = self.biases.as_ptr();
-                let dst: *mut i16 = self.accum.accum[persp].as_mut_ptr();
-                std::ptr::copy_nonoverlapping(src, dst, self.biases.len());
-            }
-
-            let mut active: ArrayVec<NNIndex, 32> = ArrayVec::default();
-            NNAccum::append_active(g, persp, &mut active);
-
-            let mut acc      = [m256i::default(); Self::NUM_REGS];
-            let mut acc_psqt = [m256i::default(); Self::NUM_REGS_PSQT];
-
-            for k in 0..HALF_DIMS / Self::TILE_HEIGHT {
-
-                let biases_tile: &[m256i] = unsafe {
-                    let bs = self.biases.get_unchecked(k * Self::TILE_HEIGHT..);
-                    cast_slice_to_m256i_unchecked(&bs)
-                };
-
-                unsafe {
-                    let src = biases_tile.as_ptr();
-                    let dst = acc.as_mut_ptr();
-                    std::ptr::copy_nonoverlapping(src, dst, Self::NUM_REGS);
-                }
-
-                for idx in active.iter() {
-                    let offset = HALF_DIMS * idx.0 + k * Self::TILE_HEIGHT;
-
-                    let column = unsafe {
-                        cast_slice_to_m256i_unchecked(&self.weights[offset..])
-                    };
-
-                    for i in 0..Self::NUM_REGS {
-                        acc[i] = add_i16_m256i(acc[i], column[i]);
-                    }
-                }
-
-                let acc_tile: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.accum[persp][k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m256i_mut_unchecked(xs)
-                };
-
-                for i in 0..Self::NUM_REGS {
-                    // vec_store(&mut accTile[k], acc[k]);
-                    store_m256i(&mut acc_tile[i], acc[i]);
-                }
-
-            }
-
-            for k in 0..Self::PSQT_BUCKETS / Self::TILE_HEIGHT_PSQT {
-                self.accum.psqt[persp].fill(0);
-
-                for idx in active.iter() {
-                    let offset = Self::PSQT_BUCKETS * idx.0 + k * Self::TILE_HEIGHT_PSQT;
-
-                    let column_psqt = unsafe {
-                        cast_slice_to_m256i_unchecked(&self.psqt_weights.get_unchecked(offset..))
-                    };
-
-                    for i in 0..Self::NUM_REGS_PSQT {
-                        unsafe {
-                            let x = add_i32_m256i(
-                                *acc_psqt.get_unchecked(i), *column_psqt.get_unchecked(i));
-                            acc_psqt[i] = x;
-                        }
-                    }
-
-                }
-
-                let acc_tile_psqt: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.psqt[persp][k * Self::TILE_HEIGHT_PSQT..];
-                    cast_slice_to_m256i_mut_unchecked(xs)
-                };
-
-                for i in 0..Self::NUM_REGS_PSQT {
-                    store_m256i(&mut acc_tile_psqt[i], acc_psqt[i]);
-                }
-
-            }
-
-        }
-
-        // #[cfg(feature = "nope")]
         pub fn _update_accum(&mut self, g: &Game, persp: Color) {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            assert!(self.biases.len() == self.accum.accum[persp].len());
-            self.accum.accum[persp].copy_from_slice(&self.biases);
-            // self.accum.accum[persp].copy_from_slice(self.biases.as_ref().as_ref());
-
-            let mut active = ArrayVec::default();
-            NNAccum::append_active(g, persp, &mut active);
-
-            let mut acc      = [m256i::default(); Self::NUM_REGS];
-            let mut acc_psqt = [m256i::default(); Self::NUM_REGS_PSQT];
-
-            for k in 0..HALF_DIMS / Self::TILE_HEIGHT {
-
-                let biases_tile: &[m256i] = unsafe {
-                    let bs = &self.biases[k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m256i(&bs)
-                };
-
-                acc[..Self::NUM_REGS].copy_from_slice(&biases_tile[..Self::NUM_REGS]);
-
-                for idx in active.iter() {
-                    let offset = HALF_DIMS * idx.0 + k * Self::TILE_HEIGHT;
-
-                    let column = unsafe { cast_slice_to_m256i(&self.weights[offset..]) };
-
-                    for i in 0..Self::NUM_REGS {
-                        acc[i] = add_i16_m256i(acc[i], column[i]);
-                    }
-                }
-
-                let acc_tile: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.accum[persp][k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m256i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS {
-                    // vec_store(&mut accTile[k], acc[k]);
-                    store_m256i(&mut acc_tile[i], acc[i]);
-                }
-
-            }
-
-            for k in 0..Self::PSQT_BUCKETS / Self::TILE_HEIGHT_PSQT {
-                self.accum.psqt[persp].fill(0);
-
-                for idx in active.iter() {
-                    let offset = Self::PSQT_BUCKETS * idx.0 + k * Self::TILE_HEIGHT_PSQT;
-
-                    let column_psqt = unsafe { cast_slice_to_m256i(&self.psqt_weights[offset..]) };
-
-                    for i in 0..Self::NUM_REGS_PSQT {
-                        acc_psqt[i] = add_i32_m256i(acc_psqt[i], column_psqt[i]);
-                    }
-                }
-
-                let acc_tile_psqt: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.psqt[persp][k * Self::TILE_HEIGHT_PSQT..];
-                    cast_slice_to_m256i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS_PSQT {
-                    store_m256i(&mut acc_tile_psqt[i], acc_psqt[i]);
-                }
-
-            }
-
-        }
-
-        pub fn _accum_inc_simd<const ADD: bool>(&mut self, persp: Color, idx: NNIndex) {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            let mut acc      = [m256i::default(); Self::NUM_REGS];
-
-            for k in 0..HALF_DIMS / Self::TILE_HEIGHT {
-                let acc_tile: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.accum[persp][k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m256i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS {
-                    acc[i] = load_m256i(&acc_tile[i]);
-                }
-
-                let offset = HALF_DIMS * idx.0 + k * Self::TILE_HEIGHT;
-                let column = unsafe { cast_slice_to_m256i(&self.weights[offset..]) };
-
-                for i in 0..Self::NUM_REGS {
-                    if ADD {
-                        acc[i] = add_i16_m256i(acc[i], column[i]);
-                    } else {
-                        acc[i] = sub_i16_m256i(acc[i], column[i]);
-                    }
-                }
-
-                for i in 0..Self::NUM_REGS {
-                    store_m256i(&mut acc_tile[i], acc[i]);
-                    // acc_tile[i] = acc[i];
-                }
-            }
-
-            // drop(acc);
-            let mut acc_psqt = [m256i::default(); Self::NUM_REGS_PSQT];
-
-            for k in 0..Self::PSQT_BUCKETS / Self::TILE_HEIGHT_PSQT {
-                let acc_tile_psqt: &mut [m256i] = unsafe {
-                    let xs = &mut self.accum.psqt[persp][k * Self::TILE_HEIGHT_PSQT..];
-                    cast_slice_to_m256i_mut(xs)
-                };
-                for i in 0..Self::NUM_REGS_PSQT {
-                    acc_psqt[i] = load_m256i(&acc_tile_psqt[i]);
-                    // acc_psqt[i] = acc_tile_psqt[i];
-                }
-                let offset = Self::PSQT_BUCKETS * idx.0 + k * Self::TILE_HEIGHT_PSQT;
-                let column_psqt = unsafe { cast_slice_to_m256i(&self.psqt_weights[offset..]) };
-                for i in 0..Self::NUM_REGS_PSQT {
-                    if ADD {
-                        acc_psqt[i] = add_i32_m256i(acc_psqt[i], column_psqt[i]);
-                    } else {
-                        acc_psqt[i] = sub_i32_m256i(acc_psqt[i], column_psqt[i]);
-                    }
-                }
-                for i in 0..Self::NUM_REGS_PSQT {
-                    store_m256i(&mut acc_tile_psqt[i], acc_psqt[i]);
-                    // acc_tile_psqt[i] = acc_psqt[i];
-                }
-            }
-
-            // let idx = idx.0;
-            // let offset = HALF_DIMS * idx;
-
-            // let mut accum = &mut self.accum.accum[persp][..HALF_DIMS];
-            // let weights = &self.weights[offset..offset + HALF_DIMS];
-
-            // for j in 0..HALF_DIMS {
-            //     if ADD {
-            //         accum[j] += weights[j];
-            //     } else {
-            //         accum[j] -= weights[j];
-            //     }
-            // }
-
-            // for k in 0..Self::PSQT_BUCKETS {
-            //     if ADD {
-            //         self.accum.psqt[persp][k] += self.psqt_weights[idx * Self::PSQT_BUCKETS + k];
-            //     } else {
-            //         self.accum.psqt[persp][k] -= self.psqt_weights[idx * Self::PSQT_BUCKETS + k];
-            //     }
-            // }
-
-        }
-
-    }
-
-    /// SIMD, SSSE3
-    #[cfg(all(not(target_feature = "avx2"), target_feature = "ssse3"))]
-    // #[cfg(feature = "test_simd")]
-    // #[cfg(feature = "nope")]
-    impl NNFeatureTrans {
-
-        // const NUM_REGS: usize = 16; // SSSE3
-        // const NUM_REGS_PSQT: usize = 1; // SSSE3
-
-        const NUM_REGS: usize = Self::best_register_count::<safe_arch::m128i, i16>(1024); // SSSE3
-        const NUM_REGS_PSQT: usize = Self::best_register_count::<safe_arch::m128i, i32>(8); // SSSE3
-
-        /// SSSE3 = 16
-        const TILE_HEIGHT: usize = Self::NUM_REGS * std::mem::size_of::<safe_arch::m128i>() / 2;
-        /// SSSE3 = 2
-        const TILE_HEIGHT_PSQT: usize = Self::NUM_REGS_PSQT * std::mem::size_of::<safe_arch::m128i>() / 4;
-
-        pub const fn best_register_count<V: Sized, W: Sized>(num_lanes: usize) -> usize {
-
-            // let num_lanes = 1024;
-            let lane_size = std::mem::size_of::<W>();
-            let reg_size  = std::mem::size_of::<safe_arch::m128i>();
-
-            let max_registers = 16; /// only for 64 bit
-
-            let ideal = (num_lanes * lane_size) / reg_size;
-
-            if ideal <= max_registers {
-                return ideal;
-            }
-
-            let mut divisor = max_registers;
-            while divisor > 1 {
-                if ideal % divisor == 0 {
-                    return divisor;
-                }
-            }
-
-            1
-        }
-
-        // pub fn _update_accum_simd(&mut self, g: &Game, persp: Color) {
-        pub fn _update_accum(&mut self, g: &Game, persp: Color) {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            assert!(self.biases.len() == self.accum.accum[persp].len());
-            self.accum.accum[persp].copy_from_slice(&self.biases);
-
-            let mut active = ArrayVec::default();
+            let mut active: ArrayVec<NNIndex, 32> = ArrayVec::default();
             NNAccum::append_active(g, persp, &mut active);
 
-            let mut acc      = [m128i::default(); Self::NUM_REGS];
-            let mut acc_psqt = [m128i::default(); Self::NUM_REGS_PSQT];
+            let offsets = active.iter()
+                .map(|idx| HALF_DIMS * idx.0)
+                .collect::<ArrayVec<usize, 32>>();
 
-            for k in 0..HALF_DIMS / Self::TILE_HEIGHT {
+            simd::accum::refresh_i16(
+                self.simd, &mut self.accum.accum[persp], &self.biases, &self.weights, &offsets);
 
-                let biases_tile: &[m128i] = unsafe {
-                    let bs = &self.biases[k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m128i(&bs)
-                };
-
-                acc[..Self::NUM_REGS].copy_from_slice(&biases_tile[..Self::NUM_REGS]);
-
-                for idx in active.iter() {
-                    let offset = HALF_DIMS * idx.0 + k * Self::TILE_HEIGHT;
-
-                    let column = unsafe { cast_slice_to_m128i(&self.weights[offset..]) };
-
-                    for i in 0..Self::NUM_REGS {
-                        acc[i] = add_i16_m128i(acc[i], column[i]);
-                    }
-                }
-
-                let acc_tile: &mut [m128i] = unsafe {
-                    let xs = &mut self.accum.accum[persp][k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m128i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS {
-                    // vec_store(&mut accTile[k], acc[k]);
-                    store_m128i(&mut acc_tile[i], acc[i]);
-                }
-
-            }
-
-            for k in 0..Self::PSQT_BUCKETS / Self::TILE_HEIGHT_PSQT {
-                self.accum.psqt[persp].fill(0);
-
-                for idx in active.iter() {
-                    let offset = Self::PSQT_BUCKETS * idx.0 + k * Self::TILE_HEIGHT_PSQT;
-
-                    let column_psqt = unsafe { cast_slice_to_m128i(&self.psqt_weights[offset..]) };
-
-                    for i in 0..Self::NUM_REGS_PSQT {
-                        acc_psqt[i] = add_i32_m128i(acc_psqt[i], column_psqt[i]);
-                    }
-                }
-
-                let acc_tile_psqt: &mut [m128i] = unsafe {
-                    let xs = &mut self.accum.psqt[persp][k * Self::TILE_HEIGHT_PSQT..];
-                    cast_slice_to_m128i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS_PSQT {
-                    store_m128i(&mut acc_tile_psqt[i], acc_psqt[i]);
+            self.accum.psqt[persp].fill(0);
+            for idx in active.iter() {
+                let offset = Self::PSQT_BUCKETS * idx.0;
+                for k in 0..Self::PSQT_BUCKETS {
+                    self.accum.psqt[persp][k] =
+                        self.accum.psqt[persp][k].wrapping_add(self.psqt_weights[offset + k]);
                 }
-
             }
-
         }
 
         pub fn _accum_inc_simd<const ADD: bool>(&mut self, persp: Color, idx: NNIndex) {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            let mut acc = [m128i::default(); Self::NUM_REGS];
-
-            for k in 0..HALF_DIMS / Self::TILE_HEIGHT {
-                let acc_tile: &mut [m128i] = unsafe {
-                    let xs = &mut self.accum.accum[persp][k * Self::TILE_HEIGHT..];
-                    cast_slice_to_m128i_mut(xs)
-                };
-
-                for i in 0..Self::NUM_REGS {
-                    acc[i] = load_m128i(&acc_tile[i]);
-                }
+            let offset = HALF_DIMS * idx.0;
+            simd::accum::update_i16::<ADD>(
+                self.simd,
+                &mut self.accum.accum[persp][..HALF_DIMS],
+                &self.weights[offset..offset + HALF_DIMS]);
 
-                let offset = HALF_DIMS * idx.0 + k * Self::TILE_HEIGHT;
-                let column = unsafe { cast_slice_to_m128i(&self.weights[offset..]) };
-
-                for i in 0..Self::NUM_REGS {
-                    if ADD {
-                        acc[i] = add_i16_m128i(acc[i], column[i]);
-                    } else {
-                        acc[i] = sub_i16_m128i(acc[i], column[i]);
-                    }
-                }
-
-                for i in 0..Self::NUM_REGS {
-                    store_m128i(&mut acc_tile[i], acc[i]);
-                    // acc_tile[i] = acc[i];
-                }
-            }
-
-            // drop(acc);
-            let mut acc_psqt = [m128i::default(); Self::NUM_REGS_PSQT];
-
-            for k in 0..Self::PSQT_BUCKETS / Self::TILE_HEIGHT_PSQT {
-                let acc_tile_psqt: &mut [m128i] = unsafe {
-                    let xs = &mut self.accum.psqt[persp][k * Self::TILE_HEIGHT_PSQT..];
-                    cast_slice_to_m128i_mut(xs)
-                };
-                for i in 0..Self::NUM_REGS_PSQT {
-                    acc_psqt[i] = load_m128i(&acc_tile_psqt[i]);
-                    // acc_psqt[i] = acc_tile_psqt[i];
-                }
-                let offset = Self::PSQT_BUCKETS * idx.0 + k * Self::TILE_HEIGHT_PSQT;
-                let column_psqt = unsafe { cast_slice_to_m128i(&self.psqt_weights[offset..]) };
-                for i in 0..Self::NUM_REGS_PSQT {
-                    if ADD {
-                        acc_psqt[i] = add_i32_m128i(acc_psqt[i], column_psqt[i]);
-                    } else {
-                        acc_psqt[i] = sub_i32_m128i(acc_psqt[i], column_psqt[i]);
-                    }
-                }
-                for i in 0..Self::NUM_REGS_PSQT {
-                    store_m128i(&mut acc_tile_psqt[i], acc_psqt[i]);
-                    // acc_tile_psqt[i] = acc_psqt[i];
-                }
+            let offset = Self::PSQT_BUCKETS * idx.0;
+            for k in 0..Self::PSQT_BUCKETS {
+                let x = self.psqt_weights[offset + k];
+                let acc = &mut self.accum.psqt[persp][k];
+                *acc = if ADD { acc.wrapping_add(x) } else { acc.wrapping_sub(x) };
             }
-
-            // let idx = idx.0;
-            // let offset = HALF_DIMS * idx;
-
-            // let mut accum = &mut self.accum.accum[persp][..HALF_DIMS];
-            // let weights = &self.weights[offset..offset + HALF_DIMS];
-
-            // for j in 0..HALF_DIMS {
-            //     if ADD {
-            //         accum[j] += weights[j];
-            //     } else {
-            //         accum[j] -= weights[j];
-            //     }
-            // }
-
-            // for k in 0..Self::PSQT_BUCKETS {
-            //     if ADD {
-            //         self.accum.psqt[persp][k] += self.psqt_weights[idx * Self::PSQT_BUCKETS + k];
-            //     } else {
-            //         self.accum.psqt[persp][k] -= self.psqt_weights[idx * Self::PSQT_BUCKETS + k];
-            //     }
-            // }
-
         }
 
     }
@@ -2293,8 +1894,11 @@ mod old {
         pub fn make_move(&mut self, g: &Game, mv: Move) {
             self.stats.moves += 1;
             if mv.piece() == Some(King) {
-                self.accum.push_copy_full(!g.state.side_to_move);
-                self.reset_accum(g);
+                /// Only the mover's king square changed
+                let side = !g.state.side_to_move;
+                self.accum.push_copy_full(side);
+                self.refresh_cached(g, side);
+                self._make_king_move(g, mv, !side);
 
                 self.stats.refresh_kingmove += 1;
             } else {
@@ -2303,6 +1907,33 @@ mod old {
             }
         }
 
+        /// The other side's king move as a delta, for persp only.
+        /// Undone by the copy pushed in make_move
+        fn _make_king_move(&mut self, g: &Game, mv: Move, persp: Color) {
+            let ksq  = g.get(King,persp).bitscan();
+            let side = !persp;
+            let idx  = |pc: Piece, side: Color, sq: Coord| {
+                NNUE4::make_index_half_ka_v2(ksq, persp, pc, side, sq)
+            };
+
+            let ((from,to),rook) = match mv {
+                Move::Castle { .. } => (mv.castle_king_mv(), Some(mv.castle_rook_mv())),
+                _                   => ((mv.sq_from(), mv.sq_to()), None),
+            };
+
+            self._accum_inc_simd::<false>(persp, idx(King, side, from));
+            self._accum_inc_simd::<true>(persp, idx(King, side, to));
+
+            if let Some((rook_from,rook_to)) = rook {
+                self._accum_inc_simd::<false>(persp, idx(Rook, side, rook_from));
+                self._accum_inc_simd::<true>(persp, idx(Rook, side, rook_to));
+            }
+
+            if let Move::Capture { to, pcs, .. } = mv {
+                self._accum_inc_simd::<false>(persp, idx(pcs.second(), persp, to));
+            }
+        }
+
         pub fn _make_move(&mut self, g: &Game, mv: Move) -> ArrayVec<NNDelta,3> {
 
             // self.update_accum(g, White);
@@ -2435,25 +2066,8 @@ mod old {
 
         pub fn accum_add(&mut self, i_w: NNIndex, i_b: NNIndex) -> NNDelta {
 
-            // #[cfg(not(target_feature = "avx2"))]
-            // self._accum_add(White, i_w);
-            // #[cfg(not(target_feature = "avx2"))]
-            // self._accum_add(Black, i_b);
-
-            // #[cfg(target_feature = "avx2")]
-            // self._accum_inc_simd::<true>(White, i_w);
-            // #[cfg(target_feature = "avx2")]
-            // self._accum_inc_simd::<true>(Black, i_b);
-
-            #[cfg(not(any(target_feature = "avx2",target_feature = "ssse3")))]
-            self._accum_rem(White, i_w);
-            #[cfg(not(any(target_feature = "avx2",target_feature = "ssse3")))]
-            self._accum_rem(Black, i_b);
-
-            #[cfg(any(target_feature = "avx2",target_feature = "ssse3"))]
-            self._accum_inc_simd::<false>(White, i_w);
-            #[cfg(any(target_feature = "avx2",target_feature = "ssse3"))]
-            self._accum_inc_simd::<false>(Black, i_b);
+            self._accum_inc_simd::<true>(White, i_w);
+            self._accum_inc_simd::<true>(Black, i_b);
 
             NNDelta::Remove(i_w,i_b)
         }
@@ -2461,14 +2075,7 @@ mod old {
         pub fn accum_rem(&mut self, i_w: NNIndex, i_b: NNIndex) -> NNDelta {
             // eprintln!("rem (i_w,i_b) = {:?}", (i_w,i_b));
 
-            #[cfg(not(any(target_feature = "avx2",target_feature = "ssse3")))]
-            self._accum_rem(White, i_w);
-            #[cfg(not(any(target_feature = "avx2",target_feature = "ssse3")))]
-            self._accum_rem(Black, i_b);
-
-            #[cfg(any(target_feature = "avx2",target_feature = "ssse3"))]
             self._accum_inc_simd::<false>(White, i_w);
-            #[cfg(any(target_feature = "avx2",target_feature = "ssse3"))]
             self._accum_inc_simd::<false>(Black, i_b);
 
             NNDelta::Add(i_w,i_b)
@@ -2524,12 +2131,88 @@ mod old {
 
         }
 
-        /// temp no simd
         pub fn reset_accum(&mut self, g: &Game) {
+            self.refresh_cached(g, White);
+            self.refresh_cached(g, Black);
+        }
+
+        /// Recompute every active feature, ignoring the refresh table
+        pub fn reset_accum_full(&mut self, g: &Game) {
             self._update_accum(g, White);
             self._update_accum(g, Black);
         }
 
+        pub fn clear_refresh_table(&mut self) {
+            if let Some(table) = self.refresh_table.as_mut() {
+                table.clear();
+            }
+        }
+
+        /// Refresh one perspective from the cached accumulator for its king square
+        pub fn refresh_cached(&mut self, g: &Game, persp: Color) {
+            /// No king, e.g. the empty board before a position is set
+            if g.get(King,persp).is_empty() {
+                self._update_accum(g, persp);
+                return;
+            }
+
+            let mut table = match self.refresh_table.take() {
+                Some(table) => table,
+                None        => {
+                    self._update_accum(g, persp);
+                    return;
+                },
+            };
+
+            let entry = table.get_mut(persp, g.get(King,persp).bitscan());
+
+            if entry.valid {
+                let mut removed: ArrayVec<NNIndex, 32> = ArrayVec::default();
+                let mut added:   ArrayVec<NNIndex, 32> = ArrayVec::default();
+                entry.diff(g, persp, &mut removed, &mut added);
+
+                for idx in removed.iter() {
+                    self._entry_inc::<false>(entry, *idx);
+                }
+                for idx in added.iter() {
+                    self._entry_inc::<true>(entry, *idx);
+                }
+
+                self.accum.accum[persp].copy_from_slice(&entry.accum[..]);
+                self.accum.psqt[persp] = entry.psqt;
+
+                self.stats.refresh_cached += 1;
+            } else {
+                self._update_accum(g, persp);
+
+                entry.accum.copy_from_slice(&self.accum.accum[persp]);
+                entry.psqt = self.accum.psqt[persp];
+                for side in [White,Black] {
+                    for pc in Piece::iter_pieces() {
+                        entry.pieces[side][pc.index()] = g.get(pc,side);
+                    }
+                }
+                entry.valid = true;
+            }
+
+            self.refresh_table = Some(table);
+        }
+
+        fn _entry_inc<const ADD: bool>(&self, entry: &mut NNRefreshEntry, idx: NNIndex) {
+            let offset = HALF_DIMS * idx.0;
+            simd::accum::update_i16::<ADD>(
+                self.simd,
+                &mut entry.accum[..],
+                &self.weights[offset..offset + HALF_DIMS]);
+
+            let offset = Self::PSQT_BUCKETS * idx.0;
+            for k in 0..Self::PSQT_BUCKETS {
+                let x = self.psqt_weights[offset + k];
+                let acc = &mut entry.psqt[k];
+                *acc = if ADD { acc.wrapping_add(x) } else { acc.wrapping_sub(x) };
+            }
+        }
+
         // pub fn reset_accum(&mut self, g: &Game) {
         //     // #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
         //     #[cfg(all(not(target_feature = "avx2")))]
@@ -2545,31 +2228,118 @@ mod old {
         //     self._update_accum_simd(g, Black);
         // }
 
-        #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
-        // #[cfg(not(feature = "test_simd"))]
-        pub fn _update_accum(&mut self, g: &Game, persp: Color) {
-            assert!(self.biases.len() == self.accum.accum[persp].len());
-            self.accum.accum[persp].copy_from_slice(&self.biases);
-            // self.accum.accum[persp].copy_from_slice(self.biases.as_ref().as_ref());
+    }
 
-            let mut active = ArrayVec::default();
-            NNAccum::append_active(g, persp, &mut active);
+}
 
-            self.accum.psqt[persp].fill(0);
 
-            for idx in active.into_iter() {
-                let offset = HALF_DIMS * idx.0;
-                for j in 0..HALF_DIMS {
-                    self.accum.accum[persp][j] += self.weights[offset + j];
-                }
-                for k in 0..Self::PSQT_BUCKETS {
-                    self.accum.psqt[persp][k] += self.psqt_weights[idx.0 * Self::PSQT_BUCKETS + k];
+#[cfg(all(test, feature = "prev_accum"))]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use crate::tables::*;
+    use crate::movegen::MoveGen;
+    use crate::sf_compat::NNUE4;
+    use crate::sf_compat::simd::{SimdLevel,check::randomize};
+
+    use std::io::{BufReader,BufWriter};
+    use std::fs::File;
+
+    use rand::prelude::{StdRng,SeedableRng,SliceRandom,Rng};
+
+    fn random_ft(rng: &mut StdRng) -> NNFeatureTrans {
+        let mut nn = NNUE4::new_empty();
+        randomize(&mut nn, rng);
+        nn.set_simd(SimdLevel::detect());
+        nn.ft
+    }
+
+    fn reload(ft: &mut NNFeatureTrans, path: &std::path::Path) {
+        let mut rdr = BufReader::new(File::open(path).unwrap());
+        ft.read_parameters(&mut rdr).unwrap();
+    }
+
+    fn same_accum(ft: &NNFeatureTrans, reference: &NNFeatureTrans) -> bool {
+        ft.accum.accum[..] == reference.accum.accum[..]
+            && ft.accum.psqt[..] == reference.accum.psqt[..]
+    }
+
+    /// Random games that mostly move the kings, so most king squares are refreshed
+    /// from the table more than once
+    #[test]
+    fn refresh_table_matches_full_refresh() {
+        let ts = Tables::new();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+
+        let mut ft = random_ft(&mut rng);
+        let mut reference = ft.clone();
+        reference.refresh_table = None;
+
+        let path = std::env::temp_dir().join(format!("rchess_refresh_{}.ft", std::process::id()));
+        let fens = [
+            STARTPOS,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+        ];
+
+        let (mut castles, mut king_captures) = (0, 0);
+        for game in 0..8 {
+            /// New weights, the same FT must not reuse cached accumulators
+            if game == 4 {
+                let mut w = BufWriter::new(File::create(&path).unwrap());
+                random_ft(&mut rng).write_parameters(&mut w).unwrap();
+                drop(w);
+                reload(&mut ft, &path);
+                reload(&mut reference, &path);
+                std::fs::remove_file(&path).unwrap();
+            }
+
+            let mut g = Game::from_fen(&ts, fens[game % fens.len()]).unwrap();
+            ft.reset_accum(&g);
+            reference.reset_accum_full(&g);
+            assert!(same_accum(&ft, &reference), "game {}, start {}", game, g.to_fen());
+
+            for _ in 0..80 {
+                let mvs = MoveGen::generate_list_legal(&ts, &g, None);
+                let kings = mvs.iter().filter(|mv| mv.piece() == Some(King)).cloned().collect::<Vec<_>>();
+                let mv = match kings.choose(&mut rng) {
+                    Some(&mv) if rng.gen_bool(0.8) => mv,
+                    _ => match mvs.choose(&mut rng) {
+                        Some(&mv) => mv,
+                        None      => break,
+                    },
+                };
+                let g2 = match g.make_move_unchecked(&ts, mv) {
+                    Ok(g2) => g2,
+                    Err(_) => break,
+                };
+
+                /// Undone by accum_pop
+                let prev = (ft.accum.accum.clone(), ft.accum.psqt.clone());
+                ft.make_move(&g2, mv);
+                ft.accum_pop();
+                assert!(prev == (ft.accum.accum.clone(), ft.accum.psqt.clone()), "game {}, undo {:?}", game, mv);
+
+                ft.make_move(&g2, mv);
+                reference.reset_accum_full(&g2);
+                assert!(same_accum(&ft, &reference), "game {}, {:?}, {}", game, mv, g2.to_fen());
+                match mv {
+                    Move::Castle { .. }                                 => castles += 1,
+                    Move::Capture { pcs, .. } if pcs.first() == King    => king_captures += 1,
+                    _                                                   => {},
                 }
+
+                /// A full reset goes through the table for both sides
+                ft.reset_accum(&g2);
+                assert!(same_accum(&ft, &reference), "game {}, reset, {}", game, g2.to_fen());
+
+                g = g2;
             }
         }
 
+        assert!(ft.stats.refresh_cached > 100, "{:?}", ft.stats);
+        assert!(castles > 0 && king_captures > 0, "{} castles, {} king captures", castles, king_captures);
     }
 
 }
-
-
@@ -0,0 +1,92 @@
+
+use crate::sf_compat::{NNUE4,NNLayer,Layer1,Layer2,HALF_DIMS};
+use crate::sf_compat::layers::ceil_to_multiple;
+
+/// Summary of one block of quantized weights
+#[derive(Debug,Default,Clone,Copy)]
+pub struct WeightStats {
+    pub count:      usize,
+    pub min:        i64,
+    pub max:        i64,
+    pub mean:       f64,
+    pub std_dev:    f64,
+    pub zeros:      usize,
+    /// At the limit of the integer type, probably clipped during quantization
+    pub saturated:  usize,
+}
+
+impl WeightStats {
+    pub fn new<I: Iterator<Item = i64>>(xs: I, limit: i64) -> Self {
+        let mut out = Self { min: i64::MAX, max: i64::MIN, ..Default::default() };
+        let mut sum    = 0.0;
+        let mut sum_sq = 0.0;
+        for x in xs {
+            out.count += 1;
+            out.min = out.min.min(x);
+            out.max = out.max.max(x);
+            if x == 0 { out.zeros += 1; }
+            if x >= limit || x <= -limit { out.saturated += 1; }
+            sum    += x as f64;
+            sum_sq += (x as f64) * (x as f64);
+        }
+        if out.count == 0 { return Self::default(); }
+        out.mean    = sum / out.count as f64;
+        out.std_dev = (sum_sq / out.count as f64 - out.mean * out.mean).max(0.0).sqrt();
+        out
+    }
+}
+
+impl std::fmt::Display for WeightStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "n = {:>9}, min = {:>7}, max = {:>7}, mean = {:>9.2}, std = {:>8.2}, zero = {:>5.1}%, saturated = {}",
+               self.count, self.min, self.max, self.mean, self.std_dev,
+               100.0 * self.zeros as f64 / self.count.max(1) as f64,
+               self.saturated)
+    }
+}
+
+impl NNUE4 {
+
+    /// Named blocks of weights, in file order
+    pub fn weight_stats(&self) -> Vec<(String,WeightStats)> {
+        let i8_lim  = i8::MAX as i64;
+        let i16_lim = i16::MAX as i64;
+        let i32_lim = i32::MAX as i64;
+
+        let mut out = vec![
+            ("ft.biases".to_string(),
+             WeightStats::new(self.ft.biases.iter().map(|x| *x as i64), i16_lim)),
+            ("ft.weights".to_string(),
+             WeightStats::new(self.ft.weights.iter().map(|x| *x as i64), i16_lim)),
+            ("ft.psqt_weights".to_string(),
+             WeightStats::new(self.ft.psqt_weights.iter().map(|x| *x as i64), i32_lim)),
+        ];
+
+        /// Padding is always zero, so only count the real inputs
+        fn weights<'a>(ws: &'a [i8], inputs: usize) -> impl Iterator<Item = i64> + 'a {
+            let padded = ceil_to_multiple(inputs, 32);
+            ws.chunks(padded).flat_map(move |row| row[..inputs].iter().map(|x| *x as i64))
+        }
+
+        for (n,layer) in self.layers.iter().enumerate() {
+            let l2 = &layer.prev.prev;
+            let l1 = &l2.prev.prev;
+
+            out.push((format!("stack {}: l1.biases", n),
+                      WeightStats::new(l1.biases.iter().map(|x| *x as i64), i32_lim)));
+            out.push((format!("stack {}: l1.weights", n),
+                      WeightStats::new(weights(&l1.weights, HALF_DIMS * 2), i8_lim)));
+            out.push((format!("stack {}: l2.biases", n),
+                      WeightStats::new(l2.biases.iter().map(|x| *x as i64), i32_lim)));
+            out.push((format!("stack {}: l2.weights", n),
+                      WeightStats::new(weights(&l2.weights, Layer1::SIZE_OUTPUT), i8_lim)));
+            out.push((format!("stack {}: out.bias", n),
+                      WeightStats::new(layer.biases.iter().map(|x| *x as i64), i32_lim)));
+            out.push((format!("stack {}: out.weights", n),
+                      WeightStats::new(weights(&layer.weights, Layer2::SIZE_OUTPUT), i8_lim)));
+        }
+
+        out
+    }
+
+}
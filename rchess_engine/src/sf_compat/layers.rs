@@ -4,6 +4,7 @@ pub use self::nn_affine::NNAffine;
 pub use self::nn_relu::NNClippedRelu;
 
 use crate::eprint_self;
+use crate::sf_compat::simd::{self, SimdLevel};
 
 use std::io::{self, Read,BufReader, BufWriter};
 use std::fs::File;
@@ -20,7 +21,7 @@ use num_traits::{Num,PrimInt,NumCast,AsPrimitive};
 use aligned::{Aligned,A64};
 
 const CACHE_LINE_SIZE: usize = 64;
-const WEIGHT_SCALE_BITS: u32 = 6;
+pub const WEIGHT_SCALE_BITS: u32 = 6;
 
 const MAX_SIMD_WIDTH: usize = 32;
 
@@ -59,6 +60,9 @@ pub trait NNLayer {
         Ok(())
     }
 
+    /// Switch kernels, before or after reading parameters
+    fn set_simd(&mut self, simd: SimdLevel);
+
 }
 
 pub const fn ceil_to_multiple(n: usize, base: usize) -> usize {
@@ -112,6 +116,8 @@ mod nn_input {
         fn read_parameters(&mut self, rdr: &mut BufReader<File>) -> io::Result<()> {
             Ok(())
         }
+
+        fn set_simd(&mut self, simd: SimdLevel) {}
     }
 
     impl<const OS: usize> NNInput<OS> {
@@ -144,64 +150,34 @@ mod nn_affine {
 
         // pub buffer:  [<NNAffine<Prev,OS,IS> as NNLayer>::OutputType; OS],
         pub buffer:  Aligned<A64,[<NNAffine<Prev,OS,IS> as NNLayer>::OutputType; OS]>,
-    }
-
-    /// Consts, AVX2
-    #[cfg(target_feature = "avx2")]
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
-        const INPUT_SIMD_WIDTH: usize = 32;
-        const MAX_NUM_OUTPUT_REGS: usize = 8;
-    }
 
-    #[cfg(all(not(target_feature = "avx2"), target_feature = "ssse3"))]
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
-        const INPUT_SIMD_WIDTH: usize = 16;
-        const MAX_NUM_OUTPUT_REGS: usize = 8;
+        /// Kernels to use, and the layout of weights
+        pub simd:    SimdLevel,
     }
 
-    #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
-    // #[cfg(not(target_feature = "avx2"))]
     impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
-        const INPUT_SIMD_WIDTH: usize = 1;
-        const MAX_NUM_OUTPUT_REGS: usize = 1;
-    }
-
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
-        const NUM_OUTPUT_REGS: usize  = if OS > Self::MAX_NUM_OUTPUT_REGS {
-            Self::MAX_NUM_OUTPUT_REGS } else { OS };
-        const SMALL_BLOCK_SIZE: usize = Self::INPUT_SIMD_WIDTH;
-        const BIG_BLOCK_SIZE: usize   = Self::NUM_OUTPUT_REGS * Self::SIZE_INPUT_PADDED;
-
-        const NUM_SMALL_BLOCKS_PER_BIG_BLOCK: usize = Self::BIG_BLOCK_SIZE / Self::SMALL_BLOCK_SIZE;
-        const NUM_SMALL_BLOCKS_PER_OUTPUT: usize = Self::SIZE_INPUT_PADDED / Self::SMALL_BLOCK_SIZE;
-
-        const NUM_BIG_BLOCKS: usize = Self::SIZE_OUTPUT / Self::NUM_OUTPUT_REGS;
-
         const SIZE_INPUT_PADDED: usize = ceil_to_multiple(Self::SIZE_INPUT, 32);
 
-    }
-
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
+        pub fn num_output_regs(simd: SimdLevel) -> usize {
+            simd.max_output_regs().min(OS)
+        }
 
-        #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
-        pub fn get_weight_index(idx: usize) -> usize {
-            idx
+        /// Whether the weights use the blocked layout for this level
+        pub fn is_blocked(simd: SimdLevel) -> bool {
+            let num_regs = Self::num_output_regs(simd);
+            simd != SimdLevel::Scalar
+                && IS == Self::SIZE_INPUT_PADDED
+                && IS >= 128
+                && IS % (2 * simd.width()) == 0
+                && OS % num_regs == 0
         }
 
-        // XXX: fix
-        #[cfg(any(target_feature = "avx2", target_feature = "ssse3"))]
-        pub fn get_weight_index(idx: usize) -> usize {
-            if Self::INPUT_SIMD_WIDTH == 1 {
-                idx
-            } else if Self::SIZE_INPUT_PADDED >= 128 {
-                // #[cfg(target_feature = "ssse3")]
-                // // return Self::_get_weight_index_scrambled(idx);
-                // return idx;
-                // #[cfg(not(target_feature = "ssse3"))]
-                return Self::_get_weight_index(idx);
-                // idx
+        /// Index into weights of the weight at idx in the file, which is in row major order
+        pub fn get_weight_index(simd: SimdLevel, idx: usize) -> usize {
+            if Self::is_blocked(simd) {
+                simd::affine::blocked_weight_index(
+                    simd, Self::num_output_regs(simd), Self::SIZE_INPUT_PADDED, idx)
             } else {
-                // Self::_get_weight_index_scrambled(idx)
                 idx
             }
         }
@@ -212,39 +188,17 @@ mod nn_affine {
                 + idx % 4
         }
 
-        pub fn _get_weight_index(idx: usize) -> usize {
-
-            // const IndexType smallBlock = (i / SmallBlockSize) % NumSmallBlocksInBigBlock;
-            let small_block = (idx / Self::SMALL_BLOCK_SIZE)
-                % Self::NUM_SMALL_BLOCKS_PER_BIG_BLOCK;
-
-            // const IndexType smallBlockCol = smallBlock / NumSmallBlocksPerOutput;
-            let small_block_col = small_block / Self::NUM_SMALL_BLOCKS_PER_OUTPUT;
-
-            // const IndexType smallBlockRow = smallBlock % NumSmallBlocksPerOutput;
-            let small_block_row = small_block % Self::NUM_SMALL_BLOCKS_PER_OUTPUT;
-
-            // const IndexType bigBlock   = i / BigBlockSize;
-            let big_block = idx / Self::BIG_BLOCK_SIZE;
-
-            // const IndexType rest       = i % SmallBlockSize;
-            let rest      = idx % Self::SMALL_BLOCK_SIZE;
-
-            big_block * Self::BIG_BLOCK_SIZE
-                + small_block_row * Self::SMALL_BLOCK_SIZE * Self::NUM_OUTPUT_REGS
-                + small_block_col * Self::SMALL_BLOCK_SIZE
-                + rest
-        }
-
         pub fn new(prev: Prev) -> Self {
             Self {
                 prev,
 
                 biases:  Aligned([0; OS]),
-                weights: Aligned(vec![0; OS * Self::SIZE_INPUT]),
+                weights: Aligned(vec![0; OS * Self::SIZE_INPUT_PADDED]),
 
                 // buffer:  [Self::OutputType::zero(); OS]
-                buffer:  Aligned([0; OS])
+                buffer:  Aligned([0; OS]),
+
+                simd:    SimdLevel::Scalar,
             }
         }
 
@@ -696,283 +650,9 @@ mod nn_affine {
 
     }
 
-    /// AVX2
-    #[cfg(target_feature = "avx2")]
     impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
 
-        // #[cfg(feature = "nope")]
-        /// TODO: when SIZE_OUTPUT % OUTPUTSIMD_WIDTH == 0
-        pub fn _propagate_avx2_small<'a>(
-            &'a mut self, trans_features: &'a [u8]
-        ) -> &'a [<NNAffine<Prev,IS,OS> as NNLayer>::OutputType] {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            let input = self.prev.propagate(trans_features);
-            let input = &input[..Self::SIZE_INPUT]; // TODO: bench
-
-            const OUTPUT_SIMD_WIDTH: usize = 32 / 4;
-            const INPUT_SIMD_WIDTH: usize = 32;
-
-            assert_eq!(Self::SIZE_INPUT % 8, 0);
-            assert!(Self::SIZE_OUTPUT % OUTPUT_SIMD_WIDTH == 0 || Self::SIZE_OUTPUT == 1);
-
-            // let input: &[u8] = unsafe {
-            //     let ptr = input.as_ptr() as *const u8;
-            //     std::slice::from_raw_parts(ptr, input.len())
-            // };
-
-            // if Self::SIZE_OUTPUT % OUTPUT_SIMD_WIDTH == 0 {
-            if false {
-                let num_chunks: usize = IS / 4;
-                let num_regs: usize = Self::SIZE_OUTPUT / OUTPUT_SIMD_WIDTH;
-
-                let input32: &[i32] = unsafe {
-                    let ptr = input.as_ptr() as *const i32;
-                    std::slice::from_raw_parts(ptr, input.len() / 4)
-                };
-
-                let bias_vec: &[m256i] = unsafe {
-                    let ptr = self.biases.as_ptr() as *const m256i;
-                    std::slice::from_raw_parts(
-                        ptr, self.biases.len() * std::mem::size_of::<i32>() / 32)
-                };
-
-                let mut acc = vec![m256i::default(); num_regs];
-
-                // for k in 0..num_regs {
-                //     acc[k] = bias_vec[k];
-                // }
-                acc[..num_regs].copy_from_slice(&bias_vec[..num_regs]);
-
-                let mut i = 0;
-                while i < num_chunks {
-                    let in0 = set_splat_i32_m256i(input32[i + 0]);
-                    let in1 = set_splat_i32_m256i(input32[i + 1]);
-
-                    let col0 = &self.weights[(i+0) * Self::SIZE_OUTPUT * 4..];
-                    let col1 = &self.weights[(i+1) * Self::SIZE_OUTPUT * 4..];
-
-                    let col0: &[m256i] = unsafe {
-                        let ptr = col0.as_ptr() as *const m256i;
-                        std::slice::from_raw_parts(ptr, col0.len() / 32)
-                    };
-                    let col1: &[m256i] = unsafe {
-                        let ptr = col1.as_ptr() as *const m256i;
-                        std::slice::from_raw_parts(ptr, col1.len() / 32)
-                    };
-
-                    for k in 0..num_regs {
-                        m256_add_dpbusd_epi32x2(&mut acc[k], in0, col0[k], in1, col1[k]);
-                    }
-
-                    let output_vec: &mut [m256i] = unsafe {
-                        let ptr = self.buffer.as_mut_ptr() as *mut m256i;
-                            std::slice::from_raw_parts_mut(
-                                ptr, self.buffer.len() * std::mem::size_of::<i32>() / 32)
-                    };
-
-                    // for k in 0..num_regs {
-                    //     output_vec[k] = acc[k];
-                    // }
-                    output_vec[..num_regs].copy_from_slice(&acc[..num_regs]);
-
-                    i += 2;
-                }
-
-            } else if Self::SIZE_OUTPUT == 1 {
-            // } else {
-                let num_chunks: usize = Self::SIZE_INPUT_PADDED / 32;
-
-                let input_vec: &[m256i] = unsafe {
-                    let ptr = input.as_ptr() as *const m256i;
-                    std::slice::from_raw_parts(ptr, input.len() / 32)
-                };
-
-                let row0: &[m256i] = unsafe {
-                    let ptr = self.weights.as_ptr() as *const m256i;
-                    std::slice::from_raw_parts(ptr, self.weights.len() / 32)
-                };
-
-                let mut sum = m256i::default();
-
-                for k in 0..num_chunks {
-                    let in0 = input_vec[k];
-                    m256_add_dpbusd_epi32(&mut sum, in0, row0[k]);
-                }
-
-                self.buffer[0] = m256_hadd(sum, self.biases[0]);
-            } else {
-                for i in 0..Self::SIZE_OUTPUT {
-                    let offset = i * Self::SIZE_INPUT_PADDED;
-                    let mut sum: i32 = self.biases[i];
-                    for (j,x) in input.iter().enumerate() {
-                        let x: i32 = x.as_();
-                        let x0 = self.weights[offset + j] as i32 * x;
-                        sum += x0;
-                    }
-                    self.buffer[i] = sum as i32;
-                }
-                // panic!();
-            }
-
-            self.buffer.as_ref()
-        }
-
-        #[cfg(target_feature = "avx2")]
-        pub fn _propagate_avx2_large<'a>(
-            &'a mut self, trans_features: &'a [u8]
-        ) -> &'a [<NNAffine<Prev,IS,OS> as NNLayer>::OutputType] {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            let input = self.prev.propagate(trans_features);
-            assert_eq!(input.len() % 32, 0);
-
-            let input_vec: &[m256i] = unsafe {
-                let ptr = input.as_ptr() as *const m256i;
-                std::slice::from_raw_parts(ptr, input.len() / 32)
-            };
-
-            let weight_vec: &[m256i] = unsafe {
-                let ptr = self.weights.as_ptr() as *const m256i;
-                std::slice::from_raw_parts(ptr, self.weights.len() / 32)
-            };
-
-            for big_block in 0..Self::NUM_BIG_BLOCKS {
-
-                // let mut acc = [m256i::default(); Self::NUM_OUTPUT_REGS];
-                // let mut acc = vec![m256i::default(); Self::NUM_OUTPUT_REGS];
-                let mut acc = [m256i::default(); OS];
-
-                let mut small_block = 0;
-                while small_block < Self::NUM_SMALL_BLOCKS_PER_OUTPUT {
-
-                    let w_offset = big_block * Self::BIG_BLOCK_SIZE
-                        + small_block * Self::SMALL_BLOCK_SIZE * Self::NUM_OUTPUT_REGS;
-                    let w_offset = w_offset / 32;
-
-                    let weight_vec = &weight_vec[w_offset..];
-
-                    let in0 = input_vec[small_block + 0];
-                    let in1 = input_vec[small_block + 1];
-
-                    for k in 0..Self::NUM_OUTPUT_REGS {
-                        let b0 = weight_vec[k];
-                        let b1 = weight_vec[k + Self::NUM_OUTPUT_REGS];
-                        m256_add_dpbusd_epi32x2(&mut acc[k], in0, b0, in1, b1)
-                    }
-
-                    small_block += 2
-                }
-
-                let bias_vec: &[m128i] = unsafe {
-                    let ptr = self.biases.as_ptr() as *const m128i;
-                    std::slice::from_raw_parts(ptr, self.biases.len() / 4)
-                };
-
-                let output_vec: &mut [m128i] = unsafe {
-                    let ptr = self.buffer.as_mut_ptr() as *mut m128i;
-                    std::slice::from_raw_parts_mut(ptr, self.buffer.len() / 4)
-                };
-
-                let mut k = 0;
-                while k < Self::NUM_OUTPUT_REGS {
-                    let idx = (big_block * Self::NUM_OUTPUT_REGS + k) / 4;
-                    output_vec[idx] = m256_haddx4(acc[k+0],acc[k+1],acc[k+2],acc[k+3],bias_vec[idx]);
-                    k += 4;
-                }
-
-                // for k in 0..Self::NUM_OUTPUT_REGS {
-                //     let idx = big_block * Self::NUM_OUTPUT_REGS + k;
-                //     self.buffer[idx] = m256_hadd(acc[k], self.biases[idx]);
-                // }
-
-            }
-
-            self.buffer.as_ref()
-        }
-
-    }
-
-    /// SSSE3
-    #[cfg(all(not(target_feature = "avx2"), target_feature = "ssse3"))]
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
-
-        pub fn _propagate_avx2_small<'a>(
-            &'a mut self, trans_features: &'a [u8]
-        ) -> &'a [<NNAffine<Prev,IS,OS> as NNLayer>::OutputType] {
-            self._propagate_nosimd(trans_features)
-        }
-
-        pub fn _propagate_avx2_large<'a>(
-            &'a mut self, trans_features: &'a [u8]
-        ) -> &'a [<NNAffine<Prev,IS,OS> as NNLayer>::OutputType] {
-            use safe_arch::*;
-            use crate::simd_utils::safe_arch::*;
-
-            let input = self.prev.propagate(trans_features);
-            assert_eq!(input.len() % 32, 0);
-
-            let input_vec: &[m128i] = unsafe {
-                cast_slice_to_m128i(&input)
-            };
-
-            let weight_vec: &[m128i] = unsafe {
-                cast_slice_to_m128i(&self.weights)
-            };
-
-            for big_block in 0..Self::NUM_BIG_BLOCKS {
-                let mut acc = [m128i::default(); OS];
-
-                let mut small_block = 0;
-                while small_block < Self::NUM_SMALL_BLOCKS_PER_OUTPUT {
-
-                    let w_offset = big_block * Self::BIG_BLOCK_SIZE
-                        + small_block * Self::SMALL_BLOCK_SIZE * Self::NUM_OUTPUT_REGS;
-
-                    /// XXX: 16 or 32 and why?
-                    // let w_offset = w_offset / 32;
-                    let w_offset = w_offset / 16;
-
-                    let weight_vec = &weight_vec[w_offset..];
-
-                    let in0 = input_vec[small_block + 0];
-                    let in1 = input_vec[small_block + 1];
-
-                    for k in 0..Self::NUM_OUTPUT_REGS {
-                        let b0 = weight_vec[k];
-                        let b1 = weight_vec[k + Self::NUM_OUTPUT_REGS];
-                        m128_add_dpbusd_epi32x2(&mut acc[k], in0, b0, in1, b1)
-                    }
-
-                    small_block += 2
-                }
-
-                let output_vec: &mut [m128i] = unsafe {
-                    cast_slice_to_m128i_mut(self.buffer.as_mut())
-                };
-
-                let bias_vec: &[m128i] = unsafe {
-                    cast_slice_to_m128i(self.biases.as_ref())
-                };
-
-                let mut k = 0;
-                while k < Self::NUM_OUTPUT_REGS {
-                    let idx = (big_block * Self::NUM_OUTPUT_REGS + k) / 4;
-                    output_vec[idx] = m128_haddx4(acc[k+0],acc[k+1],acc[k+2],acc[k+3],bias_vec[idx]);
-                    k += 4;
-                }
-
-            }
-
-            self.buffer.as_ref()
-        }
-
-    }
-
-    // #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
-    impl<Prev: NNLayer, const OS: usize, const IS: usize> NNAffine<Prev,OS,IS> {
+        /// Reference implementation, the kernels in simd must match this exactly
         pub fn _propagate_nosimd<'a>(
             &'a mut self,
             trans_features: &'a [u8]
@@ -984,13 +664,14 @@ mod nn_affine {
                 let mut sum: i32 = self.biases[i];
                 for (j,x) in input.iter().enumerate() {
                     let x: i32 = x.as_();
-                    let x0 = self.weights[offset + j] as i32 * x;
-                    sum += x0;
+                    let w = self.weights[Self::get_weight_index(self.simd, offset + j)];
+                    sum = sum.wrapping_add(w as i32 * x);
                 }
-                self.buffer[i] = sum as i32;
+                self.buffer[i] = sum;
             }
             self.buffer.as_ref()
         }
+
     }
 
     impl<Prev: NNLayer, const OS: usize, const IS: usize> NNLayer for NNAffine<Prev,OS,IS> {
@@ -1027,93 +708,47 @@ mod nn_affine {
         #[cfg(feature = "nope")]
         fn propagate(&mut self, trans_features: &[u8]) { self._propagate_ndarray(trans_features); }
 
-        // #[cfg(feature = "nope")]
-        #[cfg(target_feature = "avx2")]
-        fn propagate<'a>(&'a mut self, trans_features: &'a [u8]) -> &'a [Self::OutputType] {
-            if Self::SIZE_INPUT_PADDED >= 128 {
-                self._propagate_avx2_large(trans_features)
-            } else {
-                self._propagate_avx2_small(trans_features)
-                // self._propagate_avx2_small_nosimd(trans_features)
-            }
-        }
-
-        // fn propagate<'a>(&'a mut self, trans_features: &'a [u8]) -> &'a [Self::OutputType] {}
-
-        #[cfg(all(not(target_feature = "avx2"), target_feature = "ssse3"))]
-        fn propagate<'a>(&'a mut self, trans_features: &'a [u8]) -> &'a [Self::OutputType] {
-            if Self::SIZE_INPUT_PADDED >= 128 {
-                self._propagate_avx2_large(trans_features)
-            } else {
-                self._propagate_avx2_small(trans_features)
-                // self._propagate_avx2_small_nosimd(trans_features)
-            }
-        }
-
-        // #[cfg(feature = "nope")]
-        #[cfg(all(not(target_feature = "avx2"), not(target_feature = "ssse3")))]
-        // fn propagate(&mut self, trans_features: &[u8]) {
         fn propagate<'a>(&'a mut self, trans_features: &'a [u8]) -> &'a [Self::OutputType] {
-
-            // eprintln!("affine propagate");
-            // eprintln!("NNAffine InputType = {:?}", std::any::type_name::<Self::InputType>());
-
+            let simd = self.simd;
             let input = self.prev.propagate(trans_features);
-            // let input = self.prev.get_buf();
-            let input = &input[..Self::SIZE_INPUT];
 
             let input: &[u8] = unsafe {
-                let ptr = input.as_ptr();
-                let ptr2 = ptr as *const u8;
-                std::slice::from_raw_parts(ptr2, input.len())
+                let ptr = input.as_ptr() as *const u8;
+                std::slice::from_raw_parts(ptr, Self::SIZE_INPUT)
             };
 
-            assert_eq!(self.biases.len(), Self::SIZE_OUTPUT);
-            assert_eq!(self.buffer.len(), Self::SIZE_OUTPUT);
-            // eprintln!("Self::SIZE_OUTPUT = {:?}", Self::SIZE_OUTPUT);
-            // assert_eq!(Self::SIZE_OUTPUT % 4, 0);
-
-            // assert_eq!(Self::SIZE_INPUT, Self::SIZE_INPUT_PADDED);
-
-            // let x: u64;
-            // unsafe {
-            //     asm!(
-            //         // "2:",
-            //         "mov {}, 5",
-            //         out(reg) x
-            //     );
-            // }
-            // eprintln!("x = {:?}", x);
-
-            // let mut acc = [0i32; IS];
-            assert_eq!(IS, input.len());
-
-            for i in 0..Self::SIZE_OUTPUT {
-
-                let offset = i * Self::SIZE_INPUT_PADDED;
-
-                let weights = &self.weights[offset..offset + IS];
-                // assert_eq!(weights.len(), input.len());
-
-                // let mut sum: i32 = self.biases[i];
-                let mut sum: i32 = unsafe { *self.biases.get_unchecked(i) };
-
-                for (k,x) in input.iter().enumerate() {
-                    let x: i32 = x.as_();
-                    // let x0 = self.weights[offset + k] as i32 * x;
-                    let x0 = weights[k] as i32 * x;
-                    // let x0 = self.weights[offset + j] as i32 * *x as i32; // no benefit
-                    // let x0 = unsafe { *self.weights.get_unchecked(offset + j) } as i32 * x;
-                    sum += x0;
-                    // acc[k] = x0;
+            if Self::is_blocked(simd) {
+                simd::affine::blocked(
+                    simd,
+                    Self::num_output_regs(simd),
+                    input,
+                    &self.weights,
+                    self.biases.as_ref(),
+                    self.buffer.as_mut());
+            } else {
+                for i in 0..Self::SIZE_OUTPUT {
+                    let offset = i * Self::SIZE_INPUT_PADDED;
+                    let weights = &self.weights[offset..offset + IS];
+                    self.buffer[i] = simd::affine::dot(simd, input, weights, self.biases[i]);
                 }
-
-                self.buffer[i] = sum as i32;
             }
 
             self.buffer.as_ref()
         }
 
+        /// Moves the weights into the layout for simd
+        fn set_simd(&mut self, simd: SimdLevel) {
+            self.prev.set_simd(simd);
+            if simd == self.simd { return; }
+            let mut weights = Aligned(vec![0; self.weights.len()]);
+            for i in 0..self.weights.len() {
+                weights[Self::get_weight_index(simd, i)] =
+                    self.weights[Self::get_weight_index(self.simd, i)];
+            }
+            self.weights = weights;
+            self.simd = simd;
+        }
+
         fn read_parameters(&mut self, mut rdr: &mut BufReader<File>) -> io::Result<()> {
             self.prev.read_parameters(rdr)?;
             // println!("wat NNAffine, OS = {:?}", OS);
@@ -1134,7 +769,7 @@ mod nn_affine {
 
             for i in 0..size {
                 let x = rdr.read_i8()?;
-                self.weights[Self::get_weight_index(i)] = x;
+                self.weights[Self::get_weight_index(self.simd, i)] = x;
                 // self.weights[i] = x;
             }
 
@@ -1150,8 +785,7 @@ mod nn_affine {
             //     w.write_u8(*wt)?;
             // }
             for i in 0..Self::SIZE_OUTPUT * Self::SIZE_INPUT_PADDED {
-                // let wt = self.weights[Self::get_weight_index(i)];
-                let wt = self.weights[i];
+                let wt = self.weights[Self::get_weight_index(self.simd, i)];
                 w.write_i8(wt)?;
                 // unimplemented!()
             }
@@ -1255,6 +889,10 @@ mod nn_relu {
             Ok(())
         }
 
+        fn set_simd(&mut self, simd: SimdLevel) {
+            self.prev.set_simd(simd);
+        }
+
     }
 
 }
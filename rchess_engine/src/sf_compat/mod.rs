@@ -2,10 +2,18 @@
 pub mod layers;
 pub mod accumulator;
 pub mod feature_trans;
+pub mod simd;
+pub mod inspect;
+pub mod perspective;
+pub mod evaluator;
 
 pub use self::feature_trans::NNFeatureTrans;
 pub use self::accumulator::NNAccum;
 pub use self::layers::{NNAffine,NNClippedRelu,NNInput,NNLayer};
+pub use self::simd::SimdLevel;
+pub use self::inspect::WeightStats;
+pub use self::perspective::PerspectiveNet;
+pub use self::evaluator::{NNEvaluator,NNEval};
 
 use crate::types::*;
 
@@ -63,6 +71,71 @@ mod index {
 
 }
 
+pub use self::error::NNUEError;
+mod error {
+    use std::io;
+
+    /// Error when reading a .nnue file
+    #[derive(Debug)]
+    pub enum NNUEError {
+        /// Couldn't open or read the file
+        Io(io::Error),
+        /// File ended before all the weights were read
+        Truncated,
+        Version { found: u32, expected: u32 },
+        /// Header hash, for a different network architecture
+        ArchHash { found: u32, expected: u32 },
+        FeatureHash { found: u32, expected: u32 },
+        /// Layer stack with a different shape
+        LayerStack { stack: usize, found: u32, expected: u32 },
+        /// Description length, anything this big is not a net
+        Description(u32),
+        /// PerspectiveNet hidden layer size
+        HiddenSize(usize),
+        TrailingBytes(usize),
+    }
+
+    impl From<io::Error> for NNUEError {
+        fn from(e: io::Error) -> Self {
+            match e.kind() {
+                io::ErrorKind::UnexpectedEof => Self::Truncated,
+                _                            => Self::Io(e),
+            }
+        }
+    }
+
+    impl std::fmt::Display for NNUEError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Io(e)             => write!(f, "i/o error: {}", e),
+                Self::Truncated         => write!(f, "file is truncated"),
+                Self::Version { found, expected } =>
+                    write!(f, "bad version: {:#010x}, expected {:#010x}", found, expected),
+                Self::ArchHash { found, expected } =>
+                    write!(f, "bad architecture hash: {:#010x}, expected {:#010x}", found, expected),
+                Self::FeatureHash { found, expected } =>
+                    write!(f, "bad feature transformer hash: {:#010x}, expected {:#010x}", found, expected),
+                Self::LayerStack { stack, found, expected } =>
+                    write!(f, "unsupported layer stack {}: hash {:#010x}, expected {:#010x}",
+                           stack, found, expected),
+                Self::Description(size) => write!(f, "bad description size: {}", size),
+                Self::HiddenSize(n)     => write!(f, "unsupported hidden layer size: {}", n),
+                Self::TrailingBytes(n)  => write!(f, "{} bytes after the last layer", n),
+            }
+        }
+    }
+
+    impl std::error::Error for NNUEError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::Io(e) => Some(e),
+                _           => None,
+            }
+        }
+    }
+
+}
+
 pub use self::stats::NNStats;
 mod stats {
     use derive_more::*;
@@ -73,6 +146,8 @@ mod stats {
         pub pops:               u32,
         pub refresh_kingmove:   u32,
         pub refresh_threshold:  u32,
+        /// King move refreshes served from the refresh table
+        pub refresh_cached:     u32,
         pub transforms:         u32,
     }
 
@@ -82,6 +157,7 @@ mod stats {
             // eprintln!("pops              = {:?}", self.pops);
             eprintln!("refresh_kingmove  = {:?}", self.refresh_kingmove);
             eprintln!("refresh_threshold = {:?}", self.refresh_threshold);
+            eprintln!("refresh_cached    = {:?}", self.refresh_cached);
             eprintln!("transforms        = {:?}", self.transforms);
         }
     }
@@ -95,7 +171,7 @@ pub type Layer2 = NNClippedRelu<NNAffine<Layer1, 32, 8>, 32>;
 pub type Layer3 = NNAffine<Layer2, 1, 32>;
 
 pub const HALF_DIMS: usize = 1024;
-const OUTPUT_SCALE: Score = 16;
+pub const OUTPUT_SCALE: Score = 16;
 
 const SQUARE_NB: usize = 64;
 
@@ -137,11 +213,64 @@ const KING_BUCKETS: [i8; 64] = [
 pub struct NNUE4 {
     pub ft:      NNFeatureTrans,
     pub layers:  Vec<Layer3>,
+    /// Written to the file header
+    pub desc:    String,
+}
+
+/// Per bucket outputs, see NNUE4::trace_eval
+#[derive(Debug,Default,Eq,PartialEq,Clone,Copy)]
+pub struct NNTrace {
+    pub psqt:        [Score; 8],
+    pub positional:  [Score; 8],
+    /// Used by evaluate, from the piece count
+    pub bucket:      usize,
+}
+
+/// Start of a .nnue file
+#[derive(Debug,Eq,PartialEq,Clone)]
+pub struct NNUEHeader {
+    pub version:  u32,
+    pub hash:     u32,
+    pub desc:     String,
 }
 
 /// Misc, Consts
 impl NNUE4 {
     pub const HASH: u32 = NNFeatureTrans::HASH ^ Layer3::HASH;
+    pub const VERSION: u32 = 0x7af32f20;
+
+    pub const DEFAULT_DESC: &'static str =
+        "Network trained with the https://github.com/glinscott/nnue-pytorch trainer.";
+
+    /// All weights zero, scalar kernels
+    pub fn new_empty() -> Self {
+        let layer0 = Layer0::new();
+        let layer1 = Layer1::new(NNAffine::new(layer0));
+        let layer2 = Layer2::new(NNAffine::new(layer1));
+        let layer3 = Layer3::new(layer2);
+        Self {
+            ft:      NNFeatureTrans::new(),
+            layers:  vec![layer3; 8],
+            desc:    Self::DEFAULT_DESC.to_string(),
+        }
+    }
+}
+
+/// SIMD
+impl NNUE4 {
+
+    pub fn simd(&self) -> SimdLevel {
+        self.ft.simd
+    }
+
+    /// Select kernels, reorders the affine weights to match
+    pub fn set_simd(&mut self, simd: SimdLevel) {
+        self.ft.simd = simd;
+        for layer in self.layers.iter_mut() {
+            layer.set_simd(simd);
+        }
+    }
+
 }
 
 /// Read, write from file
@@ -151,54 +280,72 @@ impl NNUE4 {
         let mut f = std::fs::File::create(path)?;
         let mut w = io::BufWriter::new(f);
 
-        let version   = 0x7af32f20;
-        let hashvalue = NNUE4::HASH;
-        let size = 75;
-        let desc: &[u8] = b"Network trained with the https://github.com/glinscott/nnue-pytorch trainer.";
-
-        w.write_u32::<LittleEndian>(version)?;
-        w.write_u32::<LittleEndian>(hashvalue)?;
-        w.write_u32::<LittleEndian>(size)?;
-
-        assert_eq!(desc.len(), size as usize);
-        w.write_all(desc)?;
+        w.write_u32::<LittleEndian>(Self::VERSION)?;
+        w.write_u32::<LittleEndian>(Self::HASH)?;
+        w.write_u32::<LittleEndian>(self.desc.len() as u32)?;
+        w.write_all(self.desc.as_bytes())?;
 
         self.ft.write_parameters(&mut w)?;
 
         for layer in self.layers.iter() {
-            w.write_u32::<LittleEndian>(Self::HASH)?;
+            w.write_u32::<LittleEndian>(Layer3::HASH)?;
             layer.write_parameters(&mut w)?;
         }
 
         Ok(())
     }
 
-    pub fn read_nnue<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    pub fn read_header<P: AsRef<Path>>(path: P) -> Result<NNUEHeader, NNUEError> {
         let mut f = std::fs::File::open(path)?;
-        let (ft,layers) = Self::_read_nnue(f)?;
-        Ok(Self {
-            ft,
-            layers,
-            // stats:  NNStats::default(),
-        })
-    }
-
-    fn _read_nnue(mut f: std::fs::File) -> io::Result<(NNFeatureTrans,Vec<Layer3>)> {
         let mut rdr = io::BufReader::new(f);
+        Self::_read_header(&mut rdr)
+    }
 
+    pub(crate) fn _read_header(rdr: &mut BufReader<File>) -> Result<NNUEHeader, NNUEError> {
         let version   = rdr.read_u32::<LittleEndian>()?;
-        // eprintln!("version = {:#8x}", version);
-
-        let hashvalue = rdr.read_u32::<LittleEndian>()?;
-        // eprintln!("hashvalue = {:#8x}", hashvalue);
-
+        let hash      = rdr.read_u32::<LittleEndian>()?;
         let size      = rdr.read_u32::<LittleEndian>()?;
-        // eprintln!("size = {:?}", size);
+
+        /// Real descriptions are well under this, anything bigger is not a net
+        if size > 4096 {
+            return Err(NNUEError::Description(size));
+        }
 
         let mut desc = vec![0u8; size as usize];
         rdr.read_exact(&mut desc)?;
-        let desc = String::from_utf8_lossy(&desc);
-        // eprintln!("desc = {:?}", desc);
+        let desc = String::from_utf8_lossy(&desc).to_string();
+
+        Ok(NNUEHeader { version, hash, desc })
+    }
+
+    /// Uses the best kernels for this CPU, see SimdLevel::detect
+    pub fn read_nnue<P: AsRef<Path>>(path: P) -> Result<Self, NNUEError> {
+        Self::read_nnue_simd(path, SimdLevel::detect())
+    }
+
+    pub fn read_nnue_simd<P: AsRef<Path>>(path: P, simd: SimdLevel) -> Result<Self, NNUEError> {
+        let mut f = std::fs::File::open(path)?;
+        let (ft,layers,desc) = Self::_read_nnue(f)?;
+        let mut out = Self {
+            ft,
+            layers,
+            desc,
+            // stats:  NNStats::default(),
+        };
+        out.set_simd(simd);
+        Ok(out)
+    }
+
+    fn _read_nnue(mut f: std::fs::File) -> Result<(NNFeatureTrans,Vec<Layer3>,String), NNUEError> {
+        let mut rdr = io::BufReader::new(f);
+
+        let header = Self::_read_header(&mut rdr)?;
+        if header.version != Self::VERSION {
+            return Err(NNUEError::Version { found: header.version, expected: Self::VERSION });
+        }
+        if header.hash != Self::HASH {
+            return Err(NNUEError::ArchHash { found: header.hash, expected: Self::HASH });
+        }
 
         let mut ft = NNFeatureTrans::new();
         ft.read_parameters(&mut rdr)?;
@@ -216,19 +363,21 @@ impl NNUE4 {
         for (n,mut layer) in layers.iter_mut().enumerate() {
 
             let hash = rdr.read_u32::<LittleEndian>()?;
-            assert_eq!(hash, Layer3::HASH);
+            if hash != Layer3::HASH {
+                return Err(NNUEError::LayerStack { stack: n, found: hash, expected: Layer3::HASH });
+            }
 
             // eprintln!("layer = {:?}", n);
             layer.read_parameters(&mut rdr)?;
         }
 
-        // let mut r = rdr.get_mut();
         let mut xs = vec![];
         let end = rdr.read_to_end(&mut xs)?;
-        // eprintln!("end = {:?}", end);
-        assert!(end == 0);
+        if end != 0 {
+            return Err(NNUEError::TrailingBytes(end));
+        }
 
-        Ok((ft,layers))
+        Ok((ft,layers,header.desc))
     }
 
 }
@@ -236,45 +385,29 @@ impl NNUE4 {
 /// Evaluate
 impl NNUE4 {
 
-    #[cfg(feature = "nope")]
-    pub fn trace_eval(&mut self, g: &Game, adjusted: bool) -> ([Score; 8], [Score; 8],usize) {
+    /// Output of every layer stack, relative to the side to move.
+    /// Resets the accumulator for g.
+    pub fn trace_eval(&mut self, g: &Game) -> NNTrace {
+        NNEvaluator::reset(self, g);
 
-        let mut out_psqt       = [0; 8];
-        let mut out_positional = [0; 8];
-        let correct_bucket = (g.state.material.count() as usize - 1) / 4;
+        let mut out = NNTrace {
+            psqt:        [0; 8],
+            positional:  [0; 8],
+            bucket:      (g.state.material.count() as usize - 1) / 4,
+        };
 
         for bucket in 0..8 {
+            let mut transformed: Aligned<A64,_> = Aligned([0; HALF_DIMS * 2]);
+            let psqt = self.ft.transform(g, transformed.as_mut(), bucket);
 
-            let mut transformed = [0; HALF_DIMS * 2]; // 2048
-            // let psqt = self.ft.transform(&g, &mut transformed, bucket, true);
-            let psqt = self.ft.transform(g, &mut transformed, bucket);
-
-            // let mut pos_buf = [0; Layer3::BUFFER_SIZE]; // XXX: 320 ??
-            // // eprintln!("pos_buf.len() = {:?}", pos_buf.len());
-            // self.layers[bucket].propagate(&transformed, &mut pos_buf);
-
-            // let pos_buf: Vec<i32> = self.layers[bucket].propagate(&transformed);
-            self.layers[bucket].propagate(&transformed);
-            let pos_buf = self.layers[bucket].get_buf();
-            let positional = pos_buf[0] as Score;
-
-            // for (n,p) in pos_buf.iter().enumerate() {
-            //     if *p != 0 {
-            //         eprintln!("{} = {:?}", n, p);
-            //     }
-            // }
-
-            out_psqt[bucket] = psqt / OUTPUT_SCALE;
-            // out_positional[bucket] = positional as i32 / OUTPUT_SCALE;
-            out_positional[bucket] = positional as Score / 16;
-
-            // out_psqt[bucket] = psqt;
-            // out_positional[bucket] = positional as i32;
+            self.layers[bucket].propagate(&transformed.as_ref());
+            let positional = self.layers[bucket].get_buf()[0] as Score;
 
+            out.psqt[bucket]       = psqt / OUTPUT_SCALE;
+            out.positional[bucket] = positional / OUTPUT_SCALE;
         }
 
-        // (out_psqt,out_positional,correct_bucket)
-        unimplemented!()
+        out
     }
 
     // TODO: check for correctness with trace_eval
@@ -309,7 +442,7 @@ impl NNUE4 {
 
         // TODO: if adjusted
         if adjusted
-            && (g.state.material.non_pawn_value(White) - g.state.material.non_pawn_value(Black)) <= RMB {
+            && (g.state.material.non_pawn_value(White) - g.state.material.non_pawn_value(Black)).abs() <= RMB {
             ((128 - DELTA) * psqt + (128 + DELTA) * positional) / 128 / OUTPUT_SCALE
         } else {
             (psqt + positional) / OUTPUT_SCALE
@@ -360,3 +493,66 @@ impl NNUE4 {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rchess_{}_{}.nnue", name, std::process::id()))
+    }
+
+    fn read_bytes(name: &str, bytes: &[u8]) -> Result<NNUE4, NNUEError> {
+        let path = temp_path(name);
+        std::fs::write(&path, bytes).unwrap();
+        let out = NNUE4::read_nnue_simd(&path, SimdLevel::Scalar);
+        std::fs::remove_file(&path).unwrap();
+        out
+    }
+
+    fn set_u32(bytes: &[u8], offset: usize, x: u32) -> Vec<u8> {
+        let mut out = bytes.to_vec();
+        out[offset..offset + 4].copy_from_slice(&x.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn nnue4_read_errors() {
+        let nn = NNUE4::new_empty();
+        let path = temp_path("errors");
+        nn.write_nnue(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_bytes("ok", &bytes).is_ok());
+
+        let header = 12 + nn.desc.len();
+        let ft = 4 + HALF_DIMS * 2
+            + NNFeatureTrans::DIMS_IN * HALF_DIMS * 2
+            + NNFeatureTrans::DIMS_IN * NNFeatureTrans::PSQT_BUCKETS * 4;
+
+        assert!(matches!(read_bytes("version", &set_u32(&bytes, 0, 0x1234)),
+                         Err(NNUEError::Version { found: 0x1234, expected: NNUE4::VERSION })));
+        assert!(matches!(read_bytes("arch", &set_u32(&bytes, 4, 0x1234)),
+                         Err(NNUEError::ArchHash { found: 0x1234, expected: NNUE4::HASH })));
+        assert!(matches!(read_bytes("desc", &set_u32(&bytes, 8, 5000)),
+                         Err(NNUEError::Description(5000))));
+        assert!(matches!(read_bytes("feature", &set_u32(&bytes, header, 0x1234)),
+                         Err(NNUEError::FeatureHash { found: 0x1234, expected: NNFeatureTrans::HASH })));
+        assert!(matches!(read_bytes("stack", &set_u32(&bytes, header + ft, 0x1234)),
+                         Err(NNUEError::LayerStack { stack: 0, found: 0x1234, .. })));
+
+        for n in [0, 6, header + 2, header + ft / 2, bytes.len() - 1] {
+            assert!(matches!(read_bytes("truncated", &bytes[..n]), Err(NNUEError::Truncated)), "{}", n);
+        }
+
+        let mut long = bytes.clone();
+        long.extend_from_slice(&[0; 3]);
+        assert!(matches!(read_bytes("trailing", &long), Err(NNUEError::TrailingBytes(3))));
+
+        let missing = NNUE4::read_nnue(temp_path("missing"));
+        assert!(matches!(missing, Err(NNUEError::Io(_))));
+        assert!(matches!(NNUE4::read_header(temp_path("missing")), Err(NNUEError::Io(_))));
+    }
+
+}
@@ -0,0 +1,254 @@
+
+use crate::types::*;
+use crate::sf_compat::{NNUE4,NNUEError,NNUEHeader};
+
+use std::io::{self,Read,BufReader,Write,BufWriter};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
+use rand::prelude::{StdRng,Rng};
+
+/// (768 -> N) x 2 -> 1, one accumulator per perspective, no king buckets.
+/// Small enough to train quickly, and king moves are incremental like any other move.
+#[derive(Debug,Clone)]
+pub struct PerspectiveNet {
+    pub hidden:       usize,
+    /// [feature][hidden]
+    pub ft_weights:   Vec<i16>,
+    pub ft_biases:    Vec<i16>,
+    /// side to move half first
+    pub out_weights:  Vec<i16>,
+    /// scaled by QA * QB
+    pub out_bias:     i32,
+    pub desc:         String,
+
+    /// [White, Black] accumulators for each ply, current position last
+    stack:            Vec<i16>,
+}
+
+/// Consts, Init
+impl PerspectiveNet {
+    pub const INPUTS: usize = 768;
+
+    /// Activations, clipped to 0..=QA
+    pub const QA: i32 = 255;
+    pub const QB: i32 = 64;
+    /// Output to centipawns
+    pub const SCALE: i32 = 400;
+
+    pub const VERSION: u32 = 0x5045_5231;
+    const HASH_BASE: u32 = 0x9a3c_51e2;
+
+    /// Bigger than this is almost certainly a bad file
+    pub const MAX_HIDDEN: usize = 4096;
+
+    pub const DEFAULT_DESC: &'static str = "Perspective network";
+
+    pub fn hash(hidden: usize) -> u32 {
+        Self::HASH_BASE ^ hidden as u32
+    }
+
+    pub fn new_empty(hidden: usize) -> Self {
+        Self {
+            hidden,
+            ft_weights:   vec![0; Self::INPUTS * hidden],
+            ft_biases:    vec![0; hidden],
+            out_weights:  vec![0; 2 * hidden],
+            out_bias:     0,
+            desc:         Self::DEFAULT_DESC.to_string(),
+            stack:        Vec::with_capacity(2 * hidden * 256),
+        }
+    }
+
+    pub fn new_random(hidden: usize, rng: &mut StdRng) -> Self {
+        let mut out = Self::new_empty(hidden);
+        out.ft_weights.iter_mut().for_each(|x| *x = rng.gen_range(-64..64));
+        out.ft_biases.iter_mut().for_each(|x| *x = rng.gen_range(-64..64));
+        out.out_weights.iter_mut().for_each(|x| *x = rng.gen_range(-64..64));
+        out
+    }
+
+}
+
+/// Read, write from file
+impl PerspectiveNet {
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut f = std::fs::File::create(path)?;
+        let mut w = io::BufWriter::new(f);
+
+        w.write_u32::<LittleEndian>(Self::VERSION)?;
+        w.write_u32::<LittleEndian>(Self::hash(self.hidden))?;
+        w.write_u32::<LittleEndian>(self.desc.len() as u32)?;
+        w.write_all(self.desc.as_bytes())?;
+
+        w.write_u32::<LittleEndian>(self.hidden as u32)?;
+        for x in self.ft_weights.iter() {
+            w.write_i16::<LittleEndian>(*x)?;
+        }
+        for x in self.ft_biases.iter() {
+            w.write_i16::<LittleEndian>(*x)?;
+        }
+        for x in self.out_weights.iter() {
+            w.write_i16::<LittleEndian>(*x)?;
+        }
+        w.write_i32::<LittleEndian>(self.out_bias)?;
+
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, NNUEError> {
+        let mut f = std::fs::File::open(path)?;
+        let mut rdr = io::BufReader::new(f);
+
+        let header = NNUE4::_read_header(&mut rdr)?;
+        if header.version != Self::VERSION {
+            return Err(NNUEError::Version { found: header.version, expected: Self::VERSION });
+        }
+
+        let hidden = rdr.read_u32::<LittleEndian>()? as usize;
+        if hidden == 0 || hidden > Self::MAX_HIDDEN {
+            return Err(NNUEError::HiddenSize(hidden));
+        }
+        if header.hash != Self::hash(hidden) {
+            return Err(NNUEError::ArchHash { found: header.hash, expected: Self::hash(hidden) });
+        }
+
+        let mut out = Self::new_empty(hidden);
+        out.desc = header.desc;
+
+        for x in out.ft_weights.iter_mut() {
+            *x = rdr.read_i16::<LittleEndian>()?;
+        }
+        for x in out.ft_biases.iter_mut() {
+            *x = rdr.read_i16::<LittleEndian>()?;
+        }
+        for x in out.out_weights.iter_mut() {
+            *x = rdr.read_i16::<LittleEndian>()?;
+        }
+        out.out_bias = rdr.read_i32::<LittleEndian>()?;
+
+        let mut xs = vec![];
+        let end = rdr.read_to_end(&mut xs)?;
+        if end != 0 {
+            return Err(NNUEError::TrailingBytes(end));
+        }
+
+        Ok(out)
+    }
+
+}
+
+/// Accumulators
+impl PerspectiveNet {
+
+    pub fn index(persp: Color, pc: Piece, side: Color, sq: Coord) -> usize {
+        let sq = if persp == White { sq.inner() } else { sq.inner() ^ 56 };
+        let s = if side == persp { 0 } else { 1 };
+        s * 384 + pc.index() * 64 + sq as usize
+    }
+
+    fn top(&self) -> &[i16] {
+        &self.stack[self.stack.len() - 2 * self.hidden..]
+    }
+
+    /// Recompute both perspectives, and drop the rest of the stack
+    pub fn refresh(&mut self, g: &Game) {
+        self.stack.clear();
+        self.stack.extend_from_slice(&self.ft_biases);
+        self.stack.extend_from_slice(&self.ft_biases);
+
+        for side in [White,Black] {
+            for pc in Piece::iter_pieces() {
+                g.get(pc,side).into_iter().for_each(|sq| {
+                    self.update::<true>(pc, side, sq);
+                });
+            }
+        }
+    }
+
+    fn update<const ADD: bool>(&mut self, pc: Piece, side: Color, sq: Coord) {
+        let h = self.hidden;
+        let start = self.stack.len() - 2 * h;
+        for persp in [White,Black] {
+            let offset = h * Self::index(persp, pc, side, sq);
+            let ws = &self.ft_weights[offset..offset + h];
+            let acc = &mut self.stack[start + persp.fold(0, h)..][..h];
+            for k in 0..h {
+                acc[k] = if ADD { acc[k].wrapping_add(ws[k]) } else { acc[k].wrapping_sub(ws[k]) };
+            }
+        }
+    }
+
+    fn move_piece(&mut self, pc: Piece, side: Color, from: Coord, to: Coord) {
+        self.update::<false>(pc, side, from);
+        self.update::<true>(pc, side, to);
+    }
+
+    /// g2 is the position after mv
+    pub fn push_move(&mut self, g2: &Game, mv: Move) {
+        let h = self.hidden;
+        let len = self.stack.len();
+        self.stack.extend_from_within(len - 2 * h..);
+
+        let side = !g2.state.side_to_move;
+
+        match mv {
+            Move::Quiet { from, to, pc } => {
+                self.move_piece(pc, side, from, to);
+            },
+            Move::PawnDouble { from, to } => {
+                self.move_piece(Pawn, side, from, to);
+            },
+            Move::Capture { from, to, pcs } => {
+                self.move_piece(pcs.first(), side, from, to);
+                self.update::<false>(pcs.second(), !side, to);
+            },
+            Move::EnPassant { from, to, capture } => {
+                self.move_piece(Pawn, side, from, to);
+                self.update::<false>(Pawn, !side, capture);
+            },
+            Move::Castle { .. } => {
+                let ((from,to),(rook_from,rook_to)) = mv.castle_moves();
+                self.move_piece(King, side, from, to);
+                self.move_piece(Rook, side, rook_from, rook_to);
+            },
+            Move::Promotion { from, to, new_piece } => {
+                self.update::<false>(Pawn, side, from);
+                self.update::<true>(new_piece, side, to);
+            },
+            Move::PromotionCapture { from, to, pcs } => {
+                self.update::<false>(Pawn, side, from);
+                self.update::<true>(pcs.first(), side, to);
+                self.update::<false>(pcs.second(), !side, to);
+            },
+            Move::NullMove => {},
+        }
+    }
+
+    pub fn pop_move(&mut self) {
+        let len = self.stack.len();
+        assert!(len > 2 * self.hidden, "empty stack pop?");
+        self.stack.truncate(len - 2 * self.hidden);
+    }
+
+    /// Relative to the side to move
+    pub fn evaluate(&self, g: &Game) -> Score {
+        let h = self.hidden;
+        let top = self.top();
+        let stm = g.state.side_to_move;
+
+        let mut sum: i64 = 0;
+        for (p,persp) in [stm, !stm].iter().enumerate() {
+            let acc = &top[persp.fold(0, h)..][..h];
+            let ws  = &self.out_weights[p * h..][..h];
+            for k in 0..h {
+                sum += ((acc[k] as i32).clamp(0, Self::QA) * ws[k] as i32) as i64;
+            }
+        }
+
+        ((sum + self.out_bias as i64) * Self::SCALE as i64 / (Self::QA * Self::QB) as i64) as Score
+    }
+
+}
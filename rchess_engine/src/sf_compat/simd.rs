@@ -0,0 +1,612 @@
+
+//! SIMD kernels for the feature transformer and affine layers, selected at runtime.
+//!
+//! Every kernel gives the same result as the scalar version, bit for bit:
+//!   - accumulator adds wrap, like _mm256_add_epi16
+//!   - affine products are widened to i32 before summing, u8 inputs are at most 127
+//!     so maddubs can't saturate
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::*;
+
+#[derive(Debug,Eq,PartialEq,Ord,PartialOrd,Hash,Clone,Copy)]
+pub enum SimdLevel {
+    Scalar,
+    Ssse3,
+    Avx2,
+}
+
+impl Default for SimdLevel {
+    fn default() -> Self {
+        Self::detect()
+    }
+}
+
+impl SimdLevel {
+
+    /// Best level this CPU supports
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Self::Avx2;
+            } else if is_x86_feature_detected!("ssse3") {
+                return Self::Ssse3;
+            }
+        }
+        Self::Scalar
+    }
+
+    /// All levels this CPU can run, Scalar first
+    pub fn supported() -> Vec<Self> {
+        let best = Self::detect();
+        [Self::Scalar, Self::Ssse3, Self::Avx2].iter()
+            .filter(|&&s| s <= best)
+            .cloned()
+            .collect()
+    }
+
+    /// Bytes per register
+    pub const fn width(self) -> usize {
+        match self {
+            Self::Scalar => 1,
+            Self::Ssse3  => 16,
+            Self::Avx2   => 32,
+        }
+    }
+
+    pub const fn max_output_regs(self) -> usize {
+        match self {
+            Self::Scalar => 1,
+            Self::Ssse3  => 8,
+            Self::Avx2   => 8,
+        }
+    }
+
+}
+
+/// Accumulator
+pub mod accum {
+    use super::*;
+
+    /// Registers per tile
+    const NUM_REGS: usize = 16;
+
+    /// out = biases + sum of weights[offset..offset + out.len()] for each offset
+    pub fn refresh_i16(simd: SimdLevel, out: &mut [i16], biases: &[i16], weights: &[i16], offsets: &[usize]) {
+        assert_eq!(out.len(), biases.len());
+        for &offset in offsets.iter() {
+            assert!(offset + out.len() <= weights.len());
+        }
+        match simd {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 if out.len() % (NUM_REGS * 16) == 0 => unsafe {
+                refresh_i16_avx2(out, biases, weights, offsets)
+            },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Ssse3 if out.len() % (NUM_REGS * 8) == 0 => unsafe {
+                refresh_i16_ssse3(out, biases, weights, offsets)
+            },
+            _ => refresh_i16_scalar(out, biases, weights, offsets),
+        }
+    }
+
+    /// acc += ws if ADD, else acc -= ws
+    pub fn update_i16<const ADD: bool>(simd: SimdLevel, acc: &mut [i16], ws: &[i16]) {
+        assert_eq!(acc.len(), ws.len());
+        match simd {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 if acc.len() % 16 == 0 => unsafe { update_i16_avx2::<ADD>(acc, ws) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Ssse3 if acc.len() % 8 == 0 => unsafe { update_i16_sse2::<ADD>(acc, ws) },
+            _ => update_i16_scalar::<ADD>(acc, ws),
+        }
+    }
+
+    pub fn refresh_i16_scalar(out: &mut [i16], biases: &[i16], weights: &[i16], offsets: &[usize]) {
+        out.copy_from_slice(biases);
+        for &offset in offsets.iter() {
+            let ws = &weights[offset..offset + out.len()];
+            for (a,w) in out.iter_mut().zip(ws.iter()) {
+                *a = a.wrapping_add(*w);
+            }
+        }
+    }
+
+    pub fn update_i16_scalar<const ADD: bool>(acc: &mut [i16], ws: &[i16]) {
+        for (a,w) in acc.iter_mut().zip(ws.iter()) {
+            *a = if ADD { a.wrapping_add(*w) } else { a.wrapping_sub(*w) };
+        }
+    }
+
+    /// Tiles of NUM_REGS registers, so each tile of the accumulator stays in registers
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn refresh_i16_avx2(out: &mut [i16], biases: &[i16], weights: &[i16], offsets: &[usize]) {
+        const TILE: usize = NUM_REGS * 16;
+        let mut acc = [_mm256_setzero_si256(); NUM_REGS];
+        for k in 0..out.len() / TILE {
+            let bs = biases.as_ptr().add(k * TILE) as *const __m256i;
+            for i in 0..NUM_REGS {
+                acc[i] = _mm256_loadu_si256(bs.add(i));
+            }
+            for &offset in offsets.iter() {
+                let col = weights.as_ptr().add(offset + k * TILE) as *const __m256i;
+                for i in 0..NUM_REGS {
+                    acc[i] = _mm256_add_epi16(acc[i], _mm256_loadu_si256(col.add(i)));
+                }
+            }
+            let dst = out.as_mut_ptr().add(k * TILE) as *mut __m256i;
+            for i in 0..NUM_REGS {
+                _mm256_storeu_si256(dst.add(i), acc[i]);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn refresh_i16_ssse3(out: &mut [i16], biases: &[i16], weights: &[i16], offsets: &[usize]) {
+        const TILE: usize = NUM_REGS * 8;
+        let mut acc = [_mm_setzero_si128(); NUM_REGS];
+        for k in 0..out.len() / TILE {
+            let bs = biases.as_ptr().add(k * TILE) as *const __m128i;
+            for i in 0..NUM_REGS {
+                acc[i] = _mm_loadu_si128(bs.add(i));
+            }
+            for &offset in offsets.iter() {
+                let col = weights.as_ptr().add(offset + k * TILE) as *const __m128i;
+                for i in 0..NUM_REGS {
+                    acc[i] = _mm_add_epi16(acc[i], _mm_loadu_si128(col.add(i)));
+                }
+            }
+            let dst = out.as_mut_ptr().add(k * TILE) as *mut __m128i;
+            for i in 0..NUM_REGS {
+                _mm_storeu_si128(dst.add(i), acc[i]);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn update_i16_avx2<const ADD: bool>(acc: &mut [i16], ws: &[i16]) {
+        let a = acc.as_mut_ptr() as *mut __m256i;
+        let w = ws.as_ptr() as *const __m256i;
+        for i in 0..acc.len() / 16 {
+            let x = _mm256_loadu_si256(a.add(i));
+            let y = _mm256_loadu_si256(w.add(i));
+            let x = if ADD { _mm256_add_epi16(x, y) } else { _mm256_sub_epi16(x, y) };
+            _mm256_storeu_si256(a.add(i), x);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn update_i16_sse2<const ADD: bool>(acc: &mut [i16], ws: &[i16]) {
+        let a = acc.as_mut_ptr() as *mut __m128i;
+        let w = ws.as_ptr() as *const __m128i;
+        for i in 0..acc.len() / 8 {
+            let x = _mm_loadu_si128(a.add(i));
+            let y = _mm_loadu_si128(w.add(i));
+            let x = if ADD { _mm_add_epi16(x, y) } else { _mm_sub_epi16(x, y) };
+            _mm_storeu_si128(a.add(i), x);
+        }
+    }
+
+}
+
+/// Affine layers
+pub mod affine {
+    use super::*;
+
+    /// Layout used by blocked, for each block of num_regs outputs:
+    /// input chunk r of output k is at chunk (r * num_regs + k) in the block
+    pub fn blocked_weight_index(
+        simd:          SimdLevel,
+        num_regs:      usize,
+        size_in:       usize,
+        idx:           usize,
+    ) -> usize {
+        let width = simd.width();
+        let big_block_size = num_regs * size_in;
+        let small_per_output = size_in / width;
+        let small_per_big = big_block_size / width;
+
+        let small_block = (idx / width) % small_per_big;
+        let col = small_block / small_per_output;
+        let row = small_block % small_per_output;
+        let big_block = idx / big_block_size;
+
+        big_block * big_block_size + row * width * num_regs + col * width + idx % width
+    }
+
+    /// output[i] = biases[i] + dot(input, weights[i]), weights in blocked_weight_index layout.
+    /// size_in must be a multiple of 2 * width, and output.len() of num_regs
+    pub fn blocked(
+        simd:          SimdLevel,
+        num_regs:      usize,
+        input:         &[u8],
+        weights:       &[i8],
+        biases:        &[i32],
+        output:        &mut [i32],
+    ) {
+        let size_in = input.len();
+        assert!(size_in % (2 * simd.width()) == 0);
+        assert!(output.len() % num_regs == 0 && num_regs <= 8);
+        assert!(weights.len() >= size_in * output.len());
+        assert_eq!(biases.len(), output.len());
+        match simd {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2  => unsafe { blocked_avx2(num_regs, input, weights, biases, output) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Ssse3 => unsafe { blocked_ssse3(num_regs, input, weights, biases, output) },
+            _ => panic!("affine::blocked, no kernel for {:?}", simd),
+        }
+    }
+
+    /// bias + dot(input, weights)
+    pub fn dot(simd: SimdLevel, input: &[u8], weights: &[i8], bias: i32) -> i32 {
+        assert!(weights.len() >= input.len());
+        match simd {
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2  => unsafe { dot_avx2(input, weights, bias) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Ssse3 => unsafe { dot_ssse3(input, weights, bias) },
+            _                => dot_scalar(input, weights, bias),
+        }
+    }
+
+    pub fn dot_scalar(input: &[u8], weights: &[i8], bias: i32) -> i32 {
+        input.iter().zip(weights.iter())
+            .fold(bias, |acc, (&x,&w)| acc.wrapping_add(x as i32 * w as i32))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn madd_avx2(x: __m256i, w: __m256i) -> __m256i {
+        _mm256_madd_epi16(_mm256_maddubs_epi16(x, w), _mm256_set1_epi16(1))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn hsum_avx2(x: __m256i) -> i32 {
+        let x = _mm_add_epi32(_mm256_castsi256_si128(x), _mm256_extracti128_si256::<1>(x));
+        hsum_sse2(x)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn madd_ssse3(x: __m128i, w: __m128i) -> __m128i {
+        _mm_madd_epi16(_mm_maddubs_epi16(x, w), _mm_set1_epi16(1))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn hsum_sse2(x: __m128i) -> i32 {
+        let x = _mm_add_epi32(x, _mm_shuffle_epi32::<0x4E>(x));
+        let x = _mm_add_epi32(x, _mm_shuffle_epi32::<0xB1>(x));
+        _mm_cvtsi128_si32(x)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn blocked_avx2(num_regs: usize, input: &[u8], weights: &[i8], biases: &[i32], output: &mut [i32]) {
+        const W: usize = 32;
+        let size_in = input.len();
+        let big_block_size = num_regs * size_in;
+        let inp = input.as_ptr() as *const __m256i;
+        let ws = weights.as_ptr() as *const __m256i;
+
+        for big_block in 0..output.len() / num_regs {
+            let mut acc = [_mm256_setzero_si256(); 8];
+            let mut small_block = 0;
+            while small_block < size_in / W {
+                let w_offset = (big_block * big_block_size + small_block * W * num_regs) / W;
+                let in0 = _mm256_loadu_si256(inp.add(small_block));
+                let in1 = _mm256_loadu_si256(inp.add(small_block + 1));
+                for k in 0..num_regs {
+                    let b0 = _mm256_loadu_si256(ws.add(w_offset + k));
+                    let b1 = _mm256_loadu_si256(ws.add(w_offset + k + num_regs));
+                    let p = _mm256_add_epi32(madd_avx2(in0, b0), madd_avx2(in1, b1));
+                    acc[k] = _mm256_add_epi32(acc[k], p);
+                }
+                small_block += 2;
+            }
+            for k in 0..num_regs {
+                let i = big_block * num_regs + k;
+                output[i] = hsum_avx2(acc[k]).wrapping_add(biases[i]);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn blocked_ssse3(num_regs: usize, input: &[u8], weights: &[i8], biases: &[i32], output: &mut [i32]) {
+        const W: usize = 16;
+        let size_in = input.len();
+        let big_block_size = num_regs * size_in;
+        let inp = input.as_ptr() as *const __m128i;
+        let ws = weights.as_ptr() as *const __m128i;
+
+        for big_block in 0..output.len() / num_regs {
+            let mut acc = [_mm_setzero_si128(); 8];
+            let mut small_block = 0;
+            while small_block < size_in / W {
+                let w_offset = (big_block * big_block_size + small_block * W * num_regs) / W;
+                let in0 = _mm_loadu_si128(inp.add(small_block));
+                let in1 = _mm_loadu_si128(inp.add(small_block + 1));
+                for k in 0..num_regs {
+                    let b0 = _mm_loadu_si128(ws.add(w_offset + k));
+                    let b1 = _mm_loadu_si128(ws.add(w_offset + k + num_regs));
+                    let p = _mm_add_epi32(madd_ssse3(in0, b0), madd_ssse3(in1, b1));
+                    acc[k] = _mm_add_epi32(acc[k], p);
+                }
+                small_block += 2;
+            }
+            for k in 0..num_regs {
+                let i = big_block * num_regs + k;
+                output[i] = hsum_sse2(acc[k]).wrapping_add(biases[i]);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn dot_avx2(input: &[u8], weights: &[i8], bias: i32) -> i32 {
+        const W: usize = 32;
+        let n = input.len() / W;
+        let inp = input.as_ptr() as *const __m256i;
+        let ws = weights.as_ptr() as *const __m256i;
+        let mut sum = _mm256_setzero_si256();
+        for k in 0..n {
+            let p = madd_avx2(_mm256_loadu_si256(inp.add(k)), _mm256_loadu_si256(ws.add(k)));
+            sum = _mm256_add_epi32(sum, p);
+        }
+        dot_scalar(&input[n * W..], &weights[n * W..], hsum_avx2(sum).wrapping_add(bias))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn dot_ssse3(input: &[u8], weights: &[i8], bias: i32) -> i32 {
+        const W: usize = 16;
+        let n = input.len() / W;
+        let inp = input.as_ptr() as *const __m128i;
+        let ws = weights.as_ptr() as *const __m128i;
+        let mut sum = _mm_setzero_si128();
+        for k in 0..n {
+            let p = madd_ssse3(_mm_loadu_si128(inp.add(k)), _mm_loadu_si128(ws.add(k)));
+            sum = _mm_add_epi32(sum, p);
+        }
+        dot_scalar(&input[n * W..], &weights[n * W..], hsum_sse2(sum).wrapping_add(bias))
+    }
+
+}
+
+/// Checks every supported level against Scalar
+pub mod check {
+    use super::*;
+    use crate::types::*;
+    use crate::tables::*;
+    use crate::movegen::MoveGen;
+    use crate::parsing::FenMode;
+    use crate::sf_compat::{NNUE4, NNLayer, Layer3, HALF_DIMS};
+
+    use aligned::{Aligned,A64};
+    use rand::prelude::{StdRng,Rng};
+
+    #[derive(Debug,Clone)]
+    pub struct SimdError {
+        pub simd:     SimdLevel,
+        pub fen:      String,
+        /// which part differed
+        pub what:     String,
+    }
+
+    #[derive(Debug,Default,Clone,Copy)]
+    pub struct SimdStats {
+        pub levels:      usize,
+        pub positions:   u64,
+        pub moves:       u64,
+        pub bad_fens:    u64,
+    }
+
+    impl std::fmt::Display for SimdError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}: {}, {}", self.simd, self.what, self.fen)
+        }
+    }
+
+    /// Random weights, for when there's no net to check with
+    pub fn randomize(nn: &mut NNUE4, rng: &mut StdRng) {
+        let simd = nn.simd();
+        nn.set_simd(SimdLevel::Scalar);
+
+        nn.ft.biases.iter_mut().for_each(|x| *x = rng.gen_range(-64..64));
+        nn.ft.weights.iter_mut().for_each(|x| *x = rng.gen_range(-32..32));
+        nn.ft.psqt_weights.iter_mut().for_each(|x| *x = rng.gen_range(-2000..2000));
+        nn.ft.clear_refresh_table();
+
+        for layer in nn.layers.iter_mut() {
+            let l2 = &mut layer.prev.prev;
+            let l1 = &mut l2.prev.prev;
+            layer.biases.iter_mut().for_each(|x| *x = rng.gen_range(-10000..10000));
+            layer.weights.iter_mut().for_each(|x| *x = rng.gen());
+            l2.biases.iter_mut().for_each(|x| *x = rng.gen_range(-10000..10000));
+            l2.weights.iter_mut().for_each(|x| *x = rng.gen());
+            l1.biases.iter_mut().for_each(|x| *x = rng.gen_range(-10000..10000));
+            l1.weights.iter_mut().for_each(|x| *x = rng.gen_range(-16..16));
+        }
+
+        nn.set_simd(simd);
+    }
+
+    /// Outputs of each layer after propagate, input first
+    fn layer_outputs(layer: &Layer3) -> (Vec<i32>,Vec<u8>,Vec<i32>,Vec<u8>,i32) {
+        let l2 = &layer.prev.prev;
+        let l1 = &l2.prev.prev;
+        (l1.get_buf().to_vec(),
+         layer.prev.prev.prev.get_buf().to_vec(),
+         l2.get_buf().to_vec(),
+         layer.prev.get_buf().to_vec(),
+         layer.get_buf()[0])
+    }
+
+    fn same_accum(nn: &NNUE4, nn2: &NNUE4) -> bool {
+        nn.ft.accum.accum[..] == nn2.ft.accum.accum[..]
+            && nn.ft.accum.psqt[..] == nn2.ft.accum.psqt[..]
+    }
+
+    /// reference must use SimdLevel::Scalar
+    pub fn check_position(
+        ts:          &Tables,
+        reference:   &mut NNUE4,
+        nn:          &mut NNUE4,
+        g:           &Game,
+    ) -> Result<u64, String> {
+        assert_eq!(reference.simd(), SimdLevel::Scalar);
+
+        reference.ft.reset_accum(g);
+        nn.ft.reset_accum(g);
+        if !same_accum(reference, nn) {
+            return Err("reset_accum".to_string());
+        }
+
+        for bucket in 0..8 {
+            let mut t0: Aligned<A64,_> = Aligned([0u8; HALF_DIMS * 2]);
+            let mut t1: Aligned<A64,_> = Aligned([0u8; HALF_DIMS * 2]);
+            let psqt0 = reference.ft.transform(g, t0.as_mut(), bucket);
+            let psqt1 = nn.ft.transform(g, t1.as_mut(), bucket);
+            if psqt0 != psqt1 || t0[..] != t1[..] {
+                return Err(format!("transform, bucket {}", bucket));
+            }
+
+            reference.layers[bucket]._propagate_nosimd(t0.as_ref());
+            let out0 = layer_outputs(&reference.layers[bucket]);
+
+            nn.layers[bucket].propagate(t1.as_ref());
+            if layer_outputs(&nn.layers[bucket]) != out0 {
+                return Err(format!("propagate, bucket {}", bucket));
+            }
+
+            /// indexing into the reordered weights
+            nn.layers[bucket]._propagate_nosimd(t1.as_ref());
+            if layer_outputs(&nn.layers[bucket]) != out0 {
+                return Err(format!("_propagate_nosimd, bucket {}", bucket));
+            }
+        }
+
+        reference.ft.reset_accum(g);
+        nn.ft.reset_accum(g);
+        if reference.evaluate(g, true) != nn.evaluate(g, true) {
+            return Err("evaluate".to_string());
+        }
+
+        let mut moves = 0;
+        for mv in MoveGen::generate_list_legal(ts, g, None) {
+            let g2 = match g.make_move_unchecked(ts, mv) {
+                Ok(g2) => g2,
+                Err(_) => continue,
+            };
+            moves += 1;
+
+            nn.ft.reset_accum(g);
+            nn.ft.make_move(&g2, mv);
+            reference.ft.reset_accum(&g2);
+            if !same_accum(reference, nn) {
+                return Err(format!("make_move {:?}", mv));
+            }
+
+            nn.ft.accum_pop();
+            reference.ft.reset_accum(g);
+            if !same_accum(reference, nn) {
+                return Err(format!("accum_pop {:?}", mv));
+            }
+        }
+
+        Ok(moves)
+    }
+
+    /// Checks each position and all of its children, at each level in SimdLevel::supported
+    pub fn check_positions(
+        ts:          &Tables,
+        nn:          &NNUE4,
+        fens:        &[String],
+    ) -> (SimdStats, Vec<SimdError>) {
+        let mut stats = SimdStats::default();
+        let mut errors = vec![];
+
+        let mut gs = vec![];
+        for fen in fens.iter() {
+            let fen = fen.split(';').next().unwrap_or("");
+            let g = match Game::from_fen_mode(ts, fen, FenMode::Lenient) {
+                Ok(g)  => g,
+                Err(_) => {
+                    stats.bad_fens += 1;
+                    continue;
+                },
+            };
+            for mv in MoveGen::generate_list_legal(ts, &g, None) {
+                if let Ok(g2) = g.make_move_unchecked(ts, mv) {
+                    gs.push(g2);
+                }
+            }
+            gs.push(g);
+        }
+
+        /// Full refreshes, so king moves also check the refresh table
+        let mut reference = nn.clone();
+        reference.set_simd(SimdLevel::Scalar);
+        reference.ft.refresh_table = None;
+
+        for simd in SimdLevel::supported() {
+            stats.levels += 1;
+            let mut nn2 = nn.clone();
+            nn2.set_simd(simd);
+
+            for g in gs.iter() {
+                stats.positions += 1;
+                match check_position(ts, &mut reference, &mut nn2, g) {
+                    Ok(n)    => stats.moves += n,
+                    Err(what) => errors.push(SimdError { simd, fen: g.to_fen(), what }),
+                }
+            }
+        }
+
+        (stats, errors)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::check::*;
+    use crate::tables::Tables;
+    use crate::sf_compat::NNUE4;
+    use crate::util::read_epd_no_bm;
+
+    use rand::prelude::{StdRng,SeedableRng};
+
+    #[test]
+    fn simd_matches_scalar_perft_fens() {
+        let ts = Tables::new();
+
+        let mut nn = NNUE4::new_empty();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        randomize(&mut nn, &mut rng);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt");
+        let fens = read_epd_no_bm(path).unwrap().into_iter()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with("//"))
+            .collect::<Vec<_>>();
+
+        let (stats, errors) = check_positions(&ts, &nn, &fens);
+        for e in errors.iter().take(20) {
+            eprintln!("{}", e);
+        }
+        assert!(errors.is_empty(), "{} mismatches", errors.len());
+        assert_eq!(stats.bad_fens, 0);
+        assert_eq!(stats.levels, SimdLevel::supported().len());
+        assert!(stats.positions > 300 && stats.moves > 1000, "{:?}", stats);
+    }
+
+}
@@ -0,0 +1,216 @@
+
+use crate::types::*;
+use crate::tables::*;
+use crate::explore::ExHelper;
+use crate::movegen::MoveGen;
+use crate::searchstats::SearchStats;
+use crate::sf_compat::{NNUE4,NNEvaluator};
+use crate::parsing::FenMode;
+use crate::util::read_epd_no_bm;
+
+/// Checks that the evaluation doesn't depend on which side is White,
+/// or on which side of the board the pieces are on.
+///
+/// Color flip: mirror vertically and swap colors, see Game::flip_sides.
+///   evaluate_classical is from White's perspective, so should change sign.
+///   NNUE4::evaluate is from side to move's perspective, so should be equal.
+/// Mirror: flip files, only checked with no castling rights. Should be equal.
+
+#[derive(Debug,Eq,PartialEq,Clone,Copy)]
+pub enum Symmetry {
+    ColorFlip,
+    Mirror,
+}
+
+#[derive(Debug,Clone)]
+pub struct SymmetryError {
+    pub fen:       String,
+    pub fen2:      String,
+    pub symmetry:  Symmetry,
+    pub nnue:      bool,
+    pub score:     Score,
+    pub score2:    Score,
+}
+
+#[derive(Debug,Default,Clone,Copy)]
+pub struct SymmetryStats {
+    pub positions:   u64,
+    pub flipped:     u64,
+    pub mirrored:    u64,
+    /// positions with a specialized endgame eval, skipped by classical
+    pub skipped:     u64,
+    pub bad_fens:    u64,
+}
+
+impl std::fmt::Display for SymmetryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({}): {} = {}, {} = {}",
+               self.symmetry, if self.nnue { "nnue" } else { "classical" },
+               self.fen, self.score, self.fen2, self.score2)
+    }
+}
+
+/// Color flip, and mirror when there are no castling rights
+fn transforms(ts: &Tables, g: &Game) -> Vec<(Symmetry,Game)> {
+    let mut out = vec![(Symmetry::ColorFlip, g.flip_sides(ts))];
+    if let Some(g2) = g.mirror_horizontal(ts) {
+        out.push((Symmetry::Mirror, g2));
+    }
+    out
+}
+
+/// Ok(false) if skipped, for positions with a specialized endgame eval
+pub fn check_classical(
+    ts:         &Tables,
+    helper:     &mut ExHelper,
+    g:          &Game,
+) -> Result<bool, SymmetryError> {
+    let (me,_,_) = helper.material_table.get_or_insert(
+        ts, g, &helper.cfg.eval_params_mid, &helper.cfg.eval_params_end);
+    if me.eg_val.is_some() {
+        return Ok(false);
+    }
+
+    let mut stats = SearchStats::default();
+    let score = helper.evaluate_classical(ts, g, &mut stats);
+
+    for (symmetry, g2) in transforms(ts, g) {
+        let score2 = helper.evaluate_classical(ts, &g2, &mut stats);
+        let expected = if symmetry == Symmetry::ColorFlip { -score } else { score };
+        if score2 != expected {
+            return Err(SymmetryError {
+                fen:      g.to_fen(),
+                fen2:     g2.to_fen(),
+                symmetry,
+                nnue:     false,
+                score,
+                score2,
+            });
+        }
+    }
+    Ok(true)
+}
+
+pub fn check_nnue(
+    ts:         &Tables,
+    nn:         &mut NNUE4,
+    g:          &Game,
+) -> Result<(), SymmetryError> {
+    let score = nnue_eval(nn, g);
+
+    for (symmetry, g2) in transforms(ts, g) {
+        let score2 = nnue_eval(nn, &g2);
+        if score2 != score {
+            return Err(SymmetryError {
+                fen:      g.to_fen(),
+                fen2:     g2.to_fen(),
+                symmetry,
+                nnue:     true,
+                score,
+                score2,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn nnue_eval(nn: &mut NNUE4, g: &Game) -> Score {
+    NNEvaluator::reset(nn, g);
+    NNEvaluator::evaluate(nn, g)
+}
+
+/// Checks each position and all of its children.
+/// EPD operations after the first ';' are ignored.
+pub fn check_positions(
+    ts:         &Tables,
+    helper:     &mut ExHelper,
+    mut nn:     Option<&mut NNUE4>,
+    fens:       &[String],
+) -> (SymmetryStats, Vec<SymmetryError>) {
+    let mut stats = SymmetryStats::default();
+    let mut errors = vec![];
+
+    for fen in fens.iter() {
+        let fen = fen.split(';').next().unwrap_or("");
+        let g = match Game::from_fen_mode(ts, fen, FenMode::Lenient) {
+            Ok(g)  => g,
+            Err(_) => {
+                stats.bad_fens += 1;
+                continue;
+            },
+        };
+
+        let mut gs = vec![g.clone()];
+        for mv in MoveGen::generate_list_legal(ts, &g, None) {
+            if let Ok(g2) = g.make_move_unchecked(ts, mv) {
+                gs.push(g2);
+            }
+        }
+
+        for g in gs.iter() {
+            stats.positions += 1;
+            stats.flipped += 1;
+            if g.mirror_horizontal(ts).is_some() { stats.mirrored += 1; }
+
+            match check_classical(ts, helper, g) {
+                Ok(true)  => {},
+                Ok(false) => stats.skipped += 1,
+                Err(e)    => errors.push(e),
+            }
+            if let Some(nn) = nn.as_mut() {
+                if let Err(e) = check_nnue(ts, nn, g) {
+                    errors.push(e);
+                }
+            }
+        }
+    }
+
+    (stats, errors)
+}
+
+pub fn check_epd(
+    ts:         &Tables,
+    helper:     &mut ExHelper,
+    nn:         Option<&mut NNUE4>,
+    path:       &str,
+) -> std::io::Result<(SymmetryStats, Vec<SymmetryError>)> {
+    let fens = read_epd_no_bm(path)?;
+    let fens = fens.into_iter()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with("//"))
+        .collect::<Vec<_>>();
+    Ok(check_positions(ts, helper, nn, &fens))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explore::{Explorer,PerThreadData};
+    use crate::timer::TimeSettings;
+    use crate::sf_compat::simd::check::randomize;
+
+    use rand::prelude::{StdRng,SeedableRng};
+
+    #[test]
+    fn symmetry_perft_fens() {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, STARTPOS).unwrap();
+        let ex = Explorer::new(g.state.side_to_move, g, 1, TimeSettings::new_f64(0.0,1.0));
+        let mut helper = ex.build_exhelper(0, PerThreadData::default());
+
+        let mut nn = NNUE4::new_empty();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1234);
+        randomize(&mut nn, &mut rng);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../perft_fens.txt");
+        let (stats, errors) = check_epd(&ts, &mut helper, Some(&mut nn), path).unwrap();
+
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        assert!(errors.is_empty(), "{} asymmetric evals", errors.len());
+        assert_eq!(stats.bad_fens, 0);
+        assert!(stats.flipped > 300 && stats.mirrored > 100, "{:?}", stats);
+    }
+
+}
@@ -2,6 +2,7 @@
 use std::io::Write;
 
 use crate::evaluate::TaperedScore;
+use crate::bitbase::KPKBitbase;
 use crate::types::*;
 
 pub use crate::tuning::*;
@@ -136,6 +137,9 @@ pub struct Tables {
     pub eval_params_mid:  EvalParams,
     pub eval_params_end:  EvalParams,
 
+    #[serde(skip,default = "KPKBitbase::empty")]
+    pub kpk_bitbase:      KPKBitbase,
+
 }
 
 #[cfg(not(feature = "smallstack"))]
@@ -224,7 +228,8 @@ impl Tables {
         // let b: Vec<u8> = ;
         match std::fs::read(&path) {
             Ok(b) => {
-                let ts: Tables = bincode::deserialize(&b).unwrap();
+                let mut ts: Tables = bincode::deserialize(&b).unwrap();
+                ts.kpk_bitbase = KPKBitbase::generate(&ts);
                 Ok(ts)
             },
             Err(_) => {
@@ -278,7 +283,7 @@ impl Tables {
 
         let (eval_params_mid,eval_params_end) = EvalParams::new_mid_end();
 
-        Self {
+        let mut out = Self {
             pseudo_attacks_b,
             pseudo_attacks_r,
 
@@ -301,7 +306,14 @@ impl Tables {
 
             eval_params_mid,
             eval_params_end,
-        }
+
+            kpk_bitbase: KPKBitbase::empty(),
+        };
+
+        // Needs the king and pawn moves
+        out.kpk_bitbase = KPKBitbase::generate(&out);
+
+        out
     }
 
 }
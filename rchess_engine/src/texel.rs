@@ -1,519 +1,725 @@
 
+use std::io;
 use std::path::Path;
 
-use crate::brain::gensfen::TrainingData;
-use crate::brain::trainer::TDOutcome;
+use crate::brain::gensfen::TDOutcome;
+use crate::endgame::EndGameMaps;
 use crate::explore::ExHelper;
+use crate::material::{MaterialTable,PawnTable};
 use crate::types::*;
 use crate::tables::*;
 use crate::evaluate::*;
-use crate::qsearch::*;
+use crate::eval_trace::EvalTrace;
+use crate::endgame::ScaleFactor;
+use crate::tuning::*;
 use crate::searchstats::*;
-use crate::pawn_hash_table::*;
+use crate::builder_field;
 
 use derive_new::new;
-use rand::prelude::SliceRandom;
+use rayon::prelude::*;
 use serde::{Serialize,Deserialize};
 
 #[derive(Debug,Clone,Serialize,Deserialize,new)]
 pub struct TxPosition {
     pub game:     Game,
     pub result:   TDOutcome,
-    // pub q_score:  Score,
 }
 
-pub fn load_txdata<P: AsRef<Path>>(
+impl TxPosition {
+    /// 1.0 for a White win
+    pub fn result_white(&self) -> f64 {
+        match self.result {
+            TDOutcome::Win(White) => 1.0,
+            TDOutcome::Win(Black) => 0.0,
+            TDOutcome::Draw       => 0.5,
+            TDOutcome::Stalemate  => 0.5,
+        }
+    }
+}
+
+/// Labeled EPD, one position per line with the result as `c9 "1-0";` or `[1.0]`.
+/// Positions with a specialized endgame eval are skipped, their score doesn't depend on EvalParams.
+pub fn load_labeled_fens<P: AsRef<Path>>(
     ts:             &Tables,
-    mut exhelper:   &mut ExHelper,
     count:          Option<usize>,
     path:           P,
-) -> std::io::Result<Vec<TxPosition>> {
-
-    let t0 = std::time::Instant::now();
-    let tds: Vec<TrainingData> = TrainingData::load_all(path, count)?;
-    let t1 = t0.elapsed().as_secs_f64();
-
-    debug!("finished loading Vec<TrainingData> in {:.3} seconds", t1);
-
-    let ps = process_txdata(ts, &mut exhelper, count, &tds);
-
-    Ok(ps)
+) -> io::Result<Vec<TxPosition>> {
+    let f = std::fs::File::open(path)?;
+    read_labeled_fens(ts, count, std::io::BufReader::new(f))
 }
 
-pub fn process_txdata(
+/// load_labeled_fens from any reader
+pub fn read_labeled_fens<R: std::io::BufRead>(
     ts:             &Tables,
-    mut exhelper:   &mut ExHelper,
     count:          Option<usize>,
-    tds:            &[TrainingData],
-) -> Vec<TxPosition> {
-
-    let mut stats = SearchStats::default();
+    b:              R,
+) -> io::Result<Vec<TxPosition>> {
+    use regex::Regex;
 
-    let mut ps: Vec<TxPosition> = vec![];
-    let mut n = 0;
-    // let mut non_q = 0;
+    let reg = Regex::new(r##""(1-0|0-1|1/2-1/2)"|\[(1\.0|0\.0|0\.5)\]"##).unwrap();
+    let egs = EndGameMaps::get(ts);
 
-    let t0 = std::time::Instant::now();
-    for td in tds.iter() {
-        let mut g = Game::from_fen(&ts, STARTPOS).unwrap();
-        for mv in td.opening.iter() {
-            g = g.make_move_unchecked(&ts, *mv).unwrap();
-        }
+    let mut out = vec![];
+    let mut skipped = 0;
+
+    for line in b.lines() {
+        let line = line?;
+
+        let result = match reg.captures(&line).and_then(|c| c.get(1).or(c.get(2))) {
+            Some(m) => match m.as_str() {
+                "1-0" | "1.0"     => TDOutcome::Win(White),
+                "0-1" | "0.0"     => TDOutcome::Win(Black),
+                _                 => TDOutcome::Draw,
+            },
+            None    => {
+                skipped += 1;
+                continue;
+            },
+        };
 
-        // eprintln!("td.moves.len() = {:?}", td.moves.len());
-
-        for te in td.moves.iter() {
-            // eprintln!("making move = {:?}", te.mv);
-            if let Ok(g2) = g.make_move_unchecked(&ts, te.mv) {
-                g = g2;
-                if !te.skip
-                    && !te.mv.filter_all_captures()
-                    && g.state.in_check
-                    // && te.eval.abs() < DRAW_VALUE - 100
-                    && te.eval.abs() < 0 - 100
-                {
-                    let (ev_mid,ev_end) = (&exhelper.cfg.eval_params_mid,&exhelper.cfg.eval_params_end);
-                    let score   = g.sum_evaluate(&ts, &ev_mid, &ev_end, None);
-                    let q_score = exhelper.qsearch_once(&ts, &g, &mut stats);
-                    let q_score = g.state.side_to_move.fold(q_score, -q_score);
-
-                    if score == q_score {
-                        // println!("ps.push");
-                        ps.push(TxPosition::new(g.clone(), td.result));
-                    } else {
-                        // non_q += 1;
-                    }
-                }
-            } else {
-                // eprintln!("move failed");
-            }
+        let game = match Game::from_fen(ts, &line) {
+            Some(g) => g,
+            None    => {
+                skipped += 1;
+                continue;
+            },
+        };
 
+        if egs.get_value(&game).is_some() {
+            skipped += 1;
+            continue;
         }
 
-        n += 1;
-        if count.map_or(false, |c| n >= c) { break; }
+        out.push(TxPosition::new(game, result));
+        if count.map_or(false, |c| out.len() >= c) { break; }
     }
-    let t2 = t0.elapsed().as_secs_f64();
 
-    debug!("finished processing Vec<TrainingData> in {:.3} seconds", t2);
+    debug!("load_labeled_fens: {} positions, {} skipped", out.len(), skipped);
+    Ok(out)
+}
 
-    ps
+/// Coordinate descent over every EvalParams value, mid and end, or Adam on the
+/// linear coefficients from EvalTrace, see tune_adam.
+/// Each error computation is split over num_threads.
+#[derive(Debug,Clone)]
+pub struct TexelTuner {
+    /// Sigmoid scale, found with find_k if None
+    k:                  Option<f64>,
+    /// Passes over all the params, or relinearizations for Adam
+    max_loops:          Option<usize>,
+    /// Starting step for each param, halved when neither direction helps
+    step:               Score,
+    /// Adam steps per loop
+    epochs:             usize,
+    /// Adam, in param units
+    learning_rate:      f64,
+    num_threads:        usize,
+    print:              bool,
 }
 
-pub fn load_txdata_mult<P: AsRef<Path>>(
-    ts:             &Tables,
-    mut exhelper:   &mut ExHelper,
-    paths:          &[P],
-) -> std::io::Result<Vec<TxPosition>> {
-    let mut ps = vec![];
-
-    let t0 = std::time::Instant::now();
-    for path in paths.iter() {
-        let p = load_txdata(ts, &mut exhelper, None, path)?;
-        ps.extend_from_slice(&p);
-
-        let t1 = t0.elapsed().as_secs_f64();
-        debug!("ps.len() = {:?}, t = {:.1}", ps.len(), t1);
+impl TexelTuner {
+    pub fn new() -> Self {
+        Self {
+            k:              None,
+            max_loops:      None,
+            step:           8,
+            epochs:         200,
+            learning_rate:  1.0,
+            num_threads:    1,
+            print:          true,
+        }
     }
-    Ok(ps)
+    builder_field!(k, Option<f64>);
+    builder_field!(max_loops, Option<usize>);
+    builder_field!(step, Score);
+    builder_field!(epochs, usize);
+    builder_field!(learning_rate, f64);
+    builder_field!(num_threads, usize);
+    builder_field!(print, bool);
 }
 
-impl TxPosition {
-    pub fn save_txdata<P: AsRef<Path>>(
-        path:         P,
-        data:         &[TxPosition],
-    ) -> std::io::Result<()> {
-        use std::io::Write;
-        let mut file = std::fs::File::create(path)?;
-        let buf = bincode::serialize(&data).unwrap();
-        file.write_all(&buf)?;
-        Ok(())
+impl TexelTuner {
+
+    /// Starts from exhelper.cfg's params, and saves to out after every pass, as a ParamFile
+    /// if out ends in .json, otherwise with EvalParams::save_evparams.
+    /// Returns (error before, error after)
+    pub fn tune(
+        &self,
+        ts:             &Tables,
+        exhelper:       &mut ExHelper,
+        inputs:         &[TxPosition],
+        out:            &Path,
+    ) -> io::Result<(f64,f64)> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        pool.install(|| self._tune(ts, exhelper, inputs, out))
     }
 
-    pub fn load_txdata<P: AsRef<Path>>(
-        path:         P,
-    ) -> std::io::Result<Vec<TxPosition>> {
-        let mut b = std::fs::read(path)?;
-        let out = bincode::deserialize(&b).unwrap();
-        Ok(out)
-    }
+    fn _tune(
+        &self,
+        ts:             &Tables,
+        exhelper:       &mut ExHelper,
+        inputs:         &[TxPosition],
+        out:            &Path,
+    ) -> io::Result<(f64,f64)> {
+        // Cloned for every chunk of positions
+        exhelper.nnue = None;
+
+        let k = match self.k {
+            Some(k) => k,
+            None    => find_k(ts, inputs, exhelper, self.print),
+        };
 
-}
+        let error0 = average_eval_error(ts, inputs, exhelper, Some(k));
+        if self.print {
+            eprintln!("tuning {} params on {} positions, {} threads, k = {:.3}",
+                      2 * (EvalParams::LEN - 1), inputs.len(), rayon::current_num_threads(), k);
+            eprintln!("start: error = {:.6}", error0);
+        }
 
-pub fn load_labeled_fens<P: AsRef<Path>>(
-    ts:             &Tables,
-    mut exhelper:   &mut ExHelper,
-    count:          Option<usize>,
-    path:           P,
-) -> std::io::Result<Vec<TxPosition>> {
-    use regex::Regex;
-    use std::io::{self, BufRead};
+        let mut best_error = error0;
+        let mut deltas = [vec![self.step; EvalParams::LEN], vec![self.step; EvalParams::LEN]];
+        /// Params that don't change the error on any input
+        let mut unused = [vec![false; EvalParams::LEN], vec![false; EvalParams::LEN]];
 
-    let mut f = std::fs::File::open(path)?;
-    let mut b = std::io::BufReader::new(f);
+        let t0 = std::time::Instant::now();
+        let mut loops = 0;
+        loop {
+            let t1 = std::time::Instant::now();
+            let prev = best_error;
 
-    let mut out = vec![];
-    let mut n = 0;
-    let mut line = String::new();
+            for (i,&mid) in [true,false].iter().enumerate() {
+                self.optimize_once(
+                    ts, inputs, exhelper, mid, k, &mut best_error, &mut deltas[i], &mut unused[i]);
+            }
 
-    let reg = Regex::new(r##""([^"]+)-([^"]+)"##).unwrap();
+            self.save(exhelper, out)?;
 
-    loop {
+            loops += 1;
+            if self.print {
+                eprintln!("loop {:>3}: error = {:.6}, {:.1}s / {:.1}s",
+                          loops, best_error, t1.elapsed().as_secs_f64(), t0.elapsed().as_secs_f64());
+            }
 
-        let k = b.read_line(&mut line)?;
-        if k == 0 { break; }
+            let converged = best_error >= prev
+                && deltas.iter().all(|ds| ds.iter().all(|&d| d == 1));
+            if converged { break; }
+            if self.max_loops.map_or(false, |n| loops >= n) { break; }
+        }
+
+        if self.print {
+            eprintln!("error: {:.6} -> {:.6}, saved to {:?}", error0, best_error, out);
+        }
+
+        Ok((error0, best_error))
+    }
 
-        if let Some(game) = Game::from_fen(ts, &line) {
+    fn save(&self, exhelper: &ExHelper, out: &Path) -> io::Result<()> {
+        let (ev_mid,ev_end) = (&exhelper.cfg.eval_params_mid, &exhelper.cfg.eval_params_end);
+        if out.extension().map_or(false, |ext| ext == "json") {
+            ParamFile::new(*ev_mid, *ev_end, exhelper.params).save(out)
+        } else {
+            EvalParams::save_evparams(ev_mid, ev_end, out)
+        }
+    }
 
-            let res = reg.captures(&line).unwrap();
+    /// One step up or down for every param of mid or end
+    fn optimize_once(
+        &self,
+        ts:             &Tables,
+        inputs:         &[TxPosition],
+        exhelper:       &mut ExHelper,
+        mid:            bool,
+        k:              f64,
+        best_error:     &mut f64,
+        deltas:         &mut [Score],
+        unused:         &mut [bool],
+    ) {
+        let set = |exhelper: &mut ExHelper, arr: &[Score]| {
+            if mid {
+                exhelper.cfg.eval_params_mid = EvalParams::from_arr(arr);
+            } else {
+                exhelper.cfg.eval_params_end = EvalParams::from_arr(arr);
+            }
+        };
 
-            let white = res.get(1).unwrap().as_str();
-            let black = res.get(2).unwrap().as_str();
+        let mut arr = if mid {
+            exhelper.cfg.eval_params_mid.to_arr()
+        } else {
+            exhelper.cfg.eval_params_end.to_arr()
+        };
 
-            let result = match (white,black) {
-                ("1/2","1/2") => TDOutcome::Draw,
-                ("1","0")     => TDOutcome::Win(White),
-                ("0","1")     => TDOutcome::Win(Black),
-                _             => panic!("fen win string: {:?}", line),
-            };
+        /// 0 is EvalParams::mid
+        for n in 1..arr.len() {
+            if unused[n] { continue; }
 
-            let tx = TxPosition {
-                game,
-                result,
-            };
-            out.push(tx);
+            let delta = deltas[n];
+            let orig  = arr[n];
 
-        }
+            arr[n] = orig + delta;
+            set(exhelper, &arr);
+            let err_up = average_eval_error(ts, inputs, exhelper, Some(k));
 
-        line.clear();
-        n += 1;
-        if count.map_or(false, |c| n >= c) { break; }
-        // break;
+            if err_up == *best_error {
+                unused[n] = true;
+                deltas[n] = 1;
+                arr[n] = orig;
+                set(exhelper, &arr);
+                continue;
+            }
+
+            if err_up < *best_error {
+                *best_error = err_up;
+                continue;
+            }
+
+            arr[n] = orig - delta;
+            set(exhelper, &arr);
+            let err_down = average_eval_error(ts, inputs, exhelper, Some(k));
+
+            if err_down < *best_error {
+                *best_error = err_down;
+            } else {
+                arr[n] = orig;
+                set(exhelper, &arr);
+                deltas[n] = (delta / 2).max(1);
+            }
+        }
     }
-    Ok(out)
+
 }
 
-pub fn texel_optimize_once(
-    ts:                          &Tables,
-    inputs:                      &[TxPosition],
-    mut exhelper:                &mut ExHelper,
-    // mut arr_mut:                 &Vec<&mut Score>,
-    ignore_weights:              &[bool],
-    count:                       Option<usize>,
-    mid:                         bool,
-    mut best_error:              &mut f64,
-    k:                           Option<f64>,
-    // delta:                       Score,
-    mut deltas:                  &mut [Score],
-    print:                       bool,
-) {
-
-    // let mut arr_mut: Vec<&mut Score> = if mid {
-    //     exhelper.cfg.eval_params_mid.to_arr_mut()
-    // } else {
-    //     exhelper.cfg.eval_params_end.to_arr_mut()
-    // };
-
-    // let xs = arr_mut.into_iter().map(|x| RefCell::new(x))
-
-    // let mut improved = true;
-    // for mut v in arr_mut {
-    //     if !improved { break; }
-    //     improved = false;
-    //     *v = v.checked_add(delta).unwrap();
-    //     exhelper.ph_rw.purge_scores();
-    //     // .update_exhelper(&mut exhelper, mid)
-    //     // let new_error = average_eval_error(ts, &inputs, &exhelper.clone(), None);
-    // }
-
-    let mut arr: Vec<Score> = if mid {
-        exhelper.cfg.eval_params_mid.to_arr()
-    } else {
-        exhelper.cfg.eval_params_end.to_arr()
-    };
-
-    let t0 = std::time::Instant::now();
-    let nn = arr.len();
-    let mut improved = true;
-    for n in 1..arr.len() {
-        // if !improved { break; }
-        // improved = false;
-
-        // eprintln!("n = {:>4} / {:>4}, best_error = {:.6}", n, nn, best_error);
-        // eprintln!("n = {:>4} / {:>4}, best_error = {}", n, nn, best_error);
-
-        // if let Some(true) = ignore_weights.get(n) {
-        //     continue;
-        // }
-
-        let delta = deltas[n];
-        arr[n] = arr[n].checked_add(delta).unwrap();
-
-        EvalParams::from_arr(&arr).update_exhelper(&mut exhelper, mid);
-        exhelper.ph_rw.purge_scores();
-
-        let new_error = average_eval_error(ts, &inputs, &exhelper, None);
-
-        if new_error < *best_error {
-            *best_error = new_error;
-            deltas[n] = (deltas[n] / 2).max(1);
-            // improved = true;
-        } else {
-            arr[n] = arr[n].checked_sub(delta * 2).unwrap();
-            EvalParams::from_arr(&arr).update_exhelper(&mut exhelper, mid);
-            exhelper.ph_rw.purge_scores();
+/// Linear coefficients of a position's classical eval, White side
+#[derive(Debug,Clone)]
+pub struct TxCoeffs {
+    /// EvalTrace::total with the params the coeffs were found at, before scaling
+    pub base:           TaperedScore,
+    pub phase:          Phase,
+    pub scale_factor:   Score,
+    /// (param index, d mid / d param, d end / d param), only non zero
+    pub coeffs:         Vec<(u16,f32,f32)>,
+}
 
-            let new_error = average_eval_error(ts, &inputs, &exhelper, None);
+/// Params are moved by this when finding coefficients, so terms divided by a constant
+/// (e.g. imbalance) don't round to 0
+const COEFF_DELTA: Score = 16;
+
+impl TexelTuner {
+
+    /// Gradient descent with Adam, on the eval linearized around exhelper.cfg's params.
+    /// Most terms are linear in the params, the rest (king safety, scaling) are relinearized
+    /// every loop. Saves to out after every loop, same as tune.
+    /// Returns (error before, error after)
+    pub fn tune_adam(
+        &self,
+        ts:             &Tables,
+        exhelper:       &mut ExHelper,
+        inputs:         &[TxPosition],
+        out:            &Path,
+    ) -> io::Result<(f64,f64)> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        pool.install(|| self._tune_adam(ts, exhelper, inputs, out))
+    }
 
-            if new_error < *best_error {
-                *best_error = new_error;
-                deltas[n] = (deltas[n] / 2).max(1);
-                // improved = true;
-            } else {
-                arr[n] = arr[n].checked_add(delta).unwrap();
-                EvalParams::from_arr(&arr).update_exhelper(&mut exhelper, mid);
-                exhelper.ph_rw.purge_scores();
-                deltas[n] = (deltas[n] * 2).min(100);
+    fn _tune_adam(
+        &self,
+        ts:             &Tables,
+        exhelper:       &mut ExHelper,
+        inputs:         &[TxPosition],
+        out:            &Path,
+    ) -> io::Result<(f64,f64)> {
+        exhelper.nnue = None;
+
+        let k = match self.k {
+            Some(k) => k,
+            None    => find_k(ts, inputs, exhelper, self.print),
+        };
+
+        let error0 = average_eval_error(ts, inputs, exhelper, Some(k));
+        if self.print {
+            eprintln!("adam: {} params on {} positions, {} threads, k = {:.3}",
+                      2 * (EvalParams::LEN - 1), inputs.len(), rayon::current_num_threads(), k);
+            eprintln!("start: error = {:.6}", error0);
+        }
+
+        let results: Vec<f64> = inputs.iter().map(|p| p.result_white()).collect();
+        let mut best_error = error0;
+
+        let t0 = std::time::Instant::now();
+        let mut loops = 0;
+        loop {
+            let t1 = std::time::Instant::now();
+
+            let coeffs = find_coeffs(ts, inputs, exhelper);
+
+            let arr0 = [exhelper.cfg.eval_params_mid.to_arr(), exhelper.cfg.eval_params_end.to_arr()];
+            let deltas = self.adam(&coeffs, &results, k);
+
+            let mut arrs = arr0.clone();
+            for i in 0..2 {
+                for n in 1..EvalParams::LEN {
+                    arrs[i][n] += deltas[i][n].round() as Score;
+                }
+            }
+
+            let prev = (exhelper.cfg.eval_params_mid, exhelper.cfg.eval_params_end);
+            exhelper.cfg.eval_params_mid = EvalParams::from_arr(&arrs[0]);
+            exhelper.cfg.eval_params_end = EvalParams::from_arr(&arrs[1]);
+
+            let error = average_eval_error(ts, inputs, exhelper, Some(k));
+            loops += 1;
+            if self.print {
+                eprintln!("loop {:>3}: error = {:.6}, {:.1}s / {:.1}s",
+                          loops, error, t1.elapsed().as_secs_f64(), t0.elapsed().as_secs_f64());
+            }
+
+            /// The linearization was too far off
+            if error >= best_error {
+                exhelper.cfg.eval_params_mid = prev.0;
+                exhelper.cfg.eval_params_end = prev.1;
+                break;
             }
+            best_error = error;
+            self.save(exhelper, out)?;
+
+            if self.max_loops.map_or(false, |n| loops >= n) { break; }
         }
 
+        if self.print {
+            eprintln!("error: {:.6} -> {:.6}, saved to {:?}", error0, best_error, out);
+        }
 
-        if print && n % (nn / 10) == 0 {
-            let t1 = t0.elapsed().as_secs_f64();
-            eprintln!("texel_optimize {} in {:.3} seconds, {:.2} loops/sec", n, t1, n as f64 / t1);
-            eprintln!("best_error = {:.5}", best_error);
+        Ok((error0, best_error))
+    }
+
+    /// Change to each param, [mid, end], after self.epochs full batch steps
+    fn adam(&self, coeffs: &[TxCoeffs], results: &[f64], k: f64) -> [Vec<f64>; 2] {
+        const BETA1: f64 = 0.9;
+        const BETA2: f64 = 0.999;
+        const EPS: f64   = 1e-8;
+
+        let len = EvalParams::LEN;
+        let mut deltas = [vec![0.0; len], vec![0.0; len]];
+        let mut m = [vec![0.0; len], vec![0.0; len]];
+        let mut v = [vec![0.0; len], vec![0.0; len]];
+
+        let chunk = (coeffs.len() / (4 * rayon::current_num_threads())).max(1);
+
+        for epoch in 1..=self.epochs {
+            let deltas_ref = &deltas;
+
+            /// Summed in order, so the result is the same for the same inputs
+            let grads: Vec<(f64,[Vec<f64>; 2])> = coeffs.par_chunks(chunk)
+                .zip(results.par_chunks(chunk))
+                .map(|(cs,rs)| {
+                    let mut grad = [vec![0.0; len], vec![0.0; len]];
+                    let mut err = 0.0;
+                    for (c,r) in cs.iter().zip(rs.iter()) {
+                        let (mut mid, mut end) = (c.base.mid as f64, c.base.end as f64);
+                        for &(n,cm,ce) in c.coeffs.iter() {
+                            mid += cm as f64 * deltas_ref[0][n as usize];
+                            end += ce as f64 * deltas_ref[1][n as usize];
+                        }
+                        let (wm,we) = Self::taper_weights(c);
+                        let s = sigmoid(mid * wm + end * we, k);
+                        err += (r - s).powi(2);
+
+                        /// d error / d eval
+                        let d = -2.0 * (r - s) * s * (1.0 - s) * 10.0f64.ln() * k / 400.0;
+                        for &(n,cm,ce) in c.coeffs.iter() {
+                            grad[0][n as usize] += d * cm as f64 * wm;
+                            grad[1][n as usize] += d * ce as f64 * we;
+                        }
+                    }
+                    (err,grad)
+                }).collect();
+
+            let mut err  = 0.0;
+            let mut grad = [vec![0.0; len], vec![0.0; len]];
+            for (e,g) in grads.iter() {
+                err += e;
+                for i in 0..2 {
+                    for n in 0..len { grad[i][n] += g[i][n]; }
+                }
+            }
+            let num = coeffs.len() as f64;
+
+            for i in 0..2 {
+                /// 0 is EvalParams::mid
+                for n in 1..len {
+                    let g = grad[i][n] / num;
+                    m[i][n] = BETA1 * m[i][n] + (1.0 - BETA1) * g;
+                    v[i][n] = BETA2 * v[i][n] + (1.0 - BETA2) * g * g;
+                    let m_hat = m[i][n] / (1.0 - BETA1.powi(epoch as i32));
+                    let v_hat = v[i][n] / (1.0 - BETA2.powi(epoch as i32));
+                    deltas[i][n] -= self.learning_rate * m_hat / (v_hat.sqrt() + EPS);
+                }
+            }
+
+            if self.print && (epoch % 50 == 0 || epoch == self.epochs) {
+                eprintln!("    epoch {:>4}: linear error = {:.6}", epoch, err / num);
+            }
         }
 
+        deltas
+    }
+
+    /// d eval / d mid, d eval / d end, same as TaperedScore::taper with the scale factor
+    fn taper_weights(c: &TxCoeffs) -> (f64,f64) {
+        let p = c.phase as f64;
+        let sf = c.scale_factor as f64 / ScaleFactor::Normal as Score as f64;
+        ((256.0 - p) / 256.0, sf * p / 256.0)
     }
 
 }
 
-pub fn texel_optimize(
-    ts:                          &Tables,
-    inputs:                      &[TxPosition],
-    mut exhelper:                &mut ExHelper,
-    // exhelper:                    std::rc::Rc<std::cell::RefCell<ExHelper>>,
-    ignore_weights:              &[bool],
-    count:                       Option<usize>,
-    k:                           Option<f64>,
-    path:                        &str,
-) -> (EvalParams,EvalParams) {
-
-    let mut best_error = average_eval_error(ts, &inputs, exhelper, k);
-
-    let arr_len = exhelper.cfg.eval_params_mid.to_arr().len();
-
-    EvalParams::save_evparams(&exhelper.cfg.eval_params_mid, &exhelper.cfg.eval_params_end, path)
-        .unwrap();
-
-    // let mut delta = 50;
-    // let mut delta = 10;
-
-
-    // let arr_end_mut = exhelper.cfg.eval_params_end.to_arr_mut();
-    // let arr_mid_mut = exhelper.cfg.eval_params_mid.to_arr_mut();
-
-    // let print = false;
-    let print = true;
-
-    let mut deltas_mid = vec![50; arr_len];
-    let mut deltas_end = vec![50; arr_len];
-
-    println!("starting texel_optimize...");
-    // eprintln!("arr_mid.len() = {:?}", arr_mid.len());
-    let t0 = std::time::Instant::now();
-    let mut loops = 0;
-    loop {
-        let t1 = std::time::Instant::now();
-
-        // let inputs_slice = inputs.choose_multiple(&mut rng, )
-
-        texel_optimize_once(
-            // ts, inputs, &mut exhelper, ignore_weights, count, true, &mut best_error, k, delta, print);
-            ts, inputs, &mut exhelper, ignore_weights, count, true,
-            &mut best_error, k, &mut deltas_mid, print);
-
-        texel_optimize_once(
-            // ts, inputs, &mut exhelper, ignore_weights, count, false, &mut best_error, k, delta, print);
-            ts, inputs, &mut exhelper, ignore_weights, count, false,
-            &mut best_error, k, &mut deltas_end, print);
-
-        EvalParams::save_evparams(
-            &exhelper.cfg.eval_params_mid,
-            &exhelper.cfg.eval_params_end,
-            path,
-        ).unwrap();
-
-        loops += 1;
-        let t2 = t1.elapsed().as_secs_f64();
-        eprintln!("loops = {:>3}, best_error = {:.3}, time: {:.1}s / {:.1}s, {:.2} inputs/weights/s",
-                  loops, best_error,
-                  t2, t0.elapsed().as_secs_f64(),
-                  inputs.len() as f64 / (arr_len * 2) as f64 / t2,
-        );
-        if let Some(c) = count { if loops >= c { break; } }
-    }
+/// Coefficients of every param for each position, from the change in EvalTrace::total
+/// when the param is moved by COEFF_DELTA. Mid and end params are moved together,
+/// since they only change the mid and end totals
+pub fn find_coeffs(
+    ts:         &Tables,
+    inputs:     &[TxPosition],
+    exhelper:   &ExHelper,
+) -> Vec<TxCoeffs> {
+    let chunk = (inputs.len() / (4 * rayon::current_num_threads())).max(1);
+
+    let arr_mid = exhelper.cfg.eval_params_mid.to_arr();
+    let arr_end = exhelper.cfg.eval_params_end.to_arr();
 
-    // (ev_mid,ev_end)
-    (exhelper.cfg.eval_params_mid,exhelper.cfg.eval_params_end)
+    let coeffs: Vec<Vec<TxCoeffs>> = inputs.par_chunks(chunk).map(|xs| {
+        let mut helper = exhelper.clone();
+        let mut reset = |helper: &mut ExHelper| {
+            helper.material_table = MaterialTable::default();
+            helper.pawn_table     = PawnTable::default();
+        };
+        reset(&mut helper);
+
+        let mut out: Vec<TxCoeffs> = xs.iter().map(|pos| {
+            let tr = trace_position(ts, &mut helper, &pos.game);
+            TxCoeffs {
+                base:          tr.total(),
+                phase:         tr.phase,
+                scale_factor:  tr.scale_factor,
+                coeffs:        vec![],
+            }
+        }).collect();
+
+        /// 0 is EvalParams::mid
+        for n in 1..EvalParams::LEN {
+            let (mut am, mut ae) = (arr_mid.clone(), arr_end.clone());
+            am[n] += COEFF_DELTA;
+            ae[n] += COEFF_DELTA;
+            helper.cfg.eval_params_mid = EvalParams::from_arr(&am);
+            helper.cfg.eval_params_end = EvalParams::from_arr(&ae);
+            reset(&mut helper);
+
+            for (c,pos) in out.iter_mut().zip(xs.iter()) {
+                let d = trace_position(ts, &mut helper, &pos.game).total() - c.base;
+                if d.mid != 0 || d.end != 0 {
+                    let delta = COEFF_DELTA as f32;
+                    c.coeffs.push((n as u16, d.mid as f32 / delta, d.end as f32 / delta));
+                }
+            }
+        }
+
+        out
+    }).collect();
+
+    coeffs.into_iter().flatten().collect()
 }
 
+/// Scale of the sigmoid that best fits the current params, to 3 decimal places
 pub fn find_k(
     ts:         &Tables,
     inputs:     &[TxPosition],
     exhelper:   &ExHelper,
     print:      bool,
 ) -> f64 {
-    let mut start = 1.0;
+    let mut start = 0.0;
     let mut end   = 10.0;
     let mut step  = 1.0;
 
-    let mut curr = start;
-    let mut best = average_eval_error(&ts, inputs, exhelper, Some(curr));
-
-    const PREC: usize = 10;
-    for i in 0..PREC {
-
-        curr = start - step;
+    let mut best_k   = 1.0;
+    let mut best_err = average_eval_error(ts, inputs, exhelper, Some(best_k));
 
-        let mut err = 100000.0;
-
-        while curr < end {
-            curr = curr + step;
-
-            err = average_eval_error(&ts, inputs, exhelper, Some(curr));
-
-            if err < best {
-                best  = err;
-                start = curr;
+    for i in 0..4 {
+        let mut curr = start;
+        while curr <= end {
+            let err = average_eval_error(ts, inputs, exhelper, Some(curr));
+            if err < best_err {
+                best_err = err;
+                best_k   = curr;
             }
-
+            curr += step;
         }
 
         if print {
-            eprintln!("best k {:.3} on iter {}, err = {:.3}", start, i, err);
+            eprintln!("best k {:.3} on iter {}, err = {:.6}", best_k, i, best_err);
         }
 
-        end = start + step;
-        start = start - step;
-        step = step / 10.0;
+        start = best_k - step;
+        end   = best_k + step;
+        step  = step / 10.0;
     }
 
-    start
+    best_k
 }
 
+/// Mean squared error between the results and the classical eval mapped through a sigmoid.
+/// Uses exhelper.cfg's params for everything, including the PSQTs.
 pub fn average_eval_error(
     ts:         &Tables,
     inputs:     &[TxPosition],
     exhelper:   &ExHelper,
     k:          Option<f64>,
 ) -> f64 {
-    const K: f64 = 1.0;
-    let k = k.unwrap_or(K);
+    let k = k.unwrap_or(1.0);
 
-    // const K: f64 = 1.0;
+    if inputs.is_empty() { return 0.0; }
 
-    #[inline]
-    fn sigmoid(s: f64, k: f64) -> f64 {
-        1.0 / (1.0 + 10.0f64.powf(-k * s / 400.0))
-    }
+    let chunk = (inputs.len() / (4 * rayon::current_num_threads())).max(1);
 
-    // let (alpha,beta) = (i32::MIN,i32::MAX);
-    // let (alpha,beta) = (alpha + 200,beta - 200);
-    // let mut stats = SearchStats::default();
+    /// Summed in order, so the error is the same for the same params
+    let sums: Vec<f64> = inputs.par_chunks(chunk).map(|xs| {
+        /// Cached entries depend on the params
+        let mut helper = exhelper.clone();
+        helper.material_table = MaterialTable::default();
+        helper.pawn_table     = PawnTable::default();
+        let mut stats = SearchStats::default();
 
-    use rayon::prelude::*;
+        xs.iter().map(|pos| {
+            let score = eval_position(ts, &mut helper, &mut stats, &pos.game);
+            (pos.result_white() - sigmoid(score as f64, k)).powi(2)
+        }).sum::<f64>()
+    }).collect();
 
-    let ev_mid = &exhelper.cfg.eval_params_mid;
-    let ev_end = &exhelper.cfg.eval_params_end;
+    sums.iter().sum::<f64>() / inputs.len() as f64
+}
 
-    // let ph_rw  = &exhelper.ph_rw;
+/// White side
+fn eval_position(ts: &Tables, helper: &mut ExHelper, stats: &mut SearchStats, g: &Game) -> Score {
+    helper.evaluate_classical(ts, &with_psqt(helper, g), stats)
+}
 
-    // let n_cpus = num_cpus::get();
-    // let n_cpus = num_cpus::get_physical();
+fn trace_position(ts: &Tables, helper: &mut ExHelper, g: &Game) -> EvalTrace {
+    helper.trace_classical(ts, &with_psqt(helper, g))
+}
 
-    // for (n,xs) in cs.clone().enumerate() {
-    //     eprintln!("n = {:?}, xs.len() = {:?}", n, xs.len());
-    // }
+/// g.psqt_score is from the Tables, not the params being tuned
+fn with_psqt(helper: &ExHelper, g: &Game) -> Game {
+    let mut g = g.clone();
+    for side in [White,Black] {
+        g.psqt_score[side] = psqt_score(&g, &helper.cfg.eval_params_mid, &helper.cfg.eval_params_end, side);
+    }
+    g
+}
 
-    let sum: f64 = inputs.par_iter().map(move |pos| {
-        let r = match pos.result {
-            TDOutcome::Win(White) => 1.0,
-            TDOutcome::Win(Black) => 0.0,
-            TDOutcome::Draw       => 0.5,
-            TDOutcome::Stalemate  => 0.5,
-        };
-        // let ph_rw = PHTableFactory::new();
-        // let ph2 = ph_rw.handle();
-        // let ph2 = exhelper.ph_rw.clone();
-        let score = pos.game.sum_evaluate(
-            ts, &ev_mid, &ev_end,
-            // Some(&ph2),
-            // Some(&exhelper.ph_rw)
-            None,
-        );
-        (r - sigmoid(score as f64, k)).powi(2)
-    }).sum();
-
-    // let cs = inputs.chunks(inputs.len() / n_cpus);
-    // let sum: f64 = crossbeam::scope(|s| {
-    //     let ph_rw = exhelper.ph_rw.clone();
-    //     let mut hs = vec![];
-    //     for xs in cs {
-    //         let ph2 = ph_rw.clone();
-    //         let h = s.spawn(move |_| {
-    //             xs.iter().map(|pos| {
-    //                 let r = match pos.result {
-    //                     TDOutcome::Win(White) => 1.0,
-    //                     TDOutcome::Win(Black) => 0.0,
-    //                     TDOutcome::Draw       => 0.5,
-    //                     TDOutcome::Stalemate  => 0.5,
-    //                 };
-    //                 let score = pos.game.sum_evaluate(
-    //                     ts, &ev_mid, &ev_end,
-    //                     Some(&ph2),
-    //                     // None,
-    //                 );
-    //                 (r - sigmoid(score as f64, k)).powi(2)
-    //             }).sum::<f64>()
-    //         });
-    //         hs.push(h);
-    //     }
-    //     hs.into_iter().map(|h| {
-    //         h.join().unwrap()
-    //     }).sum()
-    // }).unwrap();
-
-    // let sum: f64 = inputs.iter().map(|pos| {
-    // // let sum: f64 = inputs.par_iter().map(|pos| {
-    //     let r = match pos.result {
-    //         TDOutcome::Win(White) => 1.0,
-    //         TDOutcome::Win(Black) => 0.0,
-    //         TDOutcome::Draw       => 0.5,
-    //         TDOutcome::Stalemate  => 0.5,
-    //     };
-    //     // let q_score = qsearch_once(&ts, &pos.game, pos.game.state.side_to_move, &ev_mid, &ev_end, ph_rw);
-    //     // exhelper.game = pos.game.clone();
-    //     // exhelper.side = pos.game.state.side_to_move;
-
-    //     // let q_score = exhelper.qsearch(ts, &pos.game, (0,0), (alpha, beta), &mut stats);
-    //     // let q_score = pos.game.state.side_to_move.fold(q_score, -q_score);
-
-    //     let score = pos.game.sum_evaluate(
-    //         ts, &ev_mid, &ev_end,
-    //         // Some(&exhelper.ph_rw.clone()),
-    //         // Some(&x),
-    //         Some(&ph_rw),
-    //         // None,
-    //     );
-
-    //     // assert_eq!(score, q_score);
-
-    //     (r - sigmoid(score as f64, k)).powi(2)
-    // }).sum();
-
-    sum / inputs.len() as f64
-
-    // unimplemented!()
+fn psqt_score(g: &Game, ev_mid: &EvalParams, ev_end: &EvalParams, side: Color) -> TaperedScore {
+    let mut score = TaperedScore::default();
+    for pc in Piece::iter_pieces() {
+        for sq in g.get(pc, side).into_iter() {
+            score += TaperedScore::new(ev_mid.psqt.get(pc, side, sq), ev_end.psqt.get(pc, side, sq));
+        }
+    }
+    score
+}
+
+fn sigmoid(s: f64, k: f64) -> f64 {
+    1.0 / (1.0 + 10.0f64.powf(-k * s / 400.0))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::explore::*;
+    use crate::timer::TimeSettings;
+
+    /// Mostly decided by material, with a few results against it
+    const LABELED: &'static str = r#"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 c9 "1/2-1/2";
+rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2 c9 "1/2-1/2";
+rnb1kbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 3 c9 "1-0";
+rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/1NBQKBNR w Kkq - 0 1 c9 "0-1";
+r1bqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1 [1.0]
+rnbqkbnr/pppppppp/8/8/8/8/PPPP1PPP/RNBQKBNR w KQkq - 0 1 [0.0]
+r1bqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 [0.5]
+r2q1rk1/pp2bppp/3p1n2/3Np3/4P1b1/5N2/PPP1QPPP/R3KB1R w KQ - 0 1 c9 "1-0";
+4k3/pppppppp/8/8/8/8/PPPPPPPP/4K3 w - - 0 1 c9 "1/2-1/2";
+4k3/pppppppp/8/8/8/8/PPPPPPP1/4K3 b - - 0 1 c9 "0-1";
+8/8/8/4k3/8/8/8/R3K3 w - - 0 1 c9 "1-0";
+rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1
+"#;
+
+    fn load(ts: &Tables) -> Vec<TxPosition> {
+        read_labeled_fens(ts, None, LABELED.as_bytes()).unwrap()
+    }
+
+    fn helper(ts: &Tables) -> ExHelper {
+        let g = Game::from_fen(ts, STARTPOS).unwrap();
+        let ex = Explorer::new(g.state.side_to_move, g, 1, TimeSettings::new_f64(0.0, 1.0));
+        ex.build_exhelper(0, PerThreadData::default())
+    }
+
+    #[test]
+    fn texel_load_and_find_k() {
+        let ts = Tables::new();
+        let ps = load(&ts);
+
+        /// KRvK and the line without a result are skipped
+        assert_eq!(ps.len(), 10);
+        assert_eq!(ps[2].result_white(), 1.0);
+        assert_eq!(ps[3].result_white(), 0.0);
+        assert_eq!(ps[6].result_white(), 0.5);
+
+        let h = helper(&ts);
+        let k = find_k(&ts, &ps, &h, false);
+        assert!(k > 0.5 && k < 2.0, "k = {}", k);
+
+        let err = average_eval_error(&ts, &ps, &h, Some(k));
+        for k2 in [k - 0.01, k + 0.01, k * 2.0, k / 2.0, 0.0] {
+            assert!(err <= average_eval_error(&ts, &ps, &h, Some(k2)), "k = {}, k2 = {}", k, k2);
+        }
+
+        /// Deterministic
+        assert_eq!(find_k(&ts, &ps, &h, false), k);
+    }
+
+    #[test]
+    fn texel_error_non_increasing() {
+        let ts = Tables::new();
+        let ps = load(&ts);
+        let out = std::env::temp_dir().join(format!("rchess_texel_{}.json", std::process::id()));
+
+        let mut h = helper(&ts);
+        let k = find_k(&ts, &ps, &h, false);
+        let tuner = TexelTuner::new()
+            .k(Some(k))
+            .max_loops(Some(1))
+            .print(false);
+
+        let mut prev = average_eval_error(&ts, &ps, &h, Some(k));
+        for _ in 0..2 {
+            let (e0,e1) = tuner.tune(&ts, &mut h, &ps, &out).unwrap();
+            assert_eq!(e0, prev);
+            assert!(e1 <= e0, "{} -> {}", e0, e1);
+            assert_eq!(e1, average_eval_error(&ts, &ps, &h, Some(k)));
+            prev = e1;
+        }
+        assert!(prev < average_eval_error(&ts, &ps, &helper(&ts), Some(k)));
+
+        /// Saved params are the tuned ones
+        let pf = ParamFile::read(&out).unwrap();
+        assert_eq!(pf.eval_mid, h.cfg.eval_params_mid);
+        assert_eq!(pf.eval_end, h.cfg.eval_params_end);
+        std::fs::remove_file(&out).unwrap();
+
+        let (e0,e1) = tuner.epochs(20).tune_adam(&ts, &mut h, &ps, &out).unwrap();
+        assert!(e1 <= e0, "{} -> {}", e0, e1);
+        let _ = std::fs::remove_file(&out);
+    }
+
+}
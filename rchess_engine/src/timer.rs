@@ -83,6 +83,8 @@ mod new {
         pub moves_to_go:      Option<u32>,
         pub is_per_move:      bool,
         pub ponder:           bool,
+        /// go infinite, only stopped by depth, nodes, or an external stop
+        pub infinite:         bool,
     }
 
     /// new
@@ -101,6 +103,14 @@ mod new {
             // out.time_remaining = [increment; 2];
             out
         }
+
+        /// Only stopped by depth, nodes, or an external stop
+        pub fn new_infinite() -> Self {
+            Self {
+                infinite: true,
+                ..Default::default()
+            }
+        }
     }
 
     impl TimeSettings {
@@ -122,6 +132,7 @@ mod new {
         pub limit_soft:    u64,
         pub is_per_move:   bool,
         pub ponder:        bool,
+        pub infinite:      bool,
         pub node_counter:  u64,
 
         pub should_stop:   bool,
@@ -170,6 +181,7 @@ mod new {
                 limit_soft,
                 is_per_move: settings.is_per_move,
                 ponder: settings.ponder,
+                infinite: settings.infinite,
                 node_counter:   0,
                 should_stop:    false,
                 abort_now:      false,
@@ -213,7 +225,7 @@ mod new {
 
             // eprintln!("should_stop = {:?}", nodes);
 
-            if self.ponder {
+            if self.ponder || self.infinite {
                 false
             // } else if self.node_next > nodes {
             } else if self.node_counter < Self::LOOPS_PER_TIME_CHECK {
@@ -236,7 +248,7 @@ mod new {
 
         /// From zahak
         pub fn can_do_next_iter(&self) -> bool {
-            if self.ponder {
+            if self.ponder || self.infinite {
                 true
             } else if self.should_stop || self.abort_now {
                 false
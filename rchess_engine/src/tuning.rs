@@ -16,7 +16,9 @@ use serde::{Serialize,Deserialize};
 use serde_big_array::BigArray;
 use derive_new::new;
 
-#[derive(Debug,Clone,Copy)]
+/// Missing fields in a param file are left at the default
+#[derive(Debug,PartialEq,Clone,Copy,Serialize,Deserialize)]
+#[serde(default)]
 pub struct SParams {
     pub max_ply:                  Depth,
 
@@ -43,6 +45,13 @@ pub struct SParams {
 
     pub history_max:              Score, // 20 * 20
 
+    /// Classical eval instead of NNUE when |material + psqt| is above this, 0 to always use NNUE
+    pub hybrid_threshold:         Score,
+    /// Added to hybrid_threshold at the start of the game, less as the phase goes to endgame
+    pub hybrid_phase:             Score,
+    /// hybrid_threshold is scaled by (hybrid_rule50 + halfmove) / hybrid_rule50
+    pub hybrid_rule50:            Depth,
+
 }
 
 impl Default for SParams {
@@ -74,6 +83,10 @@ impl Default for SParams {
 
             history_max:              400, // 20 * 20
 
+            hybrid_threshold:         400,
+            hybrid_phase:             100,
+            hybrid_rule50:            5,
+
         }
     }
 }
@@ -457,9 +470,9 @@ mod piece_square_tables {
                 -10,  0,  0,  0,  0,  0,  0,-10,
                 -10,  0,  5,  5,  5,  5,  0,-10,
                  -5,  0,  5,  5,  5,  5,  0, -5,
-                  0,  0,  5,  5,  5,  5,  0, -5,
-                -10,  5,  5,  5,  5,  5,  0,-10,
-                -10,  0,  5,  0,  0,  0,  0,-10,
+                 -5,  0,  5,  5,  5,  5,  0, -5,
+                -10,  0,  5,  5,  5,  5,  0,-10,
+                -10,  0,  0,  0,  0,  0,  0,-10,
                 -20,-10,-10, -5, -5,-10,-10,-20,
             ])
         }
@@ -473,7 +486,7 @@ mod piece_square_tables {
                 -20,-30,-30,-40,-40,-30,-30,-20,
                 -10,-20,-20,-20,-20,-20,-20,-10,
                  20, 20,  0,  0,  0,  0, 20, 20,
-                 20, 30, 10, -5,  0, 10, 30, 20,
+                 20, 30, 10,  0,  0, 10, 30, 20,
             ])
         }
 
@@ -620,6 +633,7 @@ pub struct EvalParams {
     pub mid:       bool,
     pub pawns:     EPPawns,
     pub pieces:    EPPieces,
+    pub imbalance: EPImbalance,
     pub psqt:      PcTables,
 }
 
@@ -630,6 +644,12 @@ impl EvalParams {
         let mut ev_end = Self::default();
         ev_mid.mid = true;
         ev_end.mid = false;
+        ev_mid.pawns = EPPawns::new_mid();
+        ev_end.pawns = EPPawns::new_end();
+        ev_mid.pieces = EPPieces::new_mid();
+        ev_end.pieces = EPPieces::new_end();
+        ev_mid.imbalance = EPImbalance::new_mid();
+        ev_end.imbalance = EPImbalance::new_end();
         (ev_mid,ev_end)
     }
 
@@ -671,17 +691,194 @@ impl EvalParams {
     }
 }
 
+/// Largest magnitude allowed for any EvalParams value in a ParamFile
+pub const PARAM_FILE_MAX: Score = 5000;
+
+/// Human readable params, as JSON. Unlike save_evparams, also has the search params
+#[derive(Debug,Clone,Copy,Serialize,Deserialize,new)]
+pub struct ParamFile {
+    pub eval_mid:    EvalParams,
+    pub eval_end:    EvalParams,
+    #[serde(default)]
+    pub search:      SParams,
+}
+
+impl Default for ParamFile {
+    fn default() -> Self {
+        let (eval_mid,eval_end) = EvalParams::new_mid_end();
+        Self::new(eval_mid, eval_end, SParams::default())
+    }
+}
+
+/// save, read
+impl ParamFile {
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        use std::io::Write;
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(self.to_json().as_bytes())
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Also validates, a bad file is an InvalidData error
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        use std::io::{Error,ErrorKind};
+        let b = std::fs::read(path)?;
+        let out: Self = serde_json::from_slice(&b)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        out.validate().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(out)
+    }
+
+}
+
+/// validate
+impl ParamFile {
+    pub fn validate(&self) -> Result<(), String> {
+
+        if !self.eval_mid.mid { return Err("eval_mid.mid must be true".to_string()); }
+        if self.eval_end.mid { return Err("eval_end.mid must be false".to_string()); }
+
+        for (name,ev) in [("eval_mid", &self.eval_mid), ("eval_end", &self.eval_end)] {
+            /// 0 is EvalParams::mid
+            for (n,v) in ev.to_arr().iter().enumerate().skip(1) {
+                if v.abs() > PARAM_FILE_MAX {
+                    return Err(format!("{}: value {} at index {} out of range, max {}",
+                                       name, v, n, PARAM_FILE_MAX));
+                }
+            }
+        }
+
+        let sp = &self.search;
+        if sp.max_ply <= 0 || sp.max_ply > MAX_SEARCH_PLY {
+            return Err(format!("search.max_ply must be in 1..={}, found {}", MAX_SEARCH_PLY, sp.max_ply));
+        }
+        // Divisors
+        if sp.lmr_reduction <= 0 { return Err("search.lmr_reduction must be > 0".to_string()); }
+        if sp.lmr_ply_const <= 0 { return Err("search.lmr_ply_const must be > 0".to_string()); }
+        if sp.hybrid_rule50 <= 0 { return Err("search.hybrid_rule50 must be > 0".to_string()); }
+
+        let depths = [
+            ("lmr_min_moves", sp.lmr_min_moves),
+            ("lmr_min_ply", sp.lmr_min_ply),
+            ("lmr_min_depth", sp.lmr_min_depth),
+            ("qs_recaps_only", sp.qs_recaps_only),
+            ("null_prune_min_depth", sp.null_prune_min_depth),
+            ("null_prune_reduction", sp.null_prune_reduction),
+            ("rfp_min_depth", sp.rfp_min_depth),
+        ];
+        for (name,d) in depths {
+            if d < 0 { return Err(format!("search.{} must be >= 0, found {}", name, d)); }
+        }
+
+        let margins = [
+            ("qs_delta_margin", sp.qs_delta_margin),
+            ("rfp_margin", sp.rfp_margin),
+            ("futility_min_alpha", sp.futility_min_alpha),
+            ("futility_margin", sp.futility_margin),
+            ("history_max", sp.history_max),
+            ("hybrid_threshold", sp.hybrid_threshold),
+            ("hybrid_phase", sp.hybrid_phase),
+        ];
+        for (name,m) in margins {
+            if m < 0 { return Err(format!("search.{} must be >= 0, found {}", name, m)); }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
 pub struct EPPieces {
+    /// Half open, open
     pub rook_open_file:  [Score; 2],
     pub outpost:         EvOutpost,
+    pub mobility:        EvMobility,
+    pub king_safety:     EvKingSafety,
+    pub threats:         EvThreats,
 }
 
 impl Default for EPPieces {
-    fn default() -> Self {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EPPieces {
+    pub fn new_mid() -> Self {
         Self {
             rook_open_file:   [10,20],
             outpost:          EvOutpost::default(),
+            mobility:         EvMobility::new_mid(),
+            king_safety:      EvKingSafety::new_mid(),
+            threats:          EvThreats::new_mid(),
+        }
+    }
+    pub fn new_end() -> Self {
+        Self {
+            rook_open_file:   [5,10],
+            outpost:          EvOutpost::new(30,20,20),
+            mobility:         EvMobility::new_end(),
+            king_safety:      EvKingSafety::new_end(),
+            threats:          EvThreats::new_end(),
+        }
+    }
+}
+
+/// Quadratic material imbalance, from stockfish, scaled by the ratio of our minor piece values.
+/// Indexed by [piece][other piece], only j <= i is used.
+/// Index 0 is the bishop pair, then Pawn, Knight, Bishop, Rook, Queen
+#[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
+pub struct EPImbalance {
+    pub ours:       [[Score; 6]; 6],
+    pub theirs:     [[Score; 6]; 6],
+}
+
+impl Default for EPImbalance {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EPImbalance {
+    pub fn new_mid() -> Self {
+        Self {
+            ours: [
+                [ 589,    0,    0,    0,    0,    0],
+                [  16,   16,    0,    0,    0,    0],
+                [  13,  104,  -25,    0,    0,    0],
+                [   0,   43,    2,    0,    0,    0],
+                [ -11,   -1,   19,   43,  -85,    0],
+                [ -77,   10,   48,   54,  -55,   -2],
+            ],
+            theirs: [
+                [   0,    0,    0,    0,    0,    0],
+                [  15,    0,    0,    0,    0,    0],
+                [   4,   26,    0,    0,    0,    0],
+                [  24,   27,   17,    0,    0,    0],
+                [  19,   16,   10,  -10,    0,    0],
+                [  40,   41,  -17,   56,  110,    0],
+            ],
+        }
+    }
+
+    pub fn new_end() -> Self {
+        Self {
+            ours: [
+                [ 539,    0,    0,    0,    0,    0],
+                [  15,   14,    0,    0,    0,    0],
+                [  12,   96,  -23,    0,    0,    0],
+                [   0,   39,    1,    0,    0,    0],
+                [ -10,   -1,   18,   39,  -78,    0],
+                [ -71,    9,   44,   50,  -50,   -2],
+            ],
+            theirs: [
+                [   0,    0,    0,    0,    0,    0],
+                [  13,    0,    0,    0,    0,    0],
+                [   3,   24,    0,    0,    0,    0],
+                [  22,   24,   16,    0,    0,    0],
+                [  17,   15,    9,   -9,    0,    0],
+                [  36,   37,  -16,   51,  100,    0],
+            ],
         }
     }
 }
@@ -706,6 +903,99 @@ impl Default for EvOutpost {
     }
 }
 
+/// Indexed by the number of safe squares attacked
+#[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
+pub struct EvMobility {
+    pub knight:     [Score; 9],
+    pub bishop:     [Score; 14],
+    pub rook:       [Score; 15],
+    pub queen:      [Score; 28],
+}
+
+impl Default for EvMobility {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EvMobility {
+    pub fn get(&self, pc: Piece, n: usize) -> Score {
+        match pc {
+            Knight => self.knight[n],
+            Bishop => self.bishop[n],
+            Rook   => self.rook[n],
+            Queen  => self.queen[n],
+            _      => 0,
+        }
+    }
+
+    pub fn new_mid() -> Self {
+        Self {
+            knight: [-50, -42, -10, -3, 2, 10, 18, 22, 26],
+            bishop: [-38, -16, 13, 21, 30, 41, 44, 50, 50, 54, 65, 65, 73, 78],
+            rook:   [-48, -16, 2, 2, 2, 9, 18, 25, 32, 32, 33, 38, 46, 46, 50],
+            queen:  [-24, -10, -6, -7, 16, 18, 18, 28, 30, 42, 51, 52, 52, 53,
+                     54, 54, 58, 58, 62, 63, 74, 86, 86, 86, 88, 91, 91, 93],
+        }
+    }
+
+    pub fn new_end() -> Self {
+        Self {
+            knight: [-77, -53, -29, -15, 5, 10, 16, 19, 24],
+            bishop: [-56, -22, -3, 12, 23, 40, 51, 54, 62, 69, 74, 82, 84, 92],
+            rook:   [-74, -16, 22, 37, 66, 94, 98, 115, 127, 132, 150, 156, 160, 161, 163],
+            queen:  [-46, -28, -7, 18, 38, 52, 56, 71, 74, 91, 91, 95, 115, 121,
+                     124, 126, 129, 134, 140, 142, 143, 160, 160, 162, 173, 173, 182, 208],
+        }
+    }
+}
+
+/// Penalties, from the side of the king being attacked.
+/// Knight, Bishop, Rook, Queen
+#[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
+pub struct EvKingSafety {
+    /// Per king zone square attacked, only with at least 2 attackers
+    pub zone_attack:     [Score; 4],
+    /// Per square a check could be given from without being captured
+    pub safe_check:      [Score; 4],
+}
+
+impl Default for EvKingSafety {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EvKingSafety {
+    pub fn new_mid() -> Self {
+        Self {
+            zone_attack:  [-8, -6, -5, -4],
+            safe_check:   [-30, -20, -35, -25],
+        }
+    }
+    pub fn new_end() -> Self {
+        Self {
+            zone_attack:  [-2, -2, -2, -1],
+            safe_check:   [-5, -5, -5, -5],
+        }
+    }
+}
+
+#[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
+pub struct EvThreats {
+    /// Enemy piece attacked and not defended
+    pub hanging:         Score,
+    /// Enemy piece attacked by a pawn that is defended or not attacked
+    pub safe_pawn:       Score,
+    /// Enemy piece that would be attacked by a safe pawn push
+    pub pawn_push:       Score,
+}
+
+impl Default for EvThreats {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EvThreats {
+    pub fn new_mid() -> Self { Self::new(55, 135, 38) }
+    pub fn new_end() -> Self { Self::new(30, 90, 37) }
+}
+
 // #[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new,EvalIndex)]
 #[derive(Debug,Eq,PartialEq,PartialOrd,Clone,Copy,Serialize,Deserialize,new)]
 pub struct EPPawns {
@@ -716,20 +1006,32 @@ pub struct EPPawns {
     pub blocked_r6:           Score,
 
     // pub candidate:            Score,
-    // pub passed:               Score,
 
     // pub doubled_isolated:     Score,
     pub doubled:              Score,
     pub isolated:             Score,
     pub backward:             Score,
 
+    /// By relative rank, cached in the pawn table
+    pub passed_rank:          [Score; 8],
+    /// Per square of distance to the square in front of the pawn,
+    /// times the weight of the pawn's rank. Not cached, see ExHelper::passed_pawns
+    pub passed_king_us:       Score,
+    pub passed_king_them:     Score,
+    /// Square in front of the pawn is empty
+    pub passed_free:          Score,
+
 }
 
 // unsafe impl std::slice::SliceIndex for EPPawns {
 // }
 
 impl Default for EPPawns {
-    fn default() -> Self {
+    fn default() -> Self { Self::new_mid() }
+}
+
+impl EPPawns {
+    pub fn new_mid() -> Self {
         Self {
             supported:            20,
             connected_ranks:      [0, 5, 10, 15, 30, 50, 80],
@@ -738,12 +1040,35 @@ impl Default for EPPawns {
             blocked_r6:           -5,
 
             // candidate:            Score,
-            // passed:               Score,
 
             // doubled_isolated:     10,
             isolated:             -5,
             backward:             -10,
             doubled:              -10,
+
+            passed_rank:          [0, 5, 10, 10, 40, 110, 180, 0],
+            passed_king_us:       0,
+            passed_king_them:     0,
+            passed_free:          10,
+        }
+    }
+
+    pub fn new_end() -> Self {
+        Self {
+            supported:            15,
+            connected_ranks:      [0, 3, 5, 8, 15, 25, 40],
+
+            blocked_r5:           -5,
+            blocked_r6:           0,
+
+            isolated:             -10,
+            backward:             -15,
+            doubled:              -25,
+
+            passed_rank:          [0, 15, 20, 25, 45, 110, 170, 0],
+            passed_king_us:       -2,
+            passed_king_them:     5,
+            passed_free:          15,
         }
     }
 }
@@ -839,31 +1164,35 @@ pub mod indexing {
         const LEN: usize = 1
             + EPPawns::LEN
             + EPPieces::LEN
+            + EPImbalance::LEN
             + PcTables::LEN;
 
         fn to_arr(&self) -> Vec<Score> {
             let mut out = vec![if self.mid { 1 } else { 0 }];
             out.extend_from_slice(&self.pawns.to_arr());
             out.extend_from_slice(&self.pieces.to_arr());
+            out.extend_from_slice(&self.imbalance.to_arr());
             out.extend_from_slice(&self.psqt.to_arr());
             out
         }
         fn from_arr(v: &[Score]) -> Self {
             let n0 = 1 + EPPawns::LEN;
             let n1 = n0 + EPPieces::LEN;
-            let n2 = n1 + PcTables::LEN;
+            let n2 = n1 + EPImbalance::LEN;
             Self {
-                mid:     v[0] == 1,
-                pawns:   EPPawns::from_arr(&v[1..n0]),
-                pieces:  EPPieces::from_arr(&v[n0..n1]),
+                mid:       v[0] == 1,
+                pawns:     EPPawns::from_arr(&v[1..n0]),
+                pieces:    EPPieces::from_arr(&v[n0..n1]),
+                imbalance: EPImbalance::from_arr(&v[n1..n2]),
                 // psqt:    PcTables::from_arr(&v[n1..n2 + 1]),
-                psqt:    PcTables::from_arr(&v[n1..]),
+                psqt:      PcTables::from_arr(&v[n2..]),
             }
         }
         fn to_arr_mut(&mut self) -> Vec<&mut Score> {
             let mut xs = vec![];
             xs.extend(self.pawns.to_arr_mut());
             xs.extend(self.pieces.to_arr_mut());
+            xs.extend(self.imbalance.to_arr_mut());
             xs.extend(self.psqt.to_arr_mut());
             xs
         }
@@ -878,7 +1207,7 @@ pub mod indexing {
     }
 
     impl Tunable for EPPawns {
-        const LEN: usize = 13;
+        const LEN: usize = 24;
 
         fn to_arr(&self) -> Vec<Score> {
             let mut out = vec![self.supported];
@@ -888,6 +1217,10 @@ pub mod indexing {
             out.push(self.isolated);
             out.push(self.backward);
             out.push(self.doubled);
+            out.extend_from_slice(&self.passed_rank);
+            out.push(self.passed_king_us);
+            out.push(self.passed_king_them);
+            out.push(self.passed_free);
             out
         }
 
@@ -904,17 +1237,27 @@ pub mod indexing {
             out.backward = v[11];
             out.doubled  = v[12];
 
+            out.passed_rank.copy_from_slice(&v[13..21]);
+            out.passed_king_us   = v[21];
+            out.passed_king_them = v[22];
+            out.passed_free      = v[23];
+
             out
         }
 
+        /// Same order as to_arr
         fn to_arr_mut(&mut self) -> Vec<&mut Score> {
             let mut xs = vec![&mut self.supported];
             xs.extend(self.connected_ranks.iter_mut());
             xs.push(&mut self.blocked_r5);
             xs.push(&mut self.blocked_r6);
-            xs.push(&mut self.doubled);
             xs.push(&mut self.isolated);
             xs.push(&mut self.backward);
+            xs.push(&mut self.doubled);
+            xs.extend(self.passed_rank.iter_mut());
+            xs.push(&mut self.passed_king_us);
+            xs.push(&mut self.passed_king_them);
+            xs.push(&mut self.passed_free);
             xs
         }
 
@@ -929,7 +1272,11 @@ pub mod indexing {
     }
 
     impl Tunable for EPPieces {
-        const LEN: usize = 2 + EvOutpost::LEN;
+        const LEN: usize = 2
+            + EvOutpost::LEN
+            + EvMobility::LEN
+            + EvKingSafety::LEN
+            + EvThreats::LEN;
 
         fn to_arr(&self) -> Vec<Score> {
             let mut out = vec![
@@ -937,14 +1284,23 @@ pub mod indexing {
                 self.rook_open_file[1],
             ];
             out.extend_from_slice(&self.outpost.to_arr());
+            out.extend_from_slice(&self.mobility.to_arr());
+            out.extend_from_slice(&self.king_safety.to_arr());
+            out.extend_from_slice(&self.threats.to_arr());
             out
         }
 
         fn from_arr(v: &[Score]) -> Self {
             assert!(v.len() >= Self::LEN);
+            let n1 = 2 + EvOutpost::LEN;
+            let n2 = n1 + EvMobility::LEN;
+            let n3 = n2 + EvKingSafety::LEN;
             Self {
                 rook_open_file: [v[0],v[1]],
-                outpost:        EvOutpost::from_arr(&v[2..]),
+                outpost:        EvOutpost::from_arr(&v[2..n1]),
+                mobility:       EvMobility::from_arr(&v[n1..n2]),
+                king_safety:    EvKingSafety::from_arr(&v[n2..n3]),
+                threats:        EvThreats::from_arr(&v[n3..]),
             }
         }
 
@@ -952,6 +1308,9 @@ pub mod indexing {
             let mut xs = vec![];
             xs.extend(self.rook_open_file.iter_mut());
             xs.extend(self.outpost.to_arr_mut());
+            xs.extend(self.mobility.to_arr_mut());
+            xs.extend(self.king_safety.to_arr_mut());
+            xs.extend(self.threats.to_arr_mut());
             xs
         }
 
@@ -965,6 +1324,166 @@ pub mod indexing {
         }
     }
 
+    impl Tunable for EPImbalance {
+        /// Lower triangles only
+        const LEN: usize = 2 * 21;
+
+        fn to_arr(&self) -> Vec<Score> {
+            let mut out = vec![];
+            for tbl in [&self.ours, &self.theirs] {
+                for i in 0..6 {
+                    out.extend_from_slice(&tbl[i][..=i]);
+                }
+            }
+            out
+        }
+
+        fn from_arr(v: &[Score]) -> Self {
+            assert!(v.len() >= Self::LEN);
+            let mut out = Self::new([[0; 6]; 6], [[0; 6]; 6]);
+            let mut k = 0;
+            for tbl in [&mut out.ours, &mut out.theirs] {
+                for i in 0..6 {
+                    for j in 0..=i {
+                        tbl[i][j] = v[k];
+                        k += 1;
+                    }
+                }
+            }
+            out
+        }
+
+        fn to_arr_mut(&mut self) -> Vec<&mut Score> {
+            let mut xs = vec![];
+            for tbl in [&mut self.ours, &mut self.theirs] {
+                for (i,row) in tbl.iter_mut().enumerate() {
+                    xs.extend(row[..=i].iter_mut());
+                }
+            }
+            xs
+        }
+
+        /// The imbalance is stored in the material table entries
+        fn update_exhelper(&self, exhelper: &mut ExHelper, mid: bool) {
+            if mid {
+                exhelper.cfg.eval_params_mid.imbalance = *self;
+            } else {
+                exhelper.cfg.eval_params_end.imbalance = *self;
+            }
+            exhelper.clear_eval_caches();
+        }
+    }
+
+    impl Tunable for EvMobility {
+        const LEN: usize = 9 + 14 + 15 + 28;
+
+        fn to_arr(&self) -> Vec<Score> {
+            let mut out = vec![];
+            out.extend_from_slice(&self.knight);
+            out.extend_from_slice(&self.bishop);
+            out.extend_from_slice(&self.rook);
+            out.extend_from_slice(&self.queen);
+            out
+        }
+
+        fn from_arr(v: &[Score]) -> Self {
+            assert!(v.len() >= Self::LEN);
+            let mut out = Self::default();
+            out.knight.copy_from_slice(&v[0..9]);
+            out.bishop.copy_from_slice(&v[9..23]);
+            out.rook.copy_from_slice(&v[23..38]);
+            out.queen.copy_from_slice(&v[38..66]);
+            out
+        }
+
+        fn to_arr_mut(&mut self) -> Vec<&mut Score> {
+            let mut xs = vec![];
+            xs.extend(self.knight.iter_mut());
+            xs.extend(self.bishop.iter_mut());
+            xs.extend(self.rook.iter_mut());
+            xs.extend(self.queen.iter_mut());
+            xs
+        }
+
+        fn update_exhelper(&self, exhelper: &mut ExHelper, mid: bool) {
+            if mid {
+                exhelper.cfg.eval_params_mid.pieces.mobility = *self;
+            } else {
+                exhelper.cfg.eval_params_end.pieces.mobility = *self;
+            }
+            exhelper.clear_eval_caches();
+        }
+    }
+
+    impl Tunable for EvKingSafety {
+        const LEN: usize = 8;
+
+        fn to_arr(&self) -> Vec<Score> {
+            let mut out = vec![];
+            out.extend_from_slice(&self.zone_attack);
+            out.extend_from_slice(&self.safe_check);
+            out
+        }
+
+        fn from_arr(v: &[Score]) -> Self {
+            assert!(v.len() >= Self::LEN);
+            let mut out = Self::default();
+            out.zone_attack.copy_from_slice(&v[0..4]);
+            out.safe_check.copy_from_slice(&v[4..8]);
+            out
+        }
+
+        fn to_arr_mut(&mut self) -> Vec<&mut Score> {
+            let mut xs = vec![];
+            xs.extend(self.zone_attack.iter_mut());
+            xs.extend(self.safe_check.iter_mut());
+            xs
+        }
+
+        fn update_exhelper(&self, exhelper: &mut ExHelper, mid: bool) {
+            if mid {
+                exhelper.cfg.eval_params_mid.pieces.king_safety = *self;
+            } else {
+                exhelper.cfg.eval_params_end.pieces.king_safety = *self;
+            }
+            exhelper.clear_eval_caches();
+        }
+    }
+
+    impl Tunable for EvThreats {
+        const LEN: usize = 3;
+
+        fn to_arr(&self) -> Vec<Score> {
+            vec![self.hanging,self.safe_pawn,self.pawn_push]
+        }
+
+        fn from_arr(v: &[Score]) -> Self {
+            assert!(v.len() >= Self::LEN);
+            Self {
+                hanging:   v[0],
+                safe_pawn: v[1],
+                pawn_push: v[2],
+            }
+        }
+
+        fn to_arr_mut(&mut self) -> Vec<&mut Score> {
+            vec![
+                &mut self.hanging,
+                &mut self.safe_pawn,
+                &mut self.pawn_push,
+            ]
+        }
+
+        fn update_exhelper(&self, exhelper: &mut ExHelper, mid: bool) {
+            if mid {
+                exhelper.cfg.eval_params_mid.pieces.threats = *self;
+            } else {
+                exhelper.cfg.eval_params_end.pieces.threats = *self;
+            }
+            exhelper.clear_eval_caches();
+        }
+    }
+
     impl Tunable for EvOutpost {
         const LEN: usize = 3;
 
@@ -991,14 +1510,84 @@ pub mod indexing {
         }
 
         fn update_exhelper(&self, exhelper: &mut ExHelper, mid: bool) {
-            // if mid {
-            //     exhelper.cfg.eval_params_mid.pieces.outpost = *self;
-            // } else {
-            //     exhelper.cfg.eval_params_end.pieces.outpost = *self;
-            // }
-            unimplemented!()
+            if mid {
+                exhelper.cfg.eval_params_mid.pieces.outpost = *self;
+            } else {
+                exhelper.cfg.eval_params_end.pieces.outpost = *self;
+            }
+            exhelper.clear_eval_caches();
         }
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tables::*;
+    use crate::explore::*;
+    use crate::searchstats::SearchStats;
+    use crate::timer::TimeSettings;
+
+    /// Knight outpost on d5, open lines to both kings, pieces en prise
+    const FEN: &'static str = "r2q1rk1/pp2bppp/3p1n2/3Np3/4P1b1/5N2/PPP1QPPP/R3KB1R w KQ - 0 1";
+
+    /// Without the bishops, and White is a knight up
+    const FEN_IMBALANCE: &'static str = "r2q1rk1/pp3ppp/3p1n2/3Np3/4P3/5N2/PPP1QPPP/R3K2R w KQ - 0 1";
+
+    fn helper(ts: &Tables, fen: &str, f: impl Fn(&mut ExConfig)) -> ExHelper {
+        let g = Game::from_fen(ts, fen).unwrap();
+        let mut ex = Explorer::new(g.state.side_to_move, g, 1, TimeSettings::new_f64(0.0, 1.0));
+        f(&mut ex.cfg);
+        ex.build_exhelper(0, PerThreadData::default())
+    }
+
+    /// Same eval as setting the params before building the helper, even with the old eval cached
+    fn check_update<T: Tunable + Copy + PartialEq + std::fmt::Debug>(
+        fen: &str,
+        get: impl Fn(&EvalParams) -> T,
+        set: impl Fn(&mut EvalParams, T),
+    ) {
+        let ts = Tables::new();
+        let g = Game::from_fen(&ts, fen).unwrap();
+        let mut stats = SearchStats::default();
+
+        for mid in [true,false] {
+            let mut h = helper(&ts, fen, |_| {});
+            let before = h.evaluate_cached(&ts, &mut stats, &g, 0, false);
+
+            let params = if mid { h.cfg.eval_params_mid } else { h.cfg.eval_params_end };
+            /// Not the same for every index, or mobility cancels out with equal pieces
+            let xs = get(&params).to_arr().iter().enumerate()
+                .map(|(i,v)| v + 10 + 7 * i as Score)
+                .collect::<Vec<_>>();
+            let x = T::from_arr(&xs);
+            x.update_exhelper(&mut h, mid);
+            let after = h.evaluate_cached(&ts, &mut stats, &g, 0, false);
+
+            let mut h2 = helper(&ts, fen, |cfg| {
+                let ps = if mid { &mut cfg.eval_params_mid } else { &mut cfg.eval_params_end };
+                set(ps, x);
+            });
+            let expected = h2.evaluate_cached(&ts, &mut stats, &g, 0, false);
+
+            assert_eq!(after, expected, "mid = {}", mid);
+            assert_ne!(after, before, "mid = {}, {:?} has no effect here", mid, x);
+        }
+    }
+
+    #[test]
+    fn update_exhelper_pieces() {
+        check_update(FEN, |p| p.pieces.mobility, |p,x| p.pieces.mobility = x);
+        check_update(FEN, |p| p.pieces.king_safety, |p,x| p.pieces.king_safety = x);
+        check_update(FEN, |p| p.pieces.threats, |p,x| p.pieces.threats = x);
+        check_update(FEN, |p| p.pieces.outpost, |p,x| p.pieces.outpost = x);
+    }
+
+    #[test]
+    fn update_exhelper_imbalance() {
+        check_update(FEN_IMBALANCE, |p| p.imbalance, |p,x| p.imbalance = x);
+    }
+
+}
@@ -11,6 +11,10 @@ use rchess_engine_lib::types::*;
 use rchess_engine_lib::tables::*;
 use rchess_engine_lib::explore::*;
 use rchess_engine_lib::evaluate::*;
+use rchess_engine_lib::eval_trace::trace_eval;
+use rchess_engine_lib::tuning::ParamFile;
+use rchess_engine_lib::parsing::FenMode;
+use rchess_engine_lib::game_record::GameRecord;
 // use rchess_engine_lib::threading::*;
 
 use std::str::FromStr;
@@ -33,6 +37,8 @@ use rayon::ThreadPoolBuilder;
 
 const STARTPOS: &'static str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
+const DEFAULT_EVAL_FILE: &'static str = "/home/me/code/rust/rchess/nn-63376713ba63.nnue";
+
 fn main() -> std::io::Result<()> {
 
     let now = chrono::Local::now();
@@ -79,7 +85,8 @@ fn main() -> std::io::Result<()> {
 
     let timesettings = TimeSettings::new_f64(0.0, 0.5);
 
-    let ts = Tables::new();
+    /// Mutable for the PSQTs in ParamsFile
+    let mut ts = Tables::new();
     // let ts = &_TABLES;
 
     let mut g = Game::from_fen(&ts, STARTPOS).unwrap();
@@ -90,22 +97,33 @@ fn main() -> std::io::Result<()> {
     #[cfg(feature = "threadpool")]
     let mut explorer = Explorer2::new(White,g, MAX_SEARCH_PLY, timesettings);
 
-    explorer.load_nnue("/home/me/code/rust/rchess/nn-63376713ba63.nnue").unwrap();
+    if let Err(e) = explorer.load_nnue(DEFAULT_EVAL_FILE) {
+        error!("EvalFile {}: {}, using classical eval", DEFAULT_EVAL_FILE, e);
+    }
 
     #[cfg(feature = "threadpool")]
     explorer.spawn_threads();
 
     // explorer.load_syzygy("/home/me/code/rust/rchess/tables/syzygy/").unwrap_or_default();
 
-    // let evpath = "/home/me/code/rust/rchess/evparams.bin";
-    // let (ev_mid,ev_end) = EvalParams::read_evparams(evpath).unwrap();
-
-    // let (ev_mid,ev_end) = EvalParams::new_mid_end();
-    // explorer.cfg.eval_params_mid = ev_mid;
-    // explorer.cfg.eval_params_end = ev_end;
+    /// rchess_uci --params <file.json>, same as setting ParamsFile
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.iter().position(|a| a == "--params").and_then(|i| args.get(i + 1)) {
+        match explorer.load_params(&mut ts, path) {
+            Ok(())  => debug!("loaded ParamsFile {}", path),
+            Err(e)  => {
+                error!("ParamsFile {}: {}", path, e);
+                println!("info string ParamsFile {}: {}, using default params", path, e);
+            },
+        }
+    }
 
     let mut g0 = Game::from_fen(&ts, STARTPOS).unwrap();
 
+    /// false until a position is set, and after a rejected position command,
+    /// so go doesn't search the previous board
+    let mut position_ok = false;
+
     let stdin = std::io::stdin();
     for line in stdin.lock().lines() {
         if let Ok(line) = line {
@@ -118,6 +136,26 @@ fn main() -> std::io::Result<()> {
                 match params.next().unwrap() {
                     "uci"        => uci(&explorer),
                     "isready"    => println!("readyok"),
+                    /// eval [json], json is only the classical terms
+                    "eval"       => match params.next() {
+                        Some("json") => {
+                            let mut helper = explorer.build_exhelper(0, PerThreadData::default());
+                            println!("{}", helper.trace_classical(&ts, &explorer.game).to_json());
+                        },
+                        _            => print!("{}", trace_eval(&ts, &explorer, &explorer.game)),
+                    },
+                    /// dumpparams [file.json], prints to stdout without a path
+                    "dumpparams" => {
+                        let path = params.clone().collect::<Vec<_>>().join(" ");
+                        let pf = explorer.dump_params();
+                        if path.is_empty() {
+                            println!("{}", pf.to_json());
+                        } else if let Err(e) = pf.save(&path) {
+                            println!("info string dumpparams {}: {}", path, e);
+                        } else {
+                            println!("info string wrote params to {}", path);
+                        }
+                    },
                     "ucinewgame" => {
                         // let mut g = Game::new();
                         let mut g = Game::from_fen(&ts, STARTPOS).unwrap();
@@ -128,13 +166,15 @@ fn main() -> std::io::Result<()> {
                         // explorer.game = g;
                         explorer.update_game(g);
                         explorer.new_game(&ts, g);
+                        position_ok = true;
                         #[cfg(feature = "threadpool")]
                         explorer.clear_threads();
                     },
                     "setoption"   => {
-                        set_option(&mut explorer, params.clone().collect());
+                        set_option(&mut ts, &mut explorer, params.clone().collect());
                     },
                     "position"   => {
+                        position_ok = false;
                         match params.next().unwrap() {
                             "fen" => {
                                 let fen = line.replace("position fen ", "");
@@ -142,7 +182,13 @@ fn main() -> std::io::Result<()> {
                                 let mut xs = fen.split("moves ");
                                 let fen = xs.next().unwrap();
 
-                                let mut g = Game::from_fen(&ts, &fen).unwrap();
+                                let mut g = match Game::from_fen_mode(&ts, &fen, FenMode::Lenient) {
+                                    Ok(g)  => g,
+                                    Err(e) => {
+                                        println!("info string bad fen: {}", e);
+                                        continue;
+                                    },
+                                };
 
                                 // eprintln!("fen = {:?}", fen);
                                 match xs.next() {
@@ -157,18 +203,23 @@ fn main() -> std::io::Result<()> {
                                         //     g = g.make_move_unchecked(&ts, mm).unwrap();
                                         // }
 
-                                        explorer.update_game_movelist(&ts, &fen, moves);
+                                        if let Err(e) = explorer.update_game_movelist(&ts, &fen, moves) {
+                                            println!("info string bad moves: {}", e);
+                                            continue;
+                                        }
 
                                     },
-                                    None => {},
+                                    None => {
+                                        explorer.update_game_record(&GameRecord::new(&ts, g));
+                                    },
                                 }
 
                                 // explorer.lock().unwrap().side = g.state.side_to_move;
                                 // explorer.lock().unwrap().game = g;
-                                debug!("setting game FEN = {}", g.to_fen());
+                                debug!("setting game FEN = {}", explorer.game.to_fen());
                                 // explorer.side = g.state.side_to_move;
                                 // explorer.game = g;
-                                explorer.update_game(g.clone());
+                                position_ok = true;
                             },
                             "startpos" => {
                                 params.next();
@@ -190,10 +241,13 @@ fn main() -> std::io::Result<()> {
                                 // explorer.update_game(g.clone());
 
                                 let moves = moves.into_iter();
-                                explorer.update_game_movelist(&ts, STARTPOS, moves);
+                                if let Err(e) = explorer.update_game_movelist(&ts, STARTPOS, moves) {
+                                    println!("info string bad moves: {}", e);
+                                    continue;
+                                }
 
                                 debug!("setting game FEN = {}", explorer.game.to_fen());
-
+                                position_ok = true;
                             },
                             x => panic!("Position not fen? {:?},  {:?}", x, params),
                         }
@@ -206,6 +260,12 @@ fn main() -> std::io::Result<()> {
 
                         debug!("explorer going: ");
 
+                        if !position_ok {
+                            println!("info string no valid position, not searching");
+                            println!("bestmove 0000");
+                            continue;
+                        }
+
                         // if let Some(ref mut nnue) = explorer.nnue {
                         //     // nnue.ft.accum.needs_refresh = [true; 2];
                         //     nnue.ft.reset_accum(&explorer.game);
@@ -252,17 +312,55 @@ fn uci(ex: &Explorer) {
     println!("id author me");
 
     ex.options.print();
+    println!("option name EvalFile type string default {}", DEFAULT_EVAL_FILE);
+    println!("option name ParamsFile type string default <empty>");
+
+    if ex.nnue.is_none() {
+        println!("info string no NNUE loaded, using classical eval");
+    }
 
     println!("uciok");
 }
 
-fn set_option(mut ex: &mut Explorer, params: Vec<&str>) {
+fn set_option(ts: &mut Tables, mut ex: &mut Explorer, params: Vec<&str>) {
     let mut ps = params.clone().into_iter();
     // println!("params = {:?}", params);
 
     ps.next().unwrap(); // name
     let name = ps.next().unwrap();
     ps.next().unwrap(); // value
+
+    /// paths can have spaces
+    if name == "EvalFile" {
+        let path = ps.collect::<Vec<_>>().join(" ");
+        match ex.load_nnue(&path) {
+            Ok(())  => println!("info string loaded EvalFile {}", path),
+            Err(e)  => {
+                error!("EvalFile {}: {}", path, e);
+                println!("info string EvalFile {}: {}, using classical eval", path, e);
+            },
+        }
+        return;
+    }
+
+    /// Empty resets everything in the file, including search params set by other options
+    if name == "ParamsFile" {
+        let path = ps.collect::<Vec<_>>().join(" ");
+        if path.is_empty() || path == "<empty>" {
+            ex.set_params(ts, &ParamFile::default());
+            println!("info string ParamsFile cleared, using default params");
+            return;
+        }
+        match ex.load_params(ts, &path) {
+            Ok(())  => println!("info string loaded ParamsFile {}", path),
+            Err(e)  => {
+                error!("ParamsFile {}: {}", path, e);
+                println!("info string ParamsFile {}: {}, params unchanged", path, e);
+            },
+        }
+        return;
+    }
+
     let val = ps.next().unwrap();
 
     ex.set_option(name, val);
@@ -281,6 +379,11 @@ fn parse_go(
 
     let mut ps = params.clone().into_iter();
 
+    ex.cfg.max_nodes = None;
+    ex.time_settings.infinite = false;
+    /// go nodes without a clock is only limited by nodes
+    let mut timed = false;
+
     while let Some(cmd) = ps.next() {
         match cmd {
             "searchmoves" => {
@@ -291,6 +394,7 @@ fn parse_go(
                 unimplemented!()
             },
             "wtime"       => {
+                timed = true;
                 let val = i64::from_str(ps.next().unwrap()).unwrap();
                 if val < 0 {
                     ex.time_settings.update_time_remaining(0, White, ex.side == White);
@@ -299,6 +403,7 @@ fn parse_go(
                 }
             },
             "btime"       => {
+                timed = true;
                 let val = i64::from_str(ps.next().unwrap()).unwrap();
                 if val < 0 {
                     ex.time_settings.update_time_remaining(0, Black, ex.side == Black);
@@ -327,7 +432,8 @@ fn parse_go(
                 let val = u32::from_str(ps.next().unwrap()).unwrap();
             },
             "nodes"       => {
-                let val = u32::from_str(ps.next().unwrap()).unwrap();
+                let val = u64::from_str(ps.next().unwrap()).unwrap();
+                ex.cfg.max_nodes = Some(val);
             },
             "mate"        => {
                 let val = u32::from_str(ps.next().unwrap()).unwrap();
@@ -342,10 +448,11 @@ fn parse_go(
 
                 ex.time_settings.move_time = val;
                 ex.time_settings.is_per_move = true;
+                timed = true;
 
             },
             "infinite"    => {
-                // ex.timer.settings.infinite = true;
+                ex.time_settings.infinite = true;
             },
             _             => {
                 debug!("unrecognized go command: {:?}", &params);
@@ -354,6 +461,10 @@ fn parse_go(
         }
     }
 
+    if ex.cfg.max_nodes.is_some() && !timed {
+        ex.time_settings.infinite = true;
+    }
+
 }
 
 // info depth 245